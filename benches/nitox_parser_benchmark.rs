@@ -25,6 +25,7 @@ fn benchmark_parser(c: &mut Criterion) {
                 subject: String::new(),
                 payload: bytes::Bytes::new(),
                 reply_to: None,
+                headers: None,
             }.into_vec()
         })
     });
@@ -86,10 +87,15 @@ fn benchmark_parser(c: &mut Criterion) {
     c.bench_function("message_write", |b| {
         b.iter(|| {
             Message {
-                subject: String::new(),
+                subject: "".into(),
                 sid: String::new(),
                 reply_to: None,
                 payload: bytes::Bytes::new(),
+                headers: None,
+                status: None,
+                description: None,
+                wire_size: 0,
+                received_at: None,
             }.into_vec()
         })
     });
@@ -1,25 +1,37 @@
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 
 use futures::{
-    future::{self, Either},
+    future::{self, Either, Loop},
     prelude::*,
     stream,
-    sync::mpsc,
+    sync::{mpsc, oneshot},
+    task::{self, AtomicTask, Task},
     Future,
 };
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json as json;
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    marker::PhantomData,
     net::{SocketAddr, ToSocketAddrs},
+    ops::Deref,
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 use tokio_executor;
+use tokio_timer::Delay;
 use url::Url;
 
+use codec::{DecoderStats, OpCodec, DEFAULT_MAX_CONTROL_LINE, DEFAULT_MAX_PAYLOAD};
+use compression::{compress_command, decompress_message};
 use error::NatsError;
 use net::*;
-use protocol::{commands::*, Op};
+use protocol::{commands::*, subject_matches, Op};
 
 /// Sink (write) part of a TCP stream
 type NatsSink = stream::SplitSink<NatsConnection>;
@@ -27,128 +39,2499 @@ type NatsSink = stream::SplitSink<NatsConnection>;
 type NatsStream = stream::SplitStream<NatsConnection>;
 /// Useless pretty much, just for code semantics
 type NatsSubscriptionId = String;
+/// Inbox of a request, along with the future of its subscription, the future of the reply and the way to
+/// release the inbox
+type RequestInbox = (
+    String,
+    Box<dyn Future<Item = (), Error = NatsError> + Send + Sync>,
+    Box<dyn Future<Item = Message, Error = NatsError> + Send + Sync>,
+    RequestSubscription,
+);
+/// Interceptor registered through `NatsClient::intercept()`
+type MessageInterceptor = Arc<dyn Fn(&Message) -> Option<Message> + Send + Sync>;
+/// Hook registered through `NatsClient::before_request()`
+type BeforeRequestHook = Arc<dyn Fn(&mut PubCommand) + Send + Sync>;
+/// Hook registered through `NatsClient::after_request()`
+type AfterRequestHook =
+    Arc<dyn Fn(&PubCommand, Result<Message, NatsError>, Duration) -> Result<Message, NatsError> + Send + Sync>;
 
-/// Keep-alive for the sink, also supposed to take care of handling verbose messaging, but can't for now
+/// Hooks wrapped around every request sent by the client, in the order they've been registered
+#[derive(Default)]
+struct RequestMiddleware {
+    before: Vec<BeforeRequestHook>,
+    after: Vec<AfterRequestHook>,
+}
+
+impl ::std::fmt::Debug for RequestMiddleware {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("RequestMiddleware")
+            .field("before", &self.before.len())
+            .field("after", &self.after.len())
+            .finish()
+    }
+}
+
+/// Acknowledgements awaited from the server: the `+OK` of the commands sent in verbose mode, and the PONG of
+/// the PINGs we send. The server answers both in order, so they're matched to their commands by counting
+#[derive(Debug, Default)]
+struct AckWaiters {
+    /// Commands sent so far that the server acknowledges in verbose mode
+    oks_expected: usize,
+    /// `+OK` (or `-ERR` in their place) received so far
+    oks_received: usize,
+    /// Waiters of a `+OK`, along with the count of acknowledged commands at which it is theirs
+    oks: VecDeque<(usize, oneshot::Sender<Result<(), NatsError>>)>,
+    pongs: VecDeque<oneshot::Sender<()>>,
+}
+
+/// Wakes up all the tasks waiting for room in the channel to the connection
+fn notify_all(blocked: &Mutex<Vec<Task>>) {
+    for task in blocked.lock().drain(..) {
+        task.notify();
+    }
+}
+
+/// Default amount of ops queued for the connection, past which sending waits for the connection to catch up
+pub const DEFAULT_SEND_BUFFER_SIZE: usize = 8192;
+
+/// Keep-alive for the sink, also taking care of matching the acknowledgements of the server to the commands
 #[derive(Clone, Debug)]
 struct NatsClientSender {
-    tx: mpsc::UnboundedSender<Op>,
-    verbose: bool,
+    /// Bounded channel to the connection, shared by all the clones so they're all subject to the same bound
+    tx: Arc<Mutex<mpsc::Sender<Op>>>,
+    /// Tasks waiting for room in the channel, woken up every time the connection takes an op from it
+    blocked: Arc<Mutex<Vec<Task>>>,
+    /// Whether the server has been asked for `+OK` acknowledgements by the CONNECT sent
+    verbose: Arc<AtomicBool>,
+    acks: Arc<Mutex<AckWaiters>>,
 }
 
 impl NatsClientSender {
-    pub fn new(sink: NatsSink) -> Self {
-        let (tx, rx) = mpsc::unbounded();
-        let rx = rx.map_err(|_| NatsError::InnerBrokenChain);
-        let work = sink.send_all(rx).map(|_| ()).map_err(|_| ());
+    pub fn new(sink: NatsSink, buffer_size: usize) -> Self {
+        let (tx, rx) = mpsc::channel(buffer_size);
+        let blocked: Arc<Mutex<Vec<Task>>> = Arc::new(Mutex::new(Vec::new()));
+        let blocked_rx = Arc::clone(&blocked);
+        let blocked_end = Arc::clone(&blocked);
+        let rx = rx
+            .inspect(move |_| notify_all(&blocked_rx))
+            .map_err(|_| NatsError::InnerBrokenChain);
+        // Once the connection is gone, the waiters find the channel closed
+        let work = sink.send_all(rx).then(move |_| {
+            notify_all(&blocked_end);
+            Ok(())
+        });
         tokio_executor::spawn(work);
 
-        NatsClientSender { tx, verbose: false }
+        NatsClientSender {
+            tx: Arc::new(Mutex::new(tx)),
+            blocked,
+            verbose: Arc::new(AtomicBool::new(false)),
+            acks: Arc::new(Mutex::new(AckWaiters::default())),
+        }
+    }
+
+    /// Amount of commands an OP is made of, each of them being acknowledged in verbose mode
+    fn commands(op: &Op) -> usize {
+        match op {
+            Op::CONNECT(_) | Op::PUB(_) | Op::SUB(_) | Op::UNSUB(_) => 1,
+            _ => 0,
+        }
+    }
+
+    /// Queues an OP made of `commands` commands if the channel has room, counting the `+OK`s they'll get. Has to
+    /// be called with the waiters locked, so that the counting happens in the same order as the sending.
+    ///
+    /// The OP is given back when the channel is full. With `park`, the current task is then woken up once the
+    /// connection has made room, which requires being called from a task
+    fn start_queue_locked(
+        &self,
+        acks: &mut AckWaiters,
+        op: Op,
+        commands: usize,
+        park: bool,
+    ) -> Result<AsyncSink<Op>, NatsError> {
+        let verbose = match op {
+            Op::CONNECT(ref cmd) => Some(cmd.verbose),
+            _ => None,
+        };
+
+        let mut tx = self.tx.lock();
+        let mut op = op;
+        for attempt in 0..2 {
+            match tx.try_send(op) {
+                Ok(_) => {
+                    if let Some(verbose) = verbose {
+                        self.verbose.store(verbose, Ordering::SeqCst);
+                    }
+
+                    if self.verbose.load(Ordering::SeqCst) {
+                        acks.oks_expected += commands;
+                    }
+
+                    return Ok(AsyncSink::Ready);
+                }
+                Err(ref e) if e.is_disconnected() => return Err(NatsError::InnerBrokenChain),
+                Err(e) => op = e.into_inner(),
+            }
+
+            if !park || attempt > 0 {
+                break;
+            }
+
+            // Room may have been made between the failed attempt and the registration, hence the second one
+            self.blocked.lock().push(task::current());
+        }
+
+        Ok(AsyncSink::NotReady(op))
     }
 
-    #[allow(dead_code)]
-    pub fn set_verbose(&mut self, verbose: bool) {
-        self.verbose = verbose;
+    /// Queues an OP made of `commands` commands as soon as the channel has room, then calls `then` with the
+    /// waiters still locked. The OP is queued right away when there's room already, even if the returned
+    /// future is never polled
+    fn queue<F, T>(&self, op: Op, commands: usize, then: F) -> impl Future<Item = T, Error = NatsError>
+    where
+        F: FnOnce(&NatsClientSender, &mut AckWaiters) -> T,
+    {
+        let op = {
+            let mut acks = self.acks.lock();
+            match self.start_queue_locked(&mut acks, op, commands, false) {
+                Ok(AsyncSink::Ready) => return Either::A(future::ok(then(self, &mut acks))),
+                Ok(AsyncSink::NotReady(op)) => op,
+                Err(e) => return Either::A(future::err(e)),
+            }
+        };
+
+        debug!(target: "nitox", "Send buffer is full, waiting for the connection to catch up");
+        let sender = self.clone();
+        let mut pending = Some((op, then));
+        Either::B(future::poll_fn(move || {
+            let (op, then) = pending.take().ok_or(NatsError::InnerBrokenChain)?;
+            let mut acks = sender.acks.lock();
+            match sender.start_queue_locked(&mut acks, op, commands, true)? {
+                AsyncSink::Ready => Ok(Async::Ready(then(&sender, &mut acks))),
+                AsyncSink::NotReady(op) => {
+                    pending = Some((op, then));
+                    Ok(Async::NotReady)
+                }
+            }
+        }))
     }
 
-    /// Sends an OP to the server
+    /// Sends an OP to the server, the future resolves once it has been queued for the connection
     pub fn send(&self, op: Op) -> impl Future<Item = (), Error = NatsError> {
-        //let _verbose = self.verbose.clone();
-        self.tx
-            .unbounded_send(op)
-            .map_err(|_| NatsError::InnerBrokenChain)
-            .into_future()
+        let commands = Self::commands(&op);
+        self.queue(op, commands, |_, _| ())
+    }
+
+    /// Queues an OP if the channel has room, giving it back otherwise. The current task is woken up once
+    /// there's room, so this is meant to be called from a task, e.g. by a `Sink`
+    pub fn start_send(&self, op: Op) -> StartSend<Op, NatsError> {
+        let commands = Self::commands(&op);
+        let mut acks = self.acks.lock();
+        self.start_queue_locked(&mut acks, op, commands, true)
+    }
+
+    /// Queues an OP even if the channel is full, for the ops that cannot wait such as the UNSUB sent when
+    /// a subscription is dropped. A new handle on the channel is always given a slot
+    pub fn send_now(&self, op: Op) -> Result<(), NatsError> {
+        let commands = Self::commands(&op);
+        let mut acks = self.acks.lock();
+        let mut tx = self.tx.lock().clone();
+        tx.try_send(op).map_err(|_| NatsError::InnerBrokenChain)?;
+        if self.verbose.load(Ordering::SeqCst) {
+            acks.oks_expected += commands;
+        }
+
+        Ok(())
+    }
+
+    /// Sends an OP to the server, resolving once the server has processed it: on its `+OK` in verbose mode,
+    /// or on the PONG of a PING sent right after it otherwise
+    pub fn send_acknowledged(&self, op: Op) -> impl Future<Item = (), Error = NatsError> {
+        let commands = Self::commands(&op);
+        self.queue_acknowledged(op, commands)
+    }
+
+    /// Sends `commands` commands already encoded in a single write, the future resolves once it has been queued
+    /// for the connection
+    pub fn send_raw(&self, buf: Bytes, commands: usize) -> impl Future<Item = (), Error = NatsError> {
+        self.queue(Op::RAW(buf), commands, |_, _| ())
+    }
+
+    /// Sends `commands` commands already encoded in a single write, resolving once the server has processed
+    /// them, like `send_acknowledged()`
+    pub fn send_encoded(&self, buf: Bytes, commands: usize) -> impl Future<Item = (), Error = NatsError> {
+        self.queue_acknowledged(Op::RAW(buf), commands)
+    }
+
+    /// Queues an OP and waits for its acknowledgement: its `+OK` in verbose mode, or the PONG of a PING
+    /// otherwise, and when there's nothing to acknowledge
+    fn queue_acknowledged(&self, op: Op, commands: usize) -> impl Future<Item = (), Error = NatsError> {
+        let sender = self.clone();
+        self.queue(op, commands, |sender, acks| {
+            if sender.verbose.load(Ordering::SeqCst) && acks.oks_expected > acks.oks_received {
+                let (tx, rx) = oneshot::channel();
+                let position = acks.oks_expected;
+                acks.oks.push_back((position, tx));
+                Some(rx)
+            } else {
+                None
+            }
+        })
+        .and_then(move |ok| match ok {
+            Some(rx) => Either::A(rx.map_err(|_| NatsError::InnerBrokenChain).and_then(|result| result)),
+            None => Either::B(sender.flush()),
+        })
+    }
+
+    /// Sends a PING, resolving on its PONG once the server has processed everything sent before
+    pub fn flush(&self) -> impl Future<Item = (), Error = NatsError> {
+        self.queue(Op::PING, 0, |_, acks| {
+            let (tx, rx) = oneshot::channel();
+            acks.pongs.push_back(tx);
+            rx
+        })
+        .and_then(|rx| rx.map_err(|_| NatsError::InnerBrokenChain))
+    }
+
+    /// Matches a `+OK`, or a `-ERR` sent in place of one, to the command it acknowledges
+    fn acknowledge(&self, result: Result<(), NatsError>) {
+        let mut acks = self.acks.lock();
+        // Errors unrelated to a command can't be told apart, but there's nothing to match them to when idle
+        if acks.oks_received == acks.oks_expected {
+            return;
+        }
+
+        acks.oks_received += 1;
+        let received = acks.oks_received;
+        let mut result = Some(result);
+        while acks.oks.front().is_some_and(|&(position, _)| position <= received) {
+            if let Some((position, tx)) = acks.oks.pop_front() {
+                let _ = if position == received {
+                    tx.send(result.take().unwrap_or(Ok(())))
+                } else {
+                    tx.send(Ok(()))
+                };
+            }
+        }
+    }
+
+    /// Resolves the oldest flush waiting for its PONG
+    fn acknowledge_pong(&self) {
+        if let Some(tx) = self.acks.lock().pongs.pop_front() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+/// Default maximum amount of messages buffered for a subscription, past which messages are dropped
+pub const DEFAULT_PENDING_MSGS_LIMIT: usize = 512 * 1024;
+/// Default maximum amount of payload bytes buffered for a subscription, past which messages are dropped
+pub const DEFAULT_PENDING_BYTES_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Bounds of the buffer of each subscription, and how to report that they've been reached
+#[derive(Debug, Clone, Copy)]
+struct PendingLimits {
+    msgs: usize,
+    bytes: usize,
+    /// Also report slow consumers by an error on the stream of the subscription
+    slow_consumer_errors: bool,
+}
+
+impl PendingLimits {
+    /// Applies the limits set on a subscription over the ones of the client
+    fn with_overrides(self, msgs: Option<usize>, bytes: Option<usize>) -> Self {
+        PendingLimits {
+            msgs: msgs.unwrap_or(self.msgs),
+            bytes: bytes.unwrap_or(self.bytes),
+            ..self
+        }
+    }
+}
+
+/// Live counters of a subscription, returned by `Subscription::stats()`. The handle is shared with the
+/// subscription, so it can be kept around for monitoring once the subscription has been moved into a future
+#[derive(Debug, Default, Clone)]
+pub struct SubscriptionStats {
+    delivered_msgs: Arc<AtomicUsize>,
+    delivered_bytes: Arc<AtomicUsize>,
+    /// Messages, and their payload bytes, received but not consumed from the stream yet
+    pending_msgs: Arc<AtomicUsize>,
+    pending_bytes: Arc<AtomicUsize>,
+    /// Messages dropped because the buffer was full
+    dropped: Arc<AtomicUsize>,
+    /// Messages discarded by the filter of the subscription
+    filtered: Arc<AtomicUsize>,
+    /// Set while messages are being dropped, until one fits in the buffer again
+    slow: Arc<AtomicBool>,
+}
+
+impl SubscriptionStats {
+    /// Amount of messages consumed from the stream of the subscription so far
+    pub fn delivered_msgs(&self) -> usize {
+        self.delivered_msgs.load(Ordering::Relaxed)
+    }
+
+    /// Amount of payload bytes consumed from the stream of the subscription so far
+    pub fn delivered_bytes(&self) -> usize {
+        self.delivered_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Amount of messages dropped so far because the buffer of the subscription was full
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Amount of messages discarded so far because their subject didn't pass the filter of the subscription
+    pub fn filtered(&self) -> usize {
+        self.filtered.load(Ordering::Relaxed)
+    }
+
+    /// Amount of messages received but not consumed from the stream yet
+    pub fn pending_msgs(&self) -> usize {
+        self.pending_msgs.load(Ordering::Relaxed)
+    }
+
+    /// Amount of payload bytes received but not consumed from the stream yet
+    pub fn pending_bytes(&self) -> usize {
+        self.pending_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Accounts for a new pending message, unless it would exceed the limits
+    fn try_add(&self, len: usize, limits: PendingLimits) -> bool {
+        let msgs = self.pending_msgs.fetch_add(1, Ordering::AcqRel) + 1;
+        let bytes = self.pending_bytes.fetch_add(len, Ordering::AcqRel) + len;
+        if msgs > limits.msgs || bytes > limits.bytes {
+            self.remove(len);
+            return false;
+        }
+
+        true
+    }
+
+    /// Accounts for a new pending message regardless of the limits
+    fn add(&self, len: usize) {
+        self.pending_msgs.fetch_add(1, Ordering::AcqRel);
+        self.pending_bytes.fetch_add(len, Ordering::AcqRel);
+    }
+
+    /// Removes a message from pending without delivering it
+    fn remove(&self, len: usize) {
+        self.pending_msgs.fetch_sub(1, Ordering::AcqRel);
+        self.pending_bytes.fetch_sub(len, Ordering::AcqRel);
+    }
+
+    /// Moves a message from pending to delivered
+    fn deliver(&self, len: usize) {
+        self.remove(len);
+        self.delivered_msgs.fetch_add(1, Ordering::Relaxed);
+        self.delivered_bytes.fetch_add(len, Ordering::Relaxed);
+    }
+}
+
+/// Filter on the subjects of the messages delivered to a subscription, set with `Subscription::set_filter()`.
+/// Mostly useful for wildcard subscriptions
+#[derive(Clone)]
+pub enum SubjectFilter {
+    /// Only the subjects for which the predicate returns `true` are delivered
+    Predicate(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+    /// Only the subjects starting with one of the prefixes are delivered
+    Prefixes(Vec<String>),
+}
+
+impl SubjectFilter {
+    pub fn predicate<F: Fn(&str) -> bool + Send + Sync + 'static>(predicate: F) -> Self {
+        SubjectFilter::Predicate(Arc::new(predicate))
+    }
+
+    pub fn prefixes<S: Into<String>, I: IntoIterator<Item = S>>(prefixes: I) -> Self {
+        SubjectFilter::Prefixes(prefixes.into_iter().map(Into::into).collect())
+    }
+
+    /// Returns `true` if the messages received on this subject should be delivered
+    pub fn matches(&self, subject: &str) -> bool {
+        match self {
+            SubjectFilter::Predicate(predicate) => predicate(subject),
+            SubjectFilter::Prefixes(prefixes) => prefixes.iter().any(|prefix| subject.starts_with(prefix.as_str())),
+        }
+    }
+}
+
+impl ::std::fmt::Debug for SubjectFilter {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            SubjectFilter::Predicate(_) => f.write_str("Predicate(Fn...)"),
+            SubjectFilter::Prefixes(prefixes) => f.debug_tuple("Prefixes").field(prefixes).finish(),
+        }
+    }
+}
+
+/// What to do with the messages received for a subscription whose buffer is full
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the message that has just been received, that's the default
+    #[default]
+    DropNewest,
+    /// Drop the oldest buffered messages to make room for the one that has just been received. A message that
+    /// exceeds the limits on its own is buffered if nothing else is, and dropped otherwise
+    DropOldest,
+    /// Stop reading from the connection until the subscription consumes its messages. This holds back every
+    /// other subscription and the PING/PONG exchange with the server as well, so it's meant for subscriptions
+    /// that are consumed steadily and must not lose messages
+    Backpressure,
+}
+
+/// Messages received for a subscription and not consumed yet. Filled by the multiplexer and consumed by the
+/// `SubscriptionReceiver`, each side waking the other up
+#[derive(Debug)]
+struct SubscriptionBuffer {
+    queue: Mutex<VecDeque<Result<Message, NatsError>>>,
+    /// Set once the subscription has been de-registered, nothing is buffered anymore
+    closed: AtomicBool,
+    /// Task waiting for messages
+    consumer: AtomicTask,
+    /// Task waiting for room in the buffer, with `OverflowPolicy::Backpressure`
+    producer: AtomicTask,
+    policy: OverflowPolicy,
+    /// Messages and bytes limits set with `Subscription::set_pending_limits()`, overriding the ones of the client
+    limits: RwLock<(Option<usize>, Option<usize>)>,
+    stats: SubscriptionStats,
+}
+
+impl SubscriptionBuffer {
+    fn new(policy: OverflowPolicy) -> Self {
+        SubscriptionBuffer {
+            queue: Mutex::new(VecDeque::new()),
+            closed: AtomicBool::new(false),
+            consumer: AtomicTask::new(),
+            producer: AtomicTask::new(),
+            policy,
+            limits: RwLock::new((None, None)),
+            stats: SubscriptionStats::default(),
+        }
+    }
+
+    /// Sets the limits of this buffer, `None` falling back to the limits of the client
+    fn set_limits(&self, msgs: Option<usize>, bytes: Option<usize>) {
+        *self.limits.write() = (msgs, bytes);
+        // Raised limits can make room for a message held back by `OverflowPolicy::Backpressure`
+        self.producer.notify();
+    }
+
+    /// Buffers a message according to the overflow policy. The message is given back when it has to wait for
+    /// room in the buffer, the current task is then notified once some room has been made
+    fn try_push(&self, msg: Message, limits: PendingLimits, events: &NatsEventEmitter) -> Option<Message> {
+        let mut queue = self.queue.lock();
+        if self.closed.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let (msgs_limit, bytes_limit) = *self.limits.read();
+        let limits = limits.with_overrides(msgs_limit, bytes_limit);
+        let len = msg.payload.len();
+        // Still slow if room had to be made for the message
+        let mut evicted = false;
+        let fits = match self.policy {
+            OverflowPolicy::DropNewest => self.stats.try_add(len, limits),
+            OverflowPolicy::DropOldest if limits.msgs == 0 || len > limits.bytes => {
+                // Dropping the buffered messages wouldn't make enough room
+                if self.stats.pending_msgs() == 0 {
+                    self.stats.add(len);
+                    true
+                } else {
+                    false
+                }
+            }
+            OverflowPolicy::DropOldest => {
+                let mut fits = self.stats.try_add(len, limits);
+                while !fits {
+                    // Errors stay queued, only messages are dropped
+                    let oldest = match queue.iter().position(Result::is_ok) {
+                        Some(position) => queue.remove(position),
+                        None => break,
+                    };
+
+                    if let Some(Ok(oldest)) = oldest {
+                        debug!(target: "nitox", "Buffer of sid {} is full, dropping oldest message", msg.sid);
+                        self.stats.remove(oldest.payload.len());
+                        self.report_dropped(&msg.sid, &mut queue, limits, events);
+                        evicted = true;
+                    }
+
+                    fits = self.stats.try_add(len, limits);
+                }
+
+                fits
+            }
+            OverflowPolicy::Backpressure => {
+                if self.stats.try_add(len, limits) {
+                    true
+                } else if queue.is_empty() {
+                    // The message alone exceeds the limits, waiting wouldn't make any room
+                    self.stats.add(len);
+                    true
+                } else {
+                    debug!(target: "nitox", "Buffer of sid {} is full, waiting for room", msg.sid);
+                    self.producer.register();
+                    return Some(msg);
+                }
+            }
+        };
+
+        if fits {
+            if !evicted {
+                self.stats.slow.store(false, Ordering::Release);
+            }
+
+            queue.push_back(Ok(msg));
+        } else {
+            debug!(target: "nitox", "Buffer of sid {} is full, dropping message", msg.sid);
+            self.report_dropped(&msg.sid, &mut queue, limits, events);
+        }
+
+        drop(queue);
+        self.consumer.notify();
+        None
+    }
+
+    /// Counts a dropped message. Slow consumers are only reported when the subscription becomes slow, not for
+    /// every dropped message
+    fn report_dropped(
+        &self,
+        sid: &str,
+        queue: &mut VecDeque<Result<Message, NatsError>>,
+        limits: PendingLimits,
+        events: &NatsEventEmitter,
+    ) {
+        let dropped = self.stats.dropped.fetch_add(1, Ordering::AcqRel) + 1;
+        if !self.stats.slow.swap(true, Ordering::AcqRel) {
+            warn!(target: "nitox", "Slow consumer on sid {}, {} messages dropped", sid, dropped);
+            if limits.slow_consumer_errors {
+                queue.push_back(Err(NatsError::SlowConsumer(dropped)));
+            }
+
+            events.emit(NatsClientEvent::SlowConsumer {
+                sid: sid.to_string(),
+                dropped,
+            });
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Ends the stream once the buffered messages have been consumed
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.consumer.notify();
+        self.producer.notify();
+    }
+
+    fn poll_next(&self) -> Poll<Option<Message>, NatsError> {
+        let mut queue = self.queue.lock();
+        match queue.pop_front() {
+            Some(Ok(msg)) => {
+                self.stats.deliver(msg.payload.len());
+                drop(queue);
+                self.producer.notify();
+                Ok(Async::Ready(Some(msg)))
+            }
+            Some(Err(e)) => Err(e),
+            None if self.closed.load(Ordering::Acquire) => Ok(Async::Ready(None)),
+            None => {
+                self.consumer.register();
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// Stream of the messages of a subscription, as registered by `NatsClientMultiplexer::for_sid()` or
+/// `Subscription::fork()`
+#[derive(Debug)]
+pub struct SubscriptionReceiver {
+    buffer: Arc<SubscriptionBuffer>,
+}
+
+impl SubscriptionReceiver {
+    /// Returns a handle on the counters of this stream, which are distinct from the ones of the other streams
+    /// of the same subscription
+    pub fn stats(&self) -> SubscriptionStats {
+        self.buffer.stats.clone()
+    }
+}
+
+impl Stream for SubscriptionReceiver {
+    type Error = NatsError;
+    type Item = Message;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.buffer.poll_next()
+    }
+}
+
+impl Drop for SubscriptionReceiver {
+    fn drop(&mut self) {
+        // Nobody reads from the buffer anymore, the multiplexer will skip it
+        self.buffer.close();
     }
 }
 
 #[derive(Debug)]
 struct SubscriptionSink {
-    tx: mpsc::UnboundedSender<Message>,
-    max_count: Option<u32>,
-    count: u32,
+    /// Buffers of the streams of the subscription, each of them gets every message
+    buffers: Vec<Arc<SubscriptionBuffer>>,
+    /// Messages on subjects that don't pass the filter are discarded here, before being buffered
+    filter: Option<SubjectFilter>,
+    /// Messages received so far, filtered or not
+    received: AtomicUsize,
+    /// The subscription is de-registered once that many messages have been received, set by
+    /// `Subscription::set_max_msgs()`
+    max_msgs: Option<usize>,
+}
+
+impl Drop for SubscriptionSink {
+    fn drop(&mut self) {
+        // De-registering the subscription stops the deliveries, the receivers still yield what has been buffered
+        for buffer in &self.buffers {
+            buffer.close();
+        }
+    }
 }
 
 /// Internal multiplexer for incoming streams and subscriptions. Quite a piece of code, with almost no overhead yay
-#[derive(Debug)]
 struct NatsClientMultiplexer {
     other_tx: Arc<mpsc::UnboundedSender<Op>>,
     subs_tx: Arc<RwLock<HashMap<NatsSubscriptionId, SubscriptionSink>>>,
+    /// Interceptors registered through `NatsClient::intercept()`, in order
+    interceptors: Arc<RwLock<Vec<MessageInterceptor>>>,
+    /// Last sid generated by `generate_sid()`
+    last_sid: AtomicUsize,
+}
+
+impl ::std::fmt::Debug for NatsClientMultiplexer {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("NatsClientMultiplexer")
+            .field("other_tx", &self.other_tx)
+            .field("subs_tx", &self.subs_tx)
+            .field("interceptors", &self.interceptors.read().len())
+            .field("last_sid", &self.last_sid)
+            .finish()
+    }
 }
 
 impl NatsClientMultiplexer {
-    pub fn new(stream: NatsStream) -> (Self, mpsc::UnboundedReceiver<Op>) {
+    /// Gzipped messages are decompressed up to `max_decompressed` bytes, and not at all without it
+    pub fn new(
+        stream: NatsStream,
+        limits: PendingLimits,
+        events: NatsEventEmitter,
+        max_decompressed: Option<usize>,
+    ) -> (Self, mpsc::UnboundedReceiver<Op>) {
         let subs_tx: Arc<RwLock<HashMap<NatsSubscriptionId, SubscriptionSink>>> =
             Arc::new(RwLock::new(HashMap::default()));
 
         let (other_tx, other_rx) = mpsc::unbounded();
         let other_tx = Arc::new(other_tx);
 
+        let interceptors: Arc<RwLock<Vec<MessageInterceptor>>> = Arc::new(RwLock::new(Vec::new()));
+
         let stx_inner = Arc::clone(&subs_tx);
         let otx_inner = Arc::clone(&other_tx);
+        let interceptors_inner = Arc::clone(&interceptors);
+        let stx_end = Arc::clone(&subs_tx);
 
         // Here we filter the incoming TCP stream Messages by subscription ID and sending it to the appropriate Sender
         let work_tx = stream
             .for_each(move |op| {
                 match op {
                     Op::MSG(msg) => {
-                        debug!(target: "nitox", "Found MSG from global Stream {:?}", msg);
-                        if let Some(s) = (*stx_inner.read()).get(&msg.sid) {
-                            debug!(target: "nitox", "Found multiplexed receiver to send to {}", msg.sid);
-                            let _ = s.tx.unbounded_send(msg);
-                        }
+                        debug!(target: "nitox", "Found MSG from global Stream {}", msg);
+                        let sid = msg.sid.clone();
+                        let (buffers, reached_max, mut msg) = {
+                            let subs = stx_inner.read();
+                            let s = match subs.get(&sid) {
+                                Some(s) => s,
+                                None => return Either::A(future::ok(())),
+                            };
+
+                            // The server counts every message delivered on the sid, filtered or not
+                            let received = s.received.fetch_add(1, Ordering::AcqRel) + 1;
+                            let reached_max = s.max_msgs.is_some_and(|max| received >= max);
+                            let msg = match max_decompressed {
+                                Some(max_len) => decompress_message(msg, max_len),
+                                None => msg,
+                            };
+                            let msg = interceptors_inner
+                                .read()
+                                .iter()
+                                .try_fold(msg, |msg, interceptor| interceptor(&msg));
+
+                            let filtered = |msg: &Message| s.filter.as_ref().is_some_and(|f| !f.matches(&msg.subject));
+                            match msg {
+                                None => {
+                                    debug!(target: "nitox", "Message dropped by an interceptor for sid {}", sid);
+                                    (Vec::new(), reached_max, None)
+                                }
+                                Some(ref msg) if filtered(msg) => {
+                                    debug!(target: "nitox", "Subject {} filtered out for sid {}", msg.subject, sid);
+                                    s.buffers[0].stats.filtered.fetch_add(1, Ordering::Relaxed);
+                                    (Vec::new(), reached_max, None)
+                                }
+                                msg => (s.buffers.clone(), reached_max, msg),
+                            }
+                        };
+
+                        debug!(target: "nitox", "Found {} multiplexed receivers to send to {}", buffers.len(), sid);
+                        // Only waits for subscriptions with `OverflowPolicy::Backpressure`, which holds back the read loop
+                        let events = events.clone();
+                        let stx = Arc::clone(&stx_inner);
+                        let mut next = 0;
+                        Either::B(future::poll_fn(move || {
+                            while next < buffers.len() {
+                                // The last buffer gets the message itself, the others a copy
+                                let last = next + 1 == buffers.len();
+                                let item = if last {
+                                    msg.take().unwrap()
+                                } else {
+                                    msg.clone().unwrap()
+                                };
+
+                                if let Some(waiting) = buffers[next].try_push(item, limits, &events) {
+                                    if last {
+                                        msg = Some(waiting);
+                                    }
+
+                                    return Ok(Async::NotReady);
+                                }
+
+                                next += 1;
+                            }
+
+                            if reached_max {
+                                debug!(target: "nitox", "Reached the maximum amount of messages on sid {}", sid);
+                                stx.write().remove(&sid);
+                            }
+
+                            Ok(Async::Ready(()))
+                        }))
                     }
                     // Forward the rest of the messages to the owning client
                     op => {
-                        debug!(target: "nitox", "Sending OP to the rest of the queue: {:?}", op);
+                        debug!(target: "nitox", "Sending OP to the rest of the queue: {}", op);
                         let _ = otx_inner.unbounded_send(op);
+                        Either::A(future::ok::<(), NatsError>(()))
                     }
                 }
-
-                future::ok::<(), NatsError>(())
-            }).map(|_| ())
-            .map_err(|_| ());
+            })
+            .then(move |_| {
+                // Nothing will be received anymore, the subscriptions end once their buffers have been consumed
+                debug!(target: "nitox", "Connection has ended, closing all subscriptions");
+                stx_end.write().clear();
+                Ok(())
+            });
 
         tokio_executor::spawn(work_tx);
 
-        (NatsClientMultiplexer { subs_tx, other_tx }, other_rx)
+        (
+            NatsClientMultiplexer {
+                subs_tx,
+                other_tx,
+                interceptors,
+                last_sid: AtomicUsize::new(0),
+            },
+            other_rx,
+        )
     }
 
-    pub fn for_sid(&self, sid: NatsSubscriptionId) -> impl Stream<Item = Message, Error = NatsError> + Send + Sync {
-        let (tx, rx) = mpsc::unbounded();
-        (*self.subs_tx.write()).insert(
-            sid,
-            SubscriptionSink {
-                tx,
-                max_count: None,
-                count: 0,
-            },
-        );
+    /// Generates a sid that is unique for this connection
+    pub fn generate_sid(&self) -> NatsSubscriptionId {
+        (self.last_sid.fetch_add(1, Ordering::Relaxed) + 1).to_string()
+    }
 
-        rx.map_err(|_| NatsError::InnerBrokenChain)
+    /// Registers a subscription, returns the stream of its messages along with its stats. Fails if the sid is
+    /// already registered, since the messages of both subscriptions would be mixed up
+    pub fn for_sid(
+        &self,
+        sid: NatsSubscriptionId,
+        policy: OverflowPolicy,
+    ) -> Result<
+        (
+            impl Stream<Item = Message, Error = NatsError> + Send + Sync,
+            SubscriptionStats,
+        ),
+        NatsError,
+    > {
+        let buffer = Arc::new(SubscriptionBuffer::new(policy));
+        match (*self.subs_tx.write()).entry(sid) {
+            Entry::Occupied(entry) => return Err(NatsError::DuplicateSid(entry.key().clone())),
+            Entry::Vacant(entry) => {
+                entry.insert(SubscriptionSink {
+                    buffers: vec![Arc::clone(&buffer)],
+                    filter: None,
+                    received: AtomicUsize::new(0),
+                    max_msgs: None,
+                });
+            }
+        }
+
+        let stats = buffer.stats.clone();
+        Ok((SubscriptionReceiver { buffer }, stats))
     }
 
-    pub fn remove_sid(&self, sid: &str) {
-        (*self.subs_tx.write()).remove(sid);
+    /// Adds a stream to a registered subscription, which gets a copy of every message. The stream ends right
+    /// away if the subscription is not registered anymore
+    pub fn fork_sid(&self, sid: &str) -> SubscriptionReceiver {
+        let mut subs = self.subs_tx.write();
+        let buffer = match subs.get_mut(sid) {
+            Some(s) => {
+                // Forks that have been dropped don't need to be kept around
+                s.buffers.retain(|buffer| !buffer.is_closed());
+                let policy = s.buffers.first().map(|buffer| buffer.policy).unwrap_or_default();
+                let buffer = Arc::new(SubscriptionBuffer::new(policy));
+                if let Some(first) = s.buffers.first() {
+                    let (msgs, bytes) = *first.limits.read();
+                    buffer.set_limits(msgs, bytes);
+                }
+
+                s.buffers.push(Arc::clone(&buffer));
+                buffer
+            }
+            None => {
+                let buffer = Arc::new(SubscriptionBuffer::new(OverflowPolicy::default()));
+                buffer.close();
+                buffer
+            }
+        };
+
+        SubscriptionReceiver { buffer }
     }
-}
 
-/// Options that are to be given to the client for initialization
-#[derive(Debug, Default, Clone, Builder)]
-#[builder(setter(into))]
-pub struct NatsClientOptions {
-    /// CONNECT command that will be sent upon calling the `connect()` method
-    pub connect_command: ConnectCommand,
-    /// Cluster URI in the IP:PORT format
-    pub cluster_uri: String,
-}
+    pub fn set_pending_limits(&self, sid: &str, msgs: Option<usize>, bytes: Option<usize>) {
+        if let Some(s) = self.subs_tx.read().get(sid) {
+            for buffer in &s.buffers {
+                buffer.set_limits(msgs, bytes);
+            }
+        }
+    }
 
-impl NatsClientOptions {
-    pub fn builder() -> NatsClientOptionsBuilder {
-        NatsClientOptionsBuilder::default()
+    pub fn set_filter(&self, sid: &str, filter: Option<SubjectFilter>) {
+        if let Some(s) = self.subs_tx.write().get_mut(sid) {
+            s.filter = filter;
+        }
     }
-}
 
-/// The NATS Client. What you'll be using mostly. All the async handling is made internally except for
-/// the system messages that are forwarded on the `Stream` that the client implements
+    /// Sets the amount of messages after which the subscription is de-registered, which is done right away if
+    /// that many messages have already been received
+    pub fn set_max_msgs(&self, sid: &str, max_msgs: usize) {
+        let mut subs = self.subs_tx.write();
+        let reached_max = match subs.get_mut(sid) {
+            Some(s) => {
+                s.max_msgs = Some(max_msgs);
+                s.received.load(Ordering::Acquire) >= max_msgs
+            }
+            None => false,
+        };
+
+        if reached_max {
+            debug!(target: "nitox", "Sid {} has already received {} messages", sid, max_msgs);
+            subs.remove(sid);
+        }
+    }
+
+    pub fn remove_sid(&self, sid: &str) {
+        (*self.subs_tx.write()).remove(sid);
+    }
+
+    /// De-registers every subscription, returns their sids
+    pub fn remove_all(&self) -> Vec<NatsSubscriptionId> {
+        (*self.subs_tx.write()).drain().map(|(sid, _)| sid).collect()
+    }
+
+    pub fn add_interceptor(&self, interceptor: MessageInterceptor) {
+        self.interceptors.write().push(interceptor);
+    }
+}
+
+/// Inbox shared by the requests of a client: a single wildcard subscription on `_INBOX.<random>.>` receives the
+/// replies of all of them, which are routed to each request by the last token of their subject
+#[derive(Debug)]
+struct RequestMux {
+    /// `_INBOX.<random>.`, each request appends its own token to it
+    prefix: String,
+    /// Sid of the wildcard subscription, once it has been sent
+    sid: Arc<Mutex<Option<NatsSubscriptionId>>>,
+    /// Requests waiting for their reply, by token
+    pending: Arc<Mutex<HashMap<String, oneshot::Sender<Message>>>>,
+    /// Last token handed out
+    last_token: AtomicUsize,
+}
+
+impl RequestMux {
+    fn new() -> Self {
+        RequestMux {
+            prefix: format!("_INBOX.{}.", PubCommand::generate_reply_to()),
+            sid: Arc::new(Mutex::new(None)),
+            pending: Arc::new(Mutex::new(HashMap::default())),
+            last_token: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns a new inbox along with the receiving end of its reply. The wildcard subscription is queued
+    /// for the connection on the first call, so that it always comes before the requests using it
+    fn inbox(
+        &self,
+        rx: &Arc<NatsClientMultiplexer>,
+        tx: &NatsClientSender,
+    ) -> Result<(String, oneshot::Receiver<Message>), NatsError> {
+        let mut sid_lock = self.sid.lock();
+        if sid_lock.is_none() {
+            let sid = rx.generate_sid();
+            let (stream, _) = rx.for_sid(sid.clone(), OverflowPolicy::default())?;
+            tx.send_now(Op::SUB(SubCommand {
+                queue_group: None,
+                sid: sid.clone(),
+                subject: format!("{}>", self.prefix),
+            }))?;
+
+            debug!(target: "nitox", "Subscribed to the request inbox {}> with sid {}", self.prefix, sid);
+            let prefix = self.prefix.clone();
+            let pending = Arc::clone(&self.pending);
+            let pending_end = Arc::clone(&self.pending);
+            let sid_end = Arc::clone(&self.sid);
+            let work = stream
+                .for_each(move |msg| {
+                    let waiting = msg
+                        .subject
+                        .get(prefix.len()..)
+                        .and_then(|token| pending.lock().remove(token));
+                    match waiting {
+                        Some(reply_tx) => {
+                            let _ = reply_tx.send(msg);
+                        }
+                        None => debug!(target: "nitox", "Nobody is waiting for the reply on {}", msg.subject),
+                    }
+
+                    Ok(())
+                })
+                .then(move |_| {
+                    // Unsubscribed by `NatsClient::drain()`, the requests in flight won't get their reply
+                    *sid_end.lock() = None;
+                    pending_end.lock().clear();
+                    Ok(())
+                });
+
+            tokio_executor::spawn(work);
+            *sid_lock = Some(sid);
+        }
+
+        let token = (self.last_token.fetch_add(1, Ordering::Relaxed) + 1).to_string();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.pending.lock().insert(token.clone(), reply_tx);
+        Ok((format!("{}{}", self.prefix, token), reply_rx))
+    }
+
+    /// Stops waiting for the reply on an inbox returned by `inbox()`
+    fn forget(&self, inbox: &str) {
+        if let Some(token) = inbox.get(self.prefix.len()..) {
+            self.pending.lock().remove(token);
+        }
+    }
+}
+
+/// Handle on a subscription, returned by `NatsClient::subscribe()`. It's the `Stream` of the messages delivered
+/// on the subscription and allows to unsubscribe later on without keeping track of the sid.
+///
+/// Dropping the handle unsubscribes, unless that has already been done
+pub struct Subscription {
+    /// Subscription ID
+    sid: String,
+    /// Subject (or subject wildcard) subscribed to
+    subject: String,
+    /// Messages delivered on this subscription
+    stream: Box<dyn Stream<Item = Message, Error = NatsError> + Send + Sync>,
+    /// Sink part to send UNSUB
+    tx: NatsClientSender,
+    /// Subscription multiplexer, to de-register the subscription
+    rx: Arc<NatsClientMultiplexer>,
+    /// Set by `unsubscribe()`, ends the stream without yielding the messages still buffered
+    unsubscribed: AtomicBool,
+    stats: SubscriptionStats,
+    /// Set by `pause()`, the stream doesn't yield anything until `resume()` is called
+    paused: AtomicBool,
+    /// Task polling the stream while it's paused, woken up by `resume()`
+    paused_task: AtomicTask,
+}
+
+impl ::std::fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("Subscription")
+            .field("sid", &self.sid)
+            .field("subject", &self.subject)
+            .field("stream", &"Box<Stream>...")
+            .finish()
+    }
+}
+
+impl Subscription {
+    /// Returns the subscription ID
+    pub fn sid(&self) -> &str {
+        &self.sid
+    }
+
+    /// Returns the subject (or subject wildcard) subscribed to
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// Returns a handle on the counters of this subscription: messages and bytes delivered, messages dropped
+    /// and messages still pending
+    pub fn stats(&self) -> SubscriptionStats {
+        self.stats.clone()
+    }
+
+    /// Makes the stream yield `NatsError::IdleTimeout` whenever no message arrives within `timeout`, to detect
+    /// publishers that went silent. The timer runs on the tokio runtime the stream is polled on
+    pub fn idle_timeout(self, timeout: Duration) -> IdleTimeout<Self> {
+        IdleTimeout::new(self, timeout)
+    }
+
+    /// Groups the messages in batches of up to `max_msgs` messages, yielding an incomplete batch once its
+    /// first message has waited for `max_delay`, which suits consumers writing or forwarding in bulk
+    pub fn batches(self, max_msgs: usize, max_delay: Duration) -> Batches<Self> {
+        Batches::new(self, max_msgs, max_delay)
+    }
+
+    /// Forwards the messages into a `std::sync::mpsc` channel from a task spawned on the executor, so threads
+    /// outside of tokio can consume them. The subscription is dropped, and thus unsubscribed, as soon as the
+    /// receiver goes away. The task ends on the first error of the stream, which is logged
+    pub fn forward_to_channel(self, tx: ::std::sync::mpsc::Sender<Message>) {
+        let sid = self.sid.clone();
+        let work = self
+            .map_err(Some)
+            .for_each(move |msg| tx.send(msg).map_err(|_| None))
+            .then(move |res| {
+                match res {
+                    Err(Some(e)) => warn!(target: "nitox", "Channel bridge for sid {} has ended: {}", sid, e),
+                    _ => debug!(target: "nitox", "Channel bridge for sid {} has ended", sid),
+                }
+
+                Ok(())
+            });
+
+        tokio_executor::spawn(work);
+    }
+
+    /// Same as `forward_to_channel()`, returning the receiving end of a new channel
+    pub fn into_channel(self) -> ::std::sync::mpsc::Receiver<Message> {
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        self.forward_to_channel(tx);
+        rx
+    }
+
+    /// Send a UNSUB command for this subscription and de-register it, which ends the `Stream` right away.
+    /// Messages that have been received but not consumed yet are discarded
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn unsubscribe(&self) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        self.unsubscribed.store(true, Ordering::Release);
+        // Ends the stream of a paused subscription as well
+        self.paused_task.notify();
+        self.send_unsub()
+    }
+
+    /// Send a UNSUB command for this subscription and de-register it, but keep the `Stream` open until the
+    /// messages that have already been received are consumed. This allows a worker to stop taking new work
+    /// without dropping the work it has been given
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn drain(&self) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        debug!(target: "nitox", "Draining sid {}", self.sid);
+        self.send_unsub()
+    }
+
+    /// Stops yielding messages until `resume()` is called, without unsubscribing. Messages received in the
+    /// meantime are buffered, within the pending limits of the subscription: past them, its `OverflowPolicy`
+    /// applies
+    pub fn pause(&self) {
+        debug!(target: "nitox", "Pausing sid {}", self.sid);
+        self.paused.store(true, Ordering::Release);
+    }
+
+    /// Yields the messages again after a call to `pause()`, starting with the ones buffered in the meantime
+    pub fn resume(&self) {
+        debug!(target: "nitox", "Resuming sid {}", self.sid);
+        self.paused.store(false, Ordering::Release);
+        self.paused_task.notify();
+    }
+
+    /// Returns `true` if the subscription has been paused
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Sets the maximum amount of messages and payload bytes buffered for this subscription and its forks,
+    /// past which its `OverflowPolicy` applies, instead of the `pending_msgs_limit` and `pending_bytes_limit`
+    /// of the client. `None` falls back to the limit of the client
+    pub fn set_pending_limits(&self, msgs: Option<usize>, bytes: Option<usize>) {
+        debug!(target: "nitox", "Setting pending limits of sid {} to {:?} messages, {:?} bytes", self.sid, msgs, bytes);
+        self.rx.set_pending_limits(&self.sid, msgs, bytes);
+    }
+
+    /// Only delivers the messages whose subject passes the filter, the others are discarded as soon as they are
+    /// received and counted by `SubscriptionStats::filtered()`. `None` removes the filter
+    pub fn set_filter(&self, filter: Option<SubjectFilter>) {
+        debug!(target: "nitox", "Setting filter of sid {} to {:?}", self.sid, filter);
+        self.rx.set_filter(&self.sid, filter);
+    }
+
+    /// Send `UNSUB <sid> <max_msgs>`, so the server stops delivering messages once `max_msgs` of them have been
+    /// sent on this subscription, counting the ones already received. Calling it again extends or reduces the
+    /// limit. The stream ends after yielding the last of them, and right away (after the buffered messages) if
+    /// that many messages have already been received
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn set_max_msgs(&self, max_msgs: u32) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        debug!(target: "nitox", "Setting max messages of sid {} to {}", self.sid, max_msgs);
+        self.rx.set_max_msgs(&self.sid, max_msgs as usize);
+        self.tx.send(Op::UNSUB(UnsubCommand {
+            sid: self.sid.clone(),
+            max_msgs: Some(max_msgs),
+        }))
+    }
+
+    /// Returns another stream of the messages of this subscription, which gets a copy of each of them from
+    /// now on, so that several tasks can consume the subscription. The forked stream has its own buffer, with
+    /// the same overflow policy, and ends along with the subscription
+    pub fn fork(&self) -> SubscriptionReceiver {
+        debug!(target: "nitox", "Forking sid {}", self.sid);
+        self.rx.fork_sid(&self.sid)
+    }
+
+    fn send_unsub(&self) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        // De-registering stops the deliveries, the stream still yields what has been buffered
+        self.rx.remove_sid(&self.sid);
+        self.tx.send(Op::UNSUB(UnsubCommand {
+            sid: self.sid.clone(),
+            max_msgs: None,
+        }))
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // Already de-registered by `unsubscribe()`, `drain()` or after reaching the maximum amount of messages
+        if !self.rx.subs_tx.read().contains_key(&self.sid) {
+            return;
+        }
+
+        debug!(target: "nitox", "Subscription {} has been dropped, unsubscribing", self.sid);
+        // Nobody will poll a future from here, the UNSUB has to be queued right away
+        self.rx.remove_sid(&self.sid);
+        let _ = self.tx.send_now(Op::UNSUB(UnsubCommand {
+            sid: self.sid.clone(),
+            max_msgs: None,
+        }));
+    }
+}
+
+impl Stream for Subscription {
+    type Error = NatsError;
+    type Item = Message;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.unsubscribed.load(Ordering::Acquire) {
+            return Ok(Async::Ready(None));
+        }
+
+        if self.paused.load(Ordering::Acquire) {
+            self.paused_task.register();
+            // `resume()` may have been called before the task got registered
+            if self.paused.load(Ordering::Acquire) {
+                return Ok(Async::NotReady);
+            }
+        }
+
+        self.stream.poll()
+    }
+}
+
+/// Stream adapter returned by `Subscription::idle_timeout()`, yielding `NatsError::IdleTimeout` every time no item
+/// has been received for the given duration. The stream can still be polled after such an error, so callers
+/// can decide whether a silent publisher is fatal or not
+#[derive(Debug)]
+pub struct IdleTimeout<S> {
+    stream: S,
+    timeout: Duration,
+    delay: Delay,
+}
+
+impl<S: Stream<Error = NatsError>> IdleTimeout<S> {
+    pub fn new(stream: S, timeout: Duration) -> Self {
+        IdleTimeout {
+            stream,
+            timeout,
+            delay: Delay::new(Instant::now() + timeout),
+        }
+    }
+
+    /// Returns the wrapped stream
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Stream<Error = NatsError>> Stream for IdleTimeout<S> {
+    type Error = NatsError;
+    type Item = S::Item;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.stream.poll() {
+            Ok(Async::NotReady) => {}
+            res => {
+                self.delay.reset(Instant::now() + self.timeout);
+                return res;
+            }
+        }
+
+        match self.delay.poll() {
+            Ok(Async::Ready(_)) => {
+                debug!(target: "nitox", "No message received for {:?}", self.timeout);
+                self.delay.reset(Instant::now() + self.timeout);
+                Err(NatsError::IdleTimeout(self.timeout))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(NatsError::GenericError(e.to_string())),
+        }
+    }
+}
+
+/// Stream adapter returned by `Subscription::batches()`, yielding the items of the wrapped stream in batches of
+/// up to `max_msgs` items, or fewer once the first item of the batch has waited for `max_delay`. Errors are
+/// yielded right away, without losing the items batched so far, and the last batch is flushed when the
+/// wrapped stream ends
+#[derive(Debug)]
+pub struct Batches<S: Stream> {
+    stream: S,
+    max_msgs: usize,
+    max_delay: Duration,
+    batch: Vec<S::Item>,
+    /// Started on the first item of a batch
+    delay: Option<Delay>,
+    done: bool,
+}
+
+impl<S: Stream<Error = NatsError>> Batches<S> {
+    pub fn new(stream: S, max_msgs: usize, max_delay: Duration) -> Self {
+        Batches {
+            stream,
+            max_msgs: max_msgs.max(1),
+            max_delay,
+            batch: Vec::new(),
+            delay: None,
+            done: false,
+        }
+    }
+
+    /// Returns the wrapped stream, the items of the current batch are discarded
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    fn take_batch(&mut self) -> Vec<S::Item> {
+        self.delay = None;
+        ::std::mem::replace(&mut self.batch, Vec::with_capacity(self.max_msgs))
+    }
+}
+
+impl<S: Stream<Error = NatsError>> Stream for Batches<S> {
+    type Error = NatsError;
+    type Item = Vec<S::Item>;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while !self.done {
+            match self.stream.poll()? {
+                Async::Ready(Some(item)) => {
+                    if self.batch.is_empty() {
+                        self.delay = Some(Delay::new(Instant::now() + self.max_delay));
+                    }
+
+                    self.batch.push(item);
+                    if self.batch.len() >= self.max_msgs {
+                        return Ok(Async::Ready(Some(self.take_batch())));
+                    }
+                }
+                Async::Ready(None) => self.done = true,
+                Async::NotReady => break,
+            }
+        }
+
+        if self.done {
+            if self.batch.is_empty() {
+                return Ok(Async::Ready(None));
+            }
+
+            return Ok(Async::Ready(Some(self.take_batch())));
+        }
+
+        let expired = match self.delay {
+            Some(ref mut delay) => delay
+                .poll()
+                .map_err(|e| NatsError::GenericError(e.to_string()))?
+                .is_ready(),
+            None => false,
+        };
+
+        if expired {
+            Ok(Async::Ready(Some(self.take_batch())))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Service answering the requests received on a subscription, returned by `NatsClient::serve()`. Dropping it
+/// unsubscribes right away, `drain()` lets the requests already received be answered first
+#[derive(Debug)]
+pub struct Responder {
+    sid: String,
+    subject: String,
+    tx: NatsClientSender,
+    rx: Arc<NatsClientMultiplexer>,
+    /// Resolved once the handler loop has ended
+    finished: Option<oneshot::Receiver<()>>,
+}
+
+impl Responder {
+    pub fn sid(&self) -> &str {
+        &self.sid
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// Send a UNSUB command for the subscription of the service, the requests that have already been received
+    /// are still handled and answered
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`, resolved once all of them have been answered
+    pub fn drain(mut self) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        debug!(target: "nitox", "Draining responder on sid {}", self.sid);
+        self.rx.remove_sid(&self.sid);
+        let finished = self.finished.take();
+        self.tx
+            .send(Op::UNSUB(UnsubCommand {
+                sid: self.sid.clone(),
+                max_msgs: None,
+            }))
+            .and_then(move |_| match finished {
+                Some(finished) => Either::A(finished.map_err(|_| NatsError::InnerBrokenChain)),
+                None => Either::B(future::ok(())),
+            })
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        // Already de-registered by `drain()`, or when the connection has ended
+        if !self.rx.subs_tx.read().contains_key(&self.sid) {
+            return;
+        }
+
+        debug!(target: "nitox", "Responder on sid {} has been dropped, unsubscribing", self.sid);
+        self.rx.remove_sid(&self.sid);
+        let _ = self.tx.send_now(Op::UNSUB(UnsubCommand {
+            sid: self.sid.clone(),
+            max_msgs: None,
+        }));
+    }
+}
+
+/// Future of the reply to a request, returned by `NatsClient::request()`. Dropping it before the reply has been
+/// received, or calling `cancel()`, stops waiting for the reply and releases the inbox of the request
+pub struct Request {
+    /// Inbox the reply is expected on, empty if the request has failed before being sent or is retried, since
+    /// every attempt has its own inbox
+    inbox: String,
+    /// Generated with the `correlation_ids` option
+    correlation_id: Option<String>,
+    subscription: RequestSubscription,
+    reply: Box<dyn Future<Item = Message, Error = NatsError> + Send + Sync>,
+    /// The inbox has been released
+    done: bool,
+}
+
+/// Subscription receiving the reply of a `Request`, depending on the `RequestStyle` of the client
+#[derive(Debug)]
+enum RequestSubscription {
+    Muxed(Arc<RequestMux>),
+    PerRequest {
+        sid: NatsSubscriptionId,
+        tx: NatsClientSender,
+        rx: Arc<NatsClientMultiplexer>,
+    },
+}
+
+impl ::std::fmt::Debug for Request {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("Request")
+            .field("inbox", &self.inbox)
+            .field("correlation_id", &self.correlation_id)
+            .field("subscription", &self.subscription)
+            .field("reply", &"Box<Future>...")
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl Request {
+    /// Returns the subject the reply is expected on, which is empty for requests that are retried
+    pub fn inbox(&self) -> &str {
+        &self.inbox
+    }
+
+    /// Returns the `Correlation-Id` header of the request, set with the `correlation_ids` option once headers
+    /// have been negotiated. Retries keep the same one
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation_id.as_deref()
+    }
+
+    /// Stops waiting for the reply, which is discarded if it's received afterwards
+    pub fn cancel(self) {
+        debug!(target: "nitox", "Cancelling request on {}", self.inbox);
+    }
+
+    /// Stops receiving on the inbox, which is a no-op once the reply has been received
+    fn release(&mut self) {
+        if self.done {
+            return;
+        }
+
+        self.done = true;
+        match self.subscription {
+            RequestSubscription::Muxed(ref requests) => requests.forget(&self.inbox),
+            RequestSubscription::PerRequest {
+                ref sid,
+                ref tx,
+                ref rx,
+            } => {
+                if !rx.subs_tx.read().contains_key(sid) {
+                    return;
+                }
+
+                rx.remove_sid(sid);
+                let _ = tx.send_now(Op::UNSUB(UnsubCommand {
+                    sid: sid.clone(),
+                    max_msgs: None,
+                }));
+            }
+        }
+    }
+}
+
+impl Future for Request {
+    type Item = Message;
+    type Error = NatsError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.reply.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            res => {
+                self.release();
+                res
+            }
+        }
+    }
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Parts of the client needed to send requests, so that retries can send new ones
+#[derive(Debug, Clone)]
+struct Requester {
+    tx: NatsClientSender,
+    rx: Arc<NatsClientMultiplexer>,
+    requests: Arc<RequestMux>,
+    style: RequestStyle,
+    timeout: Option<Duration>,
+    max_payload: Option<u32>,
+    middleware: Arc<RwLock<RequestMiddleware>>,
+}
+
+impl Requester {
+    /// Returns a `Request` failing with the given error
+    fn failed(&self, e: NatsError) -> Request {
+        Request {
+            inbox: String::new(),
+            correlation_id: None,
+            subscription: RequestSubscription::Muxed(Arc::clone(&self.requests)),
+            reply: Box::new(future::err(e)),
+            done: true,
+        }
+    }
+
+    /// Sends a single request. Its size is checked once the headers have been added, failing the request with
+    /// `NatsError::MaxPayloadOverflow` if it's too large
+    fn request(
+        &self,
+        subject: String,
+        payload: Bytes,
+        headers: Option<Headers>,
+        correlation_id: Option<String>,
+    ) -> Request {
+        let inbox = match self.style {
+            RequestStyle::Muxed => self.muxed_request_inbox(),
+            RequestStyle::PerRequest => self.subscribe_request_inbox(),
+        };
+
+        let (inbox, subscribed, reply, subscription) = match inbox {
+            Ok(inbox) => inbox,
+            Err(e) => return self.failed(e),
+        };
+
+        let mut pub_cmd = PubCommand {
+            subject,
+            payload,
+            reply_to: Some(inbox.clone()),
+            headers,
+        };
+
+        if let Some(ref id) = correlation_id {
+            pub_cmd
+                .headers
+                .get_or_insert_with(Headers::new)
+                .insert(CORRELATION_ID_HEADER, id.as_str());
+        }
+
+        let after_hooks = {
+            let middleware = self.middleware.read();
+            for hook in &middleware.before {
+                hook(&mut pub_cmd);
+            }
+
+            middleware.after.clone()
+        };
+
+        let checked = check_payload_size(pub_cmd.size(), self.max_payload);
+        let reply = match self.timeout {
+            Some(timeout) => {
+                let inbox = inbox.clone();
+                let delay = Delay::new(Instant::now() + timeout);
+                Either::A(reply.select2(delay).then(move |res| match res {
+                    Ok(Either::A((msg, _))) => Ok(msg),
+                    Err(Either::A((e, _))) => Err(e),
+                    Ok(Either::B(_)) => {
+                        debug!(target: "nitox", "No reply received on {} within {:?}", inbox, timeout);
+                        Err(NatsError::RequestTimeout(timeout))
+                    }
+                    Err(Either::B((e, _))) => Err(NatsError::GenericError(e.to_string())),
+                }))
+            }
+            None => Either::B(reply),
+        };
+
+        let tx = self.tx.clone();
+        let sent_cmd = pub_cmd.clone();
+        let started = Instant::now();
+        let reply = future::result(checked)
+            .and_then(move |_| subscribed)
+            .and_then(move |_| tx.send(Op::PUB(pub_cmd)))
+            .and_then(move |_| reply)
+            .and_then(|msg| {
+                debug!(target: "nitox", "Request got its reply {:#?}", msg);
+                if msg.is_no_responders() {
+                    return Err(NatsError::NoResponders);
+                }
+
+                Ok(msg)
+            })
+            .then(move |res| {
+                let elapsed = started.elapsed();
+                after_hooks.iter().fold(res, |res, hook| hook(&sent_cmd, res, elapsed))
+            });
+
+        Request {
+            inbox,
+            correlation_id,
+            subscription,
+            reply: Box::new(reply),
+            done: false,
+        }
+    }
+
+    /// Returns a new inbox on the wildcard subscription of the client, see `RequestStyle::Muxed`
+    fn muxed_request_inbox(&self) -> Result<RequestInbox, NatsError> {
+        let (inbox, reply) = self.requests.inbox(&self.rx, &self.tx)?;
+        let closed_inbox = inbox.clone();
+        let reply = reply.map_err(move |_| NatsError::NoReply(closed_inbox));
+        Ok((
+            inbox,
+            Box::new(future::ok(())),
+            Box::new(reply),
+            RequestSubscription::Muxed(Arc::clone(&self.requests)),
+        ))
+    }
+
+    /// Subscribes to a new inbox for a single reply, see `RequestStyle::PerRequest`
+    fn subscribe_request_inbox(&self) -> Result<RequestInbox, NatsError> {
+        let inbox = PubCommand::generate_reply_to();
+        let sid = self.rx.generate_sid();
+        let (stream, _) = self.rx.for_sid(sid.clone(), OverflowPolicy::default())?;
+        self.rx.set_max_msgs(&sid, 1);
+
+        let sub_cmd = SubCommand {
+            queue_group: None,
+            sid: sid.clone(),
+            subject: inbox.clone(),
+        };
+
+        let unsub_cmd = UnsubCommand {
+            sid: sid.clone(),
+            max_msgs: Some(1),
+        };
+
+        let tx = self.tx.clone();
+        let subscribed = self
+            .tx
+            .send(Op::SUB(sub_cmd))
+            .and_then(move |_| tx.send(Op::UNSUB(unsub_cmd)));
+        let closed_inbox = inbox.clone();
+        let reply = stream
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(msg, _)| msg.ok_or(NatsError::NoReply(closed_inbox)));
+
+        Ok((
+            inbox,
+            Box::new(subscribed),
+            Box::new(reply),
+            RequestSubscription::PerRequest {
+                sid,
+                tx: self.tx.clone(),
+                rx: Arc::clone(&self.rx),
+            },
+        ))
+    }
+}
+
+/// Stream of the replies to a request returned by `NatsClient::request_multi()`. It ends once the expected
+/// amount of replies has been received or the deadline has passed, whichever comes first, and unsubscribes the
+/// inbox when it does. Fails with `NatsError::NoResponders` if the server reports that nobody listens on the
+/// subject. Every reply goes through the `after_request()` hooks, the ones they reject are yielded as errors
+/// and the stream goes on
+pub struct Replies {
+    /// Dropped once the stream has ended, which unsubscribes if the server hasn't done it already
+    subscription: Option<Subscription>,
+    deadline: Delay,
+    /// Generated with the `correlation_ids` option
+    correlation_id: Option<String>,
+    /// Command that has been published, handed to the hooks along with every reply
+    sent_cmd: PubCommand,
+    started: Instant,
+    after_hooks: Vec<AfterRequestHook>,
+}
+
+impl ::std::fmt::Debug for Replies {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("Replies")
+            .field("subscription", &self.subscription)
+            .field("deadline", &self.deadline)
+            .field("correlation_id", &self.correlation_id)
+            .field("sent_cmd", &self.sent_cmd)
+            .field("started", &self.started)
+            .field("after_hooks", &self.after_hooks.len())
+            .finish()
+    }
+}
+
+impl Replies {
+    /// Returns the `Correlation-Id` header of the request, set with the `correlation_ids` option once headers
+    /// have been negotiated
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation_id.as_deref()
+    }
+
+    /// Runs a reply, or the failure of the request, through the `after_request()` hooks
+    fn after_hooks(&self, res: Result<Message, NatsError>) -> Result<Message, NatsError> {
+        let elapsed = self.started.elapsed();
+        self.after_hooks
+            .iter()
+            .fold(res, |res, hook| hook(&self.sent_cmd, res, elapsed))
+    }
+}
+
+impl Stream for Replies {
+    type Error = NatsError;
+    type Item = Message;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let polled = match self.subscription {
+            Some(ref mut subscription) => subscription.poll(),
+            None => return Ok(Async::Ready(None)),
+        };
+
+        match polled {
+            Ok(Async::Ready(Some(ref msg))) if msg.is_no_responders() => {
+                self.subscription = None;
+                return self
+                    .after_hooks(Err(NatsError::NoResponders))
+                    .map(|msg| Async::Ready(Some(msg)));
+            }
+            Ok(Async::Ready(Some(msg))) => return self.after_hooks(Ok(msg)).map(|msg| Async::Ready(Some(msg))),
+            Ok(Async::NotReady) => {}
+            Ok(Async::Ready(None)) | Err(_) => {
+                self.subscription = None;
+                return polled;
+            }
+        }
+
+        match self.deadline.poll() {
+            Ok(Async::Ready(_)) => {
+                debug!(target: "nitox", "Deadline of the request has passed, no more replies");
+                self.subscription = None;
+                Ok(Async::Ready(None))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(NatsError::GenericError(e.to_string())),
+        }
+    }
+}
+
+/// Streams registered on a `SubscriptionRouter`, by subject
+#[derive(Debug, Default)]
+struct Routes {
+    /// Routes for a single subject, looked up directly
+    subjects: HashMap<String, Vec<mpsc::UnboundedSender<Message>>>,
+    /// Routes for a subject wildcard, matched against every message
+    wildcards: Vec<(String, mpsc::UnboundedSender<Message>)>,
+}
+
+impl Routes {
+    /// Sends a message to every matching route, forgetting the ones whose stream has been dropped. Returns
+    /// `false` if no route matched
+    fn dispatch(&mut self, msg: &Message) -> bool {
+        let mut routed = false;
+        if let Some(txs) = self.subjects.get_mut(&*msg.subject) {
+            txs.retain(|tx| tx.unbounded_send(msg.clone()).is_ok());
+            routed = !txs.is_empty();
+        }
+
+        self.wildcards.retain(|(pattern, tx)| {
+            if !subject_matches(pattern, &msg.subject) {
+                return true;
+            }
+
+            let sent = tx.unbounded_send(msg.clone()).is_ok();
+            routed |= sent;
+            sent
+        });
+
+        routed
+    }
+}
+
+/// Shares a single wildcard subscription (e.g. `events.>`) between streams of messages for narrower subjects,
+/// created on demand with `route()`. That keeps the amount of subscriptions on the server low for services that
+/// listen to a lot of subjects, possibly changing over time.
+///
+/// The subscription is consumed by a task spawned on the executor, so the router has to be created within
+/// a tokio runtime. Dropping the router unsubscribes and ends its streams
+#[derive(Debug)]
+pub struct SubscriptionRouter {
+    sid: String,
+    subject: String,
+    routes: Arc<RwLock<Routes>>,
+    /// Messages that didn't match any route
+    unrouted: Arc<AtomicUsize>,
+    /// Dropped along with the router to stop the dispatching task
+    _stop: oneshot::Sender<()>,
+}
+
+impl SubscriptionRouter {
+    /// Starts dispatching the messages of the subscription
+    pub fn new(subscription: Subscription) -> Self {
+        let sid = subscription.sid().to_string();
+        let subject = subscription.subject().to_string();
+        let routes: Arc<RwLock<Routes>> = Arc::default();
+        let unrouted: Arc<AtomicUsize> = Arc::default();
+        let (stop_tx, stop_rx) = oneshot::channel();
+
+        let inner_routes = Arc::clone(&routes);
+        let inner_unrouted = Arc::clone(&unrouted);
+        let inner_sid = sid.clone();
+        let work = subscription
+            .for_each(move |msg| {
+                if !inner_routes.write().dispatch(&msg) {
+                    debug!(target: "nitox", "No route for subject {} on sid {}", msg.subject, inner_sid);
+                    inner_unrouted.fetch_add(1, Ordering::Relaxed);
+                }
+
+                future::ok(())
+            })
+            .map_err(|e| warn!(target: "nitox", "Router subscription has failed: {}", e))
+            // The subscription is dropped along with this future, which unsubscribes
+            .select(stop_rx.then(|_| Ok(())))
+            .map(|_| ())
+            .map_err(|_| ());
+
+        tokio_executor::spawn(work);
+
+        SubscriptionRouter {
+            sid,
+            subject,
+            routes,
+            unrouted,
+            _stop: stop_tx,
+        }
+    }
+
+    /// Returns the subscription ID
+    pub fn sid(&self) -> &str {
+        &self.sid
+    }
+
+    /// Returns the subject wildcard subscribed to
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// Returns the stream of the messages received on a subject, which can also be a narrower wildcard than the
+    /// one subscribed to. Messages are buffered without limit, the limits of the subscription apply upstream
+    pub fn route(&self, subject: &str) -> impl Stream<Item = Message, Error = NatsError> + Send + Sync {
+        let (tx, rx) = mpsc::unbounded();
+        let mut routes = self.routes.write();
+        if subject.split('.').any(|token| token == "*" || token == ">") {
+            routes.wildcards.push((subject.to_string(), tx));
+        } else {
+            routes.subjects.entry(subject.to_string()).or_default().push(tx);
+        }
+
+        rx.map_err(|_| NatsError::InnerBrokenChain)
+    }
+
+    /// Amount of messages received so far that didn't match any route, and have been discarded
+    pub fn unrouted(&self) -> usize {
+        self.unrouted.load(Ordering::Relaxed)
+    }
+}
+
+/// Decodes the payloads of the messages received by `NatsClient::subscribe_typed_with_codec()`, and encodes the
+/// ones published by `NatsClient::publish_with_codec()`
+pub trait PayloadCodec {
+    fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T, NatsError>;
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Bytes, NatsError>;
+}
+
+/// JSON payload codec, used by `NatsClient::subscribe_typed()`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JsonCodec;
+
+impl PayloadCodec for JsonCodec {
+    fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T, NatsError> {
+        json::from_slice(payload).map_err(|e| NatsError::PayloadDecodeError(e.to_string()))
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Bytes, NatsError> {
+        json::to_vec(value)
+            .map(Bytes::from)
+            .map_err(|e| NatsError::PayloadEncodeError(e.to_string()))
+    }
+}
+
+/// Subscription whose payloads are decoded into `T`, returned by `NatsClient::subscribe_typed()`. The stream
+/// yields the decoded payload along with the original message.
+///
+/// A payload that cannot be decoded is yielded as a `NatsError::PayloadDecodeError`, the stream can still be polled
+/// afterwards for the next messages. The underlying `Subscription` is available through `Deref`
+pub struct TypedSubscription<T, C = JsonCodec> {
+    subscription: Subscription,
+    codec: C,
+    payload_type: PhantomData<fn() -> T>,
+}
+
+impl<T, C: ::std::fmt::Debug> ::std::fmt::Debug for TypedSubscription<T, C> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("TypedSubscription")
+            .field("subscription", &self.subscription)
+            .field("codec", &self.codec)
+            .finish()
+    }
+}
+
+impl<T, C> TypedSubscription<T, C> {
+    /// Returns the underlying `Subscription`, which yields the raw messages
+    pub fn into_inner(self) -> Subscription {
+        self.subscription
+    }
+}
+
+impl<T, C> Deref for TypedSubscription<T, C> {
+    type Target = Subscription;
+
+    fn deref(&self) -> &Subscription {
+        &self.subscription
+    }
+}
+
+impl<T: DeserializeOwned, C: PayloadCodec> Stream for TypedSubscription<T, C> {
+    type Error = NatsError;
+    type Item = (T, Message);
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.subscription.poll()? {
+            Async::Ready(Some(msg)) => {
+                let payload = self.codec.decode(&msg.payload)?;
+                Ok(Async::Ready(Some((payload, msg))))
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Events emitted by the client about the state of the connection, as opposed to the protocol messages
+/// forwarded on the `Stream` that the client implements
+#[derive(Debug, Clone, PartialEq)]
+pub enum NatsClientEvent {
+    /// The server has sent an INFO message, either upon connection or asynchronously later on (for instance
+    /// when the cluster topology changes). The stored server info has already been updated when this is emitted
+    ServerInfoUpdated(ServerInfo),
+    /// The server has entered lame duck mode and will soon shut down
+    LameDuckMode,
+    /// The server has sent an operation this client doesn't understand, the line has been skipped
+    UnknownOperation(String),
+    /// A subscription doesn't consume its messages fast enough and its buffer is full, so messages are being
+    /// dropped. Emitted when the subscription starts dropping messages, `dropped` being the total amount of
+    /// messages dropped on it so far
+    SlowConsumer { sid: String, dropped: usize },
+}
+
+/// Broadcasts `NatsClientEvent`s to every listener registered through `NatsClient::events()`
+#[derive(Debug, Default, Clone)]
+struct NatsEventEmitter {
+    listeners: Arc<RwLock<Vec<mpsc::UnboundedSender<NatsClientEvent>>>>,
+}
+
+impl NatsEventEmitter {
+    pub fn listen(&self) -> mpsc::UnboundedReceiver<NatsClientEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.listeners.write().push(tx);
+        rx
+    }
+
+    /// Sends an event to all listeners, dropping the ones that went away
+    pub fn emit(&self, event: NatsClientEvent) {
+        debug!(target: "nitox", "Emitting client event {:?}", event);
+        self.listeners
+            .write()
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+/// Options that are to be given to the client for initialization
+#[derive(Debug, Default, Clone, Builder)]
+#[builder(setter(into))]
+pub struct NatsClientOptions {
+    /// CONNECT command that will be sent upon calling the `connect()` method
+    pub connect_command: ConnectCommand,
+    /// Cluster URI in the IP:PORT format
+    pub cluster_uri: String,
+    /// Maximum length of the control lines received from the server, defaults to the server's own default of 4096 bytes
+    #[builder(default)]
+    pub max_control_line: Option<usize>,
+    /// Maximum amount of messages buffered for each subscription, defaults to `DEFAULT_PENDING_MSGS_LIMIT`.
+    /// Messages received while a subscription is full are handled according to its `OverflowPolicy`
+    #[builder(default)]
+    pub pending_msgs_limit: Option<usize>,
+    /// Maximum amount of payload bytes buffered for each subscription, defaults to `DEFAULT_PENDING_BYTES_LIMIT`.
+    /// Messages received while a subscription is full are handled according to its `OverflowPolicy`
+    #[builder(default)]
+    pub pending_bytes_limit: Option<usize>,
+    /// When a subscription starts dropping messages, also yield a `NatsError::SlowConsumer` on its stream, on top
+    /// of emitting a `NatsClientEvent::SlowConsumer`
+    #[builder(default)]
+    pub slow_consumer_errors: bool,
+    /// Maximum payload size accepted by `publish()`, on top of the `max_payload` advertised by the server, so
+    /// oversized payloads can be rejected before the server INFO has been received
+    #[builder(default)]
+    pub max_payload: Option<u32>,
+    /// Payloads larger than that many bytes are gzipped, with a `Content-Encoding: gzip` header. Only applies
+    /// once headers have been negotiated. Gzipped messages are only decompressed on reception when this is set,
+    /// up to `max_payload` bytes (`DEFAULT_MAX_PAYLOAD` without it), larger ones being delivered as they are
+    #[builder(default)]
+    pub compress_above: Option<usize>,
+    /// What `publish()` does while the connection to the server is down, defaults to buffering up to
+    /// `DEFAULT_RECONNECT_BUFFER_SIZE` messages
+    #[builder(default)]
+    pub disconnected_publish: DisconnectedPublishPolicy,
+    /// Maximum amount of ops queued for the connection, defaults to `DEFAULT_SEND_BUFFER_SIZE`. Once reached,
+    /// the futures returned by `publish()` and the other sending methods wait for the connection to catch up
+    #[builder(default)]
+    pub send_buffer_size: Option<usize>,
+    /// Requests fail with `NatsError::RequestTimeout` when no reply has been received within that duration,
+    /// they wait for as long as it takes by default. Requests that nobody listens to fail right away with
+    /// `NatsError::NoResponders` instead, provided headers and `no_responders` have been negotiated
+    #[builder(default)]
+    pub request_timeout: Option<Duration>,
+    /// Whether `request()` receives the replies on a wildcard subscription shared by all the requests, the
+    /// default, or subscribes to a new inbox for each of them. `request_multi()`, `request_quorum()` and
+    /// `request_fastest()` always subscribe to a new inbox, whatever the style
+    #[builder(default)]
+    pub request_style: RequestStyle,
+    /// Retries of the requests that time out or have no responders, `request()` doesn't retry by default
+    #[builder(default)]
+    pub request_retry: Option<RequestRetryPolicy>,
+    /// Stamps every request with a `Correlation-Id` header holding a generated id, once headers have been
+    /// negotiated. The replies sent by `respond()` and `serve()` carry the header of their request
+    #[builder(default)]
+    pub correlation_ids: bool,
+    /// Message published by `drain()` once the subscriptions have been unsubscribed, so that other clients
+    /// can tell this one has left on purpose
+    #[builder(default)]
+    pub will: Option<PubCommand>,
+}
+
+/// Default amount of messages `publish()` buffers while the connection is down
+pub const DEFAULT_RECONNECT_BUFFER_SIZE: usize = 8192;
+
+/// What `publish()` and the other publishing methods, pipelines and publishers included, do while the
+/// connection to the server is down
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectedPublishPolicy {
+    /// Queue up to that many messages, sent once reconnected. Past that amount, publishing fails with
+    /// `NatsError::ServerDisconnected`. Batches count for the amount of messages they hold
+    Buffer(usize),
+    /// Fail right away with `NatsError::ServerDisconnected`
+    Fail,
+    /// Wait for the connection to be back, the future resolves once the message has been queued after that
+    Wait,
+}
+
+impl Default for DisconnectedPublishPolicy {
+    fn default() -> Self {
+        DisconnectedPublishPolicy::Buffer(DEFAULT_RECONNECT_BUFFER_SIZE)
+    }
+}
+
+/// How `request()` receives the replies. Requests expecting several replies aren't concerned, they always
+/// subscribe to an inbox of their own
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum RequestStyle {
+    /// A single wildcard subscription on `_INBOX.<random>.>` receives the replies of all the requests
+    #[default]
+    Muxed,
+    /// Every request subscribes to its own inbox, unsubscribed once the reply has been received. Costs a SUB
+    /// and an UNSUB per request, but doesn't need to be allowed to subscribe to a wildcard
+    PerRequest,
+}
+
+/// Retries of `request()`, for idempotent requests against responders that may be missing or slow to answer
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestRetryPolicy {
+    /// Maximum amount of attempts, the first one included
+    pub max_attempts: usize,
+    /// Delay before the first retry, doubled before each of the next ones
+    pub backoff: Duration,
+    /// Maximum delay between two attempts
+    pub max_backoff: Duration,
+    /// Retry the requests failing with `NatsError::RequestTimeout`
+    pub on_timeout: bool,
+    /// Retry the requests failing with `NatsError::NoResponders`
+    pub on_no_responders: bool,
+}
+
+impl Default for RequestRetryPolicy {
+    fn default() -> Self {
+        RequestRetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            on_timeout: true,
+            on_no_responders: true,
+        }
+    }
+}
+
+impl RequestRetryPolicy {
+    /// Indicates if a request that has failed with this error is retried
+    pub fn retries(&self, e: &NatsError) -> bool {
+        match e {
+            NatsError::RequestTimeout(_) => self.on_timeout,
+            NatsError::NoResponders => self.on_no_responders,
+            _ => false,
+        }
+    }
+}
+
+impl NatsClientOptions {
+    pub fn builder() -> NatsClientOptionsBuilder {
+        NatsClientOptionsBuilder::default()
+    }
+}
+
+/// Headers of a request or of its reply, carrying the `Correlation-Id` of the request
+fn correlation_headers(correlation_id: &str) -> Headers {
+    let mut headers = Headers::new();
+    headers.insert(CORRELATION_ID_HEADER, correlation_id);
+    headers
+}
+
+/// Fails with `NatsError::MaxPayloadOverflow` if the payload is larger than `max_payload`. The server would
+/// otherwise close the connection with a Maximum Payload Violation
+fn check_payload_size(len: usize, max_payload: Option<u32>) -> Result<(), NatsError> {
+    match max_payload {
+        Some(max_payload) if len > max_payload as usize => {
+            debug!(target: "nitox", "Payload of {} bytes exceeds max_payload of {} bytes", len, max_payload);
+            Err(NatsError::MaxPayloadOverflow(max_payload))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Applies the `DisconnectedPublishPolicy` of the client to the messages sent while the connection is down
+#[derive(Debug, Clone)]
+struct PublishGate {
+    connection: NatsConnectionStatus,
+    policy: DisconnectedPublishPolicy,
+    /// Messages published since the connection went down, for `DisconnectedPublishPolicy::Buffer`
+    buffered: Arc<AtomicUsize>,
+}
+
+impl PublishGate {
+    /// Whether `messages` messages can be sent right away, or have to wait for the connection to be back.
+    /// Fails with `NatsError::ServerDisconnected` if the policy rejects them
+    fn admit(&self, messages: usize) -> Result<bool, NatsError> {
+        if self.connection.is_connected() {
+            self.buffered.store(0, Ordering::SeqCst);
+            return Ok(true);
+        }
+
+        match self.policy {
+            DisconnectedPublishPolicy::Buffer(max) => {
+                if self.buffered.fetch_add(messages, Ordering::SeqCst) + messages <= max {
+                    Ok(true)
+                } else {
+                    self.buffered.fetch_sub(messages, Ordering::SeqCst);
+                    debug!(target: "nitox", "Reconnect buffer of {} messages is full", max);
+                    Err(NatsError::ServerDisconnected(None))
+                }
+            }
+            DisconnectedPublishPolicy::Fail => Err(NatsError::ServerDisconnected(None)),
+            DisconnectedPublishPolicy::Wait => Ok(false),
+        }
+    }
+
+    /// Ready once `messages` messages can be sent, for the `Sink` and futures sending as they're polled. The
+    /// current task is woken up upon reconnection if they have to wait
+    fn poll_admit(&self, messages: usize) -> Poll<(), NatsError> {
+        if self.admit(messages)? {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(self.connection.poll_connected())
+        }
+    }
+
+    /// Calls `send` for `messages` messages according to the policy
+    fn when_connected<F, R>(&self, messages: usize, send: F) -> impl Future<Item = (), Error = NatsError> + Send + Sync
+    where
+        F: FnOnce() -> R + Send + Sync + 'static,
+        R: Future<Item = (), Error = NatsError> + Send + Sync + 'static,
+    {
+        match self.admit(messages) {
+            Ok(true) => Either::A(send()),
+            Ok(false) => {
+                debug!(target: "nitox", "Waiting for the connection to be back before publishing");
+                let connection = self.connection.clone();
+                Either::B(Either::B(
+                    future::poll_fn(move || Ok(connection.poll_connected())).and_then(move |_| send()),
+                ))
+            }
+            Err(e) => Either::B(Either::A(future::err(e))),
+        }
+    }
+}
+
+/// Settings of the client applied to the PUB commands, captured when a `Pipeline` or a `Publisher` is created
+#[derive(Debug, Clone, Copy)]
+struct PubSettings {
+    headers_enabled: bool,
+    max_payload: Option<u32>,
+    /// Payloads larger than that are gzipped, only when headers are enabled
+    compress_above: Option<usize>,
+}
+
+/// Readies a PUB command to be sent: headers are dropped if they haven't been negotiated with the server, so
+/// the command goes out as a plain PUB rather than an HPUB the server would reject. Large payloads are then
+/// compressed if enabled, and the size is checked
+fn prepare_pub(mut cmd: PubCommand, settings: &PubSettings) -> Result<PubCommand, NatsError> {
+    let drop_headers = match cmd.headers {
+        Some(ref headers) if headers.is_empty() => true,
+        Some(_) if !settings.headers_enabled => {
+            warn!(target: "nitox", "Headers haven't been negotiated with the server, publishing on {} without them", cmd.subject);
+            true
+        }
+        _ => false,
+    };
+
+    if drop_headers {
+        cmd.headers = None;
+    }
+
+    if let (Some(threshold), true) = (settings.compress_above, settings.headers_enabled) {
+        compress_command(&mut cmd, threshold)?;
+    }
+
+    check_payload_size(cmd.size(), settings.max_payload)?;
+    Ok(cmd)
+}
+
+/// Batch of PUB commands returned by `NatsClient::pipeline()`. The commands are encoded into a single buffer
+/// as they're added, and nothing is sent until `flush()` writes the whole buffer at once
+#[derive(Debug)]
+pub struct Pipeline {
+    tx: NatsClientSender,
+    buf: BytesMut,
+    commands: usize,
+    settings: PubSettings,
+    gate: PublishGate,
+}
+
+impl Pipeline {
+    /// Adds a PUB command to the batch. Fails with `NatsError::MaxPayloadOverflow` if the payload is larger
+    /// than what the server accepts
+    pub fn publish(&mut self, cmd: PubCommand) -> Result<&mut Self, NatsError> {
+        let cmd = prepare_pub(cmd, &self.settings)?;
+        let encoded = cmd.into_vec()?;
+        self.buf.extend_from_slice(&encoded);
+        self.commands += 1;
+        Ok(self)
+    }
+
+    /// Adds the PUB command of a template to the batch. Fails with `NatsError::MaxPayloadOverflow` if the
+    /// payload is larger than what the server accepts
+    pub fn publish_template(&mut self, template: &PubTemplate, payload: &[u8]) -> Result<&mut Self, NatsError> {
+        check_payload_size(payload.len(), self.settings.max_payload)?;
+        template.encode(payload, &mut self.buf);
+        self.commands += 1;
+        Ok(self)
+    }
+
+    /// Amount of commands in the batch
+    pub fn len(&self) -> usize {
+        self.commands
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands == 0
+    }
+
+    /// Writes the batch to the server, the future resolves once the server has processed all of its commands:
+    /// on the `+OK` of the last one in verbose mode, or after a PING/PONG round-trip otherwise. While the
+    /// connection is down, the `disconnected_publish` option of the client applies to the whole batch
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn flush(self) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        debug!(target: "nitox", "Flushing pipeline of {} commands, {} bytes", self.commands, self.buf.len());
+        let (tx, buf, commands) = (self.tx, self.buf.freeze(), self.commands);
+        self.gate
+            .when_connected(commands, move || tx.send_encoded(buf, commands))
+    }
+}
+
+/// Default amount of PUB commands a `Publisher` sends before waiting for the server to process them
+pub const DEFAULT_PUBLISHER_MAX_IN_FLIGHT: usize = 1024;
+
+/// `Sink` of PUB commands returned by `NatsClient::publisher()`, so streams of messages can be forwarded to
+/// the server. Backpressure comes from the server itself: once `max_in_flight` commands have been sent, the
+/// sink waits for a PING/PONG round-trip before accepting more. While the connection is down, the
+/// `disconnected_publish` option of the client applies to every command
+pub struct Publisher {
+    tx: NatsClientSender,
+    settings: PubSettings,
+    gate: PublishGate,
+    max_in_flight: usize,
+    /// Commands sent since the last round-trip
+    in_flight: usize,
+    flush: Option<Box<dyn Future<Item = (), Error = NatsError> + Send + Sync>>,
+}
+
+impl ::std::fmt::Debug for Publisher {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("Publisher")
+            .field("tx", &self.tx)
+            .field("settings", &self.settings)
+            .field("gate", &self.gate)
+            .field("max_in_flight", &self.max_in_flight)
+            .field("in_flight", &self.in_flight)
+            .field("flush", &self.flush.as_ref().map(|_| "Box<Future>..."))
+            .finish()
+    }
+}
+
+impl Publisher {
+    /// Sets the amount of commands sent before waiting for the server to process them
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    /// Drives the round-trip in progress, if any, until the server has processed the commands in flight
+    fn poll_flush(&mut self) -> Poll<(), NatsError> {
+        if let Some(ref mut flush) = self.flush {
+            match flush.poll() {
+                Ok(Async::Ready(_)) => {}
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    self.flush = None;
+                    return Err(e);
+                }
+            }
+        }
+
+        self.flush = None;
+        Ok(Async::Ready(()))
+    }
+
+    fn start_flush(&mut self) {
+        debug!(target: "nitox", "Publisher waiting for {} commands to be processed", self.in_flight);
+        self.in_flight = 0;
+        self.flush = Some(Box::new(self.tx.flush()));
+    }
+}
+
+impl Sink for Publisher {
+    type SinkError = NatsError;
+    type SinkItem = PubCommand;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if self.poll_flush()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        if self.in_flight >= self.max_in_flight {
+            self.start_flush();
+            if self.poll_flush()?.is_not_ready() {
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+
+        if self.gate.poll_admit(1)?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        let item = prepare_pub(item, &self.settings)?;
+        match self.tx.start_send(Op::PUB(item))? {
+            AsyncSink::Ready => {
+                self.in_flight += 1;
+                Ok(AsyncSink::Ready)
+            }
+            AsyncSink::NotReady(Op::PUB(item)) => Ok(AsyncSink::NotReady(item)),
+            AsyncSink::NotReady(_) => unreachable!("the sender gives back the op it has been given"),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        if self.flush.is_none() && self.in_flight > 0 {
+            self.start_flush();
+        }
+
+        self.poll_flush()
+    }
+}
+
+/// Amount of encoded bytes `PublishAll` gathers before writing them at once
+const PUBLISH_ALL_BATCH_SIZE: usize = 64 * 1024;
+
+/// Future returned by `NatsClient::publish_all()`. The commands the stream has ready are encoded into a single
+/// buffer, written at once, and the future resolves with the amount of commands published once the stream has
+/// ended and the server has processed all of them
+pub struct PublishAll<S> {
+    stream: S,
+    tx: NatsClientSender,
+    settings: PubSettings,
+    gate: PublishGate,
+    buf: BytesMut,
+    /// Commands encoded in `buf`
+    batched: usize,
+    published: usize,
+    done: bool,
+    /// Set once the stream has ended and everything has been written, waiting for the final round-trip
+    flushing: bool,
+    /// Write of the previous batch, or round-trip once the stream has ended
+    pending: Option<Box<dyn Future<Item = (), Error = NatsError> + Send + Sync>>,
+}
+
+impl<S> ::std::fmt::Debug for PublishAll<S> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("PublishAll")
+            .field("tx", &self.tx)
+            .field("settings", &self.settings)
+            .field("gate", &self.gate)
+            .field("batched", &self.batched)
+            .field("published", &self.published)
+            .field("done", &self.done)
+            .field("flushing", &self.flushing)
+            .field("pending", &self.pending.as_ref().map(|_| "Box<Future>..."))
+            .finish()
+    }
+}
+
+impl<S: Stream<Item = PubCommand, Error = NatsError>> Future for PublishAll<S> {
+    type Item = usize;
+    type Error = NatsError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(ref mut pending) = self.pending {
+                if pending.poll()?.is_not_ready() {
+                    return Ok(Async::NotReady);
+                }
+            }
+
+            self.pending = None;
+            if self.flushing {
+                return Ok(Async::Ready(self.published));
+            }
+
+            while !self.done && self.buf.len() < PUBLISH_ALL_BATCH_SIZE {
+                match self.stream.poll()? {
+                    Async::Ready(Some(cmd)) => {
+                        let cmd = prepare_pub(cmd, &self.settings)?;
+                        Op::PUB(cmd).encode(&mut self.buf)?;
+                        self.batched += 1;
+                    }
+                    Async::Ready(None) => self.done = true,
+                    Async::NotReady => break,
+                }
+            }
+
+            if self.batched > 0 {
+                debug!(target: "nitox", "Writing batch of {} commands, {} bytes", self.batched, self.buf.len());
+                let (tx, buf, batched) = (self.tx.clone(), self.buf.take().freeze(), self.batched);
+                self.pending = Some(Box::new(
+                    self.gate.when_connected(batched, move || tx.send_raw(buf, batched)),
+                ));
+                self.published += self.batched;
+                self.batched = 0;
+            } else if self.done {
+                // Everything has been queued, the server has processed it all once it answers a PING
+                self.pending = Some(Box::new(self.tx.flush()));
+                self.flushing = true;
+            } else {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+/// The NATS Client. What you'll be using mostly. All the async handling is made internally except for
+/// the system messages that are forwarded on the `Stream` that the client implements
 pub struct NatsClient {
     /// Backup of options
     opts: NatsClientOptions,
@@ -160,6 +2543,20 @@ pub struct NatsClient {
     tx: NatsClientSender,
     /// Subscription multiplexer
     rx: Arc<NatsClientMultiplexer>,
+    /// Client events broadcaster
+    events: NatsEventEmitter,
+    /// CONNECT command as sent to the server, after negotiation of the optional features
+    negotiated_connect: Option<ConnectCommand>,
+    /// Counters of the ops received from the server
+    decoder_stats: DecoderStats,
+    /// State of the connection
+    connection: NatsConnectionStatus,
+    /// Policy of the messages published while the connection is down
+    gate: PublishGate,
+    /// Inbox receiving the replies of `request()`
+    requests: Arc<RequestMux>,
+    /// Hooks registered through `before_request()` and `after_request()`
+    request_middleware: Arc<RwLock<RequestMiddleware>>,
 }
 
 impl ::std::fmt::Debug for NatsClient {
@@ -169,238 +2566,1168 @@ impl ::std::fmt::Debug for NatsClient {
             .field("tx", &self.tx)
             .field("rx", &self.rx)
             .field("other_rx", &"Box<Stream>...")
+            .field("events", &self.events)
             .finish()
     }
-}
+}
+
+impl Stream for NatsClient {
+    type Error = NatsError;
+    type Item = Op;
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+        self.other_rx.poll().map_err(|_| NatsError::InnerBrokenChain)
+    }
+}
+
+impl NatsClient {
+    /// Creates a client and initiates a connection to the server
+    ///
+    /// Returns `impl Future<Item = Self, Error = NatsError>`
+    pub fn from_options(opts: NatsClientOptions) -> impl Future<Item = Self, Error = NatsError> + Send + Sync {
+        let tls_required = opts.connect_command.tls_required;
+        let codec = OpCodec::with_limits(
+            opts.max_control_line.unwrap_or(DEFAULT_MAX_CONTROL_LINE),
+            DEFAULT_MAX_PAYLOAD,
+        );
+        let decoder_stats = codec.stats();
+        let max_decompressed = opts.compress_above.map(|_| {
+            opts.max_payload
+                .map_or(DEFAULT_MAX_PAYLOAD, |max_payload| max_payload as usize)
+        });
+        let pending_limits = PendingLimits {
+            msgs: opts.pending_msgs_limit.unwrap_or(DEFAULT_PENDING_MSGS_LIMIT),
+            bytes: opts.pending_bytes_limit.unwrap_or(DEFAULT_PENDING_BYTES_LIMIT),
+            slow_consumer_errors: opts.slow_consumer_errors,
+        };
+        let send_buffer_size = opts.send_buffer_size.unwrap_or(DEFAULT_SEND_BUFFER_SIZE);
+
+        let cluster_uri = opts.cluster_uri.clone();
+        let cluster_sa = if let Ok(sockaddr) = SocketAddr::from_str(&cluster_uri) {
+            Ok(sockaddr)
+        } else {
+            match cluster_uri.to_socket_addrs() {
+                Ok(mut ips_iter) => ips_iter.next().ok_or(NatsError::UriDNSResolveError(None)),
+                Err(e) => Err(NatsError::UriDNSResolveError(Some(e))),
+            }
+        };
+
+        future::result(cluster_sa)
+            .from_err()
+            .and_then(move |cluster_sa| {
+                if tls_required {
+                    match Url::parse(&cluster_uri) {
+                        Ok(url) => match url.host_str() {
+                            Some(host) => future::ok(Either::B(connect_tls(host.to_string(), cluster_sa, codec))),
+                            None => future::err(NatsError::TlsHostMissingError),
+                        },
+                        Err(e) => future::err(e.into()),
+                    }
+                } else {
+                    future::ok(Either::A(connect(cluster_sa, codec)))
+                }
+            }).and_then(|either| either)
+            .and_then(move |connection| {
+                let connection_status = connection.status();
+                let (sink, stream): (NatsSink, NatsStream) = connection.split();
+                let events = NatsEventEmitter::default();
+                let (rx, other_rx) =
+                    NatsClientMultiplexer::new(stream, pending_limits, events.clone(), max_decompressed);
+                let tx = NatsClientSender::new(sink, send_buffer_size);
+
+                let (tmp_other_tx, tmp_other_rx) = mpsc::unbounded();
+                let tx_inner = tx.clone();
+                let client = NatsClient {
+                    tx,
+                    server_info: Arc::new(RwLock::new(None)),
+                    other_rx: Box::new(tmp_other_rx.map_err(|_| NatsError::InnerBrokenChain)),
+                    rx: Arc::new(rx),
+                    events,
+                    negotiated_connect: None,
+                    decoder_stats,
+                    gate: PublishGate {
+                        connection: connection_status.clone(),
+                        policy: opts.disconnected_publish,
+                        buffered: Arc::new(AtomicUsize::new(0)),
+                    },
+                    connection: connection_status,
+                    requests: Arc::new(RequestMux::new()),
+                    request_middleware: Arc::new(RwLock::new(RequestMiddleware::default())),
+                    opts,
+                };
+
+                let server_info_arc = Arc::clone(&client.server_info);
+                let events = client.events.clone();
+
+                tokio_executor::spawn(
+                    other_rx
+                        .for_each(move |op| {
+                            match op {
+                                Op::PING => {
+                                    tokio_executor::spawn(tx_inner.send(Op::PONG).map_err(|_| ()));
+                                    let _ = tmp_other_tx.unbounded_send(op);
+                                }
+                                Op::PONG => {
+                                    tx_inner.acknowledge_pong();
+                                    let _ = tmp_other_tx.unbounded_send(op);
+                                }
+                                Op::OK => {
+                                    tx_inner.acknowledge(Ok(()));
+                                    let _ = tmp_other_tx.unbounded_send(op);
+                                }
+                                Op::ERR(server_error) => {
+                                    tx_inner.acknowledge(Err(NatsError::ServerError(server_error.clone())));
+                                    let _ = tmp_other_tx.unbounded_send(Op::ERR(server_error));
+                                }
+                                Op::INFO(server_info) => {
+                                    debug!(target: "nitox", "Updating server info {:?}", server_info);
+                                    *server_info_arc.write() = Some(server_info.clone());
+                                    if server_info.ldm == Some(true) {
+                                        events.emit(NatsClientEvent::LameDuckMode);
+                                    }
+
+                                    events.emit(NatsClientEvent::ServerInfoUpdated(server_info));
+                                }
+                                Op::UNKNOWN(line) => {
+                                    events.emit(NatsClientEvent::UnknownOperation(line));
+                                }
+                                op => {
+                                    let _ = tmp_other_tx.unbounded_send(op);
+                                }
+                            }
+
+                            future::ok(())
+                        }).into_future()
+                        .map_err(|_| ()),
+                );
+
+                future::ok(client)
+            })
+    }
+
+    /// Returns the last INFO sent by the server, if any has been received yet
+    pub fn server_info(&self) -> Option<ServerInfo> {
+        self.server_info.read().clone()
+    }
+
+    /// Returns the largest payload `publish()` accepts: the smallest of the `max_payload` option and the one
+    /// advertised by the server, if any
+    pub fn max_payload(&self) -> Option<u32> {
+        let advertised = self
+            .server_info
+            .read()
+            .as_ref()
+            .map(|server_info| server_info.max_payload);
+
+        match (self.opts.max_payload, advertised) {
+            (Some(configured), Some(advertised)) => Some(configured.min(advertised)),
+            (configured, advertised) => configured.or(advertised),
+        }
+    }
+
+    /// Returns the running counters of the ops received from the server, in wire bytes. They keep running
+    /// across reconnections, so they can be used for bandwidth accounting
+    pub fn decoder_stats(&self) -> DecoderStats {
+        self.decoder_stats.clone()
+    }
+
+    /// Returns a `Stream` of the events happening on this client, such as server INFO updates. Every call
+    /// registers a new listener that will receive all the events emitted from now on
+    ///
+    /// Returns `impl Stream<Item = NatsClientEvent, Error = NatsError>`
+    pub fn events(&self) -> impl Stream<Item = NatsClientEvent, Error = NatsError> + Send + Sync {
+        self.events.listen().map_err(|_| NatsError::InnerBrokenChain)
+    }
+
+    /// Sends the CONNECT command to the server to setup connection
+    ///
+    /// The command is sent once the server INFO has been received, and the optional features the server
+    /// doesn't advertise (echo, headers, dynamic INFO) are turned off beforehand
+    ///
+    /// Returns `impl Future<Item = Self, Error = NatsError>`
+    pub fn connect(mut self) -> impl Future<Item = Self, Error = NatsError> + Send + Sync {
+        // Listening before checking the stored info guarantees we can't miss the first INFO
+        let events = self.events.listen();
+        let server_info_fut = match self.server_info() {
+            Some(server_info) => Either::A(future::ok(server_info)),
+            None => Either::B(
+                events
+                    .filter_map(|event| match event {
+                        NatsClientEvent::ServerInfoUpdated(server_info) => Some(server_info),
+                        _ => None,
+                    }).into_future()
+                    .map_err(|_| NatsError::InnerBrokenChain)
+                    .and_then(|(maybe_server_info, _)| maybe_server_info.ok_or(NatsError::ServerDisconnected(None))),
+            ),
+        };
+
+        server_info_fut.and_then(move |server_info| {
+            let connect_command = self.opts.connect_command.negotiate(&server_info);
+            self.negotiated_connect = Some(connect_command.clone());
+            self.tx
+                .send(Op::CONNECT(connect_command))
+                .and_then(move |_| future::ok(self))
+        })
+    }
+
+    /// Indicates if headers have been negotiated with the server during `connect()`
+    pub fn headers_enabled(&self) -> bool {
+        self.negotiated_connect
+            .as_ref()
+            .map(|cmd| cmd.headers_enabled())
+            .unwrap_or(false)
+    }
+
+    /// Send a raw command to the server
+    ///
+    /// Returns `impl Future<Item = Self, Error = NatsError>`
+    #[deprecated(
+        since = "0.1.4",
+        note = "Using this method prevents the library to track what you are sending to the server and causes memory leaks in case of subscriptions/unsubs, it'll be fully removed in v0.2.0"
+    )]
+    pub fn send(self, op: Op) -> impl Future<Item = Self, Error = NatsError> {
+        self.tx.send(op).and_then(move |_| future::ok(self))
+    }
+
+    fn pub_settings(&self) -> PubSettings {
+        PubSettings {
+            headers_enabled: self.headers_enabled(),
+            max_payload: self.max_payload(),
+            compress_above: self.opts.compress_above,
+        }
+    }
+
+    /// Indicates if the connection to the server is up, as opposed to being re-established
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_connected()
+    }
+
+    /// Amount of requests waiting for their reply on the wildcard subscription of `RequestStyle::Muxed`
+    pub fn pending_requests(&self) -> usize {
+        self.requests.pending.lock().len()
+    }
+
+    /// Send a PUB command to the server. Commands carrying headers are sent as HPUB if headers have been
+    /// negotiated during `connect()`, otherwise their headers are dropped and they're sent as plain PUB.
+    ///
+    /// While the connection is down, the `disconnected_publish` option tells whether the message is buffered,
+    /// rejected, or waits for the reconnection
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn publish(&self, cmd: PubCommand) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        match prepare_pub(cmd, &self.pub_settings()) {
+            Ok(cmd) => {
+                let tx = self.tx.clone();
+                Either::A(self.gate.when_connected(1, move || tx.send(Op::PUB(cmd))))
+            }
+            Err(e) => Either::B(future::err(e)),
+        }
+    }
+
+    /// Returns a `Sink` of PUB commands, to forward streams of messages to the server with backpressure
+    pub fn publisher(&self) -> Publisher {
+        Publisher {
+            tx: self.tx.clone(),
+            settings: self.pub_settings(),
+            gate: self.gate.clone(),
+            max_in_flight: DEFAULT_PUBLISHER_MAX_IN_FLIGHT,
+            in_flight: 0,
+            flush: None,
+        }
+    }
+
+    /// Publishes the same payload on several subjects in a single write, for fan-out notifications. The payload
+    /// is prepared (compressed, size checked) once and shared by the commands until they're encoded
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn publish_multi<I, S>(
+        &self,
+        subjects: I,
+        payload: Bytes,
+    ) -> impl Future<Item = (), Error = NatsError> + Send + Sync
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let settings = self.pub_settings();
+        let mut buf = BytesMut::new();
+        let mut commands = 0;
+        let mut prepared: Option<PubCommand> = None;
+        for subject in subjects {
+            let cmd = PubCommand::builder()
+                .subject(subject)
+                .build()
+                .map_err(NatsError::CommandBuildError)
+                .and_then(|mut cmd| {
+                    match prepared {
+                        Some(ref prepared) => {
+                            cmd.payload = prepared.payload.clone();
+                            cmd.headers = prepared.headers.clone();
+                        }
+                        None => {
+                            cmd.payload = payload.clone();
+                            cmd = prepare_pub(cmd, &settings)?;
+                            prepared = Some(cmd.clone());
+                        }
+                    }
+
+                    Op::PUB(cmd).encode(&mut buf).map_err(NatsError::from)
+                });
+
+            if let Err(e) = cmd {
+                return Either::A(future::err(e));
+            }
+
+            commands += 1;
+        }
+
+        if commands == 0 {
+            return Either::A(future::ok(()));
+        }
+
+        let tx = self.tx.clone();
+        Either::B(
+            self.gate
+                .when_connected(commands, move || tx.send_raw(buf.freeze(), commands)),
+        )
+    }
+
+    /// Publishes a payload with a template, for publishing many messages on the same subject without
+    /// encoding the whole control line every time. Templates don't carry headers, so payloads are never
+    /// compressed
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn publish_template(
+        &self,
+        template: &PubTemplate,
+        payload: &[u8],
+    ) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        if let Err(e) = check_payload_size(payload.len(), self.max_payload()) {
+            return Either::A(future::err(e));
+        }
+
+        let mut buf = BytesMut::new();
+        template.encode(payload, &mut buf);
+        let tx = self.tx.clone();
+        Either::B(self.gate.when_connected(1, move || tx.send_raw(buf.freeze(), 1)))
+    }
+
+    /// Publishes every PUB command of a stream, for bridging other sources of events into NATS. The commands
+    /// the stream has ready are written in batches, and the future resolves with the amount of commands
+    /// published once the stream has ended and the server has processed all of them. Fails on the first error
+    /// of the stream, or on the first command that cannot be published
+    pub fn publish_all<S>(&self, stream: S) -> PublishAll<S>
+    where
+        S: Stream<Item = PubCommand, Error = NatsError>,
+    {
+        PublishAll {
+            stream,
+            tx: self.tx.clone(),
+            settings: self.pub_settings(),
+            gate: self.gate.clone(),
+            buf: BytesMut::new(),
+            batched: 0,
+            published: 0,
+            done: false,
+            flushing: false,
+            pending: None,
+        }
+    }
+
+    /// Starts a batch of PUB commands, which are encoded into a single buffer and written at once by
+    /// `Pipeline::flush()`. Much faster than calling `publish()` for each of them when publishing in bulk
+    pub fn pipeline(&self) -> Pipeline {
+        Pipeline {
+            tx: self.tx.clone(),
+            buf: BytesMut::new(),
+            commands: 0,
+            settings: self.pub_settings(),
+            gate: self.gate.clone(),
+        }
+    }
+
+    /// Send a PUB command whose payload is `value` serialized to JSON
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn publish_json<T: Serialize>(
+        &self,
+        subject: String,
+        value: &T,
+    ) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        self.publish_with_codec(subject, value, &JsonCodec)
+    }
+
+    /// Send a PUB command whose payload is `value` encoded with the given codec. Fails with
+    /// `NatsError::PayloadEncodeError` if it cannot be encoded
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn publish_with_codec<T: Serialize, C: PayloadCodec>(
+        &self,
+        subject: String,
+        value: &T,
+        codec: &C,
+    ) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        let cmd = codec.encode(value).and_then(|payload| {
+            PubCommand::builder()
+                .subject(subject)
+                .payload(payload)
+                .build()
+                .map_err(NatsError::CommandBuildError)
+        });
+
+        match cmd {
+            Ok(cmd) => Either::A(self.publish(cmd)),
+            Err(e) => Either::B(future::err(e)),
+        }
+    }
+
+    /// Send a PUB command with a reply subject, for request patterns that manage their own inboxes
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn publish_with_reply(
+        &self,
+        subject: String,
+        reply_to: String,
+        payload: Bytes,
+    ) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        let cmd = PubCommand::builder()
+            .subject(subject)
+            .reply(reply_to)
+            .payload(payload)
+            .build();
+
+        match cmd {
+            Ok(cmd) => Either::A(self.publish(cmd)),
+            Err(e) => Either::B(future::err(NatsError::CommandBuildError(e))),
+        }
+    }
+
+    /// Send a PUB command and wait for the server to confirm it. In verbose mode, the future resolves on the
+    /// `+OK` of this very command, or fails with `NatsError::ServerError` if the server answers it with `-ERR`.
+    /// Otherwise, it resolves after a PING/PONG round-trip
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn publish_ack(&self, cmd: PubCommand) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        match prepare_pub(cmd, &self.pub_settings()) {
+            Ok(cmd) => {
+                let tx = self.tx.clone();
+                Either::A(self.gate.when_connected(1, move || tx.send_acknowledged(Op::PUB(cmd))))
+            }
+            Err(e) => Either::B(future::err(e)),
+        }
+    }
+
+    /// Send a PUB command followed by a PING, resolving on its PONG once the server has processed the PUB.
+    /// Meant for low-rate publishers whose messages must go out right away, such as heartbeats or alerts
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn publish_flush(&self, cmd: PubCommand) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        let tx = self.tx.clone();
+        self.publish(cmd).and_then(move |_| tx.flush())
+    }
+
+    /// Send a SUB command and answer the requests received on the subscription with `handler`, whose result is
+    /// published to the reply subject of each request. At most `max_concurrency` requests are handled at the
+    /// same time, the next ones stay buffered until one of them has been answered. Use the queue group of the
+    /// SUB command to spread the requests between several instances of a service.
+    ///
+    /// Messages without a reply subject are discarded. Errors returned by the handler are logged and the
+    /// request is left unanswered
+    ///
+    /// Returns `impl Future<Item = Responder, Error = NatsError>`, resolved once the subscription is registered
+    pub fn serve<F, R>(
+        &self,
+        cmd: SubCommand,
+        max_concurrency: usize,
+        handler: F,
+    ) -> impl Future<Item = Responder, Error = NatsError> + Send + Sync
+    where
+        F: Fn(Message) -> R + Send + Sync + 'static,
+        R: IntoFuture<Item = Bytes, Error = NatsError>,
+        R::Future: Send + 'static,
+    {
+        // `buffer_unordered` would never poll anything with a concurrency of 0
+        let max_concurrency = max_concurrency.max(1);
+        let tx = self.tx.clone();
+        let rx = Arc::clone(&self.rx);
+        let settings = self.pub_settings();
+        self.subscribe(cmd).map(move |subscription| {
+            let sid = subscription.sid().to_string();
+            let subject = subscription.subject().to_string();
+            let (finished_tx, finished_rx) = oneshot::channel();
+            let reply_tx = tx.clone();
+            let loop_sid = sid.clone();
+            let work = subscription
+                .filter(|msg| {
+                    if msg.reply_to.is_none() {
+                        debug!(target: "nitox", "Discarding message without reply subject on {}", msg.subject);
+                    }
+
+                    msg.reply_to.is_some()
+                })
+                .map(move |msg| {
+                    let reply_tx = reply_tx.clone();
+                    let reply_to = msg.reply_to.clone().unwrap_or_default();
+                    let headers = msg.correlation_id().map(correlation_headers);
+                    handler(msg)
+                        .into_future()
+                        .and_then(move |payload| {
+                            let cmd = PubCommand {
+                                subject: reply_to,
+                                payload,
+                                reply_to: None,
+                                headers,
+                            };
+
+                            future::result(prepare_pub(cmd, &settings)).and_then(move |cmd| reply_tx.send(Op::PUB(cmd)))
+                        })
+                        .then(|res| {
+                            if let Err(e) = res {
+                                warn!(target: "nitox", "Responder handler failed: {}", e);
+                            }
+
+                            Ok(())
+                        })
+                })
+                .buffer_unordered(max_concurrency)
+                .for_each(|_| future::ok(()))
+                .then(move |res| {
+                    match res {
+                        Ok(_) => debug!(target: "nitox", "Responder loop for sid {} has ended", loop_sid),
+                        Err(e) => warn!(target: "nitox", "Responder loop for sid {} has ended: {}", loop_sid, e),
+                    }
+
+                    let _ = finished_tx.send(());
+                    Ok(())
+                });
+
+            tokio_executor::spawn(work);
+            Responder {
+                sid,
+                subject,
+                tx,
+                rx,
+                finished: Some(finished_rx),
+            }
+        })
+    }
 
-impl Stream for NatsClient {
-    type Error = NatsError;
-    type Item = Op;
+    /// Send a PUB command to the reply subject of a message, to answer a request. Fails with
+    /// `NatsError::NoReplySubject` if the message wasn't sent as a request
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn respond(&self, msg: &Message, payload: Bytes) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        let reply_to = match msg.reply_to {
+            Some(ref reply_to) => reply_to.clone(),
+            None => return Either::A(future::err(NatsError::NoReplySubject(msg.subject.to_string()))),
+        };
 
-    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
-        self.other_rx.poll().map_err(|_| NatsError::InnerBrokenChain)
+        Either::B(self.publish(PubCommand {
+            subject: reply_to,
+            payload,
+            reply_to: None,
+            headers: msg.correlation_id().map(correlation_headers),
+        }))
     }
-}
 
-impl NatsClient {
-    /// Creates a client and initiates a connection to the server
+    /// Send a UNSUB command to the server and de-register stream in the multiplexer. With `max_msgs`, the
+    /// stream is de-registered once that many messages have been received on the subscription, and ends after
+    /// yielding the last of them
     ///
-    /// Returns `impl Future<Item = Self, Error = NatsError>`
-    pub fn from_options(opts: NatsClientOptions) -> impl Future<Item = Self, Error = NatsError> + Send + Sync {
-        let tls_required = opts.connect_command.tls_required;
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn unsubscribe(&self, cmd: UnsubCommand) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        match cmd.max_msgs {
+            Some(max) => self.rx.set_max_msgs(&cmd.sid, max as usize),
+            None => self.rx.remove_sid(&cmd.sid),
+        }
 
-        let cluster_uri = opts.cluster_uri.clone();
-        let cluster_sa = if let Ok(sockaddr) = SocketAddr::from_str(&cluster_uri) {
-            Ok(sockaddr)
-        } else {
-            match cluster_uri.to_socket_addrs() {
-                Ok(mut ips_iter) => ips_iter.next().ok_or(NatsError::UriDNSResolveError(None)),
-                Err(e) => Err(NatsError::UriDNSResolveError(Some(e))),
-            }
-        };
+        self.tx.send(Op::UNSUB(cmd))
+    }
 
-        future::result(cluster_sa)
-            .from_err()
-            .and_then(move |cluster_sa| {
-                if tls_required {
-                    match Url::parse(&cluster_uri) {
-                        Ok(url) => match url.host_str() {
-                            Some(host) => future::ok(Either::B(connect_tls(host.to_string(), cluster_sa))),
-                            None => future::err(NatsError::TlsHostMissingError),
-                        },
-                        Err(e) => future::err(e.into()),
-                    }
-                } else {
-                    future::ok(Either::A(connect(cluster_sa)))
-                }
-            }).and_then(|either| either)
-            .and_then(move |connection| {
-                let (sink, stream): (NatsSink, NatsStream) = connection.split();
-                let (rx, other_rx) = NatsClientMultiplexer::new(stream);
-                let tx = NatsClientSender::new(sink);
+    /// Generates a sid from a counter of this client, which can't collide with the other sids it generates.
+    /// The random sids of `SubCommand::builder()` are fine too, but a `SubCommand` with a sid that is already
+    /// registered on this client is rejected with `NatsError::DuplicateSid`
+    pub fn generate_sid(&self) -> String {
+        self.rx.generate_sid()
+    }
 
-                let (tmp_other_tx, tmp_other_rx) = mpsc::unbounded();
-                let tx_inner = tx.clone();
-                let client = NatsClient {
-                    tx,
-                    server_info: Arc::new(RwLock::new(None)),
-                    other_rx: Box::new(tmp_other_rx.map_err(|_| NatsError::InnerBrokenChain)),
-                    rx: Arc::new(rx),
-                    opts,
-                };
+    /// Registers an interceptor, called on every message received for a subscription before it's dispatched.
+    /// It returns the message to deliver, possibly transformed (e.g. decrypted or decompressed), or `None` to
+    /// drop it. Interceptors run in the order they've been registered, on the task reading from the server,
+    /// so they should be quick
+    pub fn intercept<F>(&self, interceptor: F)
+    where
+        F: Fn(&Message) -> Option<Message> + Send + Sync + 'static,
+    {
+        self.rx.add_interceptor(Arc::new(interceptor));
+    }
 
-                let server_info_arc = Arc::clone(&client.server_info);
+    /// Registers a hook called on every request sent by `request()`, `request_multi()` and the methods built on
+    /// them, right before it's published, e.g. to add headers to all of them. Retried requests go through the hooks on every
+    /// attempt. Hooks run in the order they've been registered
+    pub fn before_request<F>(&self, hook: F)
+    where
+        F: Fn(&mut PubCommand) + Send + Sync + 'static,
+    {
+        self.request_middleware.write().before.push(Arc::new(hook));
+    }
 
-                tokio_executor::spawn(
-                    other_rx
-                        .for_each(move |op| {
-                            match op {
-                                Op::PING => {
-                                    tokio_executor::spawn(tx_inner.send(Op::PONG).map_err(|_| ()));
-                                    let _ = tmp_other_tx.unbounded_send(op);
-                                }
-                                Op::INFO(server_info) => {
-                                    *server_info_arc.write() = Some(server_info);
-                                }
-                                op => {
-                                    let _ = tmp_other_tx.unbounded_send(op);
-                                }
-                            }
+    /// Registers a hook called once every request sent by `request()` and the methods built on it has
+    /// completed, and on every reply to the requests of `request_multi()` and the methods built on it, with the
+    /// command that has been published, the outcome and the time it took. It returns the
+    /// outcome handed to the next hook and eventually to the caller, so it can record latency metrics as well as
+    /// reject replies that don't validate. Retried requests go through the hooks on every attempt. Hooks run in
+    /// the order they've been registered
+    pub fn after_request<F>(&self, hook: F)
+    where
+        F: Fn(&PubCommand, Result<Message, NatsError>, Duration) -> Result<Message, NatsError> + Send + Sync + 'static,
+    {
+        self.request_middleware.write().after.push(Arc::new(hook));
+    }
 
-                            future::ok(())
-                        }).into_future()
-                        .map_err(|_| ()),
-                );
+    /// Send a SUB command and register subscription stream in the multiplexer and return a `Subscription`,
+    /// that is the `Stream` of the messages, in a future. Fails with `NatsError::DuplicateSid` if the sid of the
+    /// command is already in use on this client
+    ///
+    /// The future resolves once the server has registered the subscription: on its `+OK` in verbose mode,
+    /// or after a PING/PONG round-trip otherwise, so that messages published right after can't be missed
+    ///
+    /// Returns `impl Future<Item = Subscription, Error = NatsError>`
+    pub fn subscribe(&self, cmd: SubCommand) -> impl Future<Item = Subscription, Error = NatsError> + Send + Sync {
+        self.subscribe_with_overflow_policy(cmd, OverflowPolicy::default())
+    }
 
-                future::ok(client)
-            })
+    /// Same as `subscribe()`, with the given policy applied when the buffer of the subscription is full
+    ///
+    /// Returns `impl Future<Item = Subscription, Error = NatsError>`
+    pub fn subscribe_with_overflow_policy(
+        &self,
+        cmd: SubCommand,
+        policy: OverflowPolicy,
+    ) -> impl Future<Item = Subscription, Error = NatsError> + Send + Sync {
+        let sid = cmd.sid.clone();
+        let subject = cmd.subject.clone();
+        // Registering before sending SUB so that duplicate sids are never sent to the server
+        let (stream, stats) = match self.rx.for_sid(sid.clone(), policy) {
+            Ok(registered) => registered,
+            Err(e) => return Either::A(future::err(e)),
+        };
+
+        let subscription = Subscription {
+            sid,
+            subject,
+            stream: Box::new(stream),
+            tx: self.tx.clone(),
+            rx: Arc::clone(&self.rx),
+            unsubscribed: AtomicBool::new(false),
+            stats,
+            paused: AtomicBool::new(false),
+            paused_task: AtomicTask::new(),
+        };
+
+        Either::B(self.tx.send_acknowledged(Op::SUB(cmd)).map(move |_| subscription))
     }
 
-    /// Sends the CONNECT command to the server to setup connection
+    /// Send a SUB command and return a `TypedSubscription` decoding the JSON payloads of the messages into `T`
     ///
-    /// Returns `impl Future<Item = Self, Error = NatsError>`
-    pub fn connect(self) -> impl Future<Item = Self, Error = NatsError> + Send + Sync {
-        self.tx
-            .send(Op::CONNECT(self.opts.connect_command.clone()))
-            .and_then(move |_| future::ok(self))
+    /// Returns `impl Future<Item = TypedSubscription<T>, Error = NatsError>`
+    pub fn subscribe_typed<T: DeserializeOwned>(
+        &self,
+        cmd: SubCommand,
+    ) -> impl Future<Item = TypedSubscription<T>, Error = NatsError> + Send + Sync {
+        self.subscribe_typed_with_codec(cmd, JsonCodec)
     }
 
-    /// Send a raw command to the server
+    /// Send a SUB command and return a `TypedSubscription` decoding the payloads of the messages into `T` with
+    /// the given codec
     ///
-    /// Returns `impl Future<Item = Self, Error = NatsError>`
-    #[deprecated(
-        since = "0.1.4",
-        note = "Using this method prevents the library to track what you are sending to the server and causes memory leaks in case of subscriptions/unsubs, it'll be fully removed in v0.2.0"
-    )]
-    pub fn send(self, op: Op) -> impl Future<Item = Self, Error = NatsError> {
-        self.tx.send(op).and_then(move |_| future::ok(self))
+    /// Returns `impl Future<Item = TypedSubscription<T, C>, Error = NatsError>`
+    pub fn subscribe_typed_with_codec<T: DeserializeOwned, C: PayloadCodec + Send + Sync + 'static>(
+        &self,
+        cmd: SubCommand,
+        codec: C,
+    ) -> impl Future<Item = TypedSubscription<T, C>, Error = NatsError> + Send + Sync {
+        self.subscribe(cmd).map(move |subscription| TypedSubscription {
+            subscription,
+            codec,
+            payload_type: PhantomData,
+        })
     }
 
-    /// Send a PUB command to the server
+    /// Subscribe to a subject wildcard and return a `SubscriptionRouter` to dispatch its messages to per-subject
+    /// streams. The sid is generated
     ///
-    /// Returns `impl Future<Item = (), Error = NatsError>`
-    pub fn publish(&self, cmd: PubCommand) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
-        if let Some(ref server_info) = *self.server_info.read() {
-            if cmd.payload.len() > server_info.max_payload as usize {
-                return Either::A(future::err(NatsError::MaxPayloadOverflow(server_info.max_payload)));
-            }
-        }
+    /// Returns `impl Future<Item = SubscriptionRouter, Error = NatsError>`
+    pub fn subscribe_router(
+        &self,
+        subject: String,
+    ) -> impl Future<Item = SubscriptionRouter, Error = NatsError> + Send + Sync {
+        let cmd = SubCommand::builder().subject(subject).sid(self.generate_sid()).build();
 
-        Either::B(self.tx.send(Op::PUB(cmd)))
+        match cmd {
+            Ok(cmd) => Either::A(self.subscribe(cmd).map(SubscriptionRouter::new)),
+            Err(e) => Either::B(future::err(NatsError::CommandBuildError(e))),
+        }
     }
 
-    /// Send a UNSUB command to the server and de-register stream in the multiplexer
+    /// Subscribe to a subject as a member of a queue group: each message is delivered to a single member of the
+    /// group, which load-balances the messages between workers. The sid is generated
     ///
-    /// Returns `impl Future<Item = (), Error = NatsError>`
-    pub fn unsubscribe(&self, cmd: UnsubCommand) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
-        if let Some(max) = cmd.max_msgs {
-            if let Some(mut s) = (*self.rx.subs_tx.write()).get_mut(&cmd.sid) {
-                s.max_count = Some(max);
-            }
+    /// Returns `impl Future<Item = Subscription, Error = NatsError>`
+    pub fn queue_subscribe(
+        &self,
+        subject: String,
+        queue_group: String,
+    ) -> impl Future<Item = Subscription, Error = NatsError> + Send + Sync {
+        let cmd = SubCommand::builder()
+            .subject(subject)
+            .queue_group(Some(queue_group))
+            .sid(self.generate_sid())
+            .build();
+
+        match cmd {
+            Ok(cmd) => Either::A(self.subscribe(cmd)),
+            Err(e) => Either::B(future::err(NatsError::CommandBuildError(e))),
         }
+    }
 
-        self.tx.send(Op::UNSUB(cmd))
+    /// Send a SUB command followed by `UNSUB <sid> <max_msgs>`, so the server stops delivering messages once
+    /// `max_msgs` of them have been sent. The returned `Subscription` ends after yielding the last of them
+    ///
+    /// Returns `impl Future<Item = Subscription, Error = NatsError>`
+    pub fn subscribe_with_max_msgs(
+        &self,
+        cmd: SubCommand,
+        max_msgs: u32,
+    ) -> impl Future<Item = Subscription, Error = NatsError> + Send + Sync {
+        let sid = cmd.sid.clone();
+        let subject = cmd.subject.clone();
+        let unsub_cmd = UnsubCommand {
+            sid: sid.clone(),
+            max_msgs: Some(max_msgs),
+        };
+
+        // Registering before sending SUB so that no message can be missed
+        let (stream, stats) = match self.rx.for_sid(sid.clone(), OverflowPolicy::default()) {
+            Ok(registered) => registered,
+            Err(e) => return Either::A(future::err(e)),
+        };
+
+        self.rx.set_max_msgs(&sid, max_msgs as usize);
+
+        let subscription = Subscription {
+            sid,
+            subject,
+            stream: Box::new(stream),
+            tx: self.tx.clone(),
+            rx: Arc::clone(&self.rx),
+            unsubscribed: AtomicBool::new(false),
+            stats,
+            paused: AtomicBool::new(false),
+            paused_task: AtomicTask::new(),
+        };
+
+        let tx = self.tx.clone();
+        Either::B(
+            self.tx
+                .send(Op::SUB(cmd))
+                .and_then(move |_| tx.send_acknowledged(Op::UNSUB(unsub_cmd)))
+                .map(move |_| subscription),
+        )
     }
 
-    /// Send a SUB command and register subscription stream in the multiplexer and return that `Stream` in a future
+    /// Send a SUB command and consume the messages of the subscription in a task spawned on the executor,
+    /// calling `handler` for each of them. At most `max_concurrency` futures returned by the handler run at
+    /// the same time, the next messages stay buffered until one of them completes.
+    ///
+    /// Errors returned by the handler are logged and don't stop the subscription, which ends when it's
+    /// unsubscribed through `NatsClient::unsubscribe()` or the connection goes away
     ///
-    /// Returns `impl Future<Item = impl Stream<Item = Message, Error = NatsError>>`
-    pub fn subscribe(
+    /// Returns `impl Future<Item = (), Error = NatsError>`, resolved once the subscription is registered
+    pub fn subscribe_with_handler<F, R>(
         &self,
         cmd: SubCommand,
-    ) -> impl Future<Item = impl Stream<Item = Message, Error = NatsError> + Send + Sync, Error = NatsError> + Send + Sync
+        max_concurrency: usize,
+        handler: F,
+    ) -> impl Future<Item = (), Error = NatsError> + Send + Sync
+    where
+        F: Fn(Message) -> R + Send + Sync + 'static,
+        R: IntoFuture<Item = (), Error = NatsError>,
+        R::Future: Send + 'static,
     {
-        let inner_rx = self.rx.clone();
-        let sid = cmd.sid.clone();
-        self.tx.send(Op::SUB(cmd)).and_then(move |_| {
-            let stream = inner_rx.for_sid(sid.clone()).and_then(move |msg| {
-                {
-                    let mut stx = inner_rx.subs_tx.write();
-                    let mut delete = None;
-                    debug!(target: "nitox", "Retrieving sink for sid {:?}", sid);
-                    if let Some(s) = stx.get_mut(&sid) {
-                        debug!(target: "nitox", "Checking if count exists");
-                        if let Some(max_count) = s.max_count {
-                            s.count += 1;
-                            debug!(target: "nitox", "Max: {} / current: {}", max_count, s.count);
-                            if s.count >= max_count {
-                                debug!(target: "nitox", "Starting deletion");
-                                delete = Some(max_count);
-                            }
+        // `buffer_unordered` would never poll anything with a concurrency of 0
+        let max_concurrency = max_concurrency.max(1);
+        self.subscribe(cmd).map(move |subscription| {
+            let sid = subscription.sid().to_string();
+            let work = subscription
+                .map(move |msg| {
+                    handler(msg).into_future().then(|res| {
+                        if let Err(e) = res {
+                            warn!(target: "nitox", "Subscription handler failed: {}", e);
                         }
-                    }
 
-                    if let Some(count) = delete.take() {
-                        debug!(target: "nitox", "Deleted stream for sid {} at count {}", sid, count);
-                        stx.remove(&sid);
-                        return Err(NatsError::SubscriptionReachedMaxMsgs(count));
+                        Ok(())
+                    })
+                })
+                .buffer_unordered(max_concurrency)
+                .for_each(|_| future::ok(()))
+                .then(move |res| {
+                    match res {
+                        Ok(_) => debug!(target: "nitox", "Handler loop for sid {} has ended", sid),
+                        Err(e) => warn!(target: "nitox", "Handler loop for sid {} has ended: {}", sid, e),
                     }
-                }
 
-                Ok(msg)
-            });
+                    Ok(())
+                });
 
-            future::ok(stream)
+            tokio_executor::spawn(work);
         })
     }
 
     /// Performs a request to the server following the Request/Reply pattern. Returns a future containing the MSG that will be replied at some point by a third party
     ///
-    /// Returns `impl Future<Item = Message, Error = NatsError>`
-    pub fn request(
+    /// By default, the replies of all the requests are received on a single wildcard subscription, sent along
+    /// with the first request, rather than subscribing and unsubscribing a new inbox every time. See
+    /// `RequestStyle`
+    ///
+    /// If headers and `no_responders` have been enabled in the CONNECT command, the future fails with
+    /// `NatsError::NoResponders` as soon as the server reports that nobody listens on the subject, rather than
+    /// waiting for the `request_timeout` of the client to fail with `NatsError::RequestTimeout`
+    ///
+    /// With a `request_retry` policy, failed requests are sent again on a new inbox, up to its maximum amount
+    /// of attempts
+    ///
+    /// The returned `Request` stops waiting for the reply when it's dropped or cancelled
+    pub fn request(&self, subject: String, payload: Bytes) -> Request {
+        self.request_with_headers(subject, Headers::new(), payload)
+    }
+
+    /// Same as `request()`, sending the given headers along with the request. They're dropped if headers
+    /// haven't been negotiated with the server, and count towards `max_payload` otherwise
+    pub fn request_with_headers(&self, subject: String, headers: Headers, payload: Bytes) -> Request {
+        let requester = Requester {
+            tx: self.tx.clone(),
+            rx: Arc::clone(&self.rx),
+            requests: Arc::clone(&self.requests),
+            style: self.opts.request_style,
+            timeout: self.opts.request_timeout,
+            max_payload: self.max_payload(),
+            middleware: Arc::clone(&self.request_middleware),
+        };
+
+        let correlation_id = if self.opts.correlation_ids && self.headers_enabled() {
+            Some(PubCommand::generate_reply_to())
+        } else {
+            None
+        };
+
+        let headers = if headers.is_empty() {
+            None
+        } else if !self.headers_enabled() {
+            warn!(target: "nitox", "Headers haven't been negotiated with the server, requesting {} without them", subject);
+            None
+        } else {
+            Some(headers)
+        };
+
+        let policy = match self.opts.request_retry {
+            Some(ref policy) if policy.max_attempts > 1 => policy.clone(),
+            _ => return requester.request(subject, payload, headers, correlation_id),
+        };
+
+        // Every attempt is a `Request` of its own, released when the next one starts
+        let retried = requester.clone();
+        let attempt_correlation_id = correlation_id.clone();
+        let reply = future::loop_fn((1, policy.backoff), move |(attempt, backoff)| {
+            let policy = policy.clone();
+            retried
+                .request(
+                    subject.clone(),
+                    payload.clone(),
+                    headers.clone(),
+                    attempt_correlation_id.clone(),
+                )
+                .then(move |res| match res {
+                    Err(ref e) if attempt < policy.max_attempts && policy.retries(e) => {
+                        debug!(target: "nitox", "Request attempt {} has failed: {}", attempt, e);
+                        let next = (attempt + 1, (backoff * 2).min(policy.max_backoff));
+                        Either::A(
+                            Delay::new(Instant::now() + backoff)
+                                .map(move |_| Loop::Continue(next))
+                                .map_err(|e| NatsError::GenericError(e.to_string())),
+                        )
+                    }
+                    res => Either::B(future::result(res.map(Loop::Break))),
+                })
+        });
+
+        Request {
+            inbox: String::new(),
+            correlation_id,
+            subscription: RequestSubscription::Muxed(Arc::clone(&self.requests)),
+            reply: Box::new(reply),
+            done: true,
+        }
+    }
+
+    /// Performs a request whose payload is `req` serialized to JSON, and deserializes the payload of the reply
+    /// into `Resp`
+    ///
+    /// Returns `impl Future<Item = Resp, Error = NatsError>`
+    pub fn request_typed<Req: Serialize, Resp: DeserializeOwned + Send + Sync>(
         &self,
         subject: String,
-        payload: Bytes,
-    ) -> impl Future<Item = Message, Error = NatsError> + Send + Sync {
-        if let Some(ref server_info) = *self.server_info.read() {
-            if payload.len() > server_info.max_payload as usize {
-                return Either::A(future::err(NatsError::MaxPayloadOverflow(server_info.max_payload)));
-            }
+        req: &Req,
+    ) -> impl Future<Item = Resp, Error = NatsError> + Send + Sync {
+        self.request_with_codec(subject, req, JsonCodec)
+    }
+
+    /// Performs a request whose payload is `req` encoded with the given codec, and decodes the payload of the
+    /// reply into `Resp` with it. Fails with `NatsError::PayloadEncodeError` or `NatsError::PayloadDecodeError`
+    /// if either cannot be done
+    ///
+    /// Returns `impl Future<Item = Resp, Error = NatsError>`
+    pub fn request_with_codec<Req, Resp, C>(
+        &self,
+        subject: String,
+        req: &Req,
+        codec: C,
+    ) -> impl Future<Item = Resp, Error = NatsError> + Send + Sync
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned + Send + Sync,
+        C: PayloadCodec + Send + Sync + 'static,
+    {
+        match codec.encode(req) {
+            Ok(payload) => Either::A(
+                self.request(subject, payload)
+                    .and_then(move |msg| codec.decode(&msg.payload)),
+            ),
+            Err(e) => Either::B(future::err(e)),
         }
+    }
+
+    /// Performs a request expecting several replies, for scatter-gather patterns where every responder answers
+    /// the same request. The returned stream yields the replies until `max_replies` of them have been received
+    /// or `timeout` has elapsed since the call. While the connection is down, the `disconnected_publish` option
+    /// applies to the request
+    ///
+    /// The replies are received on a new inbox subscribed for this request only, even with `RequestStyle::Muxed`
+    ///
+    /// Returns `impl Future<Item = Replies, Error = NatsError>`
+    pub fn request_multi(
+        &self,
+        subject: String,
+        payload: Bytes,
+        max_replies: u32,
+        timeout: Duration,
+    ) -> impl Future<Item = Replies, Error = NatsError> + Send + Sync {
+        let deadline = Delay::new(Instant::now() + timeout);
+        let correlation_id = if self.opts.correlation_ids && self.headers_enabled() {
+            Some(PubCommand::generate_reply_to())
+        } else {
+            None
+        };
 
         let inbox = PubCommand::generate_reply_to();
-        let pub_cmd = PubCommand {
+        let mut pub_cmd = PubCommand {
             subject,
             payload,
             reply_to: Some(inbox.clone()),
+            headers: correlation_id.as_ref().map(|id| correlation_headers(id)),
+        };
+
+        let after_hooks = {
+            let middleware = self.request_middleware.read();
+            for hook in &middleware.before {
+                hook(&mut pub_cmd);
+            }
+
+            middleware.after.clone()
         };
 
+        if let Err(e) = check_payload_size(pub_cmd.size(), self.max_payload()) {
+            return Either::A(future::err(e));
+        }
+
         let sub_cmd = SubCommand {
             queue_group: None,
-            sid: SubCommand::generate_sid(),
+            sid: self.rx.generate_sid(),
             subject: inbox,
         };
 
-        let sid = sub_cmd.sid.clone();
+        // Checked before subscribing, as the subscription waits for the connection to be back anyway
+        if let Err(e) = self.gate.admit(1) {
+            return Either::A(future::err(e));
+        }
 
-        let unsub_cmd = UnsubCommand {
-            sid: sub_cmd.sid.clone(),
-            max_msgs: Some(1),
-        };
+        let tx = self.tx.clone();
+        let started = Instant::now();
+        Either::B(
+            self.subscribe_with_max_msgs(sub_cmd, max_replies.max(1))
+                .and_then(move |subscription| {
+                    let sent_cmd = pub_cmd.clone();
+                    tx.send(Op::PUB(pub_cmd)).map(move |_| Replies {
+                        subscription: Some(subscription),
+                        deadline,
+                        correlation_id,
+                        sent_cmd,
+                        started,
+                        after_hooks,
+                    })
+                }),
+        )
+    }
+
+    /// Performs a request expecting several responders, resolving with the first `quorum` replies, for
+    /// redundant services where a few concurring answers are enough. Fails with `NatsError::RequestTimeout` if
+    /// fewer replies have been received within `timeout`, or with the error of an `after_request()` hook if it
+    /// has rejected one of them
+    ///
+    /// Returns `impl Future<Item = Vec<Message>, Error = NatsError>`
+    pub fn request_quorum(
+        &self,
+        subject: String,
+        payload: Bytes,
+        quorum: u32,
+        timeout: Duration,
+    ) -> impl Future<Item = Vec<Message>, Error = NatsError> + Send + Sync {
+        let quorum = quorum.max(1);
+        self.request_multi(subject, payload, quorum, timeout)
+            .and_then(|replies| replies.then(Ok).collect())
+            .and_then(move |results| {
+                let mut replies = Vec::with_capacity(results.len());
+                let mut rejected = None;
+                for res in results {
+                    match res {
+                        Ok(reply) => replies.push(reply),
+                        Err(e) => rejected = rejected.or(Some(e)),
+                    }
+                }
+
+                if replies.len() < quorum as usize {
+                    debug!(target: "nitox", "Only {} of the {} replies expected have been received", replies.len(), quorum);
+                    return Err(rejected.unwrap_or(NatsError::RequestTimeout(timeout)));
+                }
+
+                Ok(replies)
+            })
+    }
+
+    /// Performs a request expecting several responders, resolving with the reply of the fastest one. Fails with
+    /// `NatsError::RequestTimeout` if none has replied within `timeout`
+    ///
+    /// Returns `impl Future<Item = Message, Error = NatsError>`
+    pub fn request_fastest(
+        &self,
+        subject: String,
+        payload: Bytes,
+        timeout: Duration,
+    ) -> impl Future<Item = Message, Error = NatsError> + Send + Sync {
+        self.request_quorum(subject, payload, 1, timeout)
+            .map(|mut replies| replies.remove(0))
+    }
 
-        let tx1 = self.tx.clone();
-        let tx2 = self.tx.clone();
-        let rx_arc = Arc::clone(&self.rx);
+    /// Gracefully shuts the client down: every subscription is unsubscribed, their streams still yield the
+    /// messages that have already been received, then the `will` message is published if one has been set.
+    /// The future resolves once the server has processed all of it
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn drain(&self) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        let settings = self.pub_settings();
+        let will = match self.opts.will.clone().map(|will| prepare_pub(will, &settings)) {
+            Some(Ok(cmd)) => Some(Op::PUB(cmd)),
+            Some(Err(e)) => return Either::A(future::err(e)),
+            None => None,
+        };
 
-        let stream = self
+        let mut ops: Vec<Op> = self
             .rx
-            .for_sid(sid.clone())
-            .inspect(|msg| debug!(target: "nitox", "Request saw msg in multiplexed stream {:#?}", msg))
-            .take(1)
-            .into_future()
-            .map(|(surely_message, _)| surely_message.unwrap())
-            .map_err(|(e, _)| e)
-            .and_then(move |msg| {
-                rx_arc.remove_sid(&sid);
-                future::ok(msg)
-            });
+            .remove_all()
+            .into_iter()
+            .map(|sid| Op::UNSUB(UnsubCommand { sid, max_msgs: None }))
+            .collect();
+
+        debug!(target: "nitox", "Draining client, unsubscribing {} subscriptions", ops.len());
+        ops.extend(will);
 
+        let tx = self.tx.clone();
+        let flush_tx = self.tx.clone();
         Either::B(
-            self.tx
-                .send(Op::SUB(sub_cmd))
-                .and_then(move |_| tx1.send(Op::UNSUB(unsub_cmd)))
-                .and_then(move |_| tx2.send(Op::PUB(pub_cmd)))
-                .and_then(move |_| stream),
+            stream::iter_ok(ops)
+                .for_each(move |op| tx.send(op))
+                .and_then(move |_| flush_tx.flush()),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{NatsEventEmitter, OverflowPolicy, PendingLimits, SubscriptionBuffer};
+    use protocol::commands::Message;
+
+    fn message(payload: &str) -> Message {
+        Message::builder()
+            .subject("FOO")
+            .sid("pouet")
+            .payload(payload)
+            .build()
+            .unwrap()
+    }
+
+    fn payloads(buffer: &SubscriptionBuffer) -> Vec<String> {
+        buffer
+            .queue
+            .lock()
+            .iter()
+            .map(|item| match item {
+                Ok(msg) => String::from_utf8_lossy(&msg.payload).into_owned(),
+                Err(e) => e.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_drops_oldest_messages_but_not_errors() {
+        let buffer = SubscriptionBuffer::new(OverflowPolicy::DropOldest);
+        let limits = PendingLimits {
+            msgs: 2,
+            bytes: 1024,
+            slow_consumer_errors: true,
+        };
+        let events = NatsEventEmitter::default();
+        for payload in &["1", "2", "3", "4"] {
+            assert!(buffer.try_push(message(payload), limits, &events).is_none());
+        }
+
+        // The slow consumer error is kept while the messages queued before and after it are dropped
+        assert_eq!(
+            payloads(&buffer),
+            vec!["SlowConsumer: 1 messages have been dropped", "3", "4"]
+        );
+        assert_eq!(buffer.stats.dropped(), 2);
+        assert_eq!(buffer.stats.pending_msgs(), 2);
+    }
+
+    #[test]
+    fn it_drops_oversized_messages_alone() {
+        let buffer = SubscriptionBuffer::new(OverflowPolicy::DropOldest);
+        let limits = PendingLimits {
+            msgs: 8,
+            bytes: 4,
+            slow_consumer_errors: false,
+        };
+        let events = NatsEventEmitter::default();
+        assert!(buffer.try_push(message("toto"), limits, &events).is_none());
+        assert!(buffer.try_push(message("oversized"), limits, &events).is_none());
+        assert_eq!(payloads(&buffer), vec!["toto"]);
+        assert_eq!(buffer.stats.dropped(), 1);
+
+        // Nothing is buffered anymore, so it doesn't take the room of any message
+        buffer.queue.lock().clear();
+        buffer.stats.remove(4);
+        assert!(buffer.try_push(message("oversized"), limits, &events).is_none());
+        assert_eq!(payloads(&buffer), vec!["oversized"]);
+        assert_eq!(buffer.stats.dropped(), 1);
+    }
+}
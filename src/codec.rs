@@ -1,37 +1,272 @@
-use bytes::{BufMut, BytesMut};
+use bytes::BytesMut;
+#[cfg(feature = "client")]
 use error::NatsError;
-use protocol::{CommandError, Op};
+use protocol::{commands::SubjectCache, CommandError, Op};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+#[cfg(feature = "client")]
+use std::time::Instant;
+#[cfg(feature = "client")]
 use tokio_codec::{Decoder, Encoder};
 
-/// `tokio-codec` implementation of the protocol parsing
-#[derive(Default, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+/// Default maximum length of a control line, mirroring the default `max_control_line` of the NATS server
+pub const DEFAULT_MAX_CONTROL_LINE: usize = 4096;
+/// Default maximum length of a payload, the largest `max_payload` a NATS server can be configured with
+pub const DEFAULT_MAX_PAYLOAD: usize = 64 * 1024 * 1024;
+
+/// Running counters of the ops decoded by an `OpCodec`, in wire bytes (control line and payload included).
+///
+/// Counters are shared between the clones of a codec and of its stats, so they keep running across reconnections.
+#[derive(Debug, Default, Clone)]
+pub struct DecoderStats {
+    ops: Arc<AtomicUsize>,
+    bytes: Arc<AtomicUsize>,
+    msgs: Arc<AtomicUsize>,
+    msg_bytes: Arc<AtomicUsize>,
+}
+
+impl DecoderStats {
+    /// Amount of ops decoded so far
+    pub fn ops(&self) -> usize {
+        self.ops.load(Ordering::Relaxed)
+    }
+
+    /// Amount of bytes decoded so far, all ops included
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Amount of messages (MSG and HMSG) decoded so far
+    pub fn msgs(&self) -> usize {
+        self.msgs.load(Ordering::Relaxed)
+    }
+
+    /// Amount of bytes of the messages (MSG and HMSG) decoded so far
+    pub fn msg_bytes(&self) -> usize {
+        self.msg_bytes.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, op: &Op, wire_size: usize) {
+        self.ops.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(wire_size, Ordering::Relaxed);
+        if let Op::MSG(_) = op {
+            self.msgs.fetch_add(1, Ordering::Relaxed);
+            self.msg_bytes.fetch_add(wire_size, Ordering::Relaxed);
+        }
+    }
+}
+
+/// `tokio-codec` implementation of the protocol parsing. The `tokio-codec` traits are only implemented
+/// with the `client` feature, `parse` is available either way
+#[derive(Clone, Debug)]
 pub struct OpCodec {
     /// Used as an optimization for buffer lookup
     next_index: usize,
+    /// Number of bytes consumed from the stream so far, used to give the offset of malformed commands
+    decoded_bytes: usize,
+    /// Maximum length of a control line (the first line of a command), to avoid buffering indefinitely
+    /// while looking for a CRLF that never comes
+    max_control_line: usize,
+    /// Maximum length of a payload, to avoid buffering indefinitely while waiting for a payload announced by a
+    /// bogus control line
+    max_payload: usize,
+    /// Subjects of the received messages, shared between messages
+    subjects: SubjectCache,
+    /// Counters of the decoded ops
+    stats: DecoderStats,
+}
+
+impl Default for OpCodec {
+    fn default() -> Self {
+        OpCodec {
+            next_index: 0,
+            decoded_bytes: 0,
+            max_control_line: DEFAULT_MAX_CONTROL_LINE,
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            subjects: SubjectCache::default(),
+            stats: DecoderStats::default(),
+        }
+    }
 }
 
 impl OpCodec {
     pub fn new() -> Self {
         OpCodec::default()
     }
+
+    /// Creates a codec enforcing the given maximum control line length
+    pub fn with_max_control_line(max_control_line: usize) -> Self {
+        OpCodec {
+            max_control_line,
+            ..OpCodec::default()
+        }
+    }
+
+    /// Creates a codec enforcing the given maximum control line and payload lengths
+    pub fn with_limits(max_control_line: usize, max_payload: usize) -> Self {
+        OpCodec {
+            max_control_line,
+            max_payload,
+            ..OpCodec::default()
+        }
+    }
+
+    /// Returns a handle on the running counters of this codec and its clones
+    pub fn stats(&self) -> DecoderStats {
+        self.stats.clone()
+    }
+
+    /// Fails if a control line of the given length exceeds the configured maximum
+    #[cfg(feature = "client")]
+    fn check_control_line(&self, len: usize, buf: &[u8], command_end: usize) -> Result<(), NatsError> {
+        if len > self.max_control_line {
+            debug!(target: "nitox", "control line exceeds {} bytes", self.max_control_line);
+            return Err(CommandError::ControlLineTooLong(self.max_control_line)
+                .with_context(&buf[..command_end], self.decoded_bytes, buf)
+                .into());
+        }
+
+        Ok(())
+    }
 }
 
+#[cfg(feature = "client")]
 impl Encoder for OpCodec {
     type Error = NatsError;
     type Item = Op;
 
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let buf = item.into_bytes()?;
-        let buf_len = buf.len();
-        let remaining_bytes = dst.remaining_mut();
-        if remaining_bytes < buf_len {
-            dst.reserve(buf_len);
-        }
-        dst.put(buf);
+        item.encode(dst)?;
         Ok(())
     }
 }
 
+/// Location of the next op at the beginning of a buffer
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Frame {
+    /// No CRLF has been found yet, the control line is still being received
+    PartialControlLine,
+    /// The control line, ending at `control_end`, is complete but `needed` more bytes of payload are missing
+    PartialPayload { control_end: usize, needed: usize },
+    /// The op is complete; Its name ends at `command_end` and it spans `..end`
+    Complete {
+        command_end: usize,
+        control_end: usize,
+        end: usize,
+    },
+}
+
+/// Returns the position of the end of the command name in a control line
+fn command_name_end(line: &[u8]) -> usize {
+    line.iter()
+        .position(|b| *b == b' ' || *b == b'\t' || *b == b'\r')
+        .unwrap_or(line.len())
+}
+
+/// Finds the boundaries of the next op in `buf`, looking for the end of the control line from `from` onwards.
+///
+/// Ops carrying a payload (PUB, HPUB, MSG and HMSG) are framed using the length given as last argument of their
+/// control line, so payloads may contain CRLFs. That length cannot exceed `max_payload`.
+fn frame(buf: &[u8], from: usize, max_payload: usize) -> Result<Frame, CommandError> {
+    let from = from.saturating_sub(1).min(buf.len());
+    let control_end = match buf[from..].windows(2).position(|w| w == b"\r\n") {
+        Some(offset) => from + offset,
+        None => return Ok(Frame::PartialControlLine),
+    };
+
+    let command_end = command_name_end(&buf[..control_end]);
+    let mut end = control_end.checked_add(2).ok_or(CommandError::CommandMalformed)?;
+
+    match &buf[..command_end] {
+        b"PUB" | b"HPUB" | b"MSG" | b"HMSG" => {
+            // For HPUB and HMSG, the last argument is the total length of the header block and the payload
+            let payload_len: usize = ::std::str::from_utf8(&buf[command_end..control_end])?
+                .split_whitespace()
+                .next_back()
+                .ok_or(CommandError::CommandMalformed)?
+                .parse()?;
+
+            end = end
+                .checked_add(payload_len)
+                .and_then(|end| end.checked_add(2))
+                .ok_or(CommandError::CommandMalformed)?;
+            if payload_len > max_payload {
+                return Err(CommandError::PayloadTooLong(max_payload));
+            }
+
+            if buf.len() < end {
+                return Ok(Frame::PartialPayload {
+                    control_end,
+                    needed: end - buf.len(),
+                });
+            }
+
+            if &buf[end - 2..end] != b"\r\n" {
+                return Err(CommandError::CommandMalformed);
+            }
+        }
+        _ => {}
+    }
+
+    Ok(Frame::Complete {
+        command_end,
+        control_end,
+        end,
+    })
+}
+
+/// Parses the op at the beginning of `buf`, and consumes its bytes if it is complete.
+///
+/// Returns `Ok(None)` when more bytes are needed, `bytes_needed` tells how many. Operations that aren't known
+/// are consumed and returned as `Op::UNKNOWN`. Unlike `OpCodec`, no limit is enforced on the control line length,
+/// and payloads are limited to `DEFAULT_MAX_PAYLOAD`.
+pub fn parse(buf: &mut BytesMut) -> Result<Option<Op>, CommandError> {
+    if let Frame::Complete { command_end, end, .. } = frame(buf, 0, DEFAULT_MAX_PAYLOAD)? {
+        let op = parse_frame(&buf[..command_end], &buf[..end], None)?;
+        let _ = buf.split_to(end);
+        Ok(op)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Returns the minimum amount of bytes that have to be appended to `buf` before `parse` can return an op,
+/// `0` meaning an op can be parsed right away.
+///
+/// The exact amount is only known once the control line has been received; Before that, the amount needed
+/// to complete the CRLF is returned.
+pub fn bytes_needed(buf: &[u8]) -> Result<usize, CommandError> {
+    Ok(match frame(buf, 0, DEFAULT_MAX_PAYLOAD)? {
+        Frame::Complete { .. } => 0,
+        Frame::PartialPayload { needed, .. } => needed,
+        Frame::PartialControlLine if buf.ends_with(b"\r") => 1,
+        Frame::PartialControlLine => 2,
+    })
+}
+
+/// Parses a complete op, `Ok(None)` meaning it needs more bytes after all
+fn parse_frame(command: &[u8], buf: &[u8], subjects: Option<&mut SubjectCache>) -> Result<Option<Op>, CommandError> {
+    let op = match subjects {
+        Some(subjects) => Op::from_bytes_interned(command, buf, subjects),
+        None => Op::from_bytes(command, buf),
+    };
+
+    match op {
+        Ok(op) => Ok(Some(op)),
+        Err(CommandError::CommandNotFoundOrSupported) => {
+            // Skipping the line keeps the stream usable when the server speaks a newer protocol
+            let line = String::from_utf8_lossy(&buf[..buf.len() - 2]).into_owned();
+            warn!(target: "nitox", "skipping unknown operation {:?}", line);
+            Ok(Some(Op::UNKNOWN(line)))
+        }
+        Err(CommandError::IncompleteCommandError) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(feature = "client")]
 impl Decoder for OpCodec {
     type Error = NatsError;
     type Item = Op;
@@ -42,56 +277,208 @@ impl Decoder for OpCodec {
         }
 
         debug!(target: "nitox", "codec buffer is {:?}", buf);
-        // Let's check if we find a blank space at the beginning
-        if let Some(command_offset) = buf[self.next_index..]
-            .iter()
-            .position(|b| *b == b' ' || *b == b'\t' || *b == b'\r')
-        {
-            let command_end = self.next_index + command_offset;
-            debug!(target: "nitox", "codec detected command name {:?}", &buf[..command_end]);
-
-            if let Some(command_body_offset) = buf[command_end..].windows(2).position(|w| w == b"\r\n") {
-                let mut end_buf_pos = command_end + command_body_offset + 2;
-
-                if &buf[..command_end] == b"PUB" || &buf[..command_end] == b"MSG" {
-                    debug!(target: "nitox", "detected PUB or MSG, looking for second CRLF");
-                    if let Some(new_end) = buf[end_buf_pos..].windows(2).position(|w| w == b"\r\n") {
-                        debug!(target: "nitox", "found second CRLF at position {}", end_buf_pos + new_end + 2);
-                        end_buf_pos += new_end + 2;
-                    } else {
-                        debug!(target: "nitox", "command was incomplete");
-                        return Ok(None);
-                    }
-                }
+        let frame = frame(buf, self.next_index, self.max_payload).map_err(|e| {
+            let command_end = command_name_end(buf);
+            e.with_context(&buf[..command_end], self.decoded_bytes, buf)
+        })?;
 
-                debug!(target: "nitox", "codec detected command body {:?}", &buf[..end_buf_pos]);
-                match Op::from_bytes(&buf[..command_end], &buf[..end_buf_pos]) {
-                    Err(CommandError::IncompleteCommandError) => {
-                        debug!(target: "nitox", "command was incomplete");
-                        self.next_index = buf.len();
-                        Ok(None)
-                    }
-                    Ok(op) => {
-                        debug!(target: "nitox", "codec parsed command {:#?}", op);
-                        let _ = buf.split_to(end_buf_pos);
+        match frame {
+            Frame::PartialControlLine => {
+                debug!(target: "nitox", "no CRLF found yet, continuing");
+                self.check_control_line(buf.len(), buf, command_name_end(buf))?;
+                self.next_index = buf.len();
+                Ok(None)
+            }
+            Frame::PartialPayload { control_end, needed } => {
+                self.check_control_line(control_end, buf, command_name_end(buf))?;
+                debug!(target: "nitox", "command was incomplete, {} more bytes needed", needed);
+                self.next_index = control_end;
+                Ok(None)
+            }
+            Frame::Complete {
+                command_end,
+                control_end,
+                end,
+            } => {
+                self.check_control_line(control_end, buf, command_end)?;
+                debug!(target: "nitox", "codec detected command body {:?}", &buf[..end]);
+                match parse_frame(&buf[..command_end], &buf[..end], Some(&mut self.subjects)) {
+                    Ok(Some(mut op)) => {
+                        debug!(target: "nitox", "codec parsed command {}", op);
+                        if let Op::MSG(ref mut msg) = op {
+                            msg.received_at = Some(Instant::now());
+                        }
+
+                        let _ = buf.split_to(end);
+                        self.decoded_bytes += end;
+                        self.stats.record(&op, end);
                         debug!(target: "nitox", "buffer now contains {:?}", buf);
                         self.next_index = 0;
                         Ok(Some(op))
                     }
+                    Ok(None) => {
+                        debug!(target: "nitox", "command was incomplete");
+                        self.next_index = buf.len();
+                        Ok(None)
+                    }
                     Err(e) => {
                         debug!(target: "nitox", "command couldn't be parsed {}", e);
                         self.next_index = 0;
-                        Err(e.into())
+                        Err(e
+                            .with_context(&buf[..command_end], self.decoded_bytes, &buf[..end])
+                            .into())
                     }
                 }
-            } else {
-                Ok(None)
             }
-        } else {
-            // First blank not found yet, continuing
-            debug!(target: "nitox", "no whitespace found yet, continuing");
-            self.next_index = buf.len();
-            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bytes_needed, parse, DEFAULT_MAX_PAYLOAD};
+    use bytes::BytesMut;
+    use protocol::{CommandError, Op};
+    #[cfg(feature = "client")]
+    use {
+        super::{OpCodec, DEFAULT_MAX_CONTROL_LINE},
+        error::NatsError,
+        std::time::Instant,
+        tokio_codec::Decoder,
+    };
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn it_gives_context_on_malformed_commands() {
+        let mut codec = OpCodec::new();
+        let mut buf = BytesMut::from(&b"PING\r\nMSG\tFOO\tpouet\t3\r\ntoto\r\n"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Op::PING));
+
+        match codec.decode(&mut buf) {
+            Err(NatsError::ProtocolError(CommandError::ParseError {
+                command,
+                offset,
+                snippet,
+                cause,
+            })) => {
+                assert_eq!(command, "MSG");
+                assert_eq!(offset, 6);
+                assert!(snippet.starts_with("MSG\tFOO"));
+                match *cause {
+                    CommandError::CommandMalformed => {}
+                    e => panic!("Unexpected cause {}", e),
+                }
+            }
+            r => panic!("Expected a ParseError, got {:?}", r),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn it_guards_control_line_length() {
+        let mut codec = OpCodec::with_max_control_line(16);
+        let mut buf = BytesMut::from(&b"PUB\tFOO\t5\r\nhello\r\n"[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+
+        let mut buf = BytesMut::from(&b"PUB\tFOO.BAR.BAZ.QUX\t5"[..]);
+        match codec.decode(&mut buf) {
+            Err(NatsError::ProtocolError(e)) => match e.root_cause() {
+                CommandError::ControlLineTooLong(16) => {}
+                e => panic!("Unexpected error {}", e),
+            },
+            r => panic!("Expected ControlLineTooLong, got {:?}", r),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn it_skips_unknown_operations() {
+        let mut codec = OpCodec::new();
+        let mut buf = BytesMut::from(&b"FOO\r\nLMAO\tbar\r\nPING\r\n"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Op::UNKNOWN("FOO".into())));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Op::UNKNOWN("LMAO\tbar".into())));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Op::PING));
+    }
+
+    #[test]
+    fn it_rejects_oversized_payloads() {
+        let mut buf = BytesMut::from("MSG foo 1 18446744073709551615\r\n");
+        match parse(&mut buf) {
+            Err(CommandError::CommandMalformed) => {}
+            r => panic!("Expected CommandMalformed, got {:?}", r),
+        }
+        assert!(bytes_needed(&buf).is_err());
+
+        let mut buf = BytesMut::from("MSG foo 1 67108865\r\n");
+        match parse(&mut buf) {
+            Err(CommandError::PayloadTooLong(DEFAULT_MAX_PAYLOAD)) => {}
+            r => panic!("Expected PayloadTooLong, got {:?}", r),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn it_guards_payload_length() {
+        let mut codec = OpCodec::with_limits(DEFAULT_MAX_CONTROL_LINE, 4);
+        let mut buf = BytesMut::from(&b"MSG\tFOO\tpouet\t4\r\ntoto\r\nMSG\tFOO\tpouet\t5\r\n"[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        match codec.decode(&mut buf) {
+            Err(NatsError::ProtocolError(e)) => match e.root_cause() {
+                CommandError::PayloadTooLong(4) => {}
+                e => panic!("Unexpected error {}", e),
+            },
+            r => panic!("Expected PayloadTooLong, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_parses_resumably() {
+        let mut buf = BytesMut::from(&b"PUB\tFOO\t6\r\nhel"[..]);
+        assert_eq!(bytes_needed(&buf).unwrap(), 5);
+        assert!(parse(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(b"\r\no\r\nPI");
+        assert_eq!(bytes_needed(&buf).unwrap(), 0);
+        match parse(&mut buf).unwrap() {
+            Some(Op::PUB(cmd)) => assert_eq!(cmd.payload, "hel\r\no"),
+            op => panic!("Expected a PUB, got {:?}", op),
+        }
+
+        assert_eq!(&buf[..], b"PI");
+        assert_eq!(bytes_needed(&buf).unwrap(), 2);
+        buf.extend_from_slice(b"NG\r");
+        assert_eq!(bytes_needed(&buf).unwrap(), 1);
+        buf.extend_from_slice(b"\n");
+        assert_eq!(parse(&mut buf).unwrap(), Some(Op::PING));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn it_counts_decoded_bytes() {
+        let mut codec = OpCodec::new();
+        let stats = codec.stats();
+        let mut buf = BytesMut::from(&b"PING\r\nMSG\tFOO\tpouet\t4\r\ntoto\r\nPONG\r\n"[..]);
+        while codec.decode(&mut buf).unwrap().is_some() {}
+
+        assert_eq!(stats.ops(), 3);
+        assert_eq!(stats.bytes(), 35);
+        assert_eq!(stats.msgs(), 1);
+        assert_eq!(stats.msg_bytes(), 23);
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn it_stamps_decoded_messages() {
+        let mut codec = OpCodec::new();
+        let mut buf = BytesMut::from(&b"MSG\tFOO\tpouet\t4\r\ntoto\r\n"[..]);
+        let before = Instant::now();
+        match codec.decode(&mut buf).unwrap() {
+            Some(Op::MSG(msg)) => {
+                assert!(msg.received_at.unwrap() >= before);
+                assert!(msg.age().is_some());
+            }
+            op => panic!("Decoded {:?} instead of a MSG", op),
         }
     }
 }
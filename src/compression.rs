@@ -0,0 +1,140 @@
+use bytes::Bytes;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::io::{self, Read, Write};
+
+use protocol::commands::{Headers, Message, PubCommand};
+
+/// Header telling how the payload of a message has been encoded
+pub const CONTENT_ENCODING_HEADER: &str = "Content-Encoding";
+/// Value of the `Content-Encoding` header of gzipped payloads
+pub const GZIP_ENCODING: &str = "gzip";
+
+fn gzip(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(payload.len() / 2), Compression::default());
+    encoder.write_all(payload)?;
+    encoder.finish()
+}
+
+/// Fails rather than inflating more than `max_len` bytes, so a small gzip bomb cannot exhaust the memory
+fn gunzip(payload: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity((payload.len() * 2).min(max_len));
+    GzDecoder::new(payload)
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut decoded)?;
+    if decoded.len() > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("decompressed payload exceeds {} bytes", max_len),
+        ));
+    }
+
+    Ok(decoded)
+}
+
+/// Gzips the payload of a command if it is larger than `threshold` bytes, setting the `Content-Encoding` header.
+/// Payloads that are already encoded, or that don't get any smaller, are left untouched
+pub(crate) fn compress_command(cmd: &mut PubCommand, threshold: usize) -> io::Result<()> {
+    let encoded = cmd
+        .headers
+        .as_ref()
+        .is_some_and(|headers| headers.get(CONTENT_ENCODING_HEADER).is_some());
+    if cmd.payload.len() <= threshold || encoded {
+        return Ok(());
+    }
+
+    let compressed = gzip(&cmd.payload)?;
+    if compressed.len() >= cmd.payload.len() {
+        return Ok(());
+    }
+
+    debug!(target: "nitox", "Compressed payload from {} to {} bytes", cmd.payload.len(), compressed.len());
+    cmd.payload = Bytes::from(compressed);
+    cmd.headers
+        .get_or_insert_with(Headers::new)
+        .insert(CONTENT_ENCODING_HEADER, GZIP_ENCODING);
+    Ok(())
+}
+
+/// Restores the payload of a gzipped message and removes its `Content-Encoding` header. Messages that aren't
+/// gzipped, or whose payload cannot be decompressed in less than `max_len` bytes, are returned as they are
+pub(crate) fn decompress_message(mut msg: Message, max_len: usize) -> Message {
+    let gzipped = msg
+        .headers
+        .as_ref()
+        .is_some_and(|headers| headers.get(CONTENT_ENCODING_HEADER) == Some(GZIP_ENCODING));
+    if !gzipped {
+        return msg;
+    }
+
+    match gunzip(&msg.payload, max_len) {
+        Ok(payload) => {
+            msg.payload = Bytes::from(payload);
+            if let Some(ref mut headers) = msg.headers {
+                headers.remove(CONTENT_ENCODING_HEADER);
+            }
+        }
+        Err(e) => warn!(target: "nitox", "Cannot decompress message received on {}: {}", msg.subject, e),
+    }
+
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress_command, decompress_message, CONTENT_ENCODING_HEADER};
+    use protocol::commands::*;
+
+    #[test]
+    fn it_roundtrips_payloads() {
+        let payload = "toto".repeat(256);
+        let mut cmd = PubCommand::builder()
+            .subject("FOO")
+            .payload(payload.clone())
+            .build()
+            .unwrap();
+        compress_command(&mut cmd, 512).unwrap();
+        assert!(cmd.payload.len() < payload.len());
+        assert_eq!(cmd.headers.as_ref().unwrap().get(CONTENT_ENCODING_HEADER), Some("gzip"));
+
+        let msg = Message::builder()
+            .subject("FOO")
+            .sid("pouet")
+            .payload(cmd.payload)
+            .headers(cmd.headers)
+            .build()
+            .unwrap();
+        let msg = decompress_message(msg, 1024);
+        assert_eq!(msg.payload, payload);
+        assert!(msg.headers.unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_caps_decompressed_payloads() {
+        let mut cmd = PubCommand::builder()
+            .subject("FOO")
+            .payload("\0".repeat(1 << 20))
+            .build()
+            .unwrap();
+        compress_command(&mut cmd, 512).unwrap();
+        let compressed = cmd.payload.clone();
+
+        let msg = Message::builder()
+            .subject("FOO")
+            .sid("pouet")
+            .payload(cmd.payload)
+            .headers(cmd.headers)
+            .build()
+            .unwrap();
+        let msg = decompress_message(msg, 1 << 16);
+        assert_eq!(msg.payload, compressed);
+        assert_eq!(msg.headers.unwrap().get(CONTENT_ENCODING_HEADER), Some("gzip"));
+    }
+
+    #[test]
+    fn it_skips_small_payloads() {
+        let mut cmd = PubCommand::builder().subject("FOO").payload("toto").build().unwrap();
+        compress_command(&mut cmd, 512).unwrap();
+        assert_eq!(cmd.payload, "toto");
+        assert!(cmd.headers.is_none());
+    }
+}
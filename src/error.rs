@@ -31,12 +31,14 @@ pub enum NatsError {
     #[fail(display = "UTF8Error: {}", _0)]
     UTF8Error(::std::string::FromUtf8Error),
     /// Error on TLS handling
+    #[cfg(feature = "client")]
     #[fail(display = "TlsError: {}", _0)]
     TlsError(::native_tls::Error),
     /// Occurs when the host is not provided, removing the ability for TLS to function correctly for server identify verification
     #[fail(display = "TlsHostMissingError: Host is missing, can't verify server identity")]
     TlsHostMissingError,
     /// Cannot parse an URL
+    #[cfg(feature = "client")]
     #[fail(display = "UrlParseError: {}", _0)]
     UrlParseError(::url::ParseError),
     /// Cannot parse an IP
@@ -62,9 +64,44 @@ pub enum NatsError {
     /// Generic string error
     #[fail(display = "GenericError: {}", _0)]
     GenericError(String),
-    /// Error thrown when a subscription is fused after reaching the maximum messages
-    #[fail(display = "SubscriptionReachedMaxMsgs after {} messages", _0)]
-    SubscriptionReachedMaxMsgs(u32),
+    /// A subscription doesn't consume its messages fast enough, the given amount of messages have been dropped
+    #[fail(display = "SlowConsumer: {} messages have been dropped", _0)]
+    SlowConsumer(usize),
+    /// The server replied to a request with a `503` status, meaning nobody is subscribed to the subject
+    #[fail(display = "NoResponders: no responders are available for this request")]
+    NoResponders,
+    /// A subscription with the same sid is already registered on this client
+    #[fail(display = "DuplicateSid: sid {} is already in use", _0)]
+    DuplicateSid(String),
+    /// No message has been received on a subscription for the given duration
+    #[fail(display = "IdleTimeout: no message received for {:?}", _0)]
+    IdleTimeout(::std::time::Duration),
+    /// The payload of a message cannot be decoded into the type expected by a typed subscription
+    #[fail(display = "PayloadDecodeError: {}", _0)]
+    PayloadDecodeError(String),
+    /// A value cannot be encoded into the payload of a message by the codec given to publish it
+    #[fail(display = "PayloadEncodeError: {}", _0)]
+    PayloadEncodeError(String),
+    /// Cannot respond to a message received on the given subject, since it has no reply subject
+    #[fail(display = "NoReplySubject: the message received on {} has no reply subject", _0)]
+    NoReplySubject(String),
+    /// The inbox of a request has been closed before its reply was received, because the connection has ended
+    /// or the client has been drained
+    #[fail(display = "NoReply: the inbox {} has been closed before receiving a reply", _0)]
+    NoReply(String),
+    /// No reply to a request has been received within the `request_timeout` of the client
+    #[fail(display = "RequestTimeout: no reply received within {:?}", _0)]
+    RequestTimeout(::std::time::Duration),
+    /// The JetStream API has answered a request with an error
+    #[cfg(feature = "client")]
+    #[fail(display = "JetStreamError: {}", _0)]
+    JetStreamError(::jetstream::JsApiError),
+    /// A push consumer hasn't sent any message or idle heartbeat for the given duration, it's likely stalled
+    #[fail(display = "MissedHeartbeats: no heartbeat received from the consumer for {:?}", _0)]
+    MissedHeartbeats(::std::time::Duration),
+    /// The server has answered a command with `-ERR` instead of `+OK`
+    #[fail(display = "{}", _0)]
+    ServerError(protocol::commands::ServerError),
 }
 
 impl From<io::Error> for NatsError {
@@ -78,6 +115,7 @@ impl From<io::Error> for NatsError {
     }
 }
 
+#[cfg(feature = "client")]
 impl<T> From<::futures::sync::mpsc::SendError<T>> for NatsError {
     fn from(_: ::futures::sync::mpsc::SendError<T>) -> Self {
         NatsError::InnerBrokenChain
@@ -86,7 +124,9 @@ impl<T> From<::futures::sync::mpsc::SendError<T>> for NatsError {
 
 from_error!(protocol::CommandError, NatsError, NatsError::ProtocolError);
 from_error!(::std::string::FromUtf8Error, NatsError, NatsError::UTF8Error);
+#[cfg(feature = "client")]
 from_error!(::native_tls::Error, NatsError, NatsError::TlsError);
 from_error!(String, NatsError, NatsError::GenericError);
+#[cfg(feature = "client")]
 from_error!(::url::ParseError, NatsError, NatsError::UrlParseError);
 from_error!(::std::net::AddrParseError, NatsError, NatsError::AddrParseError);
@@ -0,0 +1,399 @@
+use bytes::Bytes;
+use futures::{
+    future::{self, Either},
+    prelude::*,
+};
+use std::time::{Duration, SystemTime};
+
+use super::{check_name, format_rfc3339, nanos, JsContext, SuccessResponse};
+use error::NatsError;
+use protocol::check_command_arg;
+
+/// How the messages delivered by a consumer have to be acknowledged
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AckPolicy {
+    /// Messages don't need to be acknowledged
+    None,
+    /// Acknowledging a message also acknowledges all the ones delivered before it
+    All,
+    /// Every message has to be acknowledged on its own
+    #[default]
+    Explicit,
+}
+
+/// Where a consumer starts in the stream
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliverPolicy {
+    /// Starts with the first message of the stream
+    #[default]
+    All,
+    /// Starts with the last message of the stream
+    Last,
+    /// Only delivers the messages stored after its creation
+    New,
+    /// Starts with the message at `opt_start_seq`
+    ByStartSequence,
+    /// Starts with the first message stored at or after `opt_start_time`
+    ByStartTime,
+    /// Starts with the last message of each subject
+    LastPerSubject,
+}
+
+/// Pace at which a consumer delivers the messages already stored by the stream
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayPolicy {
+    /// Delivers the messages as fast as possible
+    #[default]
+    Instant,
+    /// Delivers the messages at the pace they have been stored
+    Original,
+}
+
+fn default_ack_wait() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn unlimited() -> i64 {
+    -1
+}
+
+/// Configuration of a consumer. Consumers with a `deliver_subject` push their messages to it, the others are
+/// pull consumers whose messages have to be fetched
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct ConsumerConfig {
+    /// Name of a durable consumer, which keeps its state when nobody is consuming. Consumers without one are
+    /// ephemeral
+    #[builder(setter(into), default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub durable_name: Option<String>,
+    /// Subject the messages are pushed to
+    #[builder(setter(into), default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deliver_subject: Option<String>,
+    #[builder(default)]
+    #[serde(default)]
+    pub deliver_policy: DeliverPolicy,
+    /// Sequence of the first message delivered, with `DeliverPolicy::ByStartSequence`
+    #[builder(setter(into), default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opt_start_seq: Option<u64>,
+    /// Time from which the messages are delivered, in the RFC 3339 format, with `DeliverPolicy::ByStartTime`
+    #[builder(setter(into), default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opt_start_time: Option<String>,
+    #[builder(default)]
+    #[serde(default)]
+    pub ack_policy: AckPolicy,
+    /// Time after which a message that hasn't been acknowledged is delivered again
+    #[builder(default = "default_ack_wait()")]
+    #[serde(with = "nanos", default = "default_ack_wait")]
+    pub ack_wait: Duration,
+    /// Amount of times a message is delivered before giving up on it, `-1` being unlimited
+    #[builder(default = "-1")]
+    #[serde(default = "unlimited")]
+    pub max_deliver: i64,
+    /// Only delivers the messages of the stream matching this subject
+    #[builder(setter(into), default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter_subject: Option<String>,
+    #[builder(default)]
+    #[serde(default)]
+    pub replay_policy: ReplayPolicy,
+    /// Interval at which a push consumer sends an idle heartbeat when it has no message to deliver, zero
+    /// disabling them
+    #[builder(default)]
+    #[serde(with = "nanos", default)]
+    pub idle_heartbeat: Duration,
+    /// Makes a push consumer wait for the client to keep up before delivering more messages, which needs an
+    /// `idle_heartbeat`. `PushSubscription` answers the flow control requests on its own
+    #[builder(default)]
+    #[serde(default)]
+    pub flow_control: bool,
+    /// Time until which the consumer doesn't deliver any message, in the RFC 3339 format
+    #[builder(setter(into), default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pause_until: Option<String>,
+}
+
+impl ConsumerConfig {
+    pub fn builder() -> ConsumerConfigBuilder {
+        ConsumerConfigBuilder::default()
+    }
+}
+
+impl ConsumerConfigBuilder {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(Some(ref durable_name)) = self.durable_name {
+            check_name(durable_name)?;
+        }
+
+        if let Some(Some(ref deliver_subject)) = self.deliver_subject {
+            check_command_arg(deliver_subject).map_err(|e| format!("deliver subject is invalid: {}", e))?;
+        }
+
+        if self.flow_control == Some(true) && self.idle_heartbeat.unwrap_or_default() == Duration::default() {
+            return Err("flow control needs an idle heartbeat".into());
+        }
+
+        let deliver_policy = self.deliver_policy.unwrap_or_default();
+        let has_start_seq = self.opt_start_seq.as_ref().is_some_and(Option::is_some);
+        if has_start_seq != (deliver_policy == DeliverPolicy::ByStartSequence) {
+            return Err("a start sequence goes along with DeliverPolicy::ByStartSequence".into());
+        }
+
+        let has_start_time = self.opt_start_time.as_ref().is_some_and(Option::is_some);
+        if has_start_time != (deliver_policy == DeliverPolicy::ByStartTime) {
+            return Err("a start time goes along with DeliverPolicy::ByStartTime".into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Last message delivered or acknowledged, as sequences of the consumer and of the stream
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SequenceInfo {
+    pub consumer_seq: u64,
+    pub stream_seq: u64,
+}
+
+/// Configuration and state of a consumer, returned by the consumer management methods of `JsContext`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConsumerInfo {
+    pub stream_name: String,
+    pub name: String,
+    pub config: ConsumerConfig,
+    /// Creation time of the consumer, in the RFC 3339 format
+    #[serde(default)]
+    pub created: String,
+    #[serde(default)]
+    pub delivered: SequenceInfo,
+    #[serde(default)]
+    pub ack_floor: SequenceInfo,
+    /// Messages delivered and waiting for an acknowledgement
+    #[serde(default)]
+    pub num_ack_pending: u64,
+    #[serde(default)]
+    pub num_redelivered: u64,
+    /// Pull requests waiting for messages
+    #[serde(default)]
+    pub num_waiting: u64,
+    /// Messages of the stream not delivered yet
+    #[serde(default)]
+    pub num_pending: u64,
+    /// Whether the consumer is paused, until `config.pause_until`
+    #[serde(default)]
+    pub paused: bool,
+    /// Time left before the consumer resumes
+    #[serde(with = "nanos", default)]
+    pub pause_remaining: Duration,
+}
+
+/// Pause state of a consumer, returned by `JsContext::pause_consumer()` and `JsContext::resume_consumer()`
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConsumerPause {
+    pub paused: bool,
+    /// Time until which the consumer is paused, in the RFC 3339 format
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pause_until: Option<String>,
+    /// Time left before the consumer resumes
+    #[serde(with = "nanos")]
+    pub pause_remaining: Duration,
+}
+
+#[derive(Serialize)]
+struct PauseConsumerRequest {
+    pause_until: String,
+}
+
+#[derive(Serialize)]
+struct CreateConsumerRequest {
+    stream_name: String,
+    config: ConsumerConfig,
+}
+
+impl JsContext {
+    /// Creates a consumer on a stream, durable if its configuration has a `durable_name`. Fails with
+    /// `JsErrorCode::ConsumerNameInUse` if a durable consumer with the same name but another configuration exists
+    /// already
+    ///
+    /// Returns `impl Future<Item = ConsumerInfo, Error = NatsError>`
+    pub fn add_consumer(
+        &self,
+        stream: &str,
+        config: &ConsumerConfig,
+    ) -> impl Future<Item = ConsumerInfo, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(stream) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        let api = match config.durable_name {
+            Some(ref durable_name) => format!("CONSUMER.DURABLE.CREATE.{}.{}", stream, durable_name),
+            None => format!("CONSUMER.CREATE.{}", stream),
+        };
+
+        let req = CreateConsumerRequest {
+            stream_name: stream.into(),
+            config: config.clone(),
+        };
+
+        Either::B(self.request_json(&api, &req))
+    }
+
+    /// Deletes a consumer of a stream, resolves with whether it has been deleted
+    ///
+    /// Returns `impl Future<Item = bool, Error = NatsError>`
+    pub fn delete_consumer(
+        &self,
+        stream: &str,
+        consumer: &str,
+    ) -> impl Future<Item = bool, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(stream).and_then(|_| check_name(consumer)) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        Either::B(
+            self.request(&format!("CONSUMER.DELETE.{}.{}", stream, consumer), Bytes::new())
+                .map(|res: SuccessResponse| res.success),
+        )
+    }
+
+    /// Fetches the configuration and state of a consumer of a stream. Fails with `JsErrorCode::ConsumerNotFound`
+    /// if it doesn't exist
+    ///
+    /// Returns `impl Future<Item = ConsumerInfo, Error = NatsError>`
+    pub fn consumer_info(
+        &self,
+        stream: &str,
+        consumer: &str,
+    ) -> impl Future<Item = ConsumerInfo, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(stream).and_then(|_| check_name(consumer)) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        Either::B(self.request(&format!("CONSUMER.INFO.{}.{}", stream, consumer), Bytes::new()))
+    }
+
+    /// Pauses a consumer until a given time, after which it resumes on its own. A paused consumer keeps its
+    /// state but doesn't deliver any message
+    ///
+    /// Returns `impl Future<Item = ConsumerPause, Error = NatsError>`
+    pub fn pause_consumer(
+        &self,
+        stream: &str,
+        consumer: &str,
+        until: SystemTime,
+    ) -> impl Future<Item = ConsumerPause, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(stream).and_then(|_| check_name(consumer)) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        let req = PauseConsumerRequest {
+            pause_until: format_rfc3339(until),
+        };
+        Either::B(self.request_json(&format!("CONSUMER.PAUSE.{}.{}", stream, consumer), &req))
+    }
+
+    /// Resumes a paused consumer right away
+    ///
+    /// Returns `impl Future<Item = ConsumerPause, Error = NatsError>`
+    pub fn resume_consumer(
+        &self,
+        stream: &str,
+        consumer: &str,
+    ) -> impl Future<Item = ConsumerPause, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(stream).and_then(|_| check_name(consumer)) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        Either::B(self.request(&format!("CONSUMER.PAUSE.{}.{}", stream, consumer), Bytes::new()))
+    }
+
+    /// Lists all the consumers of a stream, fetching as many pages from the API as needed
+    ///
+    /// Returns `impl Future<Item = Vec<ConsumerInfo>, Error = NatsError>`
+    pub fn list_consumers(
+        &self,
+        stream: &str,
+    ) -> impl Future<Item = Vec<ConsumerInfo>, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(stream) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        Either::B(self.list_paged(format!("CONSUMER.LIST.{}", stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AckPolicy, ConsumerConfig, ConsumerInfo, DeliverPolicy, ReplayPolicy};
+    use serde_json as json;
+    use std::time::Duration;
+
+    #[test]
+    fn it_encodes_consumer_configs() {
+        let config = ConsumerConfig::builder()
+            .durable_name("worker".to_string())
+            .filter_subject("orders.new".to_string())
+            .build()
+            .unwrap();
+
+        let encoded = json::to_value(&config).unwrap();
+        assert_eq!(encoded["durable_name"], "worker");
+        assert_eq!(encoded["ack_policy"], "explicit");
+        assert_eq!(encoded["ack_wait"], 30_000_000_000u64);
+        assert_eq!(encoded["replay_policy"], "instant");
+        assert!(encoded.get("deliver_subject").is_none());
+        assert_eq!(json::from_value::<ConsumerConfig>(encoded).unwrap(), config);
+
+        let config = ConsumerConfig::builder()
+            .ack_policy(AckPolicy::None)
+            .ack_wait(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        assert_eq!(json::to_value(&config).unwrap()["ack_policy"], "none");
+        assert!(json::from_str::<ReplayPolicy>(r#""fast""#).is_err());
+        assert_eq!(
+            json::from_str::<ReplayPolicy>(r#""original""#).unwrap(),
+            ReplayPolicy::Original
+        );
+        assert!(ConsumerConfig::builder()
+            .durable_name("a.b".to_string())
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn it_parses_paused_consumers() {
+        let info: ConsumerInfo = json::from_str(
+            r#"{"stream_name":"ORDERS","name":"worker","config":{"pause_until":"2018-10-01T00:00:00Z"},"paused":true,"pause_remaining":60000000000}"#,
+        ).unwrap();
+        assert!(info.paused);
+        assert_eq!(info.pause_remaining, Duration::from_secs(60));
+        assert_eq!(info.config.pause_until.unwrap(), "2018-10-01T00:00:00Z");
+    }
+
+    #[test]
+    fn it_checks_deliver_policies() {
+        let config = ConsumerConfig::builder()
+            .deliver_policy(DeliverPolicy::ByStartSequence)
+            .opt_start_seq(42)
+            .build()
+            .unwrap();
+        let encoded = json::to_value(&config).unwrap();
+        assert_eq!(encoded["deliver_policy"], "by_start_sequence");
+        assert_eq!(encoded["opt_start_seq"], 42);
+
+        assert!(ConsumerConfig::builder().opt_start_seq(42).build().is_err());
+        assert!(ConsumerConfig::builder()
+            .deliver_policy(DeliverPolicy::ByStartTime)
+            .build()
+            .is_err());
+    }
+}
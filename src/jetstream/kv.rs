@@ -0,0 +1,505 @@
+use bytes::Bytes;
+use futures::{
+    future::{self, Either, Loop},
+    prelude::*,
+};
+use std::time::Duration;
+
+use super::{
+    format_rfc3339, ConsumerConfig, DeliverPolicy, DiscardPolicy, JsContext, JsErrorCode, OrderedSubscription,
+    StorageType, StoredMessage, StreamConfig, EXPECTED_LAST_SUBJECT_SEQUENCE_HEADER, ROLLUP_HEADER,
+};
+use error::NatsError;
+use protocol::commands::Headers;
+
+/// Header telling whether a message of a bucket deletes or purges its key
+pub const KV_OPERATION_HEADER: &str = "KV-Operation";
+
+/// Maximum amount of revisions a bucket can keep for each key
+const MAX_HISTORY: i64 = 64;
+
+/// Checks the name of a bucket, which is a token of the subjects and of the name of its stream
+pub(crate) fn check_bucket(bucket: &str) -> Result<(), String> {
+    if bucket.is_empty() {
+        return Err("bucket name is empty".into());
+    }
+
+    if !bucket
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "bucket name {:?} can only contain letters, digits, dashes and underscores",
+            bucket
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_key(key: &str) -> Result<(), String> {
+    if key.is_empty() || key.starts_with('.') || key.ends_with('.') {
+        return Err(format!("key {:?} is empty or starts or ends with a dot", key));
+    }
+
+    if !key
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '/' || c == '=' || c == '.')
+    {
+        return Err(format!("key {:?} contains invalid characters", key));
+    }
+
+    Ok(())
+}
+
+/// Checks a key that can contain wildcards, `*` matching a token and a trailing `>` all the remaining ones
+fn check_key_pattern(pattern: &str) -> Result<(), String> {
+    let mut tokens = pattern.split('.').peekable();
+    while let Some(token) = tokens.next() {
+        let last = tokens.peek().is_none();
+        if token != "*" && !(token == ">" && last) {
+            check_key(token).map_err(|_| format!("key pattern {:?} is invalid", pattern))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Configuration of a Key-Value bucket, stored in the stream `KV_<bucket>`
+#[derive(Debug, Clone, PartialEq, Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct KvConfig {
+    /// Name of the bucket, which can only contain letters, digits, dashes and underscores
+    #[builder(setter(into))]
+    pub bucket: String,
+    /// Amount of revisions kept for each key, up to 64
+    #[builder(default = "1")]
+    pub history: i64,
+    /// Age after which the revisions are removed, zero being unlimited
+    #[builder(default)]
+    pub max_age: Duration,
+    /// Size of the bucket, in bytes, `-1` being unlimited
+    #[builder(default = "-1")]
+    pub max_bytes: i64,
+    #[builder(default)]
+    pub storage: StorageType,
+    #[builder(default = "1")]
+    pub replicas: usize,
+}
+
+impl KvConfig {
+    pub fn builder() -> KvConfigBuilder {
+        KvConfigBuilder::default()
+    }
+}
+
+impl KvConfigBuilder {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(ref bucket) = self.bucket {
+            check_bucket(bucket)?;
+        }
+
+        if let Some(history) = self.history {
+            if !(1..=MAX_HISTORY).contains(&history) {
+                return Err(format!("history must be between 1 and {}", MAX_HISTORY));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Operation a revision of a key has been made by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KvOperation {
+    Put,
+    /// The key has been deleted, its previous revisions being kept
+    Delete,
+    /// The key has been deleted along with its previous revisions
+    Purge,
+}
+
+/// Revision of a key, returned by `KvStore::get()` and yielded by `KvWatcher`
+#[derive(Debug, Clone, PartialEq)]
+pub struct KvEntry {
+    pub bucket: String,
+    pub key: String,
+    pub value: Bytes,
+    /// Revision of the key, which is the sequence of its message in the stream of the bucket
+    pub revision: u64,
+    pub operation: KvOperation,
+    /// Time at which the revision has been made, in the RFC 3339 format
+    pub created: String,
+}
+
+impl KvEntry {
+    fn from_stored(bucket: String, key: String, msg: StoredMessage) -> Self {
+        let operation = match msg.headers.as_ref().and_then(|h| h.get(KV_OPERATION_HEADER)) {
+            Some("DEL") => KvOperation::Delete,
+            Some("PURGE") => KvOperation::Purge,
+            _ => KvOperation::Put,
+        };
+
+        KvEntry {
+            bucket,
+            key,
+            value: msg.payload,
+            revision: msg.sequence,
+            operation,
+            created: msg.time,
+        }
+    }
+}
+
+/// Key-Value bucket, returned by `JsContext::create_bucket()` or `JsContext::key_value()`.
+///
+/// Every key is a subject of the stream of the bucket, and every revision of a key a message of it. The
+/// methods changing a key resolve with its new revision. They need headers to be negotiated with the server,
+/// except for `put()`, and fail with `NatsError::CommandBuildError` otherwise
+#[derive(Debug, Clone)]
+pub struct KvStore {
+    js: JsContext,
+    bucket: String,
+}
+
+impl KvStore {
+    /// Name of the bucket
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    /// Name of the stream of the bucket
+    fn stream(&self) -> String {
+        format!("KV_{}", self.bucket)
+    }
+
+    fn subject(&self, key: &str) -> String {
+        format!("$KV.{}.{}", self.bucket, key)
+    }
+
+    fn publish(
+        &self,
+        key: &str,
+        headers: Headers,
+        value: Bytes,
+    ) -> impl Future<Item = u64, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_key(key) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        if let Err(e) = self.js.check_headers(&headers, "key-value operations other than put") {
+            return Either::A(future::err(e));
+        }
+
+        Either::B(
+            self.js
+                .publish_with_headers(self.subject(key), headers, value)
+                .map(|ack| ack.sequence),
+        )
+    }
+
+    /// Sets the value of a key, resolves with its new revision
+    ///
+    /// Returns `impl Future<Item = u64, Error = NatsError>`
+    pub fn put(&self, key: &str, value: Bytes) -> impl Future<Item = u64, Error = NatsError> + Send + Sync {
+        self.publish(key, Headers::new(), value)
+    }
+
+    /// Sets the value of a key that doesn't exist yet. Fails with `JsErrorCode::WrongLastSequence` if it does
+    ///
+    /// Returns `impl Future<Item = u64, Error = NatsError>`
+    pub fn create(&self, key: &str, value: Bytes) -> impl Future<Item = u64, Error = NatsError> + Send + Sync {
+        self.update(key, value, 0)
+    }
+
+    /// Sets the value of a key provided its current revision is `last_revision`, which makes concurrent
+    /// updates safe. Fails with `JsErrorCode::WrongLastSequence` if the key has been changed in the meantime
+    ///
+    /// Returns `impl Future<Item = u64, Error = NatsError>`
+    pub fn update(
+        &self,
+        key: &str,
+        value: Bytes,
+        last_revision: u64,
+    ) -> impl Future<Item = u64, Error = NatsError> + Send + Sync {
+        let mut headers = Headers::new();
+        headers.insert(EXPECTED_LAST_SUBJECT_SEQUENCE_HEADER, last_revision.to_string());
+        self.publish(key, headers, value)
+    }
+
+    /// Fetches the last revision of a key, resolves with `None` if the key doesn't exist or has been deleted
+    ///
+    /// Returns `impl Future<Item = Option<KvEntry>, Error = NatsError>`
+    pub fn get(&self, key: &str) -> impl Future<Item = Option<KvEntry>, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_key(key) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        let (bucket, key) = (self.bucket.clone(), key.to_string());
+        Either::B(
+            self.js
+                .get_last_message_for_subject(&self.stream(), &self.subject(&key))
+                .then(move |res| match res {
+                    Ok(msg) => {
+                        let entry = KvEntry::from_stored(bucket, key, msg);
+                        if entry.operation == KvOperation::Put {
+                            Ok(Some(entry))
+                        } else {
+                            Ok(None)
+                        }
+                    }
+                    Err(NatsError::JetStreamError(ref e)) if e.kind() == JsErrorCode::NoMessageFound => Ok(None),
+                    Err(e) => Err(e),
+                }),
+        )
+    }
+
+    /// Deletes a key, keeping its previous revisions
+    ///
+    /// Returns `impl Future<Item = u64, Error = NatsError>`
+    pub fn delete(&self, key: &str) -> impl Future<Item = u64, Error = NatsError> + Send + Sync {
+        let mut headers = Headers::new();
+        headers.insert(KV_OPERATION_HEADER, "DEL");
+        self.publish(key, headers, Bytes::new())
+    }
+
+    /// Deletes a key along with its previous revisions
+    ///
+    /// Returns `impl Future<Item = u64, Error = NatsError>`
+    pub fn purge(&self, key: &str) -> impl Future<Item = u64, Error = NatsError> + Send + Sync {
+        let mut headers = Headers::new();
+        headers.insert(KV_OPERATION_HEADER, "PURGE");
+        headers.insert(ROLLUP_HEADER, "sub");
+        self.publish(key, headers, Bytes::new())
+    }
+
+    /// Watches the keys matching a pattern, in which `*` matches a token and a trailing `>` all the remaining
+    /// ones, e.g. `services.*.port` or `>` for the whole bucket
+    ///
+    /// Returns `impl Future<Item = KvWatcher, Error = NatsError>`
+    pub fn watch(&self, key_pattern: &str) -> impl Future<Item = KvWatcher, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_key_pattern(key_pattern) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        Either::B(self.watch_from(key_pattern, DeliverPolicy::LastPerSubject))
+    }
+
+    fn watch_from(
+        &self,
+        key_pattern: &str,
+        deliver_policy: DeliverPolicy,
+    ) -> impl Future<Item = KvWatcher, Error = NatsError> + Send + Sync {
+        let config = ConsumerConfig::builder()
+            .filter_subject(self.subject(key_pattern))
+            .deliver_policy(deliver_policy)
+            .build();
+
+        let config = match config {
+            Ok(config) => config,
+            Err(e) => return Either::A(future::err(NatsError::CommandBuildError(e))),
+        };
+
+        let bucket = self.bucket.clone();
+        Either::B(
+            self.js
+                .subscribe_ordered(&self.stream(), &config)
+                .map(move |subscription| {
+                    let pending = subscription.info().map_or(0, |info| info.num_pending);
+                    KvWatcher {
+                        bucket,
+                        subscription,
+                        pending,
+                    }
+                }),
+        )
+    }
+
+    /// Fetches all the revisions of a key still kept by the bucket, deleted and purged ones included, oldest
+    /// first. Resolves with an empty list if the key has none
+    ///
+    /// Returns `impl Future<Item = Vec<KvEntry>, Error = NatsError>`
+    pub fn history(&self, key: &str) -> impl Future<Item = Vec<KvEntry>, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_key(key) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        Either::B(self.watch_from(key, DeliverPolicy::All).and_then(|watcher| {
+            future::loop_fn((watcher, vec![]), |(watcher, mut entries)| {
+                if watcher.pending() == 0 {
+                    return Either::A(future::ok(Loop::Break(entries)));
+                }
+
+                Either::B(
+                    watcher
+                        .into_future()
+                        .map_err(|(e, _)| e)
+                        .map(|(entry, watcher)| match entry {
+                            Some(entry) => {
+                                entries.push(entry);
+                                Loop::Continue((watcher, entries))
+                            }
+                            None => Loop::Break(entries),
+                        }),
+                )
+            })
+        }))
+    }
+
+    /// Fetches a given revision of a key, resolves with `None` if the bucket doesn't keep it anymore, if it's the
+    /// revision of another key, or if it deleted the key
+    ///
+    /// Returns `impl Future<Item = Option<KvEntry>, Error = NatsError>`
+    pub fn get_revision(
+        &self,
+        key: &str,
+        revision: u64,
+    ) -> impl Future<Item = Option<KvEntry>, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_key(key) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        let (bucket, key, subject) = (self.bucket.clone(), key.to_string(), self.subject(key));
+        Either::B(
+            self.js
+                .get_message(&self.stream(), revision)
+                .then(move |res| match res {
+                    Ok(ref msg) if msg.subject != subject => Ok(None),
+                    Ok(msg) => {
+                        let entry = KvEntry::from_stored(bucket, key, msg);
+                        if entry.operation == KvOperation::Put {
+                            Ok(Some(entry))
+                        } else {
+                            Ok(None)
+                        }
+                    }
+                    Err(NatsError::JetStreamError(ref e)) if e.kind() == JsErrorCode::NoMessageFound => Ok(None),
+                    Err(e) => Err(e),
+                }),
+        )
+    }
+}
+
+/// Updates of the keys of a bucket, returned by `KvStore::watch()`. The stream starts with the last revision of
+/// every key matching the pattern, deleted and purged ones included, then yields the revisions made afterwards.
+/// It relies on an ordered consumer, and doesn't miss any revision
+#[derive(Debug)]
+pub struct KvWatcher {
+    bucket: String,
+    subscription: OrderedSubscription,
+    /// Revisions the consumer has left to deliver
+    pending: u64,
+}
+
+impl KvWatcher {
+    /// Amount of revisions stored by the bucket that haven't been yielded yet, `0` once the watcher has caught up
+    pub fn pending(&self) -> u64 {
+        self.pending
+    }
+}
+
+impl Stream for KvWatcher {
+    type Error = NatsError;
+    type Item = KvEntry;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let prefix = format!("$KV.{}.", self.bucket);
+        loop {
+            let msg = match self.subscription.poll()? {
+                Async::Ready(Some(msg)) => msg,
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+
+            let meta = match msg.metadata() {
+                Some(meta) => meta,
+                None => continue,
+            };
+
+            let msg = msg.into_message();
+            let key = match msg.subject.get(prefix.len()..) {
+                Some(key) if msg.subject.starts_with(&prefix) => key.to_string(),
+                _ => continue,
+            };
+
+            self.pending = meta.pending;
+            let stored = StoredMessage {
+                subject: msg.subject.to_string(),
+                sequence: meta.stream_sequence,
+                headers: msg.headers,
+                payload: msg.payload,
+                time: format_rfc3339(meta.timestamp),
+            };
+            return Ok(Async::Ready(Some(KvEntry::from_stored(
+                self.bucket.clone(),
+                key,
+                stored,
+            ))));
+        }
+    }
+}
+
+impl JsContext {
+    /// Creates a Key-Value bucket, or returns the existing one if it has the same configuration
+    ///
+    /// Returns `impl Future<Item = KvStore, Error = NatsError>`
+    pub fn create_bucket(&self, config: &KvConfig) -> impl Future<Item = KvStore, Error = NatsError> + Send + Sync {
+        let stream_config = StreamConfig::builder()
+            .name(format!("KV_{}", config.bucket))
+            .subjects(vec![format!("$KV.{}.>", config.bucket)])
+            .storage(config.storage)
+            .replicas(config.replicas)
+            .max_age(config.max_age)
+            .max_bytes(config.max_bytes)
+            .max_msgs_per_subject(config.history)
+            .discard(DiscardPolicy::New)
+            .allow_rollup_hdrs(true)
+            .deny_delete(true)
+            .build();
+
+        let stream_config = match stream_config {
+            Ok(stream_config) => stream_config,
+            Err(e) => return Either::A(future::err(NatsError::CommandBuildError(e))),
+        };
+
+        let store = KvStore {
+            js: self.clone(),
+            bucket: config.bucket.clone(),
+        };
+
+        Either::B(self.add_stream(&stream_config).map(move |_| store))
+    }
+
+    /// Binds to an existing Key-Value bucket
+    pub fn key_value(&self, bucket: &str) -> Result<KvStore, NatsError> {
+        check_bucket(bucket).map_err(NatsError::CommandBuildError)?;
+
+        Ok(KvStore {
+            js: self.clone(),
+            bucket: bucket.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_key, check_key_pattern, KvConfig};
+
+    #[test]
+    fn it_validates_buckets_and_keys() {
+        assert!(KvConfig::builder().bucket("config").build().is_ok());
+        assert!(KvConfig::builder().bucket("con.fig").build().is_err());
+        assert!(KvConfig::builder().bucket("config").history(65).build().is_err());
+
+        assert!(check_key("services/api.port").is_ok());
+        assert!(check_key("services.*").is_err());
+        assert!(check_key(".port").is_err());
+        assert!(check_key("").is_err());
+
+        assert!(check_key_pattern(">").is_ok());
+        assert!(check_key_pattern("services.*.port").is_ok());
+        assert!(check_key_pattern("services.>").is_ok());
+        assert!(check_key_pattern("services.>.port").is_err());
+        assert!(check_key_pattern("services..port").is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use bytes::Bytes;
+use futures::{
+    future::{self, Either},
+    prelude::*,
+};
+use std::{
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use client::NatsClient;
+use error::NatsError;
+use protocol::commands::*;
+
+/// Acknowledgements sent to the server for a message delivered by a consumer
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AckKind {
+    Ack,
+    Nak(Option<Duration>),
+    Term,
+    InProgress,
+}
+
+impl AckKind {
+    fn payload(self) -> Bytes {
+        match self {
+            AckKind::Ack => Bytes::from_static(b"+ACK"),
+            AckKind::Nak(None) => Bytes::from_static(b"-NAK"),
+            AckKind::Nak(Some(delay)) => Bytes::from(format!(
+                "-NAK {{\"delay\":{}}}",
+                delay.as_secs() * 1_000_000_000 + u64::from(delay.subsec_nanos())
+            )),
+            AckKind::Term => Bytes::from_static(b"+TERM"),
+            AckKind::InProgress => Bytes::from_static(b"+WPI"),
+        }
+    }
+}
+
+/// Delivery metadata of a message delivered by a consumer, parsed from its
+/// `$JS.ACK.<stream>.<consumer>.<delivered>.<sseq>.<cseq>.<ts>.<pending>` reply subject. Servers of the
+/// JetStream domains also put the domain and the hash of the account before the stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsMessageMeta {
+    /// Domain of the JetStream, only known with the extended reply subjects
+    pub domain: Option<String>,
+    pub stream: String,
+    pub consumer: String,
+    /// Amount of times the message has been delivered, `1` on its first delivery
+    pub delivered: u64,
+    /// Sequence of the message in the stream
+    pub stream_sequence: u64,
+    /// Sequence of the delivery in the consumer
+    pub consumer_sequence: u64,
+    /// Time at which the message has been stored by the stream
+    pub timestamp: SystemTime,
+    /// Amount of messages left to deliver by the consumer after this one
+    pub pending: u64,
+}
+
+impl JsMessageMeta {
+    /// Parses the reply subject of a message delivered by a consumer, returns `None` if it doesn't have the
+    /// expected format
+    pub fn parse(reply_to: &str) -> Option<Self> {
+        let tokens: Vec<&str> = reply_to.split('.').collect();
+        if tokens.len() < 9 || tokens[0] != "$JS" || tokens[1] != "ACK" {
+            return None;
+        }
+
+        // `$JS.ACK.<domain>.<account hash>.<stream>...`, with a trailing random token
+        let (domain, tokens) = if tokens.len() >= 11 {
+            let domain = match tokens[2] {
+                "_" => None,
+                domain => Some(domain.to_string()),
+            };
+            (domain, &tokens[4..])
+        } else {
+            (None, &tokens[2..])
+        };
+
+        Some(JsMessageMeta {
+            domain,
+            stream: tokens[0].into(),
+            consumer: tokens[1].into(),
+            delivered: tokens[2].parse().ok()?,
+            stream_sequence: tokens[3].parse().ok()?,
+            consumer_sequence: tokens[4].parse().ok()?,
+            timestamp: UNIX_EPOCH + Duration::from_nanos(tokens[5].parse().ok()?),
+            pending: tokens[6].parse().ok()?,
+        })
+    }
+}
+
+/// Message delivered by a JetStream consumer, which can be acknowledged through the client it has been received
+/// on. The underlying `Message` is available through `Deref`
+#[derive(Debug, Clone)]
+pub struct JsMessage {
+    message: Message,
+    client: Arc<NatsClient>,
+}
+
+impl JsMessage {
+    pub(crate) fn new(message: Message, client: Arc<NatsClient>) -> Self {
+        JsMessage { message, client }
+    }
+
+    /// Delivery metadata of the message, `None` if its reply subject isn't the one of a consumer
+    pub fn metadata(&self) -> Option<JsMessageMeta> {
+        self.message
+            .reply_to
+            .as_ref()
+            .and_then(|reply_to| JsMessageMeta::parse(reply_to))
+    }
+
+    /// Returns the underlying message
+    pub fn into_message(self) -> Message {
+        self.message
+    }
+
+    /// Acknowledges the message, so that it's not delivered again
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn ack(&self) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        self.send_ack(AckKind::Ack)
+    }
+
+    /// Acknowledges the message and waits for the server to confirm it has recorded the acknowledgement, so
+    /// that the message is guaranteed not to be delivered again. Relies on the `request_timeout` of the client
+    /// to give up on the confirmation
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn ack_sync(&self) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        match self.ack_subject() {
+            Ok(subject) => Either::A(self.client.request(subject, AckKind::Ack.payload()).map(|_| ())),
+            Err(e) => Either::B(future::err(e)),
+        }
+    }
+
+    /// Tells the server the message cannot be processed for now, to have it delivered again right away or
+    /// after `delay`
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn nak(&self, delay: Option<Duration>) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        self.send_ack(AckKind::Nak(delay))
+    }
+
+    /// Tells the server the message will never be processed, so that it's not delivered again
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn term(&self) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        self.send_ack(AckKind::Term)
+    }
+
+    /// Tells the server the message is still being processed, resetting its ack wait timer
+    ///
+    /// Returns `impl Future<Item = (), Error = NatsError>`
+    pub fn in_progress(&self) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        self.send_ack(AckKind::InProgress)
+    }
+
+    /// Reply subject of the message, to which the acknowledgements are sent
+    fn ack_subject(&self) -> Result<String, NatsError> {
+        self.message
+            .reply_to
+            .clone()
+            .ok_or_else(|| NatsError::NoReplySubject(self.message.subject.to_string()))
+    }
+
+    fn send_ack(&self, kind: AckKind) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        match self.ack_subject() {
+            Ok(subject) => Either::A(self.client.publish(PubCommand {
+                subject,
+                payload: kind.payload(),
+                reply_to: None,
+                headers: None,
+            })),
+            Err(e) => Either::B(future::err(e)),
+        }
+    }
+}
+
+impl Deref for JsMessage {
+    type Target = Message;
+
+    fn deref(&self) -> &Self::Target {
+        &self.message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AckKind, JsMessageMeta};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn it_encodes_acks() {
+        assert_eq!(AckKind::Ack.payload(), "+ACK");
+        assert_eq!(AckKind::Nak(None).payload(), "-NAK");
+        assert_eq!(
+            AckKind::Nak(Some(Duration::from_millis(1500))).payload(),
+            "-NAK {\"delay\":1500000000}"
+        );
+        assert_eq!(AckKind::Term.payload(), "+TERM");
+        assert_eq!(AckKind::InProgress.payload(), "+WPI");
+    }
+
+    #[test]
+    fn it_parses_metadata() {
+        let meta = JsMessageMeta::parse("$JS.ACK.ORDERS.worker.2.42.7.1538352000000000000.3").unwrap();
+        assert_eq!(meta.domain, None);
+        assert_eq!(meta.stream, "ORDERS");
+        assert_eq!(meta.consumer, "worker");
+        assert_eq!(meta.delivered, 2);
+        assert_eq!(meta.stream_sequence, 42);
+        assert_eq!(meta.consumer_sequence, 7);
+        assert_eq!(meta.timestamp, UNIX_EPOCH + Duration::from_secs(1_538_352_000));
+        assert_eq!(meta.pending, 3);
+
+        let meta = JsMessageMeta::parse("$JS.ACK.hub.ACCHASH.ORDERS.worker.1.42.7.1538352000000000000.0.rand").unwrap();
+        assert_eq!(meta.domain, Some("hub".into()));
+        assert_eq!(meta.stream, "ORDERS");
+        assert_eq!(meta.stream_sequence, 42);
+
+        let meta = JsMessageMeta::parse("$JS.ACK._.ACCHASH.ORDERS.worker.1.42.7.1538352000000000000.0.rand").unwrap();
+        assert_eq!(meta.domain, None);
+
+        assert!(JsMessageMeta::parse("_INBOX.foo").is_none());
+        assert!(JsMessageMeta::parse("$JS.ACK.ORDERS.worker.1.x.7.0.0").is_none());
+    }
+}
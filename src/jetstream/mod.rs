@@ -0,0 +1,560 @@
+use bytes::Bytes;
+use futures::{
+    future::{self, Either, Loop},
+    prelude::*,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json as json;
+use std::{
+    fmt,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use client::{JsonCodec, NatsClient, PayloadCodec};
+use error::NatsError;
+
+mod consumer;
+pub mod kv;
+mod message;
+pub mod object_store;
+mod publish;
+mod pull;
+mod push;
+mod stream;
+pub use self::consumer::*;
+pub use self::message::*;
+pub use self::publish::*;
+pub use self::pull::*;
+pub use self::push::*;
+pub use self::stream::*;
+
+/// Prefix of the subjects of the JetStream API, `$JS.API.>`
+pub const DEFAULT_API_PREFIX: &str = "$JS.API";
+
+/// Header purging the previous messages of the subject of a message, or of the whole stream if set to `all`
+pub(crate) const ROLLUP_HEADER: &str = "Nats-Rollup";
+
+/// Error codes of the JetStream API, as found in the `err_code` field of its errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsErrorCode {
+    /// The request cannot be parsed or is missing required fields
+    BadRequest,
+    /// JetStream is not enabled on the server
+    JetStreamNotEnabled,
+    /// JetStream is not enabled for the account of the client
+    JetStreamNotEnabledForAccount,
+    /// No stream has the requested name
+    StreamNotFound,
+    /// A stream with the same name but a different configuration already exists
+    StreamNameInUse,
+    /// The subjects of the stream overlap with the ones of another stream
+    StreamSubjectOverlap,
+    /// No consumer has the requested name
+    ConsumerNotFound,
+    /// A consumer with the same name but a different configuration already exists
+    ConsumerNameInUse,
+    /// The requested message doesn't exist
+    NoMessageFound,
+    /// No message of the stream has the requested sequence
+    SequenceNotFound,
+    /// The message has been rejected since it was published to another stream than the expected one
+    StreamNotMatch,
+    /// The message has been rejected since the last message id of the stream isn't the expected one
+    WrongLastMsgId,
+    /// The message has been rejected since the last sequence of the stream, or of the subject, isn't the expected
+    /// one
+    WrongLastSequence,
+    /// Any other error code
+    Other(u16),
+}
+
+impl From<u16> for JsErrorCode {
+    fn from(code: u16) -> Self {
+        match code {
+            10003 => JsErrorCode::BadRequest,
+            10013 => JsErrorCode::ConsumerNameInUse,
+            10014 => JsErrorCode::ConsumerNotFound,
+            10037 => JsErrorCode::NoMessageFound,
+            10039 => JsErrorCode::JetStreamNotEnabledForAccount,
+            10057 => JsErrorCode::SequenceNotFound,
+            10058 => JsErrorCode::StreamNameInUse,
+            10059 => JsErrorCode::StreamNotFound,
+            10060 => JsErrorCode::StreamNotMatch,
+            10065 => JsErrorCode::StreamSubjectOverlap,
+            10070 => JsErrorCode::WrongLastMsgId,
+            10071 => JsErrorCode::WrongLastSequence,
+            10076 => JsErrorCode::JetStreamNotEnabled,
+            code => JsErrorCode::Other(code),
+        }
+    }
+}
+
+/// Error returned by the JetStream API instead of the expected response
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JsApiError {
+    /// HTTP-like status code, e.g. `404`
+    pub code: u16,
+    /// JetStream specific error code, see `JsErrorCode`
+    #[serde(default)]
+    pub err_code: u16,
+    #[serde(default)]
+    pub description: String,
+}
+
+impl JsApiError {
+    /// Typed error code of the error
+    pub fn kind(&self) -> JsErrorCode {
+        JsErrorCode::from(self.err_code)
+    }
+}
+
+impl fmt::Display for JsApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (code {}, err_code {})",
+            self.description, self.code, self.err_code
+        )
+    }
+}
+
+/// Envelope of the responses of the JetStream API, which hold either the expected fields or an `error`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ApiResponse<T> {
+    Error { error: JsApiError },
+    Ok(T),
+}
+
+/// Parses the payload of a JetStream API response, turning the errors it holds into `NatsError::JetStreamError`
+pub(crate) fn parse_api_response<T: DeserializeOwned>(payload: &[u8]) -> Result<T, NatsError> {
+    match json::from_slice(payload) {
+        Ok(ApiResponse::Ok(response)) => Ok(response),
+        Ok(ApiResponse::Error { error }) => Err(NatsError::JetStreamError(error)),
+        Err(e) => Err(NatsError::PayloadDecodeError(e.to_string())),
+    }
+}
+
+/// Response of the API endpoints deleting things
+#[derive(Deserialize)]
+struct SuccessResponse {
+    success: bool,
+}
+
+/// Request of the API endpoints listing things, which are paged
+#[derive(Serialize)]
+struct ListRequest {
+    offset: usize,
+}
+
+/// Page of the response of the API endpoints listing things
+#[derive(Deserialize)]
+struct ListPage<T> {
+    total: usize,
+    #[serde(alias = "streams", alias = "consumers")]
+    items: Option<Vec<T>>,
+}
+
+/// Checks the name of a stream or a consumer, which becomes a token of the subjects of the API
+pub(crate) fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("name is empty".into());
+    }
+
+    if name.contains(|c: char| c == '.' || c == '*' || c == '>' || c.is_whitespace()) {
+        return Err(format!("name {:?} cannot contain dots, wildcards or whitespace", name));
+    }
+
+    Ok(())
+}
+
+/// Decodes the standard base64 the JetStream API encodes the headers and payloads of stored messages with
+pub(crate) fn decode_base64(encoded: &str) -> Result<Vec<u8>, NatsError> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0u32);
+    for c in encoded.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => {
+                return Err(NatsError::PayloadDecodeError(format!(
+                    "invalid base64 character {:?}",
+                    c as char
+                )))
+            }
+        };
+
+        acc = (acc << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+
+    Ok(decoded)
+}
+
+/// Encodes data with the URL and filename safe base64 alphabet, whose output can be used as a subject token
+pub(crate) fn encode_base64_url(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let block = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |block, (i, &b)| block | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(block >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+/// Formats a time in UTC with the RFC 3339 format of the JetStream API, the fraction of second being trimmed of
+/// its trailing zeros
+pub(crate) fn format_rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Civil date of a day count, from http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let mut formatted = format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    );
+    if since_epoch.subsec_nanos() > 0 {
+        let nanos = format!(".{:09}", since_epoch.subsec_nanos());
+        formatted.push_str(nanos.trim_end_matches('0'));
+    }
+    formatted.push('Z');
+    formatted
+}
+
+/// (De)serializes durations as the amount of nanoseconds the JetStream API expects
+pub(crate) mod nanos {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_nanos)
+    }
+}
+
+/// Limits of the JetStream resources of an account, `-1` meaning unlimited
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccountLimits {
+    pub max_memory: i64,
+    pub max_storage: i64,
+    pub max_streams: i64,
+    pub max_consumers: i64,
+    /// Maximum size of a single memory stream, in bytes
+    pub memory_max_stream_bytes: i64,
+    /// Maximum size of a single file stream, in bytes
+    pub storage_max_stream_bytes: i64,
+    /// Whether the streams must set their `max_bytes`
+    pub max_bytes_required: bool,
+}
+
+/// Amount left out of a limit, `None` meaning unlimited
+fn remaining(limit: i64, used: u64) -> Option<u64> {
+    if limit < 0 {
+        None
+    } else {
+        Some((limit as u64).saturating_sub(used))
+    }
+}
+
+/// Counters of the calls to the JetStream API made by an account
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiStats {
+    pub total: u64,
+    pub errors: u64,
+}
+
+/// JetStream usage of the account of the client, returned by `JsContext::account_info()`
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccountInfo {
+    /// Memory used by the streams, in bytes
+    pub memory: u64,
+    /// Storage used by the streams, in bytes
+    pub storage: u64,
+    pub streams: u64,
+    pub consumers: u64,
+    pub limits: AccountLimits,
+    pub api: ApiStats,
+    /// JetStream domain of the server that answered, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+}
+
+impl AccountInfo {
+    /// Amount of memory left to the account, in bytes, `None` meaning unlimited
+    pub fn remaining_memory(&self) -> Option<u64> {
+        remaining(self.limits.max_memory, self.memory)
+    }
+
+    /// Amount of storage left to the account, in bytes, `None` meaning unlimited
+    pub fn remaining_storage(&self) -> Option<u64> {
+        remaining(self.limits.max_storage, self.storage)
+    }
+
+    /// Amount of streams the account can still create, `None` meaning unlimited
+    pub fn remaining_streams(&self) -> Option<u64> {
+        remaining(self.limits.max_streams, self.streams)
+    }
+
+    /// Amount of consumers the account can still create, `None` meaning unlimited
+    pub fn remaining_consumers(&self) -> Option<u64> {
+        remaining(self.limits.max_consumers, self.consumers)
+    }
+}
+
+/// Entry point of the JetStream API, sending its requests through a `NatsClient`.
+///
+/// The API is reached on `$JS.API.>` by default. The JetStream of another domain, e.g. the one of a hub seen from
+/// a leaf node, is reached with `with_domain()`, and accounts importing the API under another prefix can set
+/// theirs with `with_prefix()`. Errors returned by the API fail the futures with `NatsError::JetStreamError`, and
+/// servers without JetStream make them fail with `NatsError::NoResponders` when headers are enabled, or time
+/// out according to the `request_timeout` of the client otherwise
+#[derive(Debug, Clone)]
+pub struct JsContext {
+    client: Arc<NatsClient>,
+    prefix: String,
+    domain: Option<String>,
+    publish_retry: PublishRetry,
+}
+
+impl JsContext {
+    pub fn new(client: Arc<NatsClient>) -> Self {
+        JsContext {
+            client,
+            prefix: DEFAULT_API_PREFIX.into(),
+            domain: None,
+            publish_retry: PublishRetry::default(),
+        }
+    }
+
+    /// Sets the prefix of the subjects of the JetStream API, in place of `$JS.API`
+    pub fn with_prefix<P: Into<String>>(mut self, prefix: P) -> Self {
+        let prefix = prefix.into();
+        self.prefix = prefix.trim_end_matches('.').into();
+        self.domain = None;
+        self
+    }
+
+    /// Sends the requests to the JetStream of a domain, on `$JS.<domain>.API`. An empty domain goes back to
+    /// the JetStream of the account, on `$JS.API`
+    pub fn with_domain<D: Into<String>>(mut self, domain: D) -> Self {
+        let domain = domain.into();
+        if domain.is_empty() {
+            self.prefix = DEFAULT_API_PREFIX.into();
+            self.domain = None;
+        } else {
+            self.prefix = format!("$JS.{}.API", domain);
+            self.domain = Some(domain);
+        }
+
+        self
+    }
+
+    /// Sets how the publishes are retried while no stream answers them, see `PublishRetry`
+    pub fn with_publish_retry(mut self, publish_retry: PublishRetry) -> Self {
+        self.publish_retry = publish_retry;
+        self
+    }
+
+    /// Prefix of the subjects of the JetStream API
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Domain of the JetStream the requests are sent to, if set with `with_domain()`
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+
+    /// Client sending the requests of this context
+    pub fn client(&self) -> &Arc<NatsClient> {
+        &self.client
+    }
+
+    /// Subject of an API endpoint, e.g. `STREAM.INFO.<stream>`
+    pub fn api_subject(&self, api: &str) -> String {
+        format!("{}.{}", self.prefix, api)
+    }
+
+    /// Sends a request to an API endpoint, e.g. `STREAM.INFO.<stream>`, and parses its response into `T`
+    ///
+    /// Returns `impl Future<Item = T, Error = NatsError>`
+    pub fn request<T: DeserializeOwned + Send + Sync>(
+        &self,
+        api: &str,
+        payload: Bytes,
+    ) -> impl Future<Item = T, Error = NatsError> + Send + Sync {
+        let subject = self.api_subject(api);
+        debug!(target: "nitox", "Sending JetStream API request to {}", subject);
+        self.client
+            .request(subject, payload)
+            .and_then(|msg| parse_api_response(&msg.payload))
+    }
+
+    /// Sends a request whose payload is `req` serialized to JSON to an API endpoint
+    pub(crate) fn request_json<Req: Serialize, T: DeserializeOwned + Send + Sync>(
+        &self,
+        api: &str,
+        req: &Req,
+    ) -> impl Future<Item = T, Error = NatsError> + Send + Sync {
+        match JsonCodec.encode(req) {
+            Ok(payload) => Either::A(self.request(api, payload)),
+            Err(e) => Either::B(future::err(e)),
+        }
+    }
+
+    /// Sends requests to an API endpoint listing things until all the pages have been received
+    fn list_paged<T>(&self, api: String) -> impl Future<Item = Vec<T>, Error = NatsError> + Send + Sync
+    where
+        T: DeserializeOwned + Send + Sync + 'static,
+    {
+        let js = self.clone();
+        future::loop_fn(Vec::new(), move |mut items: Vec<T>| {
+            let req = ListRequest { offset: items.len() };
+            js.request_json(&api, &req).map(move |page: ListPage<T>| {
+                let received = page.items.unwrap_or_default();
+                let exhausted = received.is_empty();
+                items.extend(received);
+                if exhausted || items.len() >= page.total {
+                    Loop::Break(items)
+                } else {
+                    Loop::Continue(items)
+                }
+            })
+        })
+    }
+
+    /// Fetches the JetStream usage and limits of the account of the client
+    ///
+    /// Returns `impl Future<Item = AccountInfo, Error = NatsError>`
+    pub fn account_info(&self) -> impl Future<Item = AccountInfo, Error = NatsError> + Send + Sync {
+        self.request("INFO", Bytes::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_base64, encode_base64_url, format_rfc3339, parse_api_response, AccountInfo, JsErrorCode};
+    use error::NatsError;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn it_parses_responses() {
+        let info: AccountInfo = parse_api_response(
+            br#"{"type":"io.nats.jetstream.api.v1.account_info_response","memory":0,"storage":1024,"streams":2,"consumers":3,"limits":{"max_memory":-1,"max_storage":-1,"max_streams":-1,"max_consumers":-1}}"#,
+        ).unwrap();
+        assert_eq!(info.storage, 1024);
+        assert_eq!(info.streams, 2);
+        assert_eq!(info.limits.max_consumers, -1);
+        assert_eq!(info.api.total, 0);
+        assert_eq!(info.remaining_streams(), None);
+    }
+
+    #[test]
+    fn it_computes_remaining_quotas() {
+        let info: AccountInfo = parse_api_response(
+            br#"{"memory":512,"storage":4096,"streams":2,"consumers":5,"limits":{"max_memory":1024,"max_storage":2048,"max_streams":3,"max_consumers":-1},"domain":"hub"}"#,
+        ).unwrap();
+        assert_eq!(info.remaining_memory(), Some(512));
+        assert_eq!(info.remaining_storage(), Some(0));
+        assert_eq!(info.remaining_streams(), Some(1));
+        assert_eq!(info.remaining_consumers(), None);
+        assert_eq!(info.domain.as_deref(), Some("hub"));
+    }
+
+    #[test]
+    fn it_parses_errors() {
+        let res: Result<AccountInfo, _> = parse_api_response(
+            br#"{"type":"io.nats.jetstream.api.v1.stream_info_response","error":{"code":404,"err_code":10059,"description":"stream not found"}}"#,
+        );
+        match res {
+            Err(NatsError::JetStreamError(e)) => {
+                assert_eq!(e.code, 404);
+                assert_eq!(e.kind(), JsErrorCode::StreamNotFound);
+                assert_eq!(e.to_string(), "stream not found (code 404, err_code 10059)");
+            }
+            r => panic!("Expected JetStreamError, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn it_decodes_base64() {
+        assert_eq!(decode_base64("dmFsdWU=").unwrap(), b"value");
+        assert_eq!(decode_base64("TkFUUy8xLjANCg0K").unwrap(), b"NATS/1.0\r\n\r\n");
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert!(decode_base64("dm-s").is_err());
+    }
+
+    #[test]
+    fn it_encodes_base64_urls() {
+        assert_eq!(encode_base64_url(b"value"), "dmFsdWU=");
+        assert_eq!(encode_base64_url(b"blob"), "YmxvYg==");
+        assert_eq!(encode_base64_url(b"abc"), "YWJj");
+        assert_eq!(encode_base64_url(&[0xfb, 0xff]), "-_8=");
+        assert_eq!(encode_base64_url(b""), "");
+    }
+
+    #[test]
+    fn it_formats_times() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_538_352_000);
+        assert_eq!(format_rfc3339(time), "2018-10-01T00:00:00Z");
+        assert_eq!(
+            format_rfc3339(time + Duration::from_millis(1500)),
+            "2018-10-01T00:00:01.5Z"
+        );
+        assert_eq!(
+            format_rfc3339(UNIX_EPOCH - Duration::from_secs(1)),
+            "1970-01-01T00:00:00Z"
+        );
+        assert_eq!(
+            format_rfc3339(UNIX_EPOCH + Duration::from_secs(951_827_696)),
+            "2000-02-29T12:34:56Z"
+        );
+    }
+}
@@ -0,0 +1,474 @@
+use bytes::{Bytes, BytesMut};
+use futures::{
+    future::{self, Either},
+    prelude::*,
+    stream,
+};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde_json as json;
+use std::time::Duration;
+
+use super::kv::check_bucket;
+use super::{
+    encode_base64_url, ConsumerConfig, DiscardPolicy, JsContext, JsErrorCode, OrderedSubscription, StorageType,
+    StreamConfig, ROLLUP_HEADER,
+};
+use client::{JsonCodec, PayloadCodec};
+use error::NatsError;
+use protocol::commands::Headers;
+
+/// Size of the chunks objects are split into, well below the default `max_payload` of the servers
+pub const DEFAULT_CHUNK_SIZE: usize = 128 * 1024;
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn generate_nuid() -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(22).collect()
+}
+
+/// Configuration of an object store, stored in the stream `OBJ_<bucket>`
+#[derive(Debug, Clone, PartialEq, Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct ObjectStoreConfig {
+    /// Name of the bucket, which can only contain letters, digits, dashes and underscores
+    #[builder(setter(into))]
+    pub bucket: String,
+    /// Age after which the objects are removed, zero being unlimited
+    #[builder(default)]
+    pub max_age: Duration,
+    /// Size of the bucket, in bytes, `-1` being unlimited
+    #[builder(default = "-1")]
+    pub max_bytes: i64,
+    #[builder(default)]
+    pub storage: StorageType,
+    #[builder(default = "1")]
+    pub replicas: usize,
+}
+
+impl ObjectStoreConfig {
+    pub fn builder() -> ObjectStoreConfigBuilder {
+        ObjectStoreConfigBuilder::default()
+    }
+}
+
+impl ObjectStoreConfigBuilder {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(ref bucket) = self.bucket {
+            check_bucket(bucket)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Description of an object, stored as the last message of `$O.<bucket>.M.<name encoded in base64>`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ObjectInfo {
+    pub name: String,
+    pub bucket: String,
+    /// Identifier of this version of the object, whose chunks are stored on `$O.<bucket>.C.<nuid>`
+    pub nuid: String,
+    /// Size of the object, in bytes
+    pub size: u64,
+    pub chunks: u64,
+    /// Time at which the object has been stored, in the RFC 3339 format
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub mtime: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub deleted: bool,
+}
+
+/// Chunks uploaded so far by `ObjectStore::put()`, and the data waiting to fill the next one
+struct Upload {
+    buffer: BytesMut,
+    size: u64,
+    chunks: u64,
+}
+
+/// Object store, returned by `JsContext::create_object_store()` or `JsContext::object_store()`.
+///
+/// Objects are split into chunks stored as messages of the stream of the bucket, so they can be larger than the
+/// `max_payload` of the server. They're uploaded and downloaded as streams of `Bytes`, one chunk at a time.
+/// Storing and deleting objects needs headers to be negotiated with the server, and fails with
+/// `NatsError::CommandBuildError` otherwise
+#[derive(Debug, Clone)]
+pub struct ObjectStore {
+    js: JsContext,
+    bucket: String,
+    chunk_size: usize,
+}
+
+impl ObjectStore {
+    /// Sets the size of the chunks the objects put from now on are split into, `DEFAULT_CHUNK_SIZE` by default
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Name of the bucket
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    fn stream(&self) -> String {
+        format!("OBJ_{}", self.bucket)
+    }
+
+    fn meta_subject(&self, name: &str) -> String {
+        format!("$O.{}.M.{}", self.bucket, encode_base64_url(name.as_bytes()))
+    }
+
+    fn chunk_subject(&self, nuid: &str) -> String {
+        format!("$O.{}.C.{}", self.bucket, nuid)
+    }
+
+    /// Headers of the descriptions of the objects, which replace the previous ones
+    fn info_headers() -> Headers {
+        let mut headers = Headers::new();
+        headers.insert(ROLLUP_HEADER, "sub");
+        headers
+    }
+
+    /// Replaces the description of an object, purging the previous ones
+    fn publish_info(&self, info: &ObjectInfo) -> impl Future<Item = (), Error = NatsError> + Send + Sync {
+        let headers = Self::info_headers();
+        if let Err(e) = self.js.check_headers(&headers, "object descriptions") {
+            return Either::A(future::err(e));
+        }
+
+        let payload = match JsonCodec.encode(info) {
+            Ok(payload) => payload,
+            Err(e) => return Either::A(future::err(e)),
+        };
+
+        Either::B(
+            self.js
+                .publish_with_headers(self.meta_subject(&info.name), headers, payload)
+                .map(|_| ()),
+        )
+    }
+
+    /// Fetches the description of an object, resolves with `None` if it doesn't exist or has been deleted
+    ///
+    /// Returns `impl Future<Item = Option<ObjectInfo>, Error = NatsError>`
+    pub fn info(&self, name: &str) -> impl Future<Item = Option<ObjectInfo>, Error = NatsError> + Send + Sync {
+        self.js
+            .get_last_message_for_subject(&self.stream(), &self.meta_subject(name))
+            .then(|res| match res {
+                Ok(msg) => {
+                    let mut info: ObjectInfo =
+                        json::from_slice(&msg.payload).map_err(|e| NatsError::PayloadDecodeError(e.to_string()))?;
+                    if info.deleted {
+                        return Ok(None);
+                    }
+
+                    if info.mtime.is_empty() {
+                        info.mtime = msg.time;
+                    }
+                    Ok(Some(info))
+                }
+                Err(NatsError::JetStreamError(ref e)) if e.kind() == JsErrorCode::NoMessageFound => Ok(None),
+                Err(e) => Err(e),
+            })
+    }
+
+    /// Uploads an object, replacing the previous one with the same name once all its chunks have been stored.
+    /// The chunks are published one at a time, each of them waiting for the acknowledgement of the stream, so that
+    /// no more than a chunk of the data is buffered. An upload that fails gets rid of the chunks stored so far
+    ///
+    /// Returns `impl Future<Item = ObjectInfo, Error = NatsError>`
+    pub fn put<S>(&self, name: &str, data: S) -> impl Future<Item = ObjectInfo, Error = NatsError> + Send + Sync
+    where
+        S: Stream<Item = Bytes, Error = NatsError> + Send + Sync + 'static,
+    {
+        if name.is_empty() {
+            return Either::A(future::err(NatsError::CommandBuildError("object name is empty".into())));
+        }
+
+        // Checked before uploading any chunk, the description of the object couldn't be stored otherwise
+        if let Err(e) = self.js.check_headers(&Self::info_headers(), "object descriptions") {
+            return Either::A(future::err(e));
+        }
+
+        let store = self.clone();
+        let name = name.to_string();
+        Either::B(self.info(&name).and_then(move |previous| {
+            let info = ObjectInfo {
+                name,
+                bucket: store.bucket.clone(),
+                nuid: generate_nuid(),
+                size: 0,
+                chunks: 0,
+                mtime: String::new(),
+                deleted: false,
+            };
+
+            let (js, chunk_size) = (store.js.clone(), store.chunk_size);
+            let subject = store.chunk_subject(&info.nuid);
+            let publish_chunk = move |mut upload: Upload, chunk: Bytes| {
+                upload.size += chunk.len() as u64;
+                upload.chunks += 1;
+                js.publish(subject.clone(), chunk).map(move |_| upload)
+            };
+
+            let upload = Upload {
+                buffer: BytesMut::new(),
+                size: 0,
+                chunks: 0,
+            };
+
+            let flush_chunk = publish_chunk.clone();
+            data.fold(upload, move |mut upload, bytes| {
+                upload.buffer.extend_from_slice(&bytes);
+                let mut full_chunks = vec![];
+                while upload.buffer.len() >= chunk_size {
+                    full_chunks.push(upload.buffer.split_to(chunk_size).freeze());
+                }
+
+                stream::iter_ok::<_, NatsError>(full_chunks).fold(upload, publish_chunk.clone())
+            })
+            .and_then(move |mut upload| {
+                if upload.buffer.is_empty() {
+                    return Either::A(future::ok(upload));
+                }
+
+                let last_chunk = upload.buffer.take().freeze();
+                Either::B(flush_chunk(upload, last_chunk))
+            })
+            .then(move |res| {
+                let stored = match res {
+                    Ok(upload) => upload,
+                    Err(e) => {
+                        debug!(target: "nitox", "Upload of object {} has failed: {}", info.name, e);
+                        let purge = store
+                            .js
+                            .purge_subject(&store.stream(), &store.chunk_subject(&info.nuid));
+                        return Either::A(purge.then(move |_| Err(e)));
+                    }
+                };
+
+                let info = ObjectInfo {
+                    size: stored.size,
+                    chunks: stored.chunks,
+                    ..info
+                };
+
+                Either::B(store.publish_info(&info).and_then(move |_| {
+                    match previous {
+                        Some(previous) => Either::A(
+                            store
+                                .js
+                                .purge_subject(&store.stream(), &store.chunk_subject(&previous.nuid))
+                                .map(move |_| info),
+                        ),
+                        None => Either::B(future::ok(info)),
+                    }
+                }))
+            })
+        }))
+    }
+
+    /// Downloads an object, resolves with `None` if it doesn't exist or has been deleted. The chunks are
+    /// delivered by an ordered consumer, whose flow control keeps the client from buffering more of them than
+    /// the reader is consuming
+    ///
+    /// Returns `impl Future<Item = Option<ObjectReader>, Error = NatsError>`
+    pub fn get(&self, name: &str) -> impl Future<Item = Option<ObjectReader>, Error = NatsError> + Send + Sync {
+        let store = self.clone();
+        self.info(name).and_then(move |info| {
+            let info = match info {
+                Some(info) => info,
+                None => return Either::A(future::ok(None)),
+            };
+
+            let reader = ObjectReader {
+                remaining: info.chunks,
+                received: 0,
+                chunks: None,
+                info,
+            };
+
+            if reader.remaining == 0 {
+                return Either::A(future::ok(Some(reader)));
+            }
+
+            let config = ConsumerConfig::builder()
+                .filter_subject(store.chunk_subject(&reader.info.nuid))
+                .build();
+
+            let config = match config {
+                Ok(config) => config,
+                Err(e) => return Either::A(future::err(NatsError::CommandBuildError(e))),
+            };
+
+            Either::B(store.js.subscribe_ordered(&store.stream(), &config).map(move |chunks| {
+                Some(ObjectReader {
+                    chunks: Some(chunks),
+                    ..reader
+                })
+            }))
+        })
+    }
+
+    /// Deletes an object along with its chunks, resolves with whether it existed
+    ///
+    /// Returns `impl Future<Item = bool, Error = NatsError>`
+    pub fn delete(&self, name: &str) -> impl Future<Item = bool, Error = NatsError> + Send + Sync {
+        let store = self.clone();
+        self.info(name).and_then(move |info| {
+            let info = match info {
+                Some(info) => info,
+                None => return Either::A(future::ok(false)),
+            };
+
+            let chunk_subject = store.chunk_subject(&info.nuid);
+            let deleted = ObjectInfo {
+                size: 0,
+                chunks: 0,
+                mtime: String::new(),
+                deleted: true,
+                ..info
+            };
+
+            Either::B(
+                store
+                    .publish_info(&deleted)
+                    .and_then(move |_| store.js.purge_subject(&store.stream(), &chunk_subject))
+                    .map(|_| true),
+            )
+        })
+    }
+}
+
+/// Stream of the chunks of an object, returned by `ObjectStore::get()`. It fails with
+/// `NatsError::PayloadDecodeError` if the chunks don't add up to the size of the object
+#[derive(Debug)]
+pub struct ObjectReader {
+    info: ObjectInfo,
+    /// Subscription the chunks are delivered on, dropped once they all have been
+    chunks: Option<OrderedSubscription>,
+    remaining: u64,
+    /// Size of the chunks received so far, in bytes
+    received: u64,
+}
+
+impl ObjectReader {
+    /// Description of the object being downloaded
+    pub fn info(&self) -> &ObjectInfo {
+        &self.info
+    }
+}
+
+impl Stream for ObjectReader {
+    type Item = Bytes;
+    type Error = NatsError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let chunks = match self.chunks {
+            Some(ref mut chunks) if self.remaining > 0 => chunks,
+            _ => {
+                // Unsubscribes from the consumer, which the server then removes
+                self.chunks = None;
+                if self.received != self.info.size {
+                    return Err(NatsError::PayloadDecodeError(format!(
+                        "object {} has {} bytes instead of {}",
+                        self.info.name, self.received, self.info.size
+                    )));
+                }
+
+                return Ok(Async::Ready(None));
+            }
+        };
+
+        let chunk = match chunks.poll()? {
+            Async::Ready(Some(msg)) => msg.into_message().payload,
+            Async::Ready(None) => {
+                return Err(NatsError::PayloadDecodeError(format!(
+                    "object {} is missing {} of its chunks",
+                    self.info.name, self.remaining
+                )))
+            }
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+
+        self.remaining -= 1;
+        self.received += chunk.len() as u64;
+        Ok(Async::Ready(Some(chunk)))
+    }
+}
+
+impl JsContext {
+    /// Creates an object store, or returns the existing one if it has the same configuration
+    ///
+    /// Returns `impl Future<Item = ObjectStore, Error = NatsError>`
+    pub fn create_object_store(
+        &self,
+        config: &ObjectStoreConfig,
+    ) -> impl Future<Item = ObjectStore, Error = NatsError> + Send + Sync {
+        let stream_config = StreamConfig::builder()
+            .name(format!("OBJ_{}", config.bucket))
+            .subjects(vec![
+                format!("$O.{}.C.>", config.bucket),
+                format!("$O.{}.M.>", config.bucket),
+            ])
+            .storage(config.storage)
+            .replicas(config.replicas)
+            .max_age(config.max_age)
+            .max_bytes(config.max_bytes)
+            .discard(DiscardPolicy::New)
+            .allow_rollup_hdrs(true)
+            .build();
+
+        let stream_config = match stream_config {
+            Ok(stream_config) => stream_config,
+            Err(e) => return Either::A(future::err(NatsError::CommandBuildError(e))),
+        };
+
+        let store = ObjectStore {
+            js: self.clone(),
+            bucket: config.bucket.clone(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        };
+
+        Either::B(self.add_stream(&stream_config).map(move |_| store))
+    }
+
+    /// Binds to an existing object store
+    pub fn object_store(&self, bucket: &str) -> Result<ObjectStore, NatsError> {
+        check_bucket(bucket).map_err(NatsError::CommandBuildError)?;
+
+        Ok(ObjectStore {
+            js: self.clone(),
+            bucket: bucket.into(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ObjectInfo;
+    use serde_json as json;
+
+    #[test]
+    fn it_encodes_object_infos() {
+        let info = ObjectInfo {
+            name: "blob".into(),
+            bucket: "files".into(),
+            nuid: "N1".into(),
+            size: 10,
+            chunks: 3,
+            mtime: String::new(),
+            deleted: false,
+        };
+
+        let encoded = json::to_string(&info).unwrap();
+        assert_eq!(
+            encoded,
+            r#"{"name":"blob","bucket":"files","nuid":"N1","size":10,"chunks":3}"#
+        );
+        assert_eq!(json::from_str::<ObjectInfo>(&encoded).unwrap(), info);
+    }
+}
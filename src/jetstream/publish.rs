@@ -0,0 +1,235 @@
+use bytes::Bytes;
+use futures::{
+    future::{self, Either, Loop},
+    prelude::*,
+};
+use std::time::{Duration, Instant};
+use tokio_timer::Delay;
+
+use super::{parse_api_response, JsContext};
+use error::NatsError;
+use protocol::commands::Headers;
+
+/// Header holding the id of a published message, which the stream uses to ignore its duplicates
+pub const MSG_ID_HEADER: &str = "Nats-Msg-Id";
+/// Header making the stream reject a message if it isn't the one storing its subject
+pub const EXPECTED_STREAM_HEADER: &str = "Nats-Expected-Stream";
+/// Header making the stream reject a message if the id of its last message isn't the given one
+pub const EXPECTED_LAST_MSG_ID_HEADER: &str = "Nats-Expected-Last-Msg-Id";
+/// Header making the stream reject a message if its last sequence isn't the given one
+pub const EXPECTED_LAST_SEQUENCE_HEADER: &str = "Nats-Expected-Last-Sequence";
+/// Header making the stream reject a message if the last one of its subject doesn't have the given sequence
+pub const EXPECTED_LAST_SUBJECT_SEQUENCE_HEADER: &str = "Nats-Expected-Last-Subject-Sequence";
+
+/// Retries of the publishes failing with `NatsError::NoResponders`, which happens while the stream storing the
+/// subject elects a new leader. By default, a publish is retried twice, 250 milliseconds apart. This applies on
+/// top of the `request_retry` policy of the client, if any
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PublishRetry {
+    /// Maximum amount of retries after the first attempt, `0` disabling them
+    pub attempts: usize,
+    /// Delay before each retry
+    pub wait: Duration,
+}
+
+impl Default for PublishRetry {
+    fn default() -> Self {
+        PublishRetry {
+            attempts: 2,
+            wait: Duration::from_millis(250),
+        }
+    }
+}
+
+/// Options of `JsContext::publish_with_options()`, which are sent as headers along with the message.
+///
+/// The `expected_*` options are guards the stream checks before storing the message, which allows optimistic
+/// concurrency control: the message is rejected with the matching `JsErrorCode` if one of them doesn't hold
+#[derive(Debug, Default, Clone, PartialEq, Builder)]
+pub struct PublishOptions {
+    /// Id of the message. The stream doesn't store a message whose id it has seen within its
+    /// `duplicate_window`, and acknowledges it as a `duplicate` instead, so retrying a publish is safe
+    #[builder(setter(into), default)]
+    pub msg_id: Option<String>,
+    /// Name of the stream expected to store the message, see `JsErrorCode::StreamNotMatch`
+    #[builder(setter(into), default)]
+    pub expected_stream: Option<String>,
+    /// Id of the last message expected in the stream, see `JsErrorCode::WrongLastMsgId`
+    #[builder(setter(into), default)]
+    pub expected_last_msg_id: Option<String>,
+    /// Sequence of the last message expected in the stream, see `JsErrorCode::WrongLastSequence`
+    #[builder(setter(into), default)]
+    pub expected_last_sequence: Option<u64>,
+    /// Sequence of the last message expected on the subject of the message, `0` meaning that there should be
+    /// none. See `JsErrorCode::WrongLastSequence`
+    #[builder(setter(into), default)]
+    pub expected_last_subject_sequence: Option<u64>,
+}
+
+impl PublishOptions {
+    pub fn builder() -> PublishOptionsBuilder {
+        PublishOptionsBuilder::default()
+    }
+
+    fn to_headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        if let Some(ref msg_id) = self.msg_id {
+            headers.insert(MSG_ID_HEADER, msg_id.as_str());
+        }
+
+        if let Some(ref stream) = self.expected_stream {
+            headers.insert(EXPECTED_STREAM_HEADER, stream.as_str());
+        }
+
+        if let Some(ref msg_id) = self.expected_last_msg_id {
+            headers.insert(EXPECTED_LAST_MSG_ID_HEADER, msg_id.as_str());
+        }
+
+        if let Some(seq) = self.expected_last_sequence {
+            headers.insert(EXPECTED_LAST_SEQUENCE_HEADER, seq.to_string());
+        }
+
+        if let Some(seq) = self.expected_last_subject_sequence {
+            headers.insert(EXPECTED_LAST_SUBJECT_SEQUENCE_HEADER, seq.to_string());
+        }
+
+        headers
+    }
+}
+
+/// Acknowledgement of a message stored by a stream, returned by `JsContext::publish()`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PubAck {
+    /// Stream that has stored the message
+    pub stream: String,
+    /// Sequence of the message in the stream
+    #[serde(rename = "seq")]
+    pub sequence: u64,
+    /// Whether the stream had already stored this message
+    #[serde(default)]
+    pub duplicate: bool,
+    /// Domain of the JetStream that has stored the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+}
+
+impl JsContext {
+    /// Publishes a message to a subject stored by a stream, and waits for the stream to acknowledge it.
+    /// Fails with `NatsError::JetStreamError` if the stream rejects the message, and with
+    /// `NatsError::NoResponders` if no stream stores the subject, provided headers are enabled, once the
+    /// retries of the `PublishRetry` of the context have been exhausted
+    ///
+    /// Returns `impl Future<Item = PubAck, Error = NatsError>`
+    pub fn publish(
+        &self,
+        subject: String,
+        payload: Bytes,
+    ) -> impl Future<Item = PubAck, Error = NatsError> + Send + Sync {
+        self.publish_with_headers(subject, Headers::new(), payload)
+    }
+
+    /// Same as `publish()`, sending the given headers along with the message. They're dropped if headers
+    /// haven't been negotiated with the server
+    ///
+    /// Returns `impl Future<Item = PubAck, Error = NatsError>`
+    pub fn publish_with_headers(
+        &self,
+        subject: String,
+        headers: Headers,
+        payload: Bytes,
+    ) -> impl Future<Item = PubAck, Error = NatsError> + Send + Sync {
+        debug!(target: "nitox", "Publishing to JetStream on {}", subject);
+        let (client, retry) = (self.client.clone(), self.publish_retry);
+        future::loop_fn(0, move |retries| {
+            client
+                .request_with_headers(subject.clone(), headers.clone(), payload.clone())
+                .then(move |res| match res {
+                    Err(NatsError::NoResponders) if retries < retry.attempts => {
+                        debug!(target: "nitox", "No stream has answered publish attempt {}, retrying", retries + 1);
+                        Either::A(
+                            Delay::new(Instant::now() + retry.wait)
+                                .map(move |_| Loop::Continue(retries + 1))
+                                .map_err(|e| NatsError::GenericError(e.to_string())),
+                        )
+                    }
+                    res => Either::B(future::result(res.map(Loop::Break))),
+                })
+        })
+        .and_then(|msg| parse_api_response(&msg.payload))
+    }
+
+    /// Same as `publish()`, with options the stream checks before storing the message. Fails with
+    /// `NatsError::CommandBuildError` if headers haven't been negotiated with the server, since the options
+    /// would be dropped otherwise
+    ///
+    /// Returns `impl Future<Item = PubAck, Error = NatsError>`
+    pub fn publish_with_options(
+        &self,
+        subject: String,
+        options: &PublishOptions,
+        payload: Bytes,
+    ) -> impl Future<Item = PubAck, Error = NatsError> + Send + Sync {
+        let headers = options.to_headers();
+        if let Err(e) = self.check_headers(&headers, "publish options") {
+            return Either::A(future::err(e));
+        }
+
+        Either::B(self.publish_with_headers(subject, headers, payload))
+    }
+
+    /// Fails with `NatsError::CommandBuildError` if headers the message cannot go without haven't been
+    /// negotiated with the server, `publish_with_headers()` would drop them
+    pub(super) fn check_headers(&self, headers: &Headers, needed_by: &str) -> Result<(), NatsError> {
+        if !headers.is_empty() && !self.client.headers_enabled() {
+            return Err(NatsError::CommandBuildError(format!(
+                "{} need headers to be negotiated with the server",
+                needed_by
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parse_api_response;
+    use super::*;
+
+    #[test]
+    fn it_parses_pub_acks() {
+        let ack: PubAck = parse_api_response(br#"{"stream":"ORDERS","seq":42}"#).unwrap();
+        assert_eq!(ack.stream, "ORDERS");
+        assert_eq!(ack.sequence, 42);
+        assert!(!ack.duplicate);
+        assert!(ack.domain.is_none());
+
+        let ack: PubAck =
+            parse_api_response(br#"{"stream":"ORDERS","seq":42,"duplicate":true,"domain":"hub"}"#).unwrap();
+        assert!(ack.duplicate);
+        assert_eq!(ack.domain, Some("hub".into()));
+    }
+
+    #[test]
+    fn it_turns_options_into_headers() {
+        assert!(PublishOptions::default().to_headers().is_empty());
+
+        let options = PublishOptions::builder()
+            .msg_id("order-42".to_string())
+            .build()
+            .unwrap();
+        assert_eq!(options.to_headers().get(MSG_ID_HEADER), Some("order-42"));
+
+        let options = PublishOptions::builder()
+            .expected_stream("ORDERS".to_string())
+            .expected_last_sequence(41)
+            .expected_last_subject_sequence(0)
+            .build()
+            .unwrap();
+        let headers = options.to_headers();
+        assert_eq!(headers.get(EXPECTED_STREAM_HEADER), Some("ORDERS"));
+        assert_eq!(headers.get(EXPECTED_LAST_SEQUENCE_HEADER), Some("41"));
+        assert_eq!(headers.get(EXPECTED_LAST_SUBJECT_SEQUENCE_HEADER), Some("0"));
+        assert!(headers.get(MSG_ID_HEADER).is_none());
+    }
+}
@@ -0,0 +1,358 @@
+use futures::{
+    future::{self, Either},
+    prelude::*,
+};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio_timer::Delay;
+
+use super::{check_name, nanos, push::MISSED_HEARTBEATS_THRESHOLD, JsApiError, JsContext, JsMessage};
+use client::{JsonCodec, NatsClient, PayloadCodec, Subscription};
+use error::NatsError;
+use protocol::commands::*;
+
+/// Time waited for the messages of a fetch past its expiry, in case the server's `408` gets lost
+const FETCH_EXPIRY_GRACE: Duration = Duration::from_secs(1);
+/// Description of the `409` ending a fetch whose next message would exceed its `max_bytes`
+const MAX_BYTES_EXCEEDED: &str = "Message Size Exceeds MaxBytes";
+
+/// Options of `PullConsumer::fetch_with_options()`, serialized as the request for the next messages. The default
+/// options fetch a single message, if one is available right away
+#[derive(Debug, Clone, PartialEq, Serialize, Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct FetchOptions {
+    /// Maximum amount of messages delivered
+    #[builder(default = "1")]
+    pub batch: usize,
+    /// Maximum amount of bytes delivered, the batch ending early when the next message would exceed it
+    #[builder(setter(into), default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<usize>,
+    /// Time the server waits for the batch to be complete, zero only fetching the messages available right away
+    #[builder(default)]
+    #[serde(with = "nanos", skip_serializing_if = "is_zero")]
+    pub expires: Duration,
+    /// Interval at which the server sends an idle heartbeat while it waits for messages, zero disabling them.
+    /// It cannot be more than half the `expires`
+    #[builder(default)]
+    #[serde(with = "nanos", skip_serializing_if = "is_zero")]
+    pub idle_heartbeat: Duration,
+    /// Ends the batch as soon as no more messages are available, even before it expires. Always set when there
+    /// is no `expires`
+    #[builder(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub no_wait: bool,
+}
+
+impl FetchOptions {
+    pub fn builder() -> FetchOptionsBuilder {
+        FetchOptionsBuilder::default()
+    }
+
+    /// Checks the options, which can be set without going through the builder
+    fn check(&self) -> Result<(), String> {
+        if self.batch == 0 {
+            return Err("a fetch needs a batch of at least one message".into());
+        }
+
+        if self.max_bytes == Some(0) {
+            return Err("max bytes cannot be zero".into());
+        }
+
+        if self.idle_heartbeat > Duration::default() && self.idle_heartbeat * 2 > self.expires {
+            return Err("idle heartbeat cannot be more than half the expiry".into());
+        }
+
+        Ok(())
+    }
+}
+
+impl FetchOptionsBuilder {
+    fn validate(&self) -> Result<(), String> {
+        FetchOptions {
+            batch: self.batch.unwrap_or(1),
+            max_bytes: self.max_bytes.unwrap_or_default(),
+            expires: self.expires.unwrap_or_default(),
+            idle_heartbeat: self.idle_heartbeat.unwrap_or_default(),
+            no_wait: self.no_wait.unwrap_or_default(),
+        }
+        .check()
+    }
+}
+
+fn is_zero(duration: &Duration) -> bool {
+    *duration == Duration::default()
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// Pull consumer of a stream, returned by `JsContext::pull_consumer()`, whose messages are fetched in batches
+#[derive(Debug, Clone)]
+pub struct PullConsumer {
+    js: JsContext,
+    stream: String,
+    name: String,
+}
+
+impl PullConsumer {
+    /// Stream of the consumer
+    pub fn stream(&self) -> &str {
+        &self.stream
+    }
+
+    /// Name of the consumer
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Asks the server for up to `batch` messages, waiting up to `expires` for them to be available. A zero
+    /// `expires` only fetches the messages available right away. The returned stream yields the messages as
+    /// they're delivered, and ends once the batch is complete or the server reports that there are no more
+    /// messages or that the request has expired. Fails with `NatsError::CommandBuildError` if `batch` is zero
+    ///
+    /// Returns `impl Future<Item = Fetch, Error = NatsError>`
+    pub fn fetch(&self, batch: usize, expires: Duration) -> impl Future<Item = Fetch, Error = NatsError> + Send + Sync {
+        let options = FetchOptions {
+            batch,
+            max_bytes: None,
+            expires,
+            idle_heartbeat: Duration::default(),
+            no_wait: false,
+        };
+
+        self.fetch_with_options(&options)
+    }
+
+    /// Same as `fetch()`, with a limit on the size of the batch and idle heartbeats telling that the request is
+    /// still waiting for messages. Fails with `NatsError::CommandBuildError` if the options are invalid
+    ///
+    /// Returns `impl Future<Item = Fetch, Error = NatsError>`
+    pub fn fetch_with_options(
+        &self,
+        options: &FetchOptions,
+    ) -> impl Future<Item = Fetch, Error = NatsError> + Send + Sync {
+        if let Err(e) = options.check() {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        let req = FetchOptions {
+            no_wait: options.no_wait || is_zero(&options.expires),
+            ..options.clone()
+        };
+
+        let payload = match JsonCodec.encode(&req) {
+            Ok(payload) => payload,
+            Err(e) => return Either::A(future::err(e)),
+        };
+
+        let client = Arc::clone(&self.js.client);
+        let inbox = PubCommand::generate_reply_to();
+        let pub_cmd = PubCommand {
+            subject: self
+                .js
+                .api_subject(&format!("CONSUMER.MSG.NEXT.{}.{}", self.stream, self.name)),
+            payload,
+            reply_to: Some(inbox.clone()),
+            headers: None,
+        };
+
+        let sub_cmd = SubCommand {
+            queue_group: None,
+            sid: client.generate_sid(),
+            subject: inbox,
+        };
+
+        let deadline = Delay::new(Instant::now() + req.expires + FETCH_EXPIRY_GRACE);
+        let heartbeat_deadline = if req.idle_heartbeat > Duration::default() {
+            Some(Delay::new(
+                Instant::now() + req.idle_heartbeat * MISSED_HEARTBEATS_THRESHOLD,
+            ))
+        } else {
+            None
+        };
+
+        Either::B(client.subscribe(sub_cmd).and_then(move |subscription| {
+            client.publish(pub_cmd).map(move |_| Fetch {
+                client,
+                subscription: Some(subscription),
+                remaining: req.batch,
+                deadline,
+                idle_heartbeat: req.idle_heartbeat,
+                heartbeat_deadline,
+            })
+        }))
+    }
+}
+
+/// Stream of the messages of a batch, returned by `PullConsumer::fetch()`. The status messages sent by the
+/// server are handled internally: the stream ends on the `404` and `408` ones, telling that no more messages are
+/// available for now, and on the `409` telling that the next message would exceed the `max_bytes` of the batch.
+/// It fails with `NatsError::JetStreamError` on the other statuses, like the `409` of a consumer that has been
+/// deleted, and with `NatsError::MissedHeartbeats` when two idle heartbeats are missing in a row. The inbox is
+/// unsubscribed once the stream has ended
+#[derive(Debug)]
+pub struct Fetch {
+    /// Client the messages are acknowledged through
+    client: Arc<NatsClient>,
+    subscription: Option<Subscription>,
+    /// Messages still expected in the batch
+    remaining: usize,
+    deadline: Delay,
+    idle_heartbeat: Duration,
+    /// Time past which the request is considered lost if nothing has been received, when heartbeats are enabled
+    heartbeat_deadline: Option<Delay>,
+}
+
+impl Fetch {
+    /// Checks whether the server has been silent for too long, once the subscription has nothing to yield
+    fn poll_heartbeat(&mut self) -> Poll<(), NatsError> {
+        let deadline = match self.heartbeat_deadline {
+            Some(ref mut deadline) => deadline,
+            None => return Ok(Async::NotReady),
+        };
+
+        match deadline.poll() {
+            Ok(Async::Ready(_)) => {
+                let timeout = self.idle_heartbeat * MISSED_HEARTBEATS_THRESHOLD;
+                debug!(target: "nitox", "No heartbeat received from fetch for {:?}", timeout);
+                self.subscription = None;
+                Err(NatsError::MissedHeartbeats(timeout))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(NatsError::GenericError(e.to_string())),
+        }
+    }
+}
+
+impl Stream for Fetch {
+    type Error = NatsError;
+    type Item = JsMessage;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let polled = match self.subscription {
+                Some(ref mut subscription) => subscription.poll(),
+                None => return Ok(Async::Ready(None)),
+            };
+
+            let msg = match polled {
+                Ok(Async::Ready(Some(msg))) => msg,
+                Ok(Async::NotReady) => break,
+                Ok(Async::Ready(None)) => {
+                    self.subscription = None;
+                    return Ok(Async::Ready(None));
+                }
+                Err(e) => {
+                    self.subscription = None;
+                    return Err(e);
+                }
+            };
+
+            let timeout = self.idle_heartbeat * MISSED_HEARTBEATS_THRESHOLD;
+            if let Some(ref mut deadline) = self.heartbeat_deadline {
+                deadline.reset(Instant::now() + timeout);
+            }
+
+            match msg.status {
+                None => {
+                    self.remaining = self.remaining.saturating_sub(1);
+                    if self.remaining == 0 {
+                        self.subscription = None;
+                    }
+
+                    return Ok(Async::Ready(Some(JsMessage::new(msg, Arc::clone(&self.client)))));
+                }
+                Some(Message::STATUS_CONTROL) => continue,
+                Some(Message::STATUS_NOT_FOUND) | Some(Message::STATUS_REQUEST_TIMEOUT) => {
+                    debug!(target: "nitox", "Fetch has ended with status {:?}", msg.status);
+                    self.subscription = None;
+                    return Ok(Async::Ready(None));
+                }
+                Some(Message::STATUS_CONFLICT) if msg.description.as_deref() == Some(MAX_BYTES_EXCEEDED) => {
+                    debug!(target: "nitox", "Fetch has reached its max bytes with {} messages missing", self.remaining);
+                    self.subscription = None;
+                    return Ok(Async::Ready(None));
+                }
+                Some(Message::STATUS_NO_RESPONDERS) => {
+                    self.subscription = None;
+                    return Err(NatsError::NoResponders);
+                }
+                Some(code) => {
+                    self.subscription = None;
+                    return Err(NatsError::JetStreamError(JsApiError {
+                        code,
+                        err_code: 0,
+                        description: msg.description.unwrap_or_default(),
+                    }));
+                }
+            }
+        }
+
+        self.poll_heartbeat()?;
+        match self.deadline.poll() {
+            Ok(Async::Ready(_)) => {
+                debug!(target: "nitox", "Fetch has expired with {} messages missing", self.remaining);
+                self.subscription = None;
+                Ok(Async::Ready(None))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(NatsError::GenericError(e.to_string())),
+        }
+    }
+}
+
+impl JsContext {
+    /// Binds to an existing pull consumer of a stream, created with `add_consumer()` without a deliver subject
+    pub fn pull_consumer(&self, stream: &str, consumer: &str) -> Result<PullConsumer, NatsError> {
+        check_name(stream)
+            .and_then(|_| check_name(consumer))
+            .map_err(NatsError::CommandBuildError)?;
+
+        Ok(PullConsumer {
+            js: self.clone(),
+            stream: stream.into(),
+            name: consumer.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FetchOptions;
+    use serde_json as json;
+    use std::time::Duration;
+
+    #[test]
+    fn it_encodes_fetch_options() {
+        let options = FetchOptions::builder()
+            .batch(10)
+            .max_bytes(1024)
+            .expires(Duration::from_secs(30))
+            .idle_heartbeat(Duration::from_secs(5))
+            .build()
+            .unwrap();
+        assert_eq!(
+            json::to_string(&options).unwrap(),
+            r#"{"batch":10,"max_bytes":1024,"expires":30000000000,"idle_heartbeat":5000000000}"#
+        );
+        assert_eq!(
+            json::to_string(&FetchOptions::builder().build().unwrap()).unwrap(),
+            r#"{"batch":1}"#
+        );
+        assert!(FetchOptions::builder().max_bytes(0).build().is_err());
+        assert!(FetchOptions {
+            batch: 0,
+            ..FetchOptions::builder().build().unwrap()
+        }
+        .check()
+        .is_err());
+        assert!(FetchOptions::builder()
+            .idle_heartbeat(Duration::from_secs(5))
+            .build()
+            .is_err());
+    }
+}
@@ -0,0 +1,402 @@
+use bytes::Bytes;
+use futures::{
+    future::{self, Either},
+    prelude::*,
+};
+use std::{
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio_timer::Delay;
+
+use super::{check_name, AckPolicy, ConsumerConfig, ConsumerInfo, DeliverPolicy, JsContext, JsMessage};
+use client::{NatsClient, Subscription};
+use error::NatsError;
+use protocol::commands::*;
+
+/// Amount of idle heartbeats missed in a row before a consumer is considered stalled
+pub(super) const MISSED_HEARTBEATS_THRESHOLD: u32 = 2;
+/// Header of the idle heartbeats sent by a consumer waiting for a flow control reply, holding its subject
+const CONSUMER_STALLED_HEADER: &str = "Nats-Consumer-Stalled";
+/// Heartbeat interval of the ordered consumers whose configuration doesn't have one
+const ORDERED_IDLE_HEARTBEAT: Duration = Duration::from_secs(5);
+
+/// Subject a flow control reply has to be sent to, if the status message is a flow control request or the
+/// heartbeat of a consumer stalled by one
+fn flow_control_subject(msg: &Message) -> Option<String> {
+    if msg.description.as_deref() == Some("FlowControl Request") {
+        return msg.reply_to.clone();
+    }
+
+    msg.headers
+        .as_ref()
+        .and_then(|headers| headers.get(CONSUMER_STALLED_HEADER))
+        .map(String::from)
+}
+
+/// Subscription to the deliver subject of a push consumer, returned by `JsContext::subscribe()`. The stream
+/// yields the messages delivered by the consumer, skipping the status messages sent by the server, and keeps
+/// track of the sequences of the last one. The underlying `Subscription` is available through `Deref`.
+///
+/// Consumers with an `idle_heartbeat` make the stream yield `NatsError::MissedHeartbeats` whenever two of their
+/// heartbeats are missing in a row. The stream can still be polled after such an error, in case the consumer
+/// recovers. The flow control requests of consumers with `flow_control` are answered once all the messages
+/// delivered before them have been yielded
+pub struct PushSubscription {
+    /// Client the messages are acknowledged through
+    client: Arc<NatsClient>,
+    subscription: Subscription,
+    info: ConsumerInfo,
+    stream_seq: u64,
+    consumer_seq: u64,
+    last_heartbeat: Option<Instant>,
+    /// Time past which the consumer is stalled if nothing has been received, when heartbeats are enabled
+    heartbeat_deadline: Option<Delay>,
+    /// Flow control replies being sent
+    flow_control_replies: Vec<Box<dyn Future<Item = (), Error = NatsError> + Send + Sync>>,
+}
+
+impl ::std::fmt::Debug for PushSubscription {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("PushSubscription")
+            .field("subscription", &self.subscription)
+            .field("info", &self.info)
+            .field("stream_seq", &self.stream_seq)
+            .field("consumer_seq", &self.consumer_seq)
+            .field("last_heartbeat", &self.last_heartbeat)
+            .field("flow_control_replies", &self.flow_control_replies.len())
+            .finish()
+    }
+}
+
+impl PushSubscription {
+    /// Consumer as it was when the subscription started
+    pub fn info(&self) -> &ConsumerInfo {
+        &self.info
+    }
+
+    /// Sequence in the stream of the last message delivered, `0` if none has been yet
+    pub fn stream_sequence(&self) -> u64 {
+        self.stream_seq
+    }
+
+    /// Sequence in the consumer of the last message delivered, `0` if none has been yet
+    pub fn consumer_sequence(&self) -> u64 {
+        self.consumer_seq
+    }
+
+    /// Time at which the last idle heartbeat has been received, if any
+    pub fn last_heartbeat(&self) -> Option<Instant> {
+        self.last_heartbeat
+    }
+
+    /// Drives the flow control replies being sent, failing if one of them cannot be
+    fn poll_flow_control(&mut self) -> Result<(), NatsError> {
+        let mut failure = None;
+        self.flow_control_replies.retain_mut(|reply| match reply.poll() {
+            Ok(Async::NotReady) => true,
+            Ok(Async::Ready(_)) => false,
+            Err(e) => {
+                failure = Some(e);
+                false
+            }
+        });
+
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn reply_to_flow_control(&mut self, subject: String) -> Result<(), NatsError> {
+        debug!(target: "nitox", "Answering flow control request of consumer {} on {}", self.info.name, subject);
+        let reply = self.client.publish(PubCommand {
+            subject,
+            payload: Bytes::new(),
+            reply_to: None,
+            headers: None,
+        });
+        self.flow_control_replies.push(Box::new(reply));
+        self.poll_flow_control()
+    }
+
+    fn stall_timeout(&self) -> Duration {
+        self.info.config.idle_heartbeat * MISSED_HEARTBEATS_THRESHOLD
+    }
+
+    /// Checks whether the consumer has been silent for too long, once the subscription has nothing to yield
+    fn poll_heartbeat(&mut self) -> Poll<Option<JsMessage>, NatsError> {
+        let timeout = self.stall_timeout();
+        let deadline = match self.heartbeat_deadline {
+            Some(ref mut deadline) => deadline,
+            None => return Ok(Async::NotReady),
+        };
+
+        match deadline.poll() {
+            Ok(Async::Ready(_)) => {
+                debug!(target: "nitox", "No heartbeat received from consumer {} for {:?}", self.info.name, timeout);
+                deadline.reset(Instant::now() + timeout);
+                Err(NatsError::MissedHeartbeats(timeout))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(NatsError::GenericError(e.to_string())),
+        }
+    }
+}
+
+impl Deref for PushSubscription {
+    type Target = Subscription;
+
+    fn deref(&self) -> &Self::Target {
+        &self.subscription
+    }
+}
+
+impl Stream for PushSubscription {
+    type Error = NatsError;
+    type Item = JsMessage;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.poll_flow_control()?;
+        loop {
+            let msg = match self.subscription.poll()? {
+                Async::Ready(Some(msg)) => msg,
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return self.poll_heartbeat(),
+            };
+
+            // Anything delivered by the consumer shows that it's alive
+            let now = Instant::now();
+            let timeout = self.stall_timeout();
+            if let Some(ref mut deadline) = self.heartbeat_deadline {
+                deadline.reset(now + timeout);
+            }
+
+            if msg.is_status() {
+                if msg.is_idle_heartbeat() {
+                    self.last_heartbeat = Some(now);
+                }
+
+                // Everything delivered before the request has been yielded already, the consumer can go on
+                if let Some(subject) = flow_control_subject(&msg) {
+                    self.reply_to_flow_control(subject)?;
+                }
+
+                debug!(target: "nitox", "Skipping status {:?} on {}", msg.status, msg.subject);
+                continue;
+            }
+
+            let msg = JsMessage::new(msg, Arc::clone(&self.client));
+            if let Some(meta) = msg.metadata() {
+                self.stream_seq = meta.stream_sequence;
+                self.consumer_seq = meta.consumer_sequence;
+            }
+
+            return Ok(Async::Ready(Some(msg)));
+        }
+    }
+}
+
+enum OrderedState {
+    Active(Box<PushSubscription>),
+    /// A new consumer is being created, after a missing message or heartbeat
+    Resetting(Box<dyn Future<Item = PushSubscription, Error = NatsError> + Send + Sync>),
+}
+
+/// Subscription to an ordered consumer, returned by `JsContext::subscribe_ordered()`. The stream yields the
+/// messages of the stream in order and without gaps: whenever a message is missing or the consumer stops sending
+/// heartbeats, the consumer is replaced by a new one starting right after the last message yielded
+pub struct OrderedSubscription {
+    js: JsContext,
+    stream: String,
+    config: ConsumerConfig,
+    state: OrderedState,
+    stream_seq: u64,
+    consumer_seq: u64,
+}
+
+impl ::std::fmt::Debug for OrderedSubscription {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let state = match self.state {
+            OrderedState::Active(ref subscription) => format!("{:?}", subscription),
+            OrderedState::Resetting(_) => "Box<Future>".to_string(),
+        };
+
+        f.debug_struct("OrderedSubscription")
+            .field("stream", &self.stream)
+            .field("config", &self.config)
+            .field("state", &state)
+            .field("stream_seq", &self.stream_seq)
+            .field("consumer_seq", &self.consumer_seq)
+            .finish()
+    }
+}
+
+impl OrderedSubscription {
+    /// Sequence in the stream of the last message yielded, `0` if none has been yet
+    pub fn stream_sequence(&self) -> u64 {
+        self.stream_seq
+    }
+
+    /// Consumer delivering the messages, as it was when it has been created. `None` while it's being replaced
+    pub fn info(&self) -> Option<&ConsumerInfo> {
+        match self.state {
+            OrderedState::Active(ref subscription) => Some(subscription.info()),
+            OrderedState::Resetting(_) => None,
+        }
+    }
+
+    /// Replaces the consumer by one starting right after the last message yielded
+    fn reset(&mut self) {
+        debug!(target: "nitox", "Resetting ordered consumer on {} after sequence {}", self.stream, self.stream_seq);
+        if self.stream_seq > 0 {
+            self.config.deliver_policy = DeliverPolicy::ByStartSequence;
+            self.config.opt_start_seq = Some(self.stream_seq + 1);
+            self.config.opt_start_time = None;
+        }
+        self.config.deliver_subject = None;
+        self.consumer_seq = 0;
+
+        // Dropping the previous subscription unsubscribes from its consumer, which the server then removes
+        self.state = OrderedState::Resetting(Box::new(self.js.subscribe(&self.stream, &self.config)));
+    }
+}
+
+impl Stream for OrderedSubscription {
+    type Error = NatsError;
+    type Item = JsMessage;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            let polled = match self.state {
+                OrderedState::Resetting(ref mut subscribing) => match subscribing.poll()? {
+                    Async::Ready(subscription) => {
+                        self.state = OrderedState::Active(Box::new(subscription));
+                        continue;
+                    }
+                    Async::NotReady => return Ok(Async::NotReady),
+                },
+                OrderedState::Active(ref mut subscription) => subscription.poll(),
+            };
+
+            let msg = match polled {
+                Ok(Async::Ready(Some(msg))) => msg,
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(NatsError::MissedHeartbeats(_)) => {
+                    self.reset();
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let meta = match msg.metadata() {
+                Some(meta) => meta,
+                None => return Ok(Async::Ready(Some(msg))),
+            };
+
+            if meta.consumer_sequence != self.consumer_seq + 1 {
+                debug!(
+                    target: "nitox",
+                    "Ordered consumer on {} expected sequence {}, got {}",
+                    self.stream,
+                    self.consumer_seq + 1,
+                    meta.consumer_sequence
+                );
+                self.reset();
+                continue;
+            }
+
+            self.stream_seq = meta.stream_sequence;
+            self.consumer_seq = meta.consumer_sequence;
+            return Ok(Async::Ready(Some(msg)));
+        }
+    }
+}
+
+impl JsContext {
+    /// Creates a push consumer on a stream, or binds to the durable one with the same configuration, and
+    /// subscribes to its deliver subject. A deliver subject is generated if the configuration doesn't have one.
+    /// The subscription is made before the consumer is created, so that no message is missed
+    ///
+    /// Returns `impl Future<Item = PushSubscription, Error = NatsError>`
+    pub fn subscribe(
+        &self,
+        stream: &str,
+        config: &ConsumerConfig,
+    ) -> impl Future<Item = PushSubscription, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(stream) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        let mut config = config.clone();
+        let deliver_subject = config
+            .deliver_subject
+            .get_or_insert_with(PubCommand::generate_reply_to)
+            .clone();
+
+        let sub_cmd = SubCommand {
+            queue_group: None,
+            sid: self.client.generate_sid(),
+            subject: deliver_subject,
+        };
+
+        let js = self.clone();
+        let stream = stream.to_string();
+        Either::B(self.client.subscribe(sub_cmd).and_then(move |subscription| {
+            js.add_consumer(&stream, &config).map(move |info| {
+                let heartbeat_deadline = if info.config.idle_heartbeat > Duration::default() {
+                    Some(Delay::new(
+                        Instant::now() + info.config.idle_heartbeat * MISSED_HEARTBEATS_THRESHOLD,
+                    ))
+                } else {
+                    None
+                };
+
+                PushSubscription {
+                    client: js.client,
+                    subscription,
+                    info,
+                    stream_seq: 0,
+                    consumer_seq: 0,
+                    last_heartbeat: None,
+                    heartbeat_deadline,
+                    flow_control_replies: Vec::new(),
+                }
+            })
+        }))
+    }
+
+    /// Creates an ordered consumer on a stream and subscribes to it. The consumer is made ephemeral, without
+    /// acknowledgements and with flow control, whatever the configuration says, its deliver policy and filter
+    /// subject being kept. It has idle heartbeats every 5 seconds, unless the configuration sets another interval
+    ///
+    /// Returns `impl Future<Item = OrderedSubscription, Error = NatsError>`
+    pub fn subscribe_ordered(
+        &self,
+        stream: &str,
+        config: &ConsumerConfig,
+    ) -> impl Future<Item = OrderedSubscription, Error = NatsError> + Send + Sync {
+        let mut config = config.clone();
+        config.durable_name = None;
+        config.deliver_subject = None;
+        config.ack_policy = AckPolicy::None;
+        config.max_deliver = 1;
+        config.flow_control = true;
+        if config.idle_heartbeat == Duration::default() {
+            config.idle_heartbeat = ORDERED_IDLE_HEARTBEAT;
+        }
+
+        let (js, stream) = (self.clone(), stream.to_string());
+        self.subscribe(&stream, &config)
+            .map(move |subscription| OrderedSubscription {
+                js,
+                stream,
+                config,
+                state: OrderedState::Active(Box::new(subscription)),
+                stream_seq: 0,
+                consumer_seq: 0,
+            })
+    }
+}
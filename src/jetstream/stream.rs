@@ -0,0 +1,766 @@
+use bytes::Bytes;
+use futures::{
+    future::{self, Either},
+    prelude::*,
+};
+use std::time::Duration;
+
+use super::{check_name, decode_base64, nanos, JsApiError, JsContext, SuccessResponse};
+use client::{JsonCodec, PayloadCodec};
+use error::NatsError;
+use protocol::commands::{Headers, Message};
+
+/// Amount of messages a stream keeps, and which of them it gets rid of first
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetentionPolicy {
+    /// Messages are kept until the limits of the stream are reached
+    #[default]
+    Limits,
+    /// Messages are kept as long as some consumers haven't acknowledged them
+    Interest,
+    /// Messages are removed once a consumer has acknowledged them
+    WorkQueue,
+}
+
+/// Where a stream stores its messages
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageType {
+    #[default]
+    File,
+    Memory,
+}
+
+/// What a stream does with new messages once one of its limits has been reached
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscardPolicy {
+    /// Removes the oldest messages to make room for the new ones
+    #[default]
+    Old,
+    /// Rejects the new messages
+    New,
+}
+
+fn unlimited() -> i64 {
+    -1
+}
+
+fn one_replica() -> usize {
+    1
+}
+
+fn default_duplicate_window() -> Duration {
+    Duration::from_secs(120)
+}
+
+/// Headers of the replies of the `DIRECT.GET` endpoint describing the message
+const DIRECT_STREAM_HEADER: &str = "Nats-Stream";
+const DIRECT_SEQUENCE_HEADER: &str = "Nats-Sequence";
+const DIRECT_SUBJECT_HEADER: &str = "Nats-Subject";
+const DIRECT_TIME_STAMP_HEADER: &str = "Nats-Time-Stamp";
+
+/// JetStream API of a stream living in another account or domain
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExternalStream {
+    /// Prefix of the subjects of the JetStream API, e.g. `$JS.hub.API`
+    pub api: String,
+    /// Prefix of the subjects the messages are delivered on
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub deliver: String,
+}
+
+impl ExternalStream {
+    /// Reaches the JetStream of a domain, on `$JS.<domain>.API`
+    pub fn domain(domain: &str) -> Self {
+        ExternalStream {
+            api: format!("$JS.{}.API", domain),
+            deliver: String::new(),
+        }
+    }
+}
+
+/// Stream whose messages are replicated by a mirror, or by a stream sourcing it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct StreamSource {
+    #[builder(setter(into))]
+    pub name: String,
+    /// Sequence of the first message replicated
+    #[builder(setter(into), default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opt_start_seq: Option<u64>,
+    /// Time from which the messages are replicated, in the RFC 3339 format
+    #[builder(setter(into), default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opt_start_time: Option<String>,
+    /// Only replicates the messages matching this subject, wildcards included
+    #[builder(setter(into), default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter_subject: Option<String>,
+    /// Where the stream lives, when it belongs to another account or domain
+    #[builder(setter(into), default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external: Option<ExternalStream>,
+}
+
+impl StreamSource {
+    pub fn builder() -> StreamSourceBuilder {
+        StreamSourceBuilder::default()
+    }
+}
+
+impl StreamSourceBuilder {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(ref name) = self.name {
+            check_name(name)?;
+        }
+
+        if let (Some(Some(_)), Some(Some(_))) = (&self.opt_start_seq, &self.opt_start_time) {
+            return Err("a source cannot start both at a sequence and at a time".into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Republishes the messages stored by a stream on core NATS subjects, along with the `Nats-Stream`,
+/// `Nats-Subject`, `Nats-Sequence` and `Nats-Last-Sequence` headers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Republish {
+    /// Subjects of the stream whose messages are republished, wildcards included
+    pub src: String,
+    /// Subject the messages are republished on, which can reuse the wildcard tokens of `src`, e.g. `{{wildcard(1)}}`
+    pub dest: String,
+    /// Only republishes the headers and the size of the messages, in the `Nats-Msg-Size` header
+    #[serde(default)]
+    pub headers_only: bool,
+}
+
+/// Configuration of a stream, limits set to `-1` or a zero `max_age` are unlimited
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct StreamConfig {
+    /// Name of the stream, which cannot contain dots, wildcards or whitespace
+    #[builder(setter(into))]
+    pub name: String,
+    /// Subjects whose messages are stored by the stream, wildcards included
+    #[builder(default)]
+    #[serde(default)]
+    pub subjects: Vec<String>,
+    #[builder(default)]
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+    #[builder(default)]
+    #[serde(default)]
+    pub storage: StorageType,
+    /// Amount of copies of the stream kept in a clustered JetStream
+    #[builder(default = "1")]
+    #[serde(rename = "num_replicas", default = "one_replica")]
+    pub replicas: usize,
+    /// Age past which messages are removed
+    #[builder(default)]
+    #[serde(with = "nanos", default)]
+    pub max_age: Duration,
+    /// Total size of the messages kept by the stream, in bytes
+    #[builder(default = "-1")]
+    #[serde(default = "unlimited")]
+    pub max_bytes: i64,
+    /// Amount of messages kept by the stream
+    #[builder(default = "-1")]
+    #[serde(default = "unlimited")]
+    pub max_msgs: i64,
+    /// Amount of messages kept for each subject
+    #[builder(default = "-1")]
+    #[serde(default = "unlimited")]
+    pub max_msgs_per_subject: i64,
+    #[builder(default)]
+    #[serde(default)]
+    pub discard: DiscardPolicy,
+    /// Allows the `Nats-Rollup` header, which purges the previous messages of the subject or of the stream
+    #[builder(default)]
+    #[serde(default)]
+    pub allow_rollup_hdrs: bool,
+    /// Forbids deleting messages through the API
+    #[builder(default)]
+    #[serde(default)]
+    pub deny_delete: bool,
+    /// Time during which the ids of the messages published with a `Nats-Msg-Id` header are remembered, in order
+    /// to ignore their duplicates
+    #[builder(default = "default_duplicate_window()")]
+    #[serde(with = "nanos", default = "default_duplicate_window")]
+    pub duplicate_window: Duration,
+    /// Allows getting messages through the `DIRECT.GET` endpoint, answered by any replica of the stream
+    #[builder(default)]
+    #[serde(default)]
+    pub allow_direct: bool,
+    /// Stream the messages are copied from, a mirror having no subjects of its own
+    #[builder(setter(into), default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<StreamSource>,
+    /// Streams whose messages are stored along with the ones published to the subjects of the stream
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<StreamSource>,
+    #[builder(setter(into), default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub republish: Option<Republish>,
+}
+
+impl StreamConfig {
+    pub fn builder() -> StreamConfigBuilder {
+        StreamConfigBuilder::default()
+    }
+}
+
+impl StreamConfigBuilder {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(ref name) = self.name {
+            check_name(name)?;
+        }
+
+        if let Some(0) = self.replicas {
+            return Err("a stream needs at least one replica".into());
+        }
+
+        if let Some(Some(_)) = self.mirror {
+            if self.subjects.as_ref().is_some_and(|subjects| !subjects.is_empty()) {
+                return Err("a mirror cannot have subjects".into());
+            }
+
+            if self.sources.as_ref().is_some_and(|sources| !sources.is_empty()) {
+                return Err("a mirror cannot have sources".into());
+            }
+        }
+
+        if let Some(Some(ref republish)) = self.republish {
+            if republish.src.is_empty() || republish.dest.is_empty() {
+                return Err("republish needs a source and a destination subject".into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Messages held by a stream
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamState {
+    pub messages: u64,
+    pub bytes: u64,
+    pub first_seq: u64,
+    pub last_seq: u64,
+    pub consumer_count: usize,
+}
+
+/// Replication state of the mirror or of a source of a stream
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamSourceInfo {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external: Option<ExternalStream>,
+    /// Amount of messages that haven't been replicated yet
+    pub lag: u64,
+}
+
+/// Configuration and state of a stream, returned by the stream management methods of `JsContext`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamInfo {
+    pub config: StreamConfig,
+    /// Creation time of the stream, in the RFC 3339 format
+    #[serde(default)]
+    pub created: String,
+    #[serde(default)]
+    pub state: StreamState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mirror: Option<StreamSourceInfo>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<StreamSourceInfo>,
+}
+
+/// Message stored by a stream, returned by `JsContext::get_message()` and its variants
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMessage {
+    pub subject: String,
+    /// Sequence of the message in the stream
+    pub sequence: u64,
+    /// Headers the message has been published with
+    pub headers: Option<Headers>,
+    pub payload: Bytes,
+    /// Time at which the message has been stored, in the RFC 3339 format
+    pub time: String,
+}
+
+impl StoredMessage {
+    /// Decodes the reply of the `DIRECT.GET` endpoint, which describes the message in its headers. The statuses
+    /// it's answered with when the message cannot be found are turned into the errors of the classic API
+    fn from_direct_reply(msg: Message) -> Result<Self, NatsError> {
+        if let Some(code) = msg.status {
+            let err_code = if code == Message::STATUS_NOT_FOUND { 10037 } else { 0 };
+            return Err(NatsError::JetStreamError(JsApiError {
+                code,
+                err_code,
+                description: msg.description.unwrap_or_default(),
+            }));
+        }
+
+        let mut headers = msg.headers.unwrap_or_default();
+        let mut take = |name: &str| headers.remove(name).and_then(|values| values.into_iter().next());
+        let sequence = take(DIRECT_SEQUENCE_HEADER)
+            .and_then(|seq| seq.parse().ok())
+            .ok_or_else(|| NatsError::PayloadDecodeError("direct get reply without a valid Nats-Sequence".into()))?;
+        let subject = take(DIRECT_SUBJECT_HEADER)
+            .ok_or_else(|| NatsError::PayloadDecodeError("direct get reply without Nats-Subject".into()))?;
+        let time = take(DIRECT_TIME_STAMP_HEADER).unwrap_or_default();
+        take(DIRECT_STREAM_HEADER);
+
+        Ok(StoredMessage {
+            subject,
+            sequence,
+            headers: if headers.is_empty() { None } else { Some(headers) },
+            payload: msg.payload,
+            time,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct MsgGetRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_by_subj: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawStoredMessage {
+    subject: String,
+    seq: u64,
+    #[serde(default)]
+    hdrs: Option<String>,
+    #[serde(default)]
+    data: Option<String>,
+    #[serde(default)]
+    time: String,
+}
+
+#[derive(Deserialize)]
+struct MsgGetResponse {
+    message: RawStoredMessage,
+}
+
+impl RawStoredMessage {
+    /// Decodes the base64 encoded headers and payload
+    fn decode(self) -> Result<StoredMessage, NatsError> {
+        let headers = match self.hdrs {
+            Some(ref hdrs) if !hdrs.is_empty() => Some(Headers::from_bytes(&decode_base64(hdrs)?)?),
+            _ => None,
+        };
+
+        let payload = match self.data {
+            Some(ref data) => Bytes::from(decode_base64(data)?),
+            None => Bytes::new(),
+        };
+
+        Ok(StoredMessage {
+            subject: self.subject,
+            sequence: self.seq,
+            headers,
+            payload,
+            time: self.time,
+        })
+    }
+}
+
+/// Options of `JsContext::purge_stream()`, which purges all the messages of the stream by default
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct PurgeOptions {
+    /// Only purges the messages matching this subject, wildcards included
+    #[builder(setter(into), default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    /// Amount of messages to keep, the most recent ones
+    #[builder(setter(into), default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep: Option<u64>,
+    /// Purges the messages up to this sequence, excluded
+    #[builder(setter(into), default)]
+    #[serde(rename = "seq", skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+}
+
+impl PurgeOptions {
+    pub fn builder() -> PurgeOptionsBuilder {
+        PurgeOptionsBuilder::default()
+    }
+}
+
+impl PurgeOptionsBuilder {
+    fn validate(&self) -> Result<(), String> {
+        if let (Some(Some(_)), Some(Some(_))) = (self.keep, self.sequence) {
+            return Err("keep and sequence cannot be both set".into());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct PurgeResponse {
+    #[serde(default)]
+    purged: u64,
+}
+
+#[derive(Serialize)]
+struct MsgDeleteRequest {
+    seq: u64,
+    /// Skips overwriting the message in the storage, which is much faster
+    no_erase: bool,
+}
+
+impl JsContext {
+    fn get_stored_message(
+        &self,
+        stream: &str,
+        req: &MsgGetRequest,
+    ) -> impl Future<Item = StoredMessage, Error = NatsError> + Send + Sync {
+        self.request_json(&format!("STREAM.MSG.GET.{}", stream), req)
+            .and_then(|res: MsgGetResponse| res.message.decode())
+    }
+
+    /// Fetches a message of a stream by its sequence. Fails with `JsErrorCode::NoMessageFound` if there is none
+    ///
+    /// Returns `impl Future<Item = StoredMessage, Error = NatsError>`
+    pub fn get_message(
+        &self,
+        stream: &str,
+        seq: u64,
+    ) -> impl Future<Item = StoredMessage, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(stream) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        let req = MsgGetRequest {
+            seq: Some(seq),
+            last_by_subj: None,
+        };
+        Either::B(self.get_stored_message(stream, &req))
+    }
+
+    /// Fetches the last message stored by a stream on a subject. Fails with `JsErrorCode::NoMessageFound` if
+    /// there is none
+    ///
+    /// Returns `impl Future<Item = StoredMessage, Error = NatsError>`
+    pub fn get_last_message_for_subject(
+        &self,
+        stream: &str,
+        subject: &str,
+    ) -> impl Future<Item = StoredMessage, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(stream) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        let req = MsgGetRequest {
+            seq: None,
+            last_by_subj: Some(subject.into()),
+        };
+        Either::B(self.get_stored_message(stream, &req))
+    }
+
+    fn direct_get(
+        &self,
+        api: String,
+        payload: Bytes,
+    ) -> impl Future<Item = StoredMessage, Error = NatsError> + Send + Sync {
+        let subject = self.api_subject(&api);
+        debug!(target: "nitox", "Sending JetStream direct get to {}", subject);
+        self.client
+            .request(subject, payload)
+            .and_then(StoredMessage::from_direct_reply)
+    }
+
+    /// Same as `get_message()` through the `DIRECT.GET` endpoint, which any replica of the stream can answer
+    /// and is faster. The stream needs `allow_direct`, nobody answers otherwise: the request fails with
+    /// `NatsError::NoResponders` when headers are enabled, or waits for the `request_timeout` of the client
+    /// otherwise, forever if it has none
+    ///
+    /// Returns `impl Future<Item = StoredMessage, Error = NatsError>`
+    pub fn direct_get_message(
+        &self,
+        stream: &str,
+        seq: u64,
+    ) -> impl Future<Item = StoredMessage, Error = NatsError> + Send + Sync {
+        let req = MsgGetRequest {
+            seq: Some(seq),
+            last_by_subj: None,
+        };
+        let payload = match check_name(stream) {
+            Ok(_) => JsonCodec.encode(&req),
+            Err(e) => Err(NatsError::CommandBuildError(e)),
+        };
+
+        match payload {
+            Ok(payload) => Either::A(self.direct_get(format!("DIRECT.GET.{}", stream), payload)),
+            Err(e) => Either::B(future::err(e)),
+        }
+    }
+
+    /// Same as `get_last_message_for_subject()` through the `DIRECT.GET` endpoint, which any replica of the
+    /// stream can answer and is faster. Without `allow_direct` on the stream, it fails or waits the same way as
+    /// `direct_get_message()`
+    ///
+    /// Returns `impl Future<Item = StoredMessage, Error = NatsError>`
+    pub fn direct_get_last_message_for_subject(
+        &self,
+        stream: &str,
+        subject: &str,
+    ) -> impl Future<Item = StoredMessage, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(stream) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        Either::B(self.direct_get(format!("DIRECT.GET.{}.{}", stream, subject), Bytes::new()))
+    }
+
+    /// Removes the messages of a stream, or some of them according to the options, resolves with the amount of
+    /// messages removed
+    ///
+    /// Returns `impl Future<Item = u64, Error = NatsError>`
+    pub fn purge_stream(
+        &self,
+        stream: &str,
+        options: &PurgeOptions,
+    ) -> impl Future<Item = u64, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(stream) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        Either::B(
+            self.request_json(&format!("STREAM.PURGE.{}", stream), options)
+                .map(|res: PurgeResponse| res.purged),
+        )
+    }
+
+    /// Removes the messages of a stream on a subject, resolves with the amount of messages removed
+    pub(crate) fn purge_subject(
+        &self,
+        stream: &str,
+        subject: &str,
+    ) -> impl Future<Item = u64, Error = NatsError> + Send + Sync {
+        let options = PurgeOptions {
+            filter: Some(subject.into()),
+            ..PurgeOptions::default()
+        };
+        self.purge_stream(stream, &options)
+    }
+
+    /// Removes a message of a stream, resolves with whether it has been removed. Fails with
+    /// `JsErrorCode::SequenceNotFound` if there is none with this sequence
+    ///
+    /// Returns `impl Future<Item = bool, Error = NatsError>`
+    pub fn delete_message(&self, stream: &str, seq: u64) -> impl Future<Item = bool, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(stream) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        let req = MsgDeleteRequest { seq, no_erase: true };
+        Either::B(
+            self.request_json(&format!("STREAM.MSG.DELETE.{}", stream), &req)
+                .map(|res: SuccessResponse| res.success),
+        )
+    }
+
+    /// Creates a stream. Fails with `JsErrorCode::StreamNameInUse` if a stream with the same name but another
+    /// configuration exists already
+    ///
+    /// Returns `impl Future<Item = StreamInfo, Error = NatsError>`
+    pub fn add_stream(&self, config: &StreamConfig) -> impl Future<Item = StreamInfo, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(&config.name) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        Either::B(self.request_json(&format!("STREAM.CREATE.{}", config.name), config))
+    }
+
+    /// Updates the configuration of an existing stream, some of its settings like its storage cannot be changed
+    ///
+    /// Returns `impl Future<Item = StreamInfo, Error = NatsError>`
+    pub fn update_stream(
+        &self,
+        config: &StreamConfig,
+    ) -> impl Future<Item = StreamInfo, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(&config.name) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        Either::B(self.request_json(&format!("STREAM.UPDATE.{}", config.name), config))
+    }
+
+    /// Deletes a stream along with its messages and consumers, resolves with whether it has been deleted
+    ///
+    /// Returns `impl Future<Item = bool, Error = NatsError>`
+    pub fn delete_stream(&self, name: &str) -> impl Future<Item = bool, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(name) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        Either::B(
+            self.request(&format!("STREAM.DELETE.{}", name), Bytes::new())
+                .map(|res: SuccessResponse| res.success),
+        )
+    }
+
+    /// Fetches the configuration and state of a stream. Fails with `JsErrorCode::StreamNotFound` if it doesn't
+    /// exist
+    ///
+    /// Returns `impl Future<Item = StreamInfo, Error = NatsError>`
+    pub fn stream_info(&self, name: &str) -> impl Future<Item = StreamInfo, Error = NatsError> + Send + Sync {
+        if let Err(e) = check_name(name) {
+            return Either::A(future::err(NatsError::CommandBuildError(e)));
+        }
+
+        Either::B(self.request(&format!("STREAM.INFO.{}", name), Bytes::new()))
+    }
+
+    /// Lists all the streams of the account, fetching as many pages from the API as needed
+    ///
+    /// Returns `impl Future<Item = Vec<StreamInfo>, Error = NatsError>`
+    pub fn list_streams(&self) -> impl Future<Item = Vec<StreamInfo>, Error = NatsError> + Send + Sync {
+        self.list_paged("STREAM.LIST".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DiscardPolicy, ExternalStream, PurgeOptions, Republish, RetentionPolicy, StorageType, StreamConfig, StreamInfo,
+        StreamSource,
+    };
+    use serde_json as json;
+    use std::time::Duration;
+
+    #[test]
+    fn it_encodes_stream_configs() {
+        let config = StreamConfig::builder()
+            .name("ORDERS")
+            .subjects(vec!["orders.>".into()])
+            .retention(RetentionPolicy::WorkQueue)
+            .storage(StorageType::Memory)
+            .max_age(Duration::from_secs(60))
+            .discard(DiscardPolicy::New)
+            .build()
+            .unwrap();
+
+        let encoded = json::to_value(&config).unwrap();
+        assert_eq!(encoded["retention"], "workqueue");
+        assert_eq!(encoded["storage"], "memory");
+        assert_eq!(encoded["discard"], "new");
+        assert_eq!(encoded["num_replicas"], 1);
+        assert_eq!(encoded["max_age"], 60_000_000_000u64);
+        assert_eq!(encoded["max_msgs"], -1);
+        assert_eq!(encoded["duplicate_window"], 120_000_000_000u64);
+        assert_eq!(json::from_value::<StreamConfig>(encoded).unwrap(), config);
+        assert!(json::from_str::<RetentionPolicy>(r#""forever""#).is_err());
+    }
+
+    #[test]
+    fn it_encodes_mirrors_and_sources() {
+        let source = StreamSource::builder()
+            .name("ORDERS")
+            .opt_start_seq(42)
+            .external(ExternalStream::domain("hub"))
+            .build()
+            .unwrap();
+        let config = StreamConfig::builder()
+            .name("ORDERS_BACKUP")
+            .mirror(source.clone())
+            .build()
+            .unwrap();
+
+        let encoded = json::to_value(&config).unwrap();
+        assert_eq!(
+            encoded["mirror"].to_string(),
+            r#"{"name":"ORDERS","opt_start_seq":42,"external":{"api":"$JS.hub.API"}}"#
+        );
+        assert!(encoded.get("sources").is_none());
+        assert_eq!(json::from_value::<StreamConfig>(encoded).unwrap(), config);
+
+        assert!(StreamConfig::builder()
+            .name("ORDERS_BACKUP")
+            .subjects(vec!["orders.>".into()])
+            .mirror(source)
+            .build()
+            .is_err());
+        assert!(StreamSource::builder()
+            .name("ORDERS")
+            .opt_start_seq(42)
+            .opt_start_time("2018-10-01T00:00:00Z".to_string())
+            .build()
+            .is_err());
+
+        let info: StreamInfo = json::from_str(
+            r#"{"config":{"name":"ORDERS_BACKUP","mirror":{"name":"ORDERS"}},"mirror":{"name":"ORDERS","lag":3}}"#,
+        )
+        .unwrap();
+        assert_eq!(info.config.mirror.unwrap().name, "ORDERS");
+        assert_eq!(info.mirror.unwrap().lag, 3);
+    }
+
+    #[test]
+    fn it_encodes_republish_configs() {
+        let republish = Republish {
+            src: "orders.*".into(),
+            dest: "events.orders.{{wildcard(1)}}".into(),
+            headers_only: true,
+        };
+        let config = StreamConfig::builder()
+            .name("ORDERS")
+            .subjects(vec!["orders.*".into()])
+            .republish(republish.clone())
+            .build()
+            .unwrap();
+
+        let encoded = json::to_value(&config).unwrap();
+        assert_eq!(
+            encoded["republish"].to_string(),
+            r#"{"src":"orders.*","dest":"events.orders.{{wildcard(1)}}","headers_only":true}"#
+        );
+        assert_eq!(json::from_value::<StreamConfig>(encoded).unwrap(), config);
+
+        let republish = Republish {
+            dest: String::new(),
+            ..republish
+        };
+        assert!(StreamConfig::builder()
+            .name("ORDERS")
+            .republish(republish)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn it_encodes_purge_options() {
+        let options = PurgeOptions::builder()
+            .filter("orders.new".to_string())
+            .sequence(42)
+            .build()
+            .unwrap();
+        assert_eq!(
+            json::to_string(&options).unwrap(),
+            r#"{"filter":"orders.new","seq":42}"#
+        );
+        assert_eq!(json::to_string(&PurgeOptions::default()).unwrap(), "{}");
+        assert!(PurgeOptions::builder().keep(1).sequence(42).build().is_err());
+    }
+
+    #[test]
+    fn it_rejects_invalid_stream_names() {
+        assert!(StreamConfig::builder().name("ORDERS.new").build().is_err());
+        assert!(StreamConfig::builder().name("").build().is_err());
+        assert!(StreamConfig::builder().name("ORDERS").replicas(0usize).build().is_err());
+    }
+}
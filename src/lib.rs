@@ -22,6 +22,14 @@
 ///! nitox = "0.1"
 ///! ```
 ///!
+///! The tokio-based client is behind the `client` feature, enabled by default. Disabling it leaves the
+///! protocol types, with their builders, validation, parsing and encoding, without pulling tokio or TLS:
+///!
+///! ```toml
+///! [dependencies]
+///! nitox = { version = "0.1", default-features = false }
+///! ```
+///!
 ///! ## Usage
 ///!
 ///! ```rust
@@ -85,18 +93,32 @@ extern crate serde_derive;
 extern crate serde_json;
 
 extern crate bytes;
+#[cfg(feature = "client")]
 extern crate parking_lot;
 extern crate rand;
 
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "client")]
+extern crate flate2;
+#[cfg(feature = "client")]
 extern crate futures;
+#[cfg(feature = "client")]
 extern crate native_tls;
+#[cfg(feature = "client")]
 extern crate tokio_codec;
+#[cfg(feature = "client")]
 extern crate tokio_executor;
+#[cfg(feature = "client")]
+extern crate tokio_io;
+#[cfg(feature = "client")]
 extern crate tokio_tcp;
+#[cfg(feature = "client")]
+extern crate tokio_timer;
+#[cfg(feature = "client")]
 extern crate tokio_tls;
+#[cfg(feature = "client")]
 extern crate url;
 
 #[macro_use]
@@ -111,7 +133,16 @@ pub mod codec;
 mod protocol;
 pub use self::protocol::*;
 
+#[cfg(feature = "client")]
 pub(crate) mod net;
 
+#[cfg(feature = "client")]
 mod client;
+#[cfg(feature = "client")]
+mod compression;
+#[cfg(feature = "client")]
+pub mod jetstream;
+#[cfg(feature = "client")]
 pub use self::client::*;
+#[cfg(feature = "client")]
+pub use self::compression::{CONTENT_ENCODING_HEADER, GZIP_ENCODING};
@@ -1,11 +1,13 @@
 use futures::{
     future::{self, Either},
     prelude::*,
+    task::{self, Task},
 };
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use std::{net::SocketAddr, sync::Arc};
 use tokio_executor;
 
+use codec::OpCodec;
 use error::NatsError;
 use protocol::Op;
 
@@ -30,6 +32,34 @@ pub(crate) enum NatsConnectionState {
     Disconnected,
 }
 
+/// Handle on the state of a connection, kept by the client once the connection has been split
+#[derive(Debug, Clone)]
+pub(crate) struct NatsConnectionStatus {
+    state: Arc<RwLock<NatsConnectionState>>,
+    reconnect_waiters: Arc<Mutex<Vec<Task>>>,
+}
+
+impl NatsConnectionStatus {
+    pub(crate) fn is_connected(&self) -> bool {
+        *self.state.read() == NatsConnectionState::Connected
+    }
+
+    /// Ready once the connection is up. Otherwise, the current task is woken up upon reconnection
+    pub(crate) fn poll_connected(&self) -> Async<()> {
+        if self.is_connected() {
+            return Async::Ready(());
+        }
+
+        self.reconnect_waiters.lock().push(task::current());
+        // The reconnection may have happened before the registration
+        if self.is_connected() {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        }
+    }
+}
+
 /// Represents a connection to a NATS server. Implements `Sink` and `Stream`
 #[derive(Debug)]
 pub struct NatsConnection {
@@ -43,9 +73,21 @@ pub struct NatsConnection {
     pub(crate) inner: Arc<RwLock<NatsConnectionInner>>,
     /// Current state of the connection
     pub(crate) state: Arc<RwLock<NatsConnectionState>>,
+    /// Tasks waiting for the connection to be back, woken up once reconnected
+    pub(crate) reconnect_waiters: Arc<Mutex<Vec<Task>>>,
+    /// Codec the connection has been created with, cloned to frame reconnected sockets the same way
+    pub(crate) codec: OpCodec,
 }
 
 impl NatsConnection {
+    /// Returns a handle on the state of the connection, which stays usable once the connection is split
+    pub(crate) fn status(&self) -> NatsConnectionStatus {
+        NatsConnectionStatus {
+            state: Arc::clone(&self.state),
+            reconnect_waiters: Arc::clone(&self.reconnect_waiters),
+        }
+    }
+
     /// Tries to reconnect once to the server; Only used internally. Blocks polling during reconnecting
     /// by forcing the object to return `Async::NotReady`/`AsyncSink::NotReady`
     fn reconnect(&self) -> impl Future<Item = (), Error = NatsError> {
@@ -53,24 +95,29 @@ impl NatsConnection {
 
         let inner_arc = Arc::clone(&self.inner);
         let inner_state = Arc::clone(&self.state);
+        let reconnect_waiters = Arc::clone(&self.reconnect_waiters);
         let is_tls = self.is_tls;
         let maybe_host = self.host.clone();
+        let codec = self.codec.clone();
         NatsConnectionInner::connect_tcp(&self.addr)
             .and_then(move |socket| {
                 if is_tls {
                     Either::A(
                         // This unwrap is safe because the value would always be present if `is_tls` is true
                         NatsConnectionInner::upgrade_tcp_to_tls(&maybe_host.unwrap(), socket)
-                            .map(NatsConnectionInner::from),
+                            .map(move |socket| NatsConnectionInner::from_tls(socket, codec)),
                     )
                 } else {
-                    Either::B(future::ok(NatsConnectionInner::from(socket)))
+                    Either::B(future::ok(NatsConnectionInner::from_tcp(socket, codec)))
                 }
             }).and_then(move |inner| {
                 {
                     *inner_arc.write() = inner;
                     *inner_state.write() = NatsConnectionState::Connected;
                 }
+                for task in reconnect_waiters.lock().drain(..) {
+                    task.notify();
+                }
                 debug!(target: "nitox", "Successfully swapped reconnected underlying connection");
                 Ok(())
             })
@@ -1,23 +1,55 @@
+use bytes::{buf::Chain, Buf, Bytes, IntoBuf};
 use codec::OpCodec;
 use futures::prelude::*;
 use native_tls::TlsConnector as NativeTlsConnector;
 use protocol::Op;
-use std::net::SocketAddr;
+use std::{
+    io::{self, Cursor},
+    net::SocketAddr,
+};
 use tokio_codec::{Decoder, Framed};
+use tokio_io::AsyncWrite;
 use tokio_tcp::TcpStream;
 use tokio_tls::{TlsConnector, TlsStream};
 
 use error::NatsError;
 
+/// Payload size from which PUB commands are written straight to the socket instead of being copied in the
+/// framed write buffer
+const DIRECT_WRITE_THRESHOLD: usize = 512 * 1024;
+
+/// Large PUB written straight to the socket: its control line, payload and trailing CRLF, chained so they're
+/// given to the socket in a single vectored write
+type DirectFrame = Chain<Chain<Cursor<Bytes>, Cursor<Bytes>>, Cursor<&'static [u8]>>;
+
 /// Inner raw stream enum over TCP and TLS/TCP
 #[derive(Debug)]
-pub(crate) enum NatsConnectionInner {
+pub(crate) enum NatsTransport {
     /// Raw TCP Stream framed connection
     Tcp(Box<Framed<TcpStream, OpCodec>>),
     /// TLS over TCP Stream framed connection
     Tls(Box<Framed<TlsStream<TcpStream>, OpCodec>>),
 }
 
+impl NatsTransport {
+    /// Writes buffers to the underlying socket, bypassing the framed write buffer. Plain TCP sockets write
+    /// all the chained buffers at once
+    fn poll_write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        match self {
+            NatsTransport::Tcp(framed) => framed.get_mut().write_buf(buf),
+            NatsTransport::Tls(framed) => framed.get_mut().write_buf(buf),
+        }
+    }
+}
+
+/// Framed connection, along with the large frame being written straight to the socket if any
+#[derive(Debug)]
+pub(crate) struct NatsConnectionInner {
+    transport: NatsTransport,
+    /// Remaining part of a large frame that is being written to the socket
+    pending_frame: Option<DirectFrame>,
+}
+
 impl NatsConnectionInner {
     /// Connects to a TCP socket
     pub(crate) fn connect_tcp(addr: &SocketAddr) -> impl Future<Item = TcpStream, Error = NatsError> {
@@ -35,47 +67,134 @@ impl NatsConnectionInner {
         debug!(target: "nitox", "Connecting to {} through TLS over TCP", host);
         tls_stream.connect(&host, socket).from_err()
     }
+
+    /// Writes what remains of the pending large frame to the socket
+    fn poll_write_frame(&mut self) -> Poll<(), NatsError> {
+        if let Some(mut frame) = self.pending_frame.take() {
+            while frame.has_remaining() {
+                match self.transport.poll_write_buf(&mut frame) {
+                    Ok(Async::Ready(0)) => {
+                        return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write frame to socket").into());
+                    }
+                    Ok(Async::Ready(_)) => {}
+                    Ok(Async::NotReady) => {
+                        self.pending_frame = Some(frame);
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
+
+impl From<NatsTransport> for NatsConnectionInner {
+    fn from(transport: NatsTransport) -> Self {
+        NatsConnectionInner {
+            transport,
+            pending_frame: None,
+        }
+    }
+}
+
+impl NatsConnectionInner {
+    /// Frames a TCP socket with the given codec
+    pub(crate) fn from_tcp(socket: TcpStream, codec: OpCodec) -> Self {
+        NatsTransport::Tcp(Box::new(codec.framed(socket))).into()
+    }
+
+    /// Frames a TLS over TCP socket with the given codec
+    pub(crate) fn from_tls(socket: TlsStream<TcpStream>, codec: OpCodec) -> Self {
+        NatsTransport::Tls(Box::new(codec.framed(socket))).into()
+    }
 }
 
 impl From<TcpStream> for NatsConnectionInner {
     fn from(socket: TcpStream) -> Self {
-        NatsConnectionInner::Tcp(Box::new(OpCodec::default().framed(socket)))
+        NatsConnectionInner::from_tcp(socket, OpCodec::default())
     }
 }
 
 impl From<TlsStream<TcpStream>> for NatsConnectionInner {
     fn from(socket: TlsStream<TcpStream>) -> Self {
-        NatsConnectionInner::Tls(Box::new(OpCodec::default().framed(socket)))
+        NatsConnectionInner::from_tls(socket, OpCodec::default())
     }
 }
 
-impl Sink for NatsConnectionInner {
+impl Sink for NatsTransport {
     type SinkError = NatsError;
     type SinkItem = Op;
 
     fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
         match self {
-            NatsConnectionInner::Tcp(framed) => framed.start_send(item),
-            NatsConnectionInner::Tls(framed) => framed.start_send(item),
+            NatsTransport::Tcp(framed) => framed.start_send(item),
+            NatsTransport::Tls(framed) => framed.start_send(item),
         }
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
         match self {
-            NatsConnectionInner::Tcp(framed) => framed.poll_complete(),
-            NatsConnectionInner::Tls(framed) => framed.poll_complete(),
+            NatsTransport::Tcp(framed) => framed.poll_complete(),
+            NatsTransport::Tls(framed) => framed.poll_complete(),
         }
     }
 }
 
-impl Stream for NatsConnectionInner {
+impl Stream for NatsTransport {
     type Error = NatsError;
     type Item = Op;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         match self {
-            NatsConnectionInner::Tcp(framed) => framed.poll(),
-            NatsConnectionInner::Tls(framed) => framed.poll(),
+            NatsTransport::Tcp(framed) => framed.poll(),
+            NatsTransport::Tls(framed) => framed.poll(),
         }
     }
 }
+
+impl Sink for NatsConnectionInner {
+    type SinkError = NatsError;
+    type SinkItem = Op;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        // A large frame is being written, nothing can be interleaved with it
+        if self.poll_write_frame()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        match item {
+            Op::PUB(cmd) if cmd.payload.len() >= DIRECT_WRITE_THRESHOLD => {
+                // What's been buffered so far has to reach the socket before we start writing there directly
+                if self.transport.poll_complete()?.is_not_ready() {
+                    return Ok(AsyncSink::NotReady(Op::PUB(cmd)));
+                }
+
+                debug!(target: "nitox", "Writing PUB with a payload of {} bytes directly", cmd.payload.len());
+                let (control_line, payload) = cmd.into_parts();
+                let crlf: &'static [u8] = b"\r\n";
+                self.pending_frame = Some(control_line.into_buf().chain(payload).chain(crlf));
+                Ok(AsyncSink::Ready)
+            }
+            item => self.transport.start_send(item),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        if self.poll_write_frame()?.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+
+        self.transport.poll_complete()
+    }
+}
+
+impl Stream for NatsConnectionInner {
+    type Error = NatsError;
+    type Item = Op;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.transport.poll()
+    }
+}
@@ -1,20 +1,21 @@
 use futures::prelude::*;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use std::net::SocketAddr;
 use std::sync::Arc;
 
 pub(crate) mod connection;
 mod connection_inner;
 
+use codec::OpCodec;
 use error::NatsError;
 
 use self::connection::NatsConnectionState;
 use self::connection_inner::*;
 
-pub(crate) use self::connection::NatsConnection;
+pub(crate) use self::connection::{NatsConnection, NatsConnectionStatus};
 
-/// Connect to a raw TCP socket
-pub(crate) fn connect(addr: SocketAddr) -> impl Future<Item = NatsConnection, Error = NatsError> {
+/// Connect to a raw TCP socket, framed with a clone of `codec`
+pub(crate) fn connect(addr: SocketAddr, codec: OpCodec) -> impl Future<Item = NatsConnection, Error = NatsError> {
     NatsConnectionInner::connect_tcp(&addr).map(move |socket| {
         debug!(target: "nitox", "Connected through TCP");
         NatsConnection {
@@ -22,13 +23,19 @@ pub(crate) fn connect(addr: SocketAddr) -> impl Future<Item = NatsConnection, Er
             addr,
             host: None,
             state: Arc::new(RwLock::new(NatsConnectionState::Connected)),
-            inner: Arc::new(RwLock::new(socket.into())),
+            reconnect_waiters: Arc::new(Mutex::new(Vec::new())),
+            inner: Arc::new(RwLock::new(NatsConnectionInner::from_tcp(socket, codec.clone()))),
+            codec,
         }
     })
 }
 
 /// Connect to a TLS over TCP socket. Upgrade is performed automatically
-pub(crate) fn connect_tls(host: String, addr: SocketAddr) -> impl Future<Item = NatsConnection, Error = NatsError> {
+pub(crate) fn connect_tls(
+    host: String,
+    addr: SocketAddr,
+    codec: OpCodec,
+) -> impl Future<Item = NatsConnection, Error = NatsError> {
     let inner_host = host.clone();
     NatsConnectionInner::connect_tcp(&addr)
         .and_then(move |socket| {
@@ -41,7 +48,9 @@ pub(crate) fn connect_tls(host: String, addr: SocketAddr) -> impl Future<Item =
                 addr,
                 host: Some(inner_host),
                 state: Arc::new(RwLock::new(NatsConnectionState::Connected)),
-                inner: Arc::new(RwLock::new(socket.into())),
+                reconnect_waiters: Arc::new(Mutex::new(Vec::new())),
+                inner: Arc::new(RwLock::new(NatsConnectionInner::from_tls(socket, codec.clone()))),
+                codec,
             }
         })
 }
@@ -1,6 +1,7 @@
 use bytes::Bytes;
-use protocol::{Command, CommandError};
+use protocol::{commands::ServerInfo, Command, CommandError};
 use serde_json as json;
+use std::fmt;
 
 /// The CONNECT message is the client version of the INFO message. Once the client has established a TCP/IP
 /// socket connection with the NATS server, and an INFO message has been received from the server, the client
@@ -44,12 +45,95 @@ pub struct ConnectCommand {
     /// which is when proto in the INFO protocol is set to at least 1.
     #[serde(skip_serializing_if = "Option::is_none")]
     echo: Option<bool>,
+    /// Optional boolean. If set to true, the server will deliver messages with headers using HMSG and accept HPUB.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headers: Option<bool>,
+    /// Optional boolean. Requires `headers`. If set to true, the server will reply to requests published on subjects
+    /// without any subscriber with a status-only `503` message instead of letting them time out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    no_responders: Option<bool>,
+    /// The public NKey to authenticate the client. This will be used to verify the signature (`sig`) against the
+    /// nonce provided in the INFO message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nkey: Option<String>,
+    /// The JWT that identifies a user permissions and account.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jwt: Option<String>,
+    /// In case the server has responded with a nonce on INFO, then a NATS client must use this field to reply with
+    /// the signed nonce.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sig: Option<String>,
 }
 
 impl ConnectCommand {
     pub fn builder() -> ConnectCommandBuilder {
         ConnectCommandBuilder::default()
     }
+
+    /// Indicates if headers have been requested in this command
+    pub fn headers_enabled(&self) -> bool {
+        self.headers == Some(true)
+    }
+
+    /// Returns a copy of this command where the optional features that the server didn't advertise in its INFO
+    /// message are turned off, so that we don't rely on things an older server is unable to honor
+    pub fn negotiate(&self, server_info: &ServerInfo) -> ConnectCommand {
+        let mut cmd = self.clone();
+
+        if let Some(protocol) = cmd.protocol {
+            if protocol > server_info.protocol_level() {
+                debug!(target: "nitox", "Server only supports protocol {}", server_info.protocol_level());
+                cmd.protocol = Some(server_info.protocol_level());
+            }
+        }
+
+        if cmd.echo.is_some() && !server_info.supports_echo() {
+            warn!(target: "nitox", "Server doesn't support the echo setting, ignoring it");
+            cmd.echo = None;
+        }
+
+        if (cmd.headers.is_some() || cmd.no_responders.is_some()) && !server_info.supports_headers() {
+            warn!(target: "nitox", "Server doesn't support headers, disabling headers and no responders");
+            cmd.headers = None;
+            cmd.no_responders = None;
+        }
+
+        cmd
+    }
+}
+
+/// Credentials are left out, so the rendering can safely end up in logs
+impl fmt::Display for ConnectCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CONNECT")?;
+        if let Some(ref name) = self.name {
+            write!(f, " name={}", name)?;
+        }
+
+        write!(
+            f,
+            " lang={} version={} verbose={} pedantic={} tls_required={}",
+            self.lang, self.version, self.verbose, self.pedantic, self.tls_required
+        )?;
+
+        if let Some(protocol) = self.protocol {
+            write!(f, " protocol={}", protocol)?;
+        }
+
+        if let Some(echo) = self.echo {
+            write!(f, " echo={}", echo)?;
+        }
+
+        if let Some(headers) = self.headers {
+            write!(f, " headers={}", headers)?;
+        }
+
+        if let Some(no_responders) = self.no_responders {
+            write!(f, " no_responders={}", no_responders)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl ConnectCommandBuilder {
@@ -58,10 +142,7 @@ impl ConnectCommandBuilder {
     }
 
     fn default_ver(&self) -> Result<String, String> {
-        match ::std::env::var("CARGO_PKG_VERSION") {
-            Ok(v) => Ok(v),
-            Err(_) => Ok("0.1.x".into()),
-        }
+        Ok(env!("CARGO_PKG_VERSION").into())
     }
 
     fn default_lang(&self) -> Result<String, String> {
@@ -94,7 +175,7 @@ impl Command for ConnectCommand {
 #[cfg(test)]
 mod tests {
     use super::{ConnectCommand, ConnectCommandBuilder};
-    use protocol::Command;
+    use protocol::{commands::ServerInfo, Command};
 
     static DEFAULT_CONNECT: &'static str = "CONNECT\t{\"verbose\":false,\"pedantic\":false,\"tls_required\":false,\"name\":\"nitox\",\"lang\":\"rust\",\"version\":\"1.0.0\"}\r\n";
 
@@ -127,4 +208,60 @@ mod tests {
 
         assert_eq!(DEFAULT_CONNECT, cmd_bytes);
     }
+
+    #[test]
+    fn it_fills_client_metadata() {
+        let cmd = ConnectCommandBuilder::default().build().unwrap();
+        assert_eq!(&cmd.lang, "rust");
+        assert_eq!(&cmd.version, env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn it_stringifies_credentials() {
+        let cmd = ConnectCommandBuilder::default()
+            .lang("rust")
+            .version("1.0.0")
+            .name(None)
+            .nkey(Some("UABC".into()))
+            .jwt(Some("eyJ0".into()))
+            .sig(Some("c2ln".into()))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            "CONNECT\t{\"verbose\":false,\"pedantic\":false,\"tls_required\":false,\"lang\":\"rust\",\"version\":\"1.0.0\",\"nkey\":\"UABC\",\"jwt\":\"eyJ0\",\"sig\":\"c2ln\"}\r\n",
+            cmd.into_vec().unwrap()
+        );
+    }
+
+    #[test]
+    fn it_negotiates_with_old_servers() {
+        let cmd = ConnectCommandBuilder::default()
+            .protocol(Some(1))
+            .echo(Some(false))
+            .headers(Some(true))
+            .no_responders(Some(true))
+            .build()
+            .unwrap();
+
+        let mut server_info = ServerInfo::builder()
+            .server_id("test")
+            .version("1.0.0")
+            .go("go1.10.3")
+            .host("0.0.0.0")
+            .port(4222u32)
+            .max_payload(4000u32)
+            .build()
+            .unwrap();
+
+        let negotiated = cmd.negotiate(&server_info);
+        assert_eq!(negotiated.protocol, Some(0));
+        assert!(negotiated.echo.is_none());
+        assert!(!negotiated.headers_enabled());
+        assert!(negotiated.no_responders.is_none());
+
+        server_info.proto = Some(1);
+        server_info.headers = Some(true);
+        assert_eq!(cmd.negotiate(&server_info), cmd);
+    }
 }
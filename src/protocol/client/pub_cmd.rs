@@ -1,11 +1,19 @@
 use bytes::{BufMut, Bytes, BytesMut};
-use protocol::{Command, CommandError};
+use protocol::{commands::Headers, fmt_payload, Command, CommandError};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use std::fmt::{self, Write};
+
+/// Largest `max_payload` a server can be configured with. Payloads larger than this are rejected by
+/// `PubCommandBuilder` already, the actual limit of the server is checked when publishing
+pub const MAX_PAYLOAD_LIMIT: usize = 64 * 1024 * 1024;
 
 /// The PUB message publishes the message payload to the given subject name, optionally supplying a reply subject.
 /// If a reply subject is supplied, it will be delivered to eligible subscribers along with the supplied payload.
 /// Note that the payload itself is optional.
-#[derive(Debug, Clone, PartialEq, Builder)]
+///
+/// Commands carrying headers are sent through the HPUB variant of the command, which the server only accepts
+/// when headers have been negotiated in the CONNECT command.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
 #[builder(build_fn(validate = "Self::validate"))]
 pub struct PubCommand {
     /// The destination subject to publish to
@@ -17,13 +25,54 @@ pub struct PubCommand {
     /// The message payload data
     #[builder(default, setter(into))]
     pub payload: Bytes,
+    /// Optional headers, the command is sent as HPUB when they're set
+    #[builder(default)]
+    pub headers: Option<Headers>,
 }
 
 impl PubCommand {
+    /// Command name of commands carrying headers
+    pub const HPUB_CMD_NAME: &'static [u8] = b"HPUB";
+
     pub fn builder() -> PubCommandBuilder {
         PubCommandBuilder::default()
     }
 
+    /// Size of what the server counts against its `max_payload`: the payload, plus the header block for HPUB
+    pub fn size(&self) -> usize {
+        self.payload.len() + self.headers.as_ref().map(|h| h.to_bytes().len()).unwrap_or(0)
+    }
+
+    /// Splits the command into the buffers written to the server: what precedes the payload (the control line,
+    /// followed by the header block for HPUB) and the payload, which has to be followed by a CRLF. The payload
+    /// isn't copied, so it can be written straight from the caller's buffer
+    pub(crate) fn into_parts(self) -> (Bytes, Bytes) {
+        let rt = if let Some(ref reply_to) = self.reply_to {
+            format!("\t{}", reply_to)
+        } else {
+            "".into()
+        };
+
+        let head = if let Some(ref headers) = self.headers {
+            let header_block = headers.to_bytes();
+            let control_line = format!(
+                "HPUB\t{}{}\t{}\t{}\r\n",
+                self.subject,
+                rt,
+                header_block.len(),
+                header_block.len() + self.payload.len()
+            );
+            let mut head = BytesMut::with_capacity(control_line.len() + header_block.len());
+            head.put(control_line.as_bytes());
+            head.put(header_block);
+            head.freeze()
+        } else {
+            format!("PUB\t{}{}\t{}\r\n", self.subject, rt, self.payload.len()).into()
+        };
+
+        (head, self.payload)
+    }
+
     /// Generates a random `reply_to` `String`
     pub fn generate_reply_to() -> String {
         let mut rng = thread_rng();
@@ -35,16 +84,10 @@ impl Command for PubCommand {
     const CMD_NAME: &'static [u8] = b"PUB";
 
     fn into_vec(self) -> Result<Bytes, CommandError> {
-        let rt = if let Some(reply_to) = self.reply_to {
-            format!("\t{}", reply_to)
-        } else {
-            "".into()
-        };
-
-        let cmd_str = format!("PUB\t{}{}\t{}\r\n", self.subject, rt, self.payload.len());
-        let mut bytes = BytesMut::with_capacity(cmd_str.len() + self.payload.len() + 2);
-        bytes.put(cmd_str.as_bytes());
-        bytes.put(self.payload);
+        let (control_line, payload) = self.into_parts();
+        let mut bytes = BytesMut::with_capacity(control_line.len() + payload.len() + 2);
+        bytes.put(control_line);
+        bytes.put(payload);
         bytes.put("\r\n");
 
         Ok(bytes.freeze())
@@ -62,25 +105,46 @@ impl Command for PubCommand {
                 return Err(CommandError::CommandMalformed);
             }
 
-            let payload: Bytes = buf[payload_start + 2..len - 2].into();
+            let body = &buf[payload_start + 2..len - 2];
 
             let whole_command = ::std::str::from_utf8(&buf[..payload_start])?;
             let mut split = whole_command.split_whitespace();
             let cmd = split.next().ok_or_else(|| CommandError::CommandMalformed)?;
             // Check if we're still on the right command
-            if cmd.as_bytes() != Self::CMD_NAME {
-                return Err(CommandError::CommandMalformed);
-            }
+            let has_headers = match cmd.as_bytes() {
+                Self::CMD_NAME => false,
+                Self::HPUB_CMD_NAME => true,
+                _ => return Err(CommandError::CommandMalformed),
+            };
 
+            // For HPUB, this is the total length of the header block and the payload
             let payload_len: usize = split
                 .next_back()
                 .ok_or_else(|| CommandError::CommandMalformed)?
                 .parse()?;
 
-            if payload.len() != payload_len {
+            if body.len() != payload_len {
+                return Err(CommandError::CommandMalformed);
+            }
+
+            let header_len: usize = if has_headers {
+                split.next_back().ok_or(CommandError::CommandMalformed)?.parse()?
+            } else {
+                0
+            };
+
+            if header_len > payload_len {
                 return Err(CommandError::CommandMalformed);
             }
 
+            let headers = if has_headers {
+                Some(Headers::from_bytes(&body[..header_len])?)
+            } else {
+                None
+            };
+
+            let payload: Bytes = body[header_len..].into();
+
             // Extract subject
             let subject: String = split.next().ok_or_else(|| CommandError::CommandMalformed)?.into();
 
@@ -90,6 +154,7 @@ impl Command for PubCommand {
                 subject,
                 payload,
                 reply_to,
+                headers,
             })
         } else {
             Err(CommandError::CommandMalformed)
@@ -97,6 +162,85 @@ impl Command for PubCommand {
     }
 }
 
+impl fmt::Display for PubCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            if self.headers.is_some() { "HPUB" } else { "PUB" },
+            self.subject
+        )?;
+        if let Some(ref reply_to) = self.reply_to {
+            write!(f, " {}", reply_to)?;
+        }
+
+        write!(f, " {}", self.payload.len())?;
+        if let Some(ref headers) = self.headers {
+            write!(f, " {}", headers)?;
+        }
+
+        write!(f, " ")?;
+        fmt_payload(f, &self.payload)
+    }
+}
+
+/// PUB command with a fixed subject and reply subject, for publishing many messages on the same subject. The
+/// beginning of the control line (`PUB <subject> [reply] `) is encoded once, publishing only appends the
+/// payload length and the payload. Templates don't carry headers
+#[derive(Debug, Clone, PartialEq)]
+pub struct PubTemplate {
+    subject: String,
+    reply_to: Option<String>,
+    prefix: Bytes,
+}
+
+impl PubTemplate {
+    /// Creates a template, failing if the subject or the reply subject is invalid
+    pub fn new<S: Into<String>>(subject: S, reply_to: Option<String>) -> Result<Self, String> {
+        let cmd = PubCommand::builder().subject(subject).reply_to(reply_to).build()?;
+        let prefix = match cmd.reply_to {
+            Some(ref reply_to) => format!("PUB\t{}\t{}\t", cmd.subject, reply_to),
+            None => format!("PUB\t{}\t", cmd.subject),
+        };
+
+        Ok(PubTemplate {
+            subject: cmd.subject,
+            reply_to: cmd.reply_to,
+            prefix: prefix.into(),
+        })
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn reply_to(&self) -> Option<&str> {
+        self.reply_to.as_deref()
+    }
+
+    /// Encodes the PUB command of the given payload at the end of `dst`
+    pub fn encode(&self, payload: &[u8], dst: &mut BytesMut) {
+        // The length takes 20 digits at most
+        dst.reserve(self.prefix.len() + 20 + payload.len() + 4);
+        dst.put_slice(&self.prefix);
+        // Writing into a `BytesMut` with enough capacity cannot fail
+        let _ = write!(dst, "{}", payload.len());
+        dst.put_slice(b"\r\n");
+        dst.put_slice(payload);
+        dst.put_slice(b"\r\n");
+    }
+
+    /// Returns the PUB command of the given payload
+    pub fn command<P: Into<Bytes>>(&self, payload: P) -> PubCommand {
+        PubCommand {
+            subject: self.subject.clone(),
+            reply_to: self.reply_to.clone(),
+            payload: payload.into(),
+            headers: None,
+        }
+    }
+}
+
 impl PubCommandBuilder {
     pub fn auto_reply_to(&mut self) -> &mut Self {
         let inbox = PubCommand::generate_reply_to();
@@ -104,6 +248,21 @@ impl PubCommandBuilder {
         self
     }
 
+    /// Sets the reply subject, saves wrapping it in `Some`
+    pub fn reply<S: Into<String>>(&mut self, reply_to: S) -> &mut Self {
+        self.reply_to = Some(Some(reply_to.into()));
+        self
+    }
+
+    /// Sets a header, replacing all the previous values of this key. The command will be sent as HPUB
+    pub fn header<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) -> &mut Self {
+        self.headers
+            .get_or_insert(None)
+            .get_or_insert_with(Headers::new)
+            .insert(key, value);
+        self
+    }
+
     fn validate(&self) -> Result<(), String> {
         if let Some(ref subj) = self.subject {
             check_cmd_arg!(subj, "subject");
@@ -115,16 +274,37 @@ impl PubCommandBuilder {
             }
         }
 
+        if let Some(ref payload) = self.payload {
+            if payload.len() > MAX_PAYLOAD_LIMIT {
+                return Err(format!(
+                    "payload of {} bytes exceeds the maximum of {} bytes",
+                    payload.len(),
+                    MAX_PAYLOAD_LIMIT
+                ));
+            }
+        }
+
+        if let Some(Some(ref headers)) = self.headers {
+            for (key, value) in headers.iter() {
+                check_cmd_arg!(key, "header name");
+                if key.contains(':') || value.contains('\r') || value.contains('\n') {
+                    return Err(format!("header {:?} cannot be encoded", key));
+                }
+            }
+        }
+
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{PubCommand, PubCommandBuilder};
+    use super::{PubCommand, PubCommandBuilder, PubTemplate, MAX_PAYLOAD_LIMIT};
+    use bytes::BytesMut;
     use protocol::Command;
 
     static DEFAULT_PUB: &'static str = "PUB\tFOO\t11\r\nHello NATS!\r\n";
+    static DEFAULT_HPUB: &'static str = "HPUB\tFOO\t22\t33\r\nNATS/1.0\r\nFoo: bar\r\n\r\nHello NATS!\r\n";
 
     #[test]
     fn it_parses() {
@@ -150,4 +330,72 @@ mod tests {
 
         assert_eq!(DEFAULT_PUB, cmd_bytes);
     }
+
+    #[test]
+    fn it_sets_reply_subjects() {
+        let cmd = PubCommandBuilder::default()
+            .subject("FOO")
+            .reply("INBOX")
+            .payload("Hello NATS!")
+            .build()
+            .unwrap();
+
+        assert_eq!(cmd.reply_to, Some("INBOX".to_string()));
+        assert_eq!(cmd.into_vec().unwrap(), "PUB\tFOO\tINBOX\t11\r\nHello NATS!\r\n");
+        assert!(PubCommandBuilder::default()
+            .subject("FOO")
+            .reply("IN BOX")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn it_rejects_oversized_payloads() {
+        let payload = vec![0u8; MAX_PAYLOAD_LIMIT + 1];
+        assert!(PubCommandBuilder::default()
+            .subject("FOO")
+            .payload(payload)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn it_parses_headers() {
+        let cmd = PubCommand::try_parse(DEFAULT_HPUB.as_bytes()).unwrap();
+        assert_eq!(&cmd.subject, "FOO");
+        assert_eq!(&cmd.payload, "Hello NATS!");
+        assert_eq!(cmd.headers.unwrap().get("Foo"), Some("bar"));
+    }
+
+    #[test]
+    fn it_stringifies_headers() {
+        let cmd = PubCommandBuilder::default()
+            .subject("FOO")
+            .header("Foo", "bar")
+            .payload("Hello NATS!")
+            .build()
+            .unwrap();
+
+        assert_eq!(cmd.size(), 33);
+        assert_eq!(cmd.into_vec().unwrap(), DEFAULT_HPUB);
+        assert!(PubCommandBuilder::default()
+            .subject("FOO")
+            .header("Foo", "b\r\nar")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn it_encodes_templates() {
+        let template = PubTemplate::new("FOO", Some("INBOX".into())).unwrap();
+        let mut dst = BytesMut::new();
+        template.encode(b"Hello NATS!", &mut dst);
+        template.encode(b"", &mut dst);
+
+        let mut expected = BytesMut::new();
+        expected.extend_from_slice(&template.command("Hello NATS!").into_vec().unwrap());
+        expected.extend_from_slice(&template.command("").into_vec().unwrap());
+        assert_eq!(dst, expected);
+        assert!(PubTemplate::new("F OO", None).is_err());
+    }
 }
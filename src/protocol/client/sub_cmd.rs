@@ -1,9 +1,10 @@
 use bytes::Bytes;
 use protocol::{Command, CommandError};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use std::fmt;
 
 /// SUB initiates a subscription to a subject, optionally joining a distributed queue group.
-#[derive(Debug, Clone, PartialEq, Builder)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
 #[builder(build_fn(validate = "Self::validate"))]
 pub struct SubCommand {
     /// The subject name to subscribe to
@@ -73,6 +74,17 @@ impl Command for SubCommand {
     }
 }
 
+impl fmt::Display for SubCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SUB {}", self.subject)?;
+        if let Some(ref queue_group) = self.queue_group {
+            write!(f, " {}", queue_group)?;
+        }
+
+        write!(f, " {}", self.sid)
+    }
+}
+
 impl SubCommandBuilder {
     fn validate(&self) -> Result<(), String> {
         if let Some(ref subj) = self.subject {
@@ -81,6 +93,10 @@ impl SubCommandBuilder {
 
         if let Some(ref qg_maybe) = self.queue_group {
             if let Some(ref qg) = qg_maybe {
+                if qg.is_empty() {
+                    return Err("queue group is empty".into());
+                }
+
                 check_cmd_arg!(qg, "queue group");
             }
         }
@@ -119,4 +135,17 @@ mod tests {
 
         assert_eq!(DEFAULT_SUB, cmd_bytes);
     }
+
+    #[test]
+    fn it_rejects_invalid_queue_groups() {
+        let build = |qg: &str| {
+            SubCommand::builder()
+                .subject("FOO")
+                .queue_group(Some(qg.into()))
+                .build()
+        };
+        assert!(build("").is_err());
+        assert!(build("a b").is_err());
+        assert!(build("workers").is_ok());
+    }
 }
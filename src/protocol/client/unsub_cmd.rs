@@ -1,9 +1,10 @@
 use bytes::Bytes;
 use protocol::{commands::SubCommand, Command, CommandError};
+use std::fmt;
 
 /// UNSUB unsubcribes the connection from the specified subject, or auto-unsubscribes after the
 /// specified number of messages has been received.
-#[derive(Debug, Clone, PartialEq, Builder)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
 pub struct UnsubCommand {
     /// The unique alphanumeric subscription ID of the subject to unsubscribe from
     #[builder(setter(into))]
@@ -68,6 +69,17 @@ impl Command for UnsubCommand {
     }
 }
 
+impl fmt::Display for UnsubCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UNSUB {}", self.sid)?;
+        if let Some(max_msgs) = self.max_msgs {
+            write!(f, " {}", max_msgs)?;
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{UnsubCommand, UnsubCommandBuilder};
@@ -28,9 +28,57 @@ pub enum CommandError {
     /// Occurs when the payload length exceeds the bounds of integers
     #[fail(display = "PayloadLengthParseError: {}", _0)]
     PayloadLengthParseError(::std::num::ParseIntError),
+    /// Occurs when the first line of a command exceeds the maximum allowed length, given here
+    #[fail(display = "ControlLineTooLong: control line exceeds {} bytes", _0)]
+    ControlLineTooLong(usize),
+    /// Occurs when the payload announced by a command exceeds the maximum allowed length, given here
+    #[fail(display = "PayloadTooLong: payload exceeds {} bytes", _0)]
+    PayloadTooLong(usize),
     /// Generic error for untyped `String` errors
     #[fail(display = "GenericError: {}", _0)]
     GenericError(String),
+    /// Wraps an error that occured while decoding a command with the context needed to debug it
+    #[fail(
+        display = "{} (command: {}, offset: {}, buffer: {:?})",
+        cause, command, offset, snippet
+    )]
+    ParseError {
+        /// Name of the command that failed to parse
+        command: String,
+        /// Offset, in bytes, of the beginning of the command in the stream
+        offset: usize,
+        /// Beginning of the offending buffer
+        snippet: String,
+        /// Underlying error
+        cause: Box<CommandError>,
+    },
+}
+
+/// Maximum length of the buffer snippet kept in `CommandError::ParseError`
+const ERROR_SNIPPET_LEN: usize = 64;
+
+impl CommandError {
+    /// Adds the parsing context to the error: the name of the command, the offset of the command in the stream and
+    /// the beginning of the offending buffer
+    pub fn with_context(self, command: &[u8], offset: usize, buf: &[u8]) -> Self {
+        match self {
+            CommandError::ParseError { .. } | CommandError::IncompleteCommandError => self,
+            cause => CommandError::ParseError {
+                command: String::from_utf8_lossy(command).into_owned(),
+                offset,
+                snippet: String::from_utf8_lossy(&buf[..buf.len().min(ERROR_SNIPPET_LEN)]).into_owned(),
+                cause: Box::new(cause),
+            },
+        }
+    }
+
+    /// Returns the underlying error, stripped of its parsing context if any
+    pub fn root_cause(&self) -> &CommandError {
+        match self {
+            CommandError::ParseError { cause, .. } => cause.root_cause(),
+            e => e,
+        }
+    }
 }
 
 from_error!(json::Error, CommandError, CommandError::JsonError);
@@ -49,7 +97,8 @@ from_error!(
 from_error!(String, CommandError, CommandError::GenericError);
 
 /// This error is designed to be given when an argument like the `subject` or `queue_group` arguments are
-/// containing spaces or tabs, which is prohibited by the protocol and trigger an error server-side
+/// containing spaces or tabs, which is prohibited by the protocol and trigger an error server-side, or when
+/// a `Subject` is malformed
 #[derive(Debug, Clone, Eq, PartialEq, Fail)]
 pub enum ArgumentValidationError {
     /// The argument contains spaces
@@ -58,4 +107,10 @@ pub enum ArgumentValidationError {
     /// The argument contains tabs
     #[fail(display = "The argument contains tabs")]
     ContainsTab,
+    /// The subject has an empty token, like `foo..bar` or `.foo`
+    #[fail(display = "The subject contains an empty token")]
+    EmptySubjectToken,
+    /// The `>` wildcard of the subject is not its last token
+    #[fail(display = "The full wildcard is only allowed as the last token of the subject")]
+    MisplacedFullWildcard,
 }
@@ -0,0 +1,198 @@
+use bytes::Bytes;
+use protocol::CommandError;
+use std::{collections::BTreeMap, fmt};
+
+/// Version line every header block starts with
+pub(crate) const HEADER_VERSION_LINE: &str = "NATS/1.0";
+/// Header identifying a request and its reply, set by `NatsClient::request()` with the `correlation_ids` option
+pub const CORRELATION_ID_HEADER: &str = "Correlation-Id";
+
+/// Header map carried by messages when the server and the client both support headers (HMSG).
+///
+/// The block is encoded on the wire as `NATS/1.0[ <code>[ <description>]]\r\n` followed by `Key: Value\r\n`
+/// lines and terminated by an empty `\r\n` line. The optional status of the version line is not part of the
+/// map, it is exposed on `Message` instead.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Headers {
+    /// Header values, a key can hold several values
+    entries: BTreeMap<String, Vec<String>>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Headers::default()
+    }
+
+    /// Sets a header, replacing all the previous values of this key
+    pub fn insert<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) -> &mut Self {
+        self.entries.insert(key.into(), vec![value.into()]);
+        self
+    }
+
+    /// Adds a value to a header, keeping the previous values of this key
+    pub fn append<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) -> &mut Self {
+        self.entries.entry(key.into()).or_default().push(value.into());
+        self
+    }
+
+    /// Returns the first value of a header
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .get(key)
+            .and_then(|values| values.first())
+            .map(|value| value.as_str())
+    }
+
+    /// Returns all the values of a header
+    pub fn get_all(&self, key: &str) -> Option<&[String]> {
+        self.entries.get(key).map(|values| values.as_slice())
+    }
+
+    /// Removes a header and returns its values
+    pub fn remove(&mut self, key: &str) -> Option<Vec<String>> {
+        self.entries.remove(key)
+    }
+
+    /// Iterates over the `(key, value)` pairs, keys holding several values are yielded once per value
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .flat_map(|(k, values)| values.iter().map(move |v| (k.as_str(), v.as_str())))
+    }
+
+    /// Returns `true` if there are no headers at all
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Encodes the header block, including its trailing empty line
+    pub fn to_bytes(&self) -> Bytes {
+        self.to_bytes_with_status(None, None)
+    }
+
+    /// Encodes the header block with an optional status code and description on the version line
+    pub(crate) fn to_bytes_with_status(&self, status: Option<u16>, description: Option<&str>) -> Bytes {
+        let mut block = String::from(HEADER_VERSION_LINE);
+        if let Some(status) = status {
+            block.push_str(&format!(" {}", status));
+            if let Some(description) = description {
+                block.push(' ');
+                block.push_str(description);
+            }
+        }
+        block.push_str("\r\n");
+
+        for (k, v) in self.iter() {
+            block.push_str(k);
+            block.push_str(": ");
+            block.push_str(v);
+            block.push_str("\r\n");
+        }
+
+        block.push_str("\r\n");
+        block.into()
+    }
+
+    /// Parses a header block, including its trailing empty line
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, CommandError> {
+        Self::from_bytes_with_status(buf).map(|(headers, _, _)| headers)
+    }
+
+    /// Parses a header block, also returning the status code and description found on the version line
+    pub(crate) fn from_bytes_with_status(buf: &[u8]) -> Result<(Self, Option<u16>, Option<String>), CommandError> {
+        let block = ::std::str::from_utf8(buf)?;
+        if !block.ends_with("\r\n\r\n") {
+            return Err(CommandError::CommandMalformed);
+        }
+
+        let mut lines = block[..block.len() - 4].split("\r\n");
+        let version_line = lines.next().ok_or(CommandError::CommandMalformed)?;
+        if !version_line.starts_with(HEADER_VERSION_LINE) {
+            return Err(CommandError::CommandMalformed);
+        }
+
+        // Status line is `NATS/1.0 <code> <description>` where both the code and the description are optional
+        let mut status_split = version_line[HEADER_VERSION_LINE.len()..].trim().splitn(2, ' ');
+        let status: Option<u16> = match status_split.next() {
+            Some(code) if !code.is_empty() => Some(code.parse()?),
+            _ => None,
+        };
+        let description = status_split
+            .next()
+            .map(|d| d.trim())
+            .filter(|d| !d.is_empty())
+            .map(String::from);
+
+        let mut headers = Headers::default();
+        for line in lines {
+            let colon = line.find(':').ok_or(CommandError::CommandMalformed)?;
+            let key = line[..colon].trim();
+            if key.is_empty() {
+                return Err(CommandError::CommandMalformed);
+            }
+
+            headers.append(key, line[colon + 1..].trim());
+        }
+
+        Ok((headers, status, description))
+    }
+}
+
+impl fmt::Display for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{")?;
+        for (i, (k, v)) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+
+            write!(f, "{}: {}", k, v)?;
+        }
+
+        write!(f, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Headers;
+
+    static DEFAULT_HEADERS: &'static str = "NATS/1.0\r\nBar: baz\r\nFoo: bar\r\nFoo: qux\r\n\r\n";
+
+    #[test]
+    fn it_parses() {
+        let parse_res = Headers::from_bytes(DEFAULT_HEADERS.as_bytes());
+        assert!(parse_res.is_ok());
+        let headers = parse_res.unwrap();
+        assert_eq!(headers.get("Bar"), Some("baz"));
+        assert_eq!(headers.get("Foo"), Some("bar"));
+        assert_eq!(headers.get_all("Foo").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn it_parses_status_line() {
+        let (headers, status, description) = Headers::from_bytes_with_status(b"NATS/1.0 503\r\n\r\n").unwrap();
+        assert_eq!(status, Some(503));
+        assert!(description.is_none());
+        assert!(headers.is_empty());
+
+        let (_, status, description) = Headers::from_bytes_with_status(b"NATS/1.0 100 Idle Heartbeat\r\n\r\n").unwrap();
+        assert_eq!(status, Some(100));
+        assert_eq!(description, Some("Idle Heartbeat".into()));
+    }
+
+    #[test]
+    #[should_panic]
+    fn it_rejects_invalid_status() {
+        Headers::from_bytes(b"NATS/1.0 foo\r\n\r\n").unwrap();
+    }
+
+    #[test]
+    fn it_stringifies() {
+        let mut headers = Headers::new();
+        headers.insert("Foo", "bar").append("Foo", "qux").insert("Bar", "baz");
+
+        assert_eq!(DEFAULT_HEADERS, headers.to_bytes());
+    }
+}
@@ -1,4 +1,8 @@
 use bytes::Bytes;
+use std::fmt;
+
+/// Amount of payload bytes rendered by the `Display` implementations of the commands
+const DISPLAY_PAYLOAD_LEN: usize = 32;
 
 /// Trait used to implement a common interface for implementing new commands
 pub trait Command {
@@ -21,6 +25,16 @@ pub(crate) fn check_command_arg(s: &str) -> Result<(), ArgumentValidationError>
 
     Ok(())
 }
+/// Writes a payload for logging purposes, truncated to `DISPLAY_PAYLOAD_LEN` bytes
+pub(crate) fn fmt_payload(f: &mut fmt::Formatter, payload: &[u8]) -> fmt::Result {
+    let shown = &payload[..payload.len().min(DISPLAY_PAYLOAD_LEN)];
+    write!(f, "{:?}", String::from_utf8_lossy(shown))?;
+    if payload.len() > shown.len() {
+        write!(f, "...(+{} bytes)", payload.len() - shown.len())?;
+    }
+
+    Ok(())
+}
 
 macro_rules! check_cmd_arg {
     ($val:ident, $part:expr) => {
@@ -34,6 +48,9 @@ macro_rules! check_cmd_arg {
             Err(ArgumentValidationError::ContainsTab) => {
                 return Err(format!("{} contains tabs", $part).into());
             }
+            Err(e) => {
+                return Err(format!("{} is invalid: {}", $part, e).into());
+            }
         }
     };
 }
@@ -42,14 +59,19 @@ mod error;
 pub use self::error::*;
 
 mod client;
+mod headers;
 mod server;
 
 mod op;
 pub use self::op::*;
 
+mod subject;
+pub use self::subject::{subject_matches, Subject};
+
 pub mod commands {
     pub use super::{
         client::{connect::*, pub_cmd::*, sub_cmd::*, unsub_cmd::*},
+        headers::{Headers, CORRELATION_ID_HEADER},
         server::{info::*, message::*, server_error::ServerError},
     };
     pub use Command;
@@ -1,20 +1,23 @@
 use super::{commands::*, Command, CommandError};
-use bytes::Bytes;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::fmt;
 
 /// Abstraction over NATS protocol messages
-#[derive(Debug, Clone, PartialEq)]
+///
+/// Ops can be (de)serialized with `serde`, which allows logging, capturing and replaying protocol traffic.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Op {
     /// **SERVER** Sent to client after initial TCP/IP connection
     INFO(ServerInfo),
     /// **CLIENT** Sent to server to specify connection information
     CONNECT(ConnectCommand),
-    /// **CLIENT** Publish a message to a subject, with optional reply subject
+    /// **CLIENT** Publish a message to a subject, with optional reply subject, and with headers when sent through HPUB
     PUB(PubCommand),
     /// **CLIENT** Subscribe to a subject (or subject wildcard)
     SUB(SubCommand),
     /// **CLIENT** Unsubscribe (or auto-unsubscribe) from subject
     UNSUB(UnsubCommand),
-    /// **SERVER** Delivers a message payload to a subscriber
+    /// **SERVER** Delivers a message payload to a subscriber, with headers when delivered through HMSG
     MSG(Message),
     /// **BOTH** PING keep-alive message
     PING,
@@ -24,6 +27,12 @@ pub enum Op {
     OK,
     /// **SERVER** Indicates a protocol error. May cause client disconnect.
     ERR(ServerError),
+    /// **SERVER** Operation this client doesn't know about, e.g. introduced by a newer protocol revision.
+    /// Holds the skipped line, without its trailing CRLF
+    UNKNOWN(String),
+    /// **CLIENT** One or several commands already encoded, written as they are. Allows pipelining commands
+    /// into a single write
+    RAW(Bytes),
 }
 
 macro_rules! op_from_cmd {
@@ -52,16 +61,52 @@ impl Op {
             Op::PONG => "PONG\r\n".into(),
             Op::OK => "+OK\r\n".into(),
             Op::ERR(se) => format!("-ERR {}\r\n", se).as_bytes().into(),
+            Op::UNKNOWN(line) => format!("{}\r\n", line).as_bytes().into(),
+            Op::RAW(buf) => buf,
         })
     }
 
+    /// Encodes the op at the end of `dst`. The payload of a PUB is copied once, from the command to `dst`, instead of
+    /// going through an intermediate buffer
+    pub(crate) fn encode(self, dst: &mut BytesMut) -> Result<(), CommandError> {
+        match self {
+            Op::PUB(cmd) => {
+                let (control_line, payload) = cmd.into_parts();
+                dst.reserve(control_line.len() + payload.len() + 2);
+                dst.put(control_line);
+                dst.put(payload);
+                dst.put_slice(b"\r\n");
+            }
+            op => {
+                let buf = op.into_bytes()?;
+                dst.reserve(buf.len());
+                dst.put(buf);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Tries to parse from a pair of command name and whole buffer, sharing the subjects of the messages
+    /// through the given cache
+    pub(crate) fn from_bytes_interned(
+        cmd_name: &[u8],
+        buf: &[u8],
+        subjects: &mut SubjectCache,
+    ) -> Result<Self, CommandError> {
+        match cmd_name {
+            Message::CMD_NAME | Message::HMSG_CMD_NAME => Message::try_parse_interned(buf, subjects).map(Op::MSG),
+            _ => Op::from_bytes(cmd_name, buf),
+        }
+    }
+
     /// Tries to parse from a pair of command name and whole buffer
     pub fn from_bytes(cmd_name: &[u8], buf: &[u8]) -> Result<Self, CommandError> {
         match cmd_name {
             ServerInfo::CMD_NAME => op_from_cmd!(buf, ServerInfo::try_parse, Op::INFO),
             ConnectCommand::CMD_NAME => op_from_cmd!(buf, ConnectCommand::try_parse, Op::CONNECT),
-            Message::CMD_NAME => op_from_cmd!(buf, Message::try_parse, Op::MSG),
-            PubCommand::CMD_NAME => op_from_cmd!(buf, PubCommand::try_parse, Op::PUB),
+            Message::CMD_NAME | Message::HMSG_CMD_NAME => op_from_cmd!(buf, Message::try_parse, Op::MSG),
+            PubCommand::CMD_NAME | PubCommand::HPUB_CMD_NAME => op_from_cmd!(buf, PubCommand::try_parse, Op::PUB),
             SubCommand::CMD_NAME => op_from_cmd!(buf, SubCommand::try_parse, Op::SUB),
             UnsubCommand::CMD_NAME => op_from_cmd!(buf, UnsubCommand::try_parse, Op::UNSUB),
             b"PING" => {
@@ -93,7 +138,7 @@ impl Op {
                 }
             }
             _ => {
-                if buf.len() > 7 {
+                if buf.len() > 7 || buf.ends_with(b"\r\n") {
                     Err(CommandError::CommandNotFoundOrSupported)
                 } else {
                     Err(CommandError::IncompleteCommandError)
@@ -103,4 +148,143 @@ impl Op {
     }
 }
 
-// TODO: Write tests
+/// Renders the op on a single line, close to its wire format, with payloads truncated
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::INFO(si) => si.fmt(f),
+            Op::CONNECT(con) => con.fmt(f),
+            Op::PUB(pc) => pc.fmt(f),
+            Op::SUB(sc) => sc.fmt(f),
+            Op::UNSUB(uc) => uc.fmt(f),
+            Op::MSG(msg) => msg.fmt(f),
+            Op::PING => write!(f, "PING"),
+            Op::PONG => write!(f, "PONG"),
+            Op::OK => write!(f, "+OK"),
+            Op::ERR(se) => write!(f, "-ERR {}", se),
+            Op::UNKNOWN(line) => write!(f, "{:?}", line),
+            Op::RAW(buf) => write!(f, "RAW ({} bytes)", buf.len()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Op;
+    use bytes::BytesMut;
+    use protocol::commands::*;
+    use serde_json as json;
+
+    #[test]
+    fn it_encodes_like_into_bytes() {
+        let ops = vec![
+            Op::PUB(
+                PubCommand::builder()
+                    .subject("FOO")
+                    .reply_to(Some("BAR".into()))
+                    .payload("Hello NATS!")
+                    .build()
+                    .unwrap(),
+            ),
+            Op::SUB(SubCommand::builder().subject("FOO").sid("pouet").build().unwrap()),
+            Op::PING,
+        ];
+
+        let mut dst = BytesMut::from("PONG\r\n");
+        let mut expected = BytesMut::from("PONG\r\n");
+        for op in ops {
+            expected.extend_from_slice(&op.clone().into_bytes().unwrap());
+            op.encode(&mut dst).unwrap();
+        }
+
+        assert_eq!(dst, expected);
+    }
+
+    #[test]
+    fn it_roundtrips_through_serde() {
+        let ops = vec![
+            Op::PUB(
+                PubCommand::builder()
+                    .subject("FOO")
+                    .payload("Hello NATS!")
+                    .build()
+                    .unwrap(),
+            ),
+            Op::SUB(SubCommand::builder().subject("FOO").sid("pouet").build().unwrap()),
+            Op::MSG(
+                Message::builder()
+                    .subject("FOO")
+                    .sid("pouet")
+                    .payload("toto")
+                    .status(Some(503))
+                    .build()
+                    .unwrap(),
+            ),
+            Op::ERR(ServerError::from("'Unknown Protocol Operation'".to_string())),
+            Op::PING,
+        ];
+
+        for op in ops {
+            let serialized = json::to_string(&op).unwrap();
+            let deserialized: Op = json::from_str(&serialized).unwrap();
+            assert_eq!(op, deserialized);
+        }
+    }
+
+    #[test]
+    fn it_displays() {
+        let large_payload = vec![b'a'; 100];
+        let ops = vec![
+            (
+                Op::PUB(
+                    PubCommand::builder()
+                        .subject("FOO")
+                        .reply_to(Some("BAR".into()))
+                        .payload(large_payload)
+                        .build()
+                        .unwrap(),
+                ),
+                format!("PUB FOO BAR 100 \"{}\"...(+68 bytes)", "a".repeat(32)),
+            ),
+            (
+                Op::SUB(
+                    SubCommand::builder()
+                        .subject("FOO")
+                        .queue_group(Some("workers".into()))
+                        .sid("pouet")
+                        .build()
+                        .unwrap(),
+                ),
+                "SUB FOO workers pouet".into(),
+            ),
+            (
+                Op::MSG(
+                    Message::builder()
+                        .subject("FOO")
+                        .sid("pouet")
+                        .payload("toto")
+                        .build()
+                        .unwrap(),
+                ),
+                "MSG FOO pouet 4 \"toto\"".into(),
+            ),
+            (
+                Op::MSG(
+                    Message::builder()
+                        .subject("_INBOX")
+                        .sid("pouet")
+                        .payload("")
+                        .status(Some(503))
+                        .build()
+                        .unwrap(),
+                ),
+                "HMSG _INBOX pouet 0 503 \"\"".into(),
+            ),
+            (Op::PING, "PING".into()),
+        ];
+
+        for (op, rendering) in ops {
+            assert_eq!(op.to_string(), rendering);
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use bytes::Bytes;
 use protocol::{Command, CommandError};
 use serde_json as json;
+use std::fmt;
 
 /// As soon as the server accepts a connection from the client, it will send information about itself and the
 /// configuration and security requirements that are necessary for the client to successfully authenticate with
@@ -12,56 +13,114 @@ use serde_json as json;
 pub struct ServerInfo {
     /// The unique identifier of the NATS server
     #[builder(setter(into))]
-    pub(crate) server_id: String,
+    pub server_id: String,
     /// The version of the NATS server
     #[builder(setter(into))]
-    pub(crate) version: String,
+    pub version: String,
     /// The version of golang the NATS server was built with
     #[builder(setter(into))]
-    pub(crate) go: String,
+    pub go: String,
     /// The IP address used to start the NATS server, by default this will be 0.0.0.0 and can be configured with
     /// `-client_advertise host:port`
     #[builder(setter(into))]
-    pub(crate) host: String,
+    pub host: String,
     /// The port number the NATS server is configured to listen on
     #[builder(setter(into))]
-    pub(crate) port: u32,
+    pub port: u32,
     /// Maximum payload size, in bytes, that the server will accept from the client.
     #[builder(setter(into))]
-    pub(crate) max_payload: u32,
+    pub max_payload: u32,
     /// An integer indicating the protocol version of the server. The server version 1.2.0 sets this to 1 to indicate
     /// that it supports the “Echo” feature.
     #[builder(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) proto: Option<u8>,
+    pub proto: Option<u8>,
     /// An optional unsigned integer (64 bits) representing the internal client identifier in the server. This can be
     /// used to filter client connections in monitoring, correlate with error logs, etc…
     #[builder(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) client_id: Option<u64>,
+    pub client_id: Option<u64>,
     /// If this is set, then the client should try to authenticate upon connect.
     #[builder(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) auth_required: Option<bool>,
+    pub auth_required: Option<bool>,
     /// If this is set, then the client must perform the TLS/1.2 handshake. Note, this used to be ssl_required and has
     /// been updated along with the protocol from SSL to TLS.
     #[builder(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) tls_required: Option<bool>,
+    pub tls_required: Option<bool>,
     /// If this is set, the client must provide a valid certificate during the TLS handshake.
     #[builder(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) tls_verify: Option<bool>,
+    pub tls_verify: Option<bool>,
     /// An optional list of server urls that a client can connect to.
     #[builder(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub(crate) connect_urls: Option<Vec<String>>,
+    pub connect_urls: Option<Vec<String>>,
+    /// If this is set, the server is in lame duck mode: it will soon shut down and clients should reconnect
+    /// to another server of the cluster.
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ldm: Option<bool>,
+    /// If this is set, the server supports headers (HPUB/HMSG).
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<bool>,
+    /// If this is set, the client must sign it with its NKey and send the signature in the `sig` field of CONNECT.
+    #[builder(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
 }
 
 impl ServerInfo {
     pub fn builder() -> ServerInfoBuilder {
         ServerInfoBuilder::default()
     }
+
+    /// Protocol level advertised by the server, servers that predate the `proto` field are considered as level 0
+    pub fn protocol_level(&self) -> u8 {
+        self.proto.unwrap_or(0)
+    }
+
+    /// Indicates if the server is able to honor the `echo` setting of the CONNECT command
+    pub fn supports_echo(&self) -> bool {
+        self.protocol_level() >= 1
+    }
+
+    /// Indicates if the server can send INFO messages asynchronously to clients supporting it
+    pub fn supports_dynamic_info(&self) -> bool {
+        self.protocol_level() >= 1
+    }
+
+    /// Indicates if the server supports headers, and by extension no responders notifications
+    pub fn supports_headers(&self) -> bool {
+        self.headers == Some(true)
+    }
+}
+
+impl fmt::Display for ServerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "INFO server_id={} version={} host={}:{} max_payload={} proto={}",
+            self.server_id,
+            self.version,
+            self.host,
+            self.port,
+            self.max_payload,
+            self.protocol_level()
+        )?;
+
+        if let Some(ref connect_urls) = self.connect_urls {
+            write!(f, " connect_urls=[{}]", connect_urls.join(", "))?;
+        }
+
+        if self.ldm == Some(true) {
+            write!(f, " ldm=true")?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Command for ServerInfo {
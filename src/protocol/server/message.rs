@@ -1,13 +1,54 @@
 use bytes::{BufMut, Bytes, BytesMut};
-use protocol::{Command, CommandError};
+use protocol::{
+    commands::{Headers, CORRELATION_ID_HEADER},
+    fmt_payload, Command, CommandError,
+};
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Amount of distinct subjects kept by a `SubjectCache`, past which it's emptied to bound memory usage
+/// (e.g. when receiving replies on many unique inboxes)
+const SUBJECT_CACHE_CAPACITY: usize = 8192;
+
+/// Interns the subjects of the received messages, so clients receiving a lot of messages on the same
+/// subjects share a single allocation per subject instead of allocating a fresh one for every message
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct SubjectCache {
+    subjects: HashSet<Arc<str>>,
+}
+
+impl SubjectCache {
+    /// Returns the shared instance of a subject, storing it if it hasn't been seen yet
+    pub(crate) fn intern(&mut self, subject: &str) -> Arc<str> {
+        if let Some(subject) = self.subjects.get(subject) {
+            return Arc::clone(subject);
+        }
+
+        if self.subjects.len() >= SUBJECT_CACHE_CAPACITY {
+            debug!(target: "nitox", "subject cache is full, emptying it");
+            self.subjects.clear();
+        }
+
+        let subject: Arc<str> = subject.into();
+        self.subjects.insert(Arc::clone(&subject));
+        subject
+    }
+}
 
 /// The MSG protocol message is used to deliver an application message to the client.
-#[derive(Debug, Clone, PartialEq, Builder)]
+///
+/// Messages carrying headers are delivered through the HMSG variant of the command, which is parsed
+/// into the same struct with `headers`, and possibly `status` and `description`, set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Builder)]
 #[builder(build_fn(validate = "Self::validate"))]
 pub struct Message {
-    /// Subject name this message was received on
+    /// Subject name this message was received on, shared with the other messages received on the same subject
     #[builder(setter(into))]
-    pub subject: String,
+    pub subject: Arc<str>,
     /// The unique alphanumeric subscription ID of the subject
     #[builder(setter(into))]
     pub sid: String,
@@ -17,34 +58,52 @@ pub struct Message {
     /// The message payload data
     #[builder(setter(into))]
     pub payload: Bytes,
+    /// Headers of the message, only present when it has been delivered through HMSG
+    #[builder(default)]
+    pub headers: Option<Headers>,
+    /// Status code found on the header version line (`NATS/1.0 <code> <description>`). Status messages are
+    /// sent by the server itself, e.g. `503` for no responders, `408` for request timeouts or `100` for idle heartbeats
+    #[builder(default)]
+    pub status: Option<u16>,
+    /// Description following the status code, if any
+    #[builder(default)]
+    pub description: Option<String>,
+    /// Size of the command on the wire (control line, header block and payload included), only known for
+    /// parsed messages
+    #[builder(default)]
+    #[serde(skip)]
+    pub wire_size: usize,
+    /// Instant at which the message has been read off the socket, only known for messages received through
+    /// an `OpCodec`
+    #[builder(default)]
+    #[serde(skip)]
+    pub received_at: Option<Instant>,
 }
 
 impl Message {
+    /// Command name of messages carrying headers
+    pub const HMSG_CMD_NAME: &'static [u8] = b"HMSG";
+    /// Status of control messages such as idle heartbeats and flow control requests
+    pub const STATUS_CONTROL: u16 = 100;
+    /// Status of JetStream replies when no message is available
+    pub const STATUS_NOT_FOUND: u16 = 404;
+    /// Status of expired requests
+    pub const STATUS_REQUEST_TIMEOUT: u16 = 408;
+    /// Status of conflicting requests, like exceeding the pull limits of a JetStream consumer
+    pub const STATUS_CONFLICT: u16 = 409;
+    /// Status of requests published on a subject nobody listens on
+    pub const STATUS_NO_RESPONDERS: u16 = 503;
+
     pub fn builder() -> MessageBuilder {
         MessageBuilder::default()
     }
-}
 
-impl Command for Message {
-    const CMD_NAME: &'static [u8] = b"MSG";
-
-    fn into_vec(self) -> Result<Bytes, CommandError> {
-        let rt = if let Some(reply_to) = self.reply_to {
-            format!("\t{}", reply_to)
-        } else {
-            "".into()
-        };
-
-        let cmd_str = format!("MSG\t{}\t{}{}\t{}\r\n", self.subject, self.sid, rt, self.payload.len());
-        let mut bytes = BytesMut::with_capacity(cmd_str.len() + self.payload.len() + 2);
-        bytes.put(cmd_str.as_bytes());
-        bytes.put(self.payload);
-        bytes.put("\r\n");
-
-        Ok(bytes.freeze())
+    /// Parses a MSG or HMSG command, taking its subject from the given cache
+    pub(crate) fn try_parse_interned(buf: &[u8], subjects: &mut SubjectCache) -> Result<Self, CommandError> {
+        Self::parse(buf, Some(subjects))
     }
 
-    fn try_parse(buf: &[u8]) -> Result<Self, CommandError> {
+    fn parse(buf: &[u8], subjects: Option<&mut SubjectCache>) -> Result<Self, CommandError> {
         let len = buf.len();
 
         if buf[len - 2..] != [b'\r', b'\n'] {
@@ -56,27 +115,53 @@ impl Command for Message {
                 return Err(CommandError::CommandMalformed);
             }
 
-            let payload: Bytes = buf[payload_start + 2..len - 2].into();
+            let body = &buf[payload_start + 2..len - 2];
 
             let whole_command = ::std::str::from_utf8(&buf[..payload_start])?;
             let mut split = whole_command.split_whitespace();
             let cmd = split.next().ok_or_else(|| CommandError::CommandMalformed)?;
             // Check if we're still on the right command
-            if cmd.as_bytes() != Self::CMD_NAME {
-                return Err(CommandError::CommandMalformed);
-            }
+            let has_headers = match cmd.as_bytes() {
+                Self::CMD_NAME => false,
+                Self::HMSG_CMD_NAME => true,
+                _ => return Err(CommandError::CommandMalformed),
+            };
 
+            // For HMSG, this is the total length of the header block and the payload
             let payload_len: usize = split
                 .next_back()
                 .ok_or_else(|| CommandError::CommandMalformed)?
                 .parse()?;
 
-            if payload.len() != payload_len {
+            if body.len() != payload_len {
+                return Err(CommandError::CommandMalformed);
+            }
+
+            let header_len: usize = if has_headers {
+                split.next_back().ok_or(CommandError::CommandMalformed)?.parse()?
+            } else {
+                0
+            };
+
+            if header_len > payload_len {
                 return Err(CommandError::CommandMalformed);
             }
 
+            let (headers, status, description) = if has_headers {
+                let (headers, status, description) = Headers::from_bytes_with_status(&body[..header_len])?;
+                (Some(headers), status, description)
+            } else {
+                (None, None, None)
+            };
+
+            let payload: Bytes = body[header_len..].into();
+
             // Extract subject
-            let subject: String = split.next().ok_or_else(|| CommandError::CommandMalformed)?.into();
+            let subject = split.next().ok_or_else(|| CommandError::CommandMalformed)?;
+            let subject: Arc<str> = match subjects {
+                Some(subjects) => subjects.intern(subject),
+                None => subject.into(),
+            };
 
             let sid: String = split.next().ok_or_else(|| CommandError::CommandMalformed)?.into();
 
@@ -87,11 +172,133 @@ impl Command for Message {
                 sid,
                 payload,
                 reply_to,
+                headers,
+                status,
+                description,
+                wire_size: len,
+                received_at: None,
             })
         } else {
             Err(CommandError::CommandMalformed)
         }
     }
+
+    /// Indicates if this message is the status-only `503` reply sent by the server when a request
+    /// has been published on a subject nobody is subscribed to
+    pub fn is_no_responders(&self) -> bool {
+        self.payload.is_empty() && self.status == Some(Self::STATUS_NO_RESPONDERS)
+    }
+
+    /// Indicates if this message is a `100 Idle Heartbeat` sent by JetStream to a consumer
+    pub fn is_idle_heartbeat(&self) -> bool {
+        self.status == Some(Self::STATUS_CONTROL)
+            && self
+                .description
+                .as_ref()
+                .map(|d| d.starts_with("Idle Heartbeat"))
+                .unwrap_or(false)
+    }
+
+    /// Indicates if this message is a `408` status telling a request (e.g. a JetStream pull) has expired
+    pub fn is_request_timeout(&self) -> bool {
+        self.status == Some(Self::STATUS_REQUEST_TIMEOUT)
+    }
+
+    /// Indicates if this message is a control message emitted by the server, as opposed to data published by a client
+    pub fn is_status(&self) -> bool {
+        self.status.is_some()
+    }
+
+    /// Returns the `Correlation-Id` header of a request, or of the reply to a request
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.headers
+            .as_ref()
+            .and_then(|headers| headers.get(CORRELATION_ID_HEADER))
+    }
+
+    /// Time elapsed since the message has been read off the socket, if known
+    pub fn age(&self) -> Option<Duration> {
+        self.received_at.map(|received_at| received_at.elapsed())
+    }
+}
+
+impl Command for Message {
+    const CMD_NAME: &'static [u8] = b"MSG";
+
+    fn into_vec(self) -> Result<Bytes, CommandError> {
+        let rt = if let Some(reply_to) = self.reply_to {
+            format!("\t{}", reply_to)
+        } else {
+            "".into()
+        };
+
+        let header_block = match (self.headers, self.status) {
+            (None, None) => None,
+            (headers, status) => Some(
+                headers
+                    .unwrap_or_default()
+                    .to_bytes_with_status(status, self.description.as_deref()),
+            ),
+        };
+        let cmd_str = if let Some(ref header_block) = header_block {
+            format!(
+                "HMSG\t{}\t{}{}\t{}\t{}\r\n",
+                self.subject,
+                self.sid,
+                rt,
+                header_block.len(),
+                header_block.len() + self.payload.len()
+            )
+        } else {
+            format!("MSG\t{}\t{}{}\t{}\r\n", self.subject, self.sid, rt, self.payload.len())
+        };
+
+        let header_len = header_block.as_ref().map(|h| h.len()).unwrap_or(0);
+        let mut bytes = BytesMut::with_capacity(cmd_str.len() + header_len + self.payload.len() + 2);
+        bytes.put(cmd_str.as_bytes());
+        if let Some(header_block) = header_block {
+            bytes.put(header_block);
+        }
+        bytes.put(self.payload);
+        bytes.put("\r\n");
+
+        Ok(bytes.freeze())
+    }
+
+    fn try_parse(buf: &[u8]) -> Result<Self, CommandError> {
+        Self::parse(buf, None)
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let has_headers = self.headers.is_some() || self.status.is_some();
+        write!(
+            f,
+            "{} {} {}",
+            if has_headers { "HMSG" } else { "MSG" },
+            self.subject,
+            self.sid
+        )?;
+        if let Some(ref reply_to) = self.reply_to {
+            write!(f, " {}", reply_to)?;
+        }
+
+        write!(f, " {}", self.payload.len())?;
+        if let Some(status) = self.status {
+            write!(f, " {}", status)?;
+            if let Some(ref description) = self.description {
+                write!(f, " {:?}", description)?;
+            }
+        }
+
+        if let Some(ref headers) = self.headers {
+            write!(f, " {}", headers)?;
+        }
+
+        write!(f, " ")?;
+        fmt_payload(f, &self.payload)
+    }
 }
 
 impl MessageBuilder {
@@ -112,10 +319,14 @@ impl MessageBuilder {
 
 #[cfg(test)]
 mod tests {
-    use super::{Message, MessageBuilder};
-    use protocol::Command;
+    use super::{Message, MessageBuilder, SubjectCache};
+    use protocol::{commands::Headers, Command};
+    use std::sync::Arc;
 
     static DEFAULT_MSG: &'static str = "MSG\tFOO\tpouet\t4\r\ntoto\r\n";
+    static DEFAULT_HMSG: &'static str = "HMSG\tFOO\tpouet\t22\t26\r\nNATS/1.0\r\nFoo: bar\r\n\r\ntoto\r\n";
+    static IDLE_HEARTBEAT_HMSG: &'static str = "HMSG\tFOO\tpouet\t31\t31\r\nNATS/1.0 100 Idle Heartbeat\r\n\r\n\r\n";
+    static NO_RESPONDERS_HMSG: &'static str = "HMSG\t_INBOX\tpouet\t16\t16\r\nNATS/1.0 503\r\n\r\n\r\n";
 
     #[test]
     fn it_parses() {
@@ -123,9 +334,10 @@ mod tests {
         assert!(parse_res.is_ok());
         let cmd = parse_res.unwrap();
         assert!(cmd.reply_to.is_none());
-        assert_eq!(&cmd.subject, "FOO");
+        assert_eq!(&*cmd.subject, "FOO");
         assert_eq!(&cmd.sid, "pouet");
         assert_eq!(cmd.payload, "toto");
+        assert_eq!(cmd.wire_size, DEFAULT_MSG.len());
     }
 
     #[test]
@@ -143,4 +355,75 @@ mod tests {
 
         assert_eq!(DEFAULT_MSG, cmd_bytes);
     }
+
+    #[test]
+    fn it_parses_headers() {
+        let parse_res = Message::try_parse(DEFAULT_HMSG.as_bytes());
+        assert!(parse_res.is_ok());
+        let cmd = parse_res.unwrap();
+        assert_eq!(&*cmd.subject, "FOO");
+        assert_eq!(&cmd.sid, "pouet");
+        assert_eq!(cmd.payload, "toto");
+        assert_eq!(cmd.headers.unwrap().get("Foo"), Some("bar"));
+    }
+
+    #[test]
+    fn it_stringifies_headers() {
+        let mut headers = Headers::new();
+        headers.insert("Foo", "bar");
+
+        let cmd = MessageBuilder::default()
+            .subject("FOO")
+            .sid("pouet")
+            .payload("toto")
+            .headers(Some(headers))
+            .build()
+            .unwrap();
+
+        let cmd_bytes_res = cmd.into_vec();
+        assert!(cmd_bytes_res.is_ok());
+        let cmd_bytes = cmd_bytes_res.unwrap();
+
+        assert_eq!(DEFAULT_HMSG, cmd_bytes);
+    }
+
+    #[test]
+    fn it_detects_no_responders() {
+        let cmd = Message::try_parse(NO_RESPONDERS_HMSG.as_bytes()).unwrap();
+        assert!(cmd.is_no_responders());
+        assert!(!Message::try_parse(DEFAULT_HMSG.as_bytes()).unwrap().is_no_responders());
+    }
+
+    #[test]
+    fn it_parses_status() {
+        let cmd = Message::try_parse(IDLE_HEARTBEAT_HMSG.as_bytes()).unwrap();
+        assert_eq!(cmd.status, Some(100));
+        assert_eq!(cmd.description, Some("Idle Heartbeat".into()));
+        assert!(cmd.is_idle_heartbeat());
+        assert!(cmd.is_status());
+        assert!(!cmd.is_request_timeout());
+        assert!(!Message::try_parse(DEFAULT_MSG.as_bytes()).unwrap().is_status());
+    }
+
+    #[test]
+    fn it_stringifies_status() {
+        let cmd = MessageBuilder::default()
+            .subject("FOO")
+            .sid("pouet")
+            .payload("")
+            .status(Some(100))
+            .description(Some("Idle Heartbeat".into()))
+            .build()
+            .unwrap();
+
+        assert_eq!(IDLE_HEARTBEAT_HMSG, cmd.into_vec().unwrap());
+    }
+
+    #[test]
+    fn it_interns_subjects() {
+        let mut subjects = SubjectCache::default();
+        let first = Message::try_parse_interned(DEFAULT_MSG.as_bytes(), &mut subjects).unwrap();
+        let second = Message::try_parse_interned(DEFAULT_HMSG.as_bytes(), &mut subjects).unwrap();
+        assert!(Arc::ptr_eq(&first.subject, &second.subject));
+    }
 }
@@ -4,7 +4,7 @@ use std::fmt;
 /// connection error to the client. Most of these errors result in the server closing the connection.
 ///
 /// Handling of these errors usually has to be done asynchronously.
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct ServerError(String);
 impl From<String> for ServerError {
     fn from(s: String) -> Self {
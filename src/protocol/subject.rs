@@ -0,0 +1,225 @@
+use protocol::{check_command_arg, ArgumentValidationError};
+use std::{fmt, ops::Deref, str::FromStr};
+
+/// Separator of the tokens of a subject
+const TOKEN_SEPARATOR: char = '.';
+/// Wildcard matching exactly one token
+const SINGLE_WILDCARD: &str = "*";
+/// Wildcard matching one or more tokens, only valid as the last token of a pattern
+const FULL_WILDCARD: &str = ">";
+
+/// A subject composed token by token, so hierarchies don't have to be formatted by hand:
+///
+/// ```
+/// # use nitox::Subject;
+/// let subject = Subject::new("habitat").sub("svc").sub("redis.default");
+/// assert_eq!(&*subject, "habitat.svc.redis.default");
+/// assert_eq!(&*Subject::new("habitat").sub("svc").full_wildcard(), "habitat.svc.>");
+/// ```
+///
+/// Tokens aren't checked while composing, `validate()` or parsing the subject from a `&str` does. A `Subject`
+/// derefs to `str` and converts into `String`, so it can be given wherever a subject is expected
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Subject(String);
+
+impl Subject {
+    /// Starts a subject with its first token
+    pub fn new<S: Into<String>>(token: S) -> Self {
+        Subject(token.into())
+    }
+
+    /// Appends a token to the subject
+    #[allow(clippy::should_implement_trait)]
+    pub fn sub<S: AsRef<str>>(mut self, token: S) -> Self {
+        self.0.push(TOKEN_SEPARATOR);
+        self.0.push_str(token.as_ref());
+        self
+    }
+
+    /// Appends the `*` wildcard, matching exactly one token
+    pub fn single_wildcard(self) -> Self {
+        self.sub(SINGLE_WILDCARD)
+    }
+
+    /// Appends the `>` wildcard, matching one or more tokens. Nothing can be appended after it
+    pub fn full_wildcard(self) -> Self {
+        self.sub(FULL_WILDCARD)
+    }
+
+    /// Returns the tokens of the subject
+    pub fn tokens(&self) -> impl Iterator<Item = &str> {
+        self.0.split(TOKEN_SEPARATOR)
+    }
+
+    /// Indicates if the subject is a pattern, matching other subjects through wildcards
+    pub fn is_wildcard(&self) -> bool {
+        self.tokens()
+            .any(|token| token == SINGLE_WILDCARD || token == FULL_WILDCARD)
+    }
+
+    /// Checks if the given subject matches this one, see `subject_matches()`
+    pub fn matches(&self, subject: &str) -> bool {
+        subject_matches(&self.0, subject)
+    }
+
+    /// Checks that the subject can be sent to the server: no empty tokens, no spaces or tabs, and `>` only as
+    /// the last token
+    pub fn validate(&self) -> Result<(), ArgumentValidationError> {
+        check_command_arg(&self.0)?;
+        let mut tokens = self.tokens().peekable();
+        while let Some(token) = tokens.next() {
+            if token.is_empty() {
+                return Err(ArgumentValidationError::EmptySubjectToken);
+            } else if token == FULL_WILDCARD && tokens.peek().is_some() {
+                return Err(ArgumentValidationError::MisplacedFullWildcard);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the subject as a string slice
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Subject {
+    type Err = ArgumentValidationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let subject = Subject(s.into());
+        subject.validate()?;
+        Ok(subject)
+    }
+}
+
+impl Deref for Subject {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Subject {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<Subject> for String {
+    fn from(subject: Subject) -> Self {
+        subject.0
+    }
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Checks if a subject matches a subscription pattern, following the same rules as the server: `*` matches
+/// exactly one token and `>`, as the last token, matches one or more tokens.
+///
+/// Patterns and subjects with empty tokens (`foo..bar`, `.foo`) never match, nor do patterns where `>` is not
+/// the last token
+pub fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut pattern_tokens = pattern.split(TOKEN_SEPARATOR).peekable();
+    let mut subject_tokens = subject.split(TOKEN_SEPARATOR);
+
+    while let Some(pattern_token) = pattern_tokens.next() {
+        let subject_token = match subject_tokens.next() {
+            Some(token) if !token.is_empty() => token,
+            _ => return false,
+        };
+
+        match pattern_token {
+            "" => return false,
+            FULL_WILDCARD => {
+                // The rest of the subject is matched, as long as it doesn't have empty tokens
+                return pattern_tokens.peek().is_none() && subject_tokens.all(|token| !token.is_empty());
+            }
+            SINGLE_WILDCARD => {}
+            literal if literal != subject_token => return false,
+            _ => {}
+        }
+    }
+
+    subject_tokens.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{subject_matches, Subject};
+    use protocol::ArgumentValidationError;
+
+    #[test]
+    fn it_matches_literals() {
+        assert!(subject_matches("foo.bar", "foo.bar"));
+        assert!(!subject_matches("foo.bar", "foo.baz"));
+        assert!(!subject_matches("foo.bar", "foo"));
+        assert!(!subject_matches("foo", "foo.bar"));
+    }
+
+    #[test]
+    fn it_matches_single_wildcards() {
+        assert!(subject_matches("foo.*", "foo.bar"));
+        assert!(subject_matches("*.bar", "foo.bar"));
+        assert!(subject_matches("foo.*.baz", "foo.bar.baz"));
+        assert!(!subject_matches("foo.*", "foo"));
+        assert!(!subject_matches("foo.*", "foo.bar.baz"));
+    }
+
+    #[test]
+    fn it_matches_full_wildcards() {
+        assert!(subject_matches(">", "foo"));
+        assert!(subject_matches("foo.>", "foo.bar"));
+        assert!(subject_matches("foo.>", "foo.bar.baz"));
+        assert!(subject_matches("*.>", "foo.bar"));
+        assert!(!subject_matches("foo.>", "foo"));
+        assert!(!subject_matches("foo.>.baz", "foo.bar.baz"));
+    }
+
+    #[test]
+    fn it_rejects_empty_tokens() {
+        assert!(!subject_matches("foo..bar", "foo..bar"));
+        assert!(!subject_matches("foo.*", "foo."));
+        assert!(!subject_matches("foo.>", "foo.bar..baz"));
+        assert!(!subject_matches(".foo", ".foo"));
+    }
+
+    #[test]
+    fn it_composes_subjects() {
+        let subject = Subject::new("habitat").sub("svc").sub("redis");
+        assert_eq!(&*subject, "habitat.svc.redis");
+        assert_eq!(subject.tokens().collect::<Vec<_>>(), vec!["habitat", "svc", "redis"]);
+        assert!(!subject.is_wildcard());
+        assert!(subject.validate().is_ok());
+
+        let pattern = Subject::new("habitat").single_wildcard().full_wildcard();
+        assert_eq!(pattern.to_string(), "habitat.*.>");
+        assert!(pattern.is_wildcard());
+        assert!(pattern.matches("habitat.svc.redis"));
+        assert!(!pattern.matches("habitat.svc"));
+        assert_eq!(String::from(pattern), "habitat.*.>");
+    }
+
+    #[test]
+    fn it_validates_subjects() {
+        assert!("habitat.svc.>".parse::<Subject>().is_ok());
+        assert_eq!(
+            Subject::new("habitat").sub("").validate(),
+            Err(ArgumentValidationError::EmptySubjectToken)
+        );
+        assert_eq!(
+            Subject::new("habitat").full_wildcard().sub("svc").validate(),
+            Err(ArgumentValidationError::MisplacedFullWildcard)
+        );
+        assert_eq!(
+            "habitat svc".parse::<Subject>(),
+            Err(ArgumentValidationError::ContainsSpace)
+        );
+    }
+}
@@ -4,6 +4,7 @@ extern crate env_logger;
 extern crate futures;
 extern crate nitox;
 extern crate parking_lot;
+extern crate serde_json;
 extern crate tokio;
 extern crate tokio_codec;
 extern crate tokio_executor;
@@ -12,10 +13,20 @@ extern crate tokio_tcp;
 use futures::{
     future,
     prelude::*,
+    stream,
     sync::{mpsc, oneshot},
 };
-use nitox::{codec::OpCodec, commands::*, NatsClient, NatsClientOptions, NatsError, Op};
+use nitox::jetstream::*;
+use nitox::{
+    codec::OpCodec, commands::*, DisconnectedPublishPolicy, NatsClient, NatsClientEvent, NatsClientOptions, NatsError,
+    Op, OverflowPolicy, RequestRetryPolicy, RequestStyle, SubjectFilter,
+};
 use parking_lot::RwLock;
+use std::{
+    collections::{HashMap, HashSet},
+    thread,
+    time::{Duration, Instant},
+};
 use tokio_codec::Decoder;
 use tokio_tcp::TcpListener;
 
@@ -25,6 +36,316 @@ macro_rules! elog {
     };
 }
 
+/// Answers the requests made to the JetStream API of the mock server
+fn js_api_response(subject: &str, payload: &[u8]) -> String {
+    let stream_info = |config: String| {
+        format!(
+            r#"{{"config":{},"created":"2018-10-01T00:00:00Z","state":{{"messages":3,"last_seq":3}}}}"#,
+            config
+        )
+    };
+
+    let consumer_info = |stream: &str, name: &str, config: String| {
+        format!(
+            r#"{{"stream_name":"{}","name":"{}","config":{},"num_pending":5}}"#,
+            stream, name, config
+        )
+    };
+
+    // The API of the `hub` domain is the same as the local one
+    let api = subject.split_once(".API.").unwrap().1;
+    match api {
+        "INFO" => r#"{"type":"io.nats.jetstream.api.v1.account_info_response","memory":0,"storage":1024,"streams":1,"consumers":0,"limits":{"max_memory":-1,"max_storage":-1,"max_streams":-1,"max_consumers":-1}}"#.into(),
+        "STREAM.INFO.missing" => {
+            r#"{"error":{"code":404,"err_code":10059,"description":"stream not found"}}"#.into()
+        }
+        "STREAM.LIST" => {
+            // Pages of two streams out of three
+            let req: serde_json::Value = serde_json::from_slice(payload).unwrap();
+            let offset = req["offset"].as_u64().unwrap() as usize;
+            let streams: Vec<_> = ["A", "B", "C"]
+                .iter()
+                .skip(offset)
+                .take(2)
+                .map(|name| stream_info(format!(r#"{{"name":"{}"}}"#, name)))
+                .collect();
+            format!(r#"{{"total":3,"offset":{},"limit":2,"streams":[{}]}}"#, offset, streams.join(","))
+        }
+        _ if api.starts_with("STREAM.CREATE.") || api.starts_with("STREAM.UPDATE.") => {
+            stream_info(String::from_utf8_lossy(payload).into())
+        }
+        _ if api.starts_with("STREAM.INFO.") => {
+            stream_info(format!(r#"{{"name":"{}"}}"#, &api["STREAM.INFO.".len()..]))
+        }
+        _ if api.starts_with("STREAM.DELETE.") || api.starts_with("CONSUMER.DELETE.") => r#"{"success":true}"#.into(),
+        "CONSUMER.CREATE.KV_config" if String::from_utf8_lossy(payload).contains("$KV.config.missing") => {
+            // Nothing is stored for that key, the consumer has nothing to deliver
+            let req: serde_json::Value = serde_json::from_slice(payload).unwrap();
+            format!(
+                r#"{{"stream_name":"KV_config","name":"ephemeral","config":{},"num_pending":0}}"#,
+                req["config"]
+            )
+        }
+        _ if api.starts_with("CONSUMER.DURABLE.CREATE.") || api.starts_with("CONSUMER.CREATE.") => {
+            let req: serde_json::Value = serde_json::from_slice(payload).unwrap();
+            let name = req["config"]["durable_name"].as_str().unwrap_or("ephemeral");
+            consumer_info(req["stream_name"].as_str().unwrap(), name, req["config"].to_string())
+        }
+        _ if api.starts_with("CONSUMER.INFO.") && api.ends_with(".missing") => {
+            r#"{"error":{"code":404,"err_code":10014,"description":"consumer not found"}}"#.into()
+        }
+        _ if api.starts_with("CONSUMER.INFO.") => {
+            let mut tokens = api["CONSUMER.INFO.".len()..].split('.');
+            let (stream, name) = (tokens.next().unwrap(), tokens.next().unwrap());
+            consumer_info(stream, name, format!(r#"{{"durable_name":"{}"}}"#, name))
+        }
+        "CONSUMER.PAUSE.ORDERS.worker" if payload.is_empty() => r#"{"paused":false,"pause_remaining":0}"#.into(),
+        "CONSUMER.PAUSE.ORDERS.worker" => {
+            let req: serde_json::Value = serde_json::from_slice(payload).unwrap();
+            format!(
+                r#"{{"paused":true,"pause_until":{},"pause_remaining":60000000000}}"#,
+                req["pause_until"]
+            )
+        }
+        _ if api.starts_with("CONSUMER.LIST.") => {
+            let stream = &api["CONSUMER.LIST.".len()..];
+            format!(
+                r#"{{"total":1,"offset":0,"limit":256,"consumers":[{}]}}"#,
+                consumer_info(stream, "worker", r#"{"durable_name":"worker"}"#.into())
+            )
+        }
+        "STREAM.MSG.GET.KV_config" if String::from_utf8_lossy(payload).contains(r#""seq""#) => {
+            // The revisions delivered by `js_ordered_messages()`
+            let req: serde_json::Value = serde_json::from_slice(payload).unwrap();
+            match req["seq"].as_u64().unwrap() {
+                1 => r#"{"message":{"subject":"$KV.config.port","seq":1,"data":"ODA4MA==","time":"2018-10-01T00:00:00Z"}}"#.into(),
+                2 => r#"{"message":{"subject":"$KV.config.port","seq":2,"hdrs":"TkFUUy8xLjANCktWLU9wZXJhdGlvbjogREVMDQoNCg==","time":"2018-10-01T00:00:00Z"}}"#.into(),
+                3 => r#"{"message":{"subject":"$KV.config.host","seq":3,"data":"bG9jYWxob3N0","time":"2018-10-01T00:00:00Z"}}"#.into(),
+                _ => r#"{"error":{"code":404,"err_code":10037,"description":"no message found"}}"#.into(),
+            }
+        }
+        "STREAM.MSG.GET.KV_config" => {
+            let req: serde_json::Value = serde_json::from_slice(payload).unwrap();
+            match req["last_by_subj"].as_str().unwrap() {
+                "$KV.config.missing" => {
+                    r#"{"error":{"code":404,"err_code":10037,"description":"no message found"}}"#.into()
+                }
+                // Headers holding `KV-Operation: DEL`
+                "$KV.config.deleted" => r#"{"message":{"subject":"$KV.config.deleted","seq":3,"hdrs":"TkFUUy8xLjANCktWLU9wZXJhdGlvbjogREVMDQoNCg==","time":"2018-10-01T00:00:00Z"}}"#.into(),
+                subject => format!(
+                    r#"{{"message":{{"subject":"{}","seq":1,"data":"dmFsdWU=","time":"2018-10-01T00:00:00Z"}}}}"#,
+                    subject
+                ),
+            }
+        }
+        "STREAM.MSG.GET.ORDERS" => {
+            // The only message is `msg 1` on `orders.new`, published with the id `order-1`
+            let req: serde_json::Value = serde_json::from_slice(payload).unwrap();
+            if req["seq"] == 1 || req["last_by_subj"] == "orders.new" {
+                r#"{"message":{"subject":"orders.new","seq":1,"hdrs":"TkFUUy8xLjANCk5hdHMtTXNnLUlkOiBvcmRlci0xDQoNCg==","data":"bXNnIDE=","time":"2018-10-01T00:00:00Z"}}"#.into()
+            } else {
+                r#"{"error":{"code":404,"err_code":10037,"description":"no message found"}}"#.into()
+            }
+        }
+        "STREAM.MSG.GET.OBJ_files" => {
+            // The object `blob` is made of the chunks delivered by `js_ordered_messages()`, and so is `torn`,
+            // whose size doesn't match them
+            let req: serde_json::Value = serde_json::from_slice(payload).unwrap();
+            match req["last_by_subj"].as_str() {
+                Some("$O.files.M.dG9ybg==") => r#"{"message":{"subject":"$O.files.M.dG9ybg==","seq":5,"data":"eyJuYW1lIjoidG9ybiIsImJ1Y2tldCI6ImZpbGVzIiwibnVpZCI6Ik4xIiwic2l6ZSI6MTIsImNodW5rcyI6M30=","time":"2018-10-01T00:00:00Z"}}"#.into(),
+                Some("$O.files.M.YmxvYg==") => r#"{"message":{"subject":"$O.files.M.YmxvYg==","seq":4,"data":"eyJuYW1lIjoiYmxvYiIsImJ1Y2tldCI6ImZpbGVzIiwibnVpZCI6Ik4xIiwic2l6ZSI6MTAsImNodW5rcyI6M30=","time":"2018-10-01T00:00:00Z"}}"#.into(),
+                _ => r#"{"error":{"code":404,"err_code":10037,"description":"no message found"}}"#.into(),
+            }
+        }
+        "STREAM.PURGE.ORDERS" => {
+            // The stream holds the sequences 1 to 10, 3 of them being on the filtered subject
+            let req: serde_json::Value = serde_json::from_slice(payload).unwrap();
+            let purged = match (req["keep"].as_u64(), req["seq"].as_u64()) {
+                (Some(keep), _) => 10 - keep,
+                (_, Some(seq)) => seq - 1,
+                _ if req["filter"].is_string() => 3,
+                _ => 10,
+            };
+            format!(r#"{{"success":true,"purged":{}}}"#, purged)
+        }
+        _ if api.starts_with("STREAM.PURGE.") => r#"{"success":true,"purged":3}"#.into(),
+        "STREAM.MSG.DELETE.ORDERS" => {
+            let req: serde_json::Value = serde_json::from_slice(payload).unwrap();
+            if req["seq"] == 1 && req["no_erase"] == true {
+                r#"{"success":true}"#.into()
+            } else {
+                r#"{"error":{"code":400,"err_code":10057,"description":"sequence not found"}}"#.into()
+            }
+        }
+        _ => r#"{"error":{"code":400,"err_code":10003,"description":"bad request"}}"#.into(),
+    }
+}
+
+/// Acknowledges the messages published to the streams of the mock server, which store the `js.>` subjects
+/// and the keys of the `config` bucket, whose only revision is `1`. Messages with the id `dup` have been stored
+/// already, and the last sequence of the `JS` stream is `6`
+fn js_publish_response(cmd: &PubCommand) -> String {
+    let header = |name| cmd.headers.as_ref().and_then(|headers| headers.get(name));
+    if header("Nats-Msg-Id") == Some("dup") {
+        return r#"{"stream":"JS","seq":6,"duplicate":true}"#.into();
+    }
+
+    if header("Nats-Expected-Stream").is_some_and(|stream| stream != "JS") {
+        return r#"{"error":{"code":400,"err_code":10060,"description":"expected stream does not match"}}"#.into();
+    }
+
+    if header("Nats-Expected-Last-Sequence").is_some_and(|seq| seq != "6") {
+        return r#"{"error":{"code":400,"err_code":10071,"description":"wrong last sequence: 6"}}"#.into();
+    }
+
+    match (cmd.subject.as_str(), header("Nats-Expected-Last-Subject-Sequence")) {
+        ("js.full", _) => r#"{"error":{"code":503,"err_code":10077,"description":"maximum messages exceeded"}}"#.into(),
+        (_, Some(seq)) if seq != "1" => {
+            r#"{"error":{"code":400,"err_code":10071,"description":"wrong last sequence: 1"}}"#.into()
+        }
+        (subject, _) if subject.starts_with("$KV.config.") => r#"{"stream":"KV_config","seq":2}"#.into(),
+        _ => r#"{"stream":"JS","seq":7}"#.into(),
+    }
+}
+
+/// Delivers the messages pulled from the consumers of the mock server, which hold two messages of 5 bytes and send an
+/// idle heartbeat in between. The `stalled` consumer never answers, and batches of more than 256 messages are refused
+fn js_pull_messages(cmd: &PubCommand, sid: &str) -> Vec<Message> {
+    let req: serde_json::Value = serde_json::from_slice(&cmd.payload).unwrap();
+    let batch = req["batch"].as_u64().unwrap();
+    let inbox = cmd.reply_to.clone().unwrap();
+    let mut tokens = cmd.subject["$JS.API.CONSUMER.MSG.NEXT.".len()..].split('.');
+    let (stream, consumer) = (tokens.next().unwrap(), tokens.next().unwrap());
+    let status = |status: u16, description: &str| {
+        Message::builder()
+            .subject(inbox.as_str())
+            .sid(sid)
+            .payload("")
+            .status(Some(status))
+            .description(Some(description.into()))
+            .build()
+            .unwrap()
+    };
+
+    if consumer == "stalled" {
+        return vec![];
+    }
+    if batch > 256 {
+        return vec![status(409, "Exceeded MaxRequestBatch of 256")];
+    }
+
+    let available = batch.min(2);
+    let delivered = available.min(req["max_bytes"].as_u64().unwrap_or(u64::MAX) / 5);
+    let mut messages = vec![];
+    for seq in 1..=delivered {
+        if seq == 2 {
+            messages.push(status(100, "Idle Heartbeat"));
+        }
+
+        messages.push(
+            Message::builder()
+                .subject("js.orders")
+                .sid(sid)
+                .reply_to(Some(format!(
+                    "$JS.ACK.{}.{}.1.{}.{}.1538352000000000000.{}",
+                    stream,
+                    consumer,
+                    seq,
+                    seq,
+                    2 - seq
+                )))
+                .payload(format!("msg {}", seq))
+                .build()
+                .unwrap(),
+        );
+    }
+
+    if delivered < available {
+        messages.push(status(409, "Message Size Exceeds MaxBytes"));
+    } else if batch > 2 {
+        // No more messages right away, or none before the request expires
+        messages.push(match req["no_wait"].as_bool() {
+            Some(true) => status(404, "No Messages"),
+            _ => status(408, "Request Timeout"),
+        });
+    }
+
+    messages
+}
+
+/// Delivers the messages of the streams matching their filter to the ordered consumers created on them, whatever
+/// their deliver policy. The `config` bucket holds `port` set at `1`, deleted at `2`, and `host` set at `3`, the
+/// consumers watching all its keys from the beginning losing the revision `2` on the way. The `files` object store
+/// holds the chunks of `blob`
+fn js_ordered_messages(payload: &[u8], sids: &HashMap<String, String>) -> Vec<Message> {
+    let req: serde_json::Value = serde_json::from_slice(payload).unwrap();
+    let stream = req["stream_name"].as_str().unwrap();
+    let deliver_subject = match req["config"]["deliver_subject"].as_str() {
+        Some(deliver_subject) => deliver_subject,
+        None => return vec![],
+    };
+    let start = req["config"]["opt_start_seq"].as_u64();
+    let filter = req["config"]["filter_subject"].as_str().unwrap_or(">");
+    let (stored, lost) = match stream {
+        "KV_config" => (
+            vec![
+                ("$KV.config.port", "8080", false),
+                ("$KV.config.port", "", true),
+                ("$KV.config.host", "localhost", false),
+            ],
+            Some(2),
+        ),
+        "OBJ_files" => (
+            vec![
+                ("$O.files.C.N1", "0123", false),
+                ("$O.files.C.N1", "4567", false),
+                ("$O.files.C.N1", "89", false),
+            ],
+            None,
+        ),
+        _ => return vec![],
+    };
+
+    let delivered: Vec<_> = (1..)
+        .zip(stored)
+        .filter(|&(seq, (subject, _, _))| {
+            let matches = match filter.strip_suffix('>') {
+                Some(prefix) => subject.starts_with(prefix),
+                None => subject == filter,
+            };
+            matches && seq >= start.unwrap_or(1)
+        })
+        .collect();
+
+    let mut messages = vec![];
+    for (consumer_seq, &(seq, (subject, data, deleted))) in (1..).zip(&delivered) {
+        if start.is_none() && filter.ends_with('>') && Some(seq) == lost {
+            continue;
+        }
+
+        let mut builder = Message::builder();
+        builder
+            .subject(subject)
+            .sid(sids[deliver_subject].as_str())
+            .reply_to(Some(format!(
+                "$JS.ACK.{}.ephemeral.1.{}.{}.1538352000000000000.{}",
+                stream,
+                seq,
+                consumer_seq,
+                delivered.len() - consumer_seq
+            )))
+            .payload(data);
+        if deleted {
+            let mut headers = Headers::new();
+            headers.insert("KV-Operation", "DEL");
+            builder.headers(Some(headers));
+        }
+        messages.push(builder.build().unwrap());
+    }
+
+    messages
+}
+
 fn create_tcp_mock(
     runtime: &mut tokio::runtime::Runtime,
     port: usize,
@@ -47,6 +368,8 @@ fn create_tcp_mock(
                         .host("127.0.0.1")
                         .port(4222u32)
                         .max_payload(::std::u32::MAX)
+                        .proto(Some(1))
+                        .headers(Some(true))
                         .build()
                         .unwrap(),
                 ))
@@ -58,46 +381,192 @@ fn create_tcp_mock(
                 tokio_executor::spawn(sink.send_all(rx).map(|_| ()).map_err(|_| ()));
 
                 let sid_lock = RwLock::new(String::new());
+                // Deliver subjects of the push consumers, which get their messages whatever the last sid is
+                let deliver_sids = RwLock::new(HashMap::new());
+                let subject_sids = RwLock::new(HashMap::new());
+                // Publishes to the `js.election.*` subjects made so far, the first two not getting any answer
+                let election_attempts = RwLock::new(HashMap::new());
 
                 stream.for_each(move |op| {
                     debug!(target: "nitox", "Got OP from client {:#?}", op);
                     match op {
                         Op::PONG => {
                             debug!(target: "nitox", "Got PONG from client");
-                            if verbose {
-                                let _ = tx.unbounded_send(Op::OK);
-                            }
                         }
                         Op::PING => {
+                            let _ = tx.unbounded_send(Op::PONG);
+                        }
+                        Op::CONNECT(_) => {
                             if verbose {
                                 let _ = tx.unbounded_send(Op::OK);
                             }
-                            let _ = tx.unbounded_send(Op::PONG);
+
+                            // Simulates a cluster topology change happening right after the connection
+                            let _ = tx.unbounded_send(Op::INFO(
+                                ServerInfo::builder()
+                                    .server_id("nitox-nats")
+                                    .version(::std::env::var("CARGO_PKG_VERSION").unwrap())
+                                    .go("lol")
+                                    .host("127.0.0.1")
+                                    .port(4222u32)
+                                    .max_payload(::std::u32::MAX)
+                                    .connect_urls(Some(vec!["127.0.0.1:4223".into()]))
+                                    .build()
+                                    .unwrap(),
+                            ));
                         }
                         Op::SUB(cmd) => {
-                            if verbose {
+                            if verbose && cmd.subject == "forbidden" {
+                                let _ = tx.unbounded_send(Op::ERR(ServerError::from(
+                                    "'Permissions Violation for Subscription to forbidden'".to_string(),
+                                )));
+                                return future::ok(());
+                            } else if verbose {
                                 let _ = tx.unbounded_send(Op::OK);
                             }
 
+                            if cmd.subject.starts_with("push.") {
+                                deliver_sids.write().insert(cmd.subject.clone(), cmd.sid.clone());
+                            }
+                            subject_sids.write().insert(cmd.subject.clone(), cmd.sid.clone());
                             *sid_lock.write() = cmd.sid;
                         }
                         Op::PUB(cmd) => {
                             debug!(target: "nitox", "Got PUB command {:#?}", cmd);
-                            if verbose {
+                            if verbose && cmd.subject == "forbidden" {
+                                let _ = tx.unbounded_send(Op::ERR(ServerError::from(
+                                    "'Permissions Violation for Publish to forbidden'".to_string(),
+                                )));
+                                return future::ok(());
+                            } else if verbose {
                                 let _ = tx.unbounded_send(Op::OK);
                             }
+                            // Acknowledgements are only answered when the client waits for the server to confirm them
+                            if cmd.subject == "silent"
+                                || (cmd.subject.starts_with("$JS.ACK.") && cmd.reply_to.is_none())
+                            {
+                                return future::ok(());
+                            }
+                            // Push consumers resume their deliveries once their flow control request is answered
+                            if cmd.subject.starts_with("$JS.FC.") {
+                                let resumed = Message::builder()
+                                    .subject("push.flow")
+                                    .sid(deliver_sids.read().get("push.flow").unwrap().as_str())
+                                    .reply_to(Some("$JS.ACK.ORDERS.worker.1.2.2.1538352000000000000.0".into()))
+                                    .payload("resumed")
+                                    .build()
+                                    .unwrap();
+                                let _ = tx.unbounded_send(Op::MSG(resumed));
+                                return future::ok(());
+                            }
+                            // Direct gets of the only message of the `ORDERS` stream, see `js_api_response()`
+                            if cmd.subject.starts_with("$JS.API.DIRECT.GET.ORDERS") {
+                                let mut builder = Message::builder();
+                                builder.subject(cmd.reply_to.unwrap()).sid(sid_lock.read().as_str());
+                                let found = cmd.subject.ends_with(".orders.new")
+                                    || serde_json::from_slice::<serde_json::Value>(&cmd.payload)
+                                        .map(|req| req["seq"] == 1)
+                                        .unwrap_or(false);
+                                if found {
+                                    let mut headers = Headers::new();
+                                    headers
+                                        .insert("Nats-Stream", "ORDERS")
+                                        .insert("Nats-Sequence", "1")
+                                        .insert("Nats-Subject", "orders.new")
+                                        .insert("Nats-Time-Stamp", "2018-10-01T00:00:00Z")
+                                        .insert("Nats-Msg-Id", "order-1");
+                                    builder.headers(Some(headers)).payload("msg 1");
+                                } else {
+                                    builder
+                                        .payload("")
+                                        .status(Some(404))
+                                        .description(Some("Message Not Found".into()));
+                                }
+                                let _ = tx.unbounded_send(Op::MSG(builder.build().unwrap()));
+                                return future::ok(());
+                            }
+                            if cmd.subject.starts_with("$JS.API.CONSUMER.MSG.NEXT.") {
+                                // Each fetch has its own inbox
+                                let sid = cmd
+                                    .reply_to
+                                    .as_ref()
+                                    .and_then(|inbox| subject_sids.read().get(inbox).cloned())
+                                    .unwrap_or_else(|| sid_lock.read().clone());
+                                for msg in js_pull_messages(&cmd, &sid) {
+                                    let _ = tx.unbounded_send(Op::MSG(msg));
+                                }
+                                return future::ok(());
+                            }
+                            // Ordered consumers get their messages right after being created
+                            let watched = if cmd.subject.starts_with("$JS.API.CONSUMER.CREATE.") {
+                                js_ordered_messages(&cmd.payload, &subject_sids.read())
+                            } else {
+                                vec![]
+                            };
                             let mut builder = Message::builder();
                             let sub = cmd.subject.clone();
-                            builder.subject(cmd.reply_to.unwrap_or(sub));
+                            builder.subject(cmd.reply_to.clone().unwrap_or(sub));
+                            {
+                                // Responses go to the inbox of the requests when they have been made through one
+                                let inbox_sid = cmd.reply_to.as_ref().and_then(|reply_to| {
+                                    let inbox = reply_to.rsplit_once('.')?.0;
+                                    subject_sids.read().get(&format!("{}.>", inbox)).cloned()
+                                });
+                                let sid = inbox_sid.unwrap_or_else(|| sid_lock.read().clone());
+                                builder.sid(deliver_sids.read().get(&cmd.subject).unwrap_or(&sid).clone());
+                            }
+                            let mut elected = true;
+                            if cmd.subject.starts_with("js.election.") {
+                                let mut attempts = election_attempts.write();
+                                let attempt = attempts.entry(cmd.subject.clone()).or_insert(0);
+                                *attempt += 1;
+                                elected = *attempt > 2;
+                            }
+                            if cmd.subject == "no-responders" || !elected {
+                                builder.payload("");
+                                builder.status(Some(503));
+                            } else if cmd.subject == "echo" || cmd.subject == "answer" {
+                                builder.payload(cmd.payload);
+                                builder.headers(cmd.headers);
+                            } else if cmd.subject.starts_with("push.") && cmd.payload == "flow-control" {
+                                builder.reply_to(Some("$JS.FC.ORDERS.1".into()));
+                                builder.payload("");
+                                builder.status(Some(100));
+                                builder.description(Some("FlowControl Request".into()));
+                            } else if cmd.subject.starts_with("push.") && cmd.payload == "heartbeat" {
+                                builder.payload("");
+                                builder.status(Some(100));
+                                builder.description(Some("Idle Heartbeat".into()));
+                            } else if cmd.subject.starts_with("push.") {
+                                // Delivered by a push consumer, the payload holds the sequences of the message
+                                let seq = String::from_utf8_lossy(&cmd.payload).to_string();
+                                builder.reply_to(Some(format!(
+                                    "$JS.ACK.ORDERS.worker.1.{}.{}.1538352000000000000.0",
+                                    seq, seq
+                                )));
+                                builder.payload(cmd.payload);
+                            } else if cmd.subject.starts_with("$JS.ACK.") {
+                                builder.payload("");
+                            } else if cmd.subject.starts_with("js.")
+                                || cmd.subject.starts_with("$KV.")
+                                || cmd.subject.starts_with("$O.")
                             {
-                                let sid = sid_lock.read();
-                                builder.sid((*sid).clone());
+                                builder.payload(js_publish_response(&cmd));
+                            } else if cmd.subject.starts_with("$JS.API.") || cmd.subject.starts_with("$JS.hub.API.") {
+                                builder.payload(js_api_response(&cmd.subject, &cmd.payload));
+                            } else if cmd.subject == "ask" {
+                                builder.reply_to(Some("answer".into()));
+                                builder.payload("bar");
+                            } else {
+                                builder.payload("bar");
                             }
-                            builder.payload("bar");
 
                             let msg = builder.build().unwrap();
                             debug!(target: "nitox", "Replying with MSG command {:#?}", msg);
                             let _ = tx.unbounded_send(Op::MSG(msg));
+                            for msg in watched {
+                                let _ = tx.unbounded_send(Op::MSG(msg));
+                            }
                         }
                         _ => {
                             if verbose {
@@ -233,7 +702,7 @@ fn can_subscribe_for_1000_messages() {
                     );
                 }
 
-                future::join_all(fut_vec).and_then(|_| stream.for_each(|_| future::ok(())))
+                future::join_all(fut_vec).and_then(|_| stream.collect())
             })
         });
 
@@ -242,169 +711,281 @@ fn can_subscribe_for_1000_messages() {
     let connection_result = rx.wait().expect("Cannot wait for a result");
     let _ = runtime.shutdown_now().wait();
     debug!(target: "nitox", "can_subscribe_for_1000_messages::connection_result {:#?}", connection_result);
-    println!("{:?}", connection_result);
-    match connection_result {
-        Ok(msg) => panic!("We shouldn't get Ok since we reached the end of the stream {:?}", msg),
-        Err(NatsError::SubscriptionReachedMaxMsgs(i)) => {
-            assert_eq!(i, 1000);
-        }
-        Err(e) => panic!("{}", e),
-    }
+    // The stream ends once the budget of the UNSUB command has been delivered
+    assert_eq!(connection_result.unwrap().len(), 1000);
 }
 
 #[test]
-fn can_request() {
+fn can_subscribe_with_max_msgs() {
     elog!();
     let mut runtime = tokio::runtime::Runtime::new().unwrap();
 
-    let tcp_res = create_tcp_mock(&mut runtime, 1339, None);
-    debug!(target: "nitox", "can_request::tcp_result {:#?}", tcp_res);
+    let tcp_res = create_tcp_mock(&mut runtime, 1343, None);
     assert!(tcp_res.is_ok());
 
     let connect_cmd = ConnectCommand::builder().build().unwrap();
     let options = NatsClientOptions::builder()
         .connect_command(connect_cmd)
-        .cluster_uri("127.0.0.1:1339")
+        .cluster_uri("127.0.0.1:1343")
         .build()
         .unwrap();
 
     let fut = NatsClient::from_options(options)
         .and_then(|client| client.connect())
-        .and_then(|client| client.request("foo2".into(), "foo".into()));
+        .and_then(|client| {
+            client
+                .subscribe_with_max_msgs(SubCommand::builder().subject("foo").build().unwrap(), 2)
+                .and_then(move |stream| {
+                    let publishes: Vec<_> = (0..3)
+                        .map(|_| client.publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap()))
+                        .collect();
+
+                    future::join_all(publishes).and_then(|_| stream.collect())
+                })
+        });
 
     let (tx, rx) = oneshot::channel();
     runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
-    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let result = rx.wait().expect("Cannot wait for a result");
     let _ = runtime.shutdown_now().wait();
-    debug!("can_request::connection_result {:#?}", connection_result);
-    assert!(connection_result.is_ok());
-    let msg = connection_result.unwrap();
-    debug!("can_request::msg {:#?}", msg);
-    assert_eq!(msg.payload, "bar");
+    debug!(target: "nitox", "can_subscribe_with_max_msgs::result {:#?}", result);
+    assert_eq!(result.unwrap().len(), 2);
 }
 
-type BoxFutNothing = Box<dyn Future<Item = (), Error = NatsError> + Send + 'static>;
-fn spawn_responder(
-    client: NatsClient,
-    sub_stream: impl Stream<Item = Message, Error = NatsError> + Send + 'static,
-) -> BoxFutNothing {
-    tokio::spawn(
-        sub_stream
-            .for_each(move |msg| {
-                let pub_command = PubCommand::builder()
-                    .subject(msg.reply_to.unwrap())
-                    .payload("bar")
-                    .build()
-                    .unwrap();
+#[test]
+fn can_unsubscribe_through_handle() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
 
-                client.publish(pub_command)
-            }).into_future()
-            .map_err(|_| ()),
-    );
+    let tcp_res = create_tcp_mock(&mut runtime, 1344, None);
+    assert!(tcp_res.is_ok());
 
-    Box::new(future::ok(()))
-}
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1344")
+        .build()
+        .unwrap();
 
-const ROUNDTRIP_COUNT: usize = 1_000_000;
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("foo").sid("pouet").build().unwrap())
+                .and_then(move |subscription| {
+                    assert_eq!(subscription.sid(), "pouet");
+                    assert_eq!(subscription.subject(), "foo");
+                    let publishes: Vec<_> = (0..2)
+                        .map(|_| client.publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap()))
+                        .collect();
+
+                    future::join_all(publishes)
+                        .and_then(move |_| client.request("bar".into(), "baz".into()))
+                        .and_then(move |_| subscription.into_future().map_err(|(e, _)| e))
+                })
+        }).and_then(|(msg, subscription)| {
+            assert!(msg.is_some());
+            // The second message is still buffered, but is discarded
+            subscription.unsubscribe().and_then(move |_| subscription.collect())
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_unsubscribe_through_handle::result {:#?}", result);
+    assert!(result.unwrap().is_empty());
+}
 
 #[test]
-fn can_request_a_lot() {
+fn can_drain_subscriptions() {
     elog!();
     let mut runtime = tokio::runtime::Runtime::new().unwrap();
 
+    let tcp_res = create_tcp_mock(&mut runtime, 1348, None);
+    assert!(tcp_res.is_ok());
+
     let connect_cmd = ConnectCommand::builder().build().unwrap();
     let options = NatsClientOptions::builder()
         .connect_command(connect_cmd)
-        .cluster_uri("127.0.0.1:4222")
+        .cluster_uri("127.0.0.1:1348")
         .build()
         .unwrap();
 
-    let fut_requester = NatsClient::from_options(options.clone())
+    let fut = NatsClient::from_options(options)
         .and_then(|client| client.connect())
         .and_then(|client| {
-            let mut fut_vec = vec![];
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    let publishes: Vec<_> = (0..2)
+                        .map(|_| client.publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap()))
+                        .collect();
 
-            for _ in 0..ROUNDTRIP_COUNT {
-                fut_vec.push(client.request("foo-requests".into(), "foo".into()).map_err(|_| ()));
-            }
+                    future::join_all(publishes)
+                        .and_then(move |_| client.request("bar".into(), "baz".into()))
+                        .and_then(move |_| subscription.into_future().map_err(|(e, _)| e))
+                })
+        }).and_then(|(msg, subscription)| {
+            assert!(msg.is_some());
+            subscription.drain().and_then(move |_| subscription.collect())
+        });
 
-            future::join_all(fut_vec).map_err(|_| NatsError::InnerBrokenChain)
-        }).map(|_| ());
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_drain_subscriptions::result {:#?}", result);
+    assert_eq!(result.unwrap().len(), 1);
+}
 
-    let fut_answerer = NatsClient::from_options(options.clone())
+#[test]
+fn can_reject_duplicate_sids() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1350, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1350")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
         .and_then(|client| client.connect())
         .and_then(|client| {
-            let sub_command = SubCommand::builder().subject("foo-requests").build().unwrap();
+            let sid = client.generate_sid();
+            assert_ne!(sid, client.generate_sid());
+
+            let cmd = SubCommand::builder().subject("foo").sid(sid).build().unwrap();
             client
-                .subscribe(sub_command)
-                .map_err(|_| NatsError::InnerBrokenChain)
-                .and_then(move |sub_stream| spawn_responder(client, sub_stream))
+                .subscribe(cmd.clone())
+                .and_then(move |subscription| client.subscribe(cmd).then(move |res| Ok((subscription, res))))
         });
 
     let (tx, rx) = oneshot::channel();
-    runtime.spawn(fut_requester.then(|r| tx.send(r).map_err(|_| ())));
-    runtime.spawn(fut_answerer.map_err(|_| ()));
-    let connection_result = rx.wait().expect("Cannot wait for a result");
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
     let _ = runtime.shutdown_now().wait();
-    debug!("can_request_a_lot::connection_result {:#?}", connection_result);
-    assert!(connection_result.is_ok());
+    debug!(target: "nitox", "can_reject_duplicate_sids::result {:#?}", result);
+    let (_, duplicate) = result.unwrap();
+    match duplicate {
+        Err(NatsError::DuplicateSid(_)) => {}
+        res => panic!("Duplicate sid has been accepted: {:?}", res),
+    }
 }
 
 #[test]
-fn can_request_a_lot_pedantic() {
+fn can_time_out_idle_subscriptions() {
     elog!();
     let mut runtime = tokio::runtime::Runtime::new().unwrap();
 
-    let connect_cmd = ConnectCommand::builder().pedantic(true).build().unwrap();
+    let tcp_res = create_tcp_mock(&mut runtime, 1351, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
     let options = NatsClientOptions::builder()
         .connect_command(connect_cmd)
-        .cluster_uri("127.0.0.1:4222")
+        .cluster_uri("127.0.0.1:1351")
         .build()
         .unwrap();
 
-    let fut_requester = NatsClient::from_options(options.clone())
+    let fut = NatsClient::from_options(options)
         .and_then(|client| client.connect())
         .and_then(|client| {
-            let mut fut_vec = vec![];
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    let subscription = subscription.idle_timeout(Duration::from_millis(100));
+                    client
+                        .publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap())
+                        .and_then(move |_| subscription.into_future().map_err(|(e, _)| e))
+                })
+        })
+        .and_then(|(msg, subscription)| {
+            assert!(msg.is_some());
+            subscription.into_future().then(|res| match res {
+                Err((NatsError::IdleTimeout(timeout), _)) => Ok(timeout),
+                Err((e, _)) => Err(e),
+                Ok((msg, _)) => panic!("Idle subscription has yielded {:?}", msg),
+            })
+        });
 
-            for _ in 0..ROUNDTRIP_COUNT / 100 {
-                fut_vec.push(client.request("foo-requests".into(), "foo".into()).map_err(|_| ()));
-            }
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_time_out_idle_subscriptions::result {:#?}", result);
+    assert_eq!(result.unwrap(), Duration::from_millis(100));
+}
 
-            future::join_all(fut_vec).map_err(|_| NatsError::InnerBrokenChain)
-        }).map(|_| ());
+#[test]
+fn can_subscribe_typed() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
 
-    let fut_answerer = NatsClient::from_options(options.clone())
+    let tcp_res = create_tcp_mock(&mut runtime, 1352, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1352")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
         .and_then(|client| client.connect())
         .and_then(|client| {
-            let sub_command = SubCommand::builder().subject("foo-requests").build().unwrap();
             client
-                .subscribe(sub_command)
-                .map_err(|_| NatsError::InnerBrokenChain)
-                .and_then(move |sub_stream| spawn_responder(client, sub_stream))
+                .subscribe_typed::<Vec<u32>>(SubCommand::builder().subject("echo").build().unwrap())
+                .and_then(move |subscription| {
+                    let publishes: Vec<_> = vec!["[1, 2]", "not json", "[3]"]
+                        .into_iter()
+                        .map(|payload| {
+                            client.publish(PubCommand::builder().subject("echo").payload(payload).build().unwrap())
+                        })
+                        .collect();
+
+                    future::join_all(publishes).map(move |_| subscription)
+                })
+        })
+        .and_then(|subscription| {
+            subscription
+                .then(|res| Ok::<_, NatsError>(res.map(|(payload, _)| payload)))
+                .take(3)
+                .collect()
         });
 
     let (tx, rx) = oneshot::channel();
-    runtime.spawn(fut_requester.then(|r| tx.send(r).map_err(|_| ())));
-    runtime.spawn(fut_answerer.map_err(|_| ()));
-    let connection_result = rx.wait().expect("Cannot wait for a result");
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
     let _ = runtime.shutdown_now().wait();
-    debug!("can_request_a_lot::connection_result {:#?}", connection_result);
-    assert!(connection_result.is_ok());
+    debug!(target: "nitox", "can_subscribe_typed::result {:#?}", result);
+    let payloads = result.unwrap();
+    assert_eq!(payloads[0].as_ref().unwrap(), &vec![1, 2]);
+    match payloads[1] {
+        Err(NatsError::PayloadDecodeError(_)) => {}
+        ref res => panic!("Invalid payload has been decoded: {:?}", res),
+    }
+    assert_eq!(payloads[2].as_ref().unwrap(), &vec![3]);
 }
 
 #[test]
-fn can_pong_to_ping() {
+fn can_respond() {
     elog!();
     let mut runtime = tokio::runtime::Runtime::new().unwrap();
-    let tcp_res = create_tcp_mock(&mut runtime, 1338, None);
-    debug!(target: "nitox", "can_pong_to_ping::tcp_result {:#?}", tcp_res);
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1353, None);
     assert!(tcp_res.is_ok());
 
     let connect_cmd = ConnectCommand::builder().build().unwrap();
     let options = NatsClientOptions::builder()
         .connect_command(connect_cmd)
-        .cluster_uri("127.0.0.1:1338")
+        .cluster_uri("127.0.0.1:1353")
         .build()
         .unwrap();
 
@@ -412,16 +993,3657 @@ fn can_pong_to_ping() {
         .and_then(|client| client.connect())
         .and_then(|client| {
             client
-                .skip_while(|op| future::ok(*op != Op::PING))
-                .into_future()
-                .map(|(op, _)| op.unwrap())
-                .map_err(|(e, _)| e)
+                .subscribe(SubCommand::builder().subject("ask").build().unwrap())
+                .and_then(move |subscription| {
+                    client
+                        .publish(PubCommand::builder().subject("ask").payload("foo").build().unwrap())
+                        .and_then(move |_| subscription.into_future().map_err(|(e, _)| e))
+                        .and_then(move |(msg, subscription)| {
+                            // The mock server delivers the response on the same subscription
+                            client
+                                .respond(&msg.unwrap(), "baz".into())
+                                .and_then(move |_| subscription.into_future().map_err(|(e, _)| e))
+                                .and_then(move |(response, _)| {
+                                    let response = response.unwrap();
+                                    client
+                                        .respond(&response, "baz".into())
+                                        .then(move |res| Ok((response, res)))
+                                })
+                        })
+                })
         });
 
     let (tx, rx) = oneshot::channel();
     runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
-    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let result = rx.wait().expect("Cannot wait for a result");
     let _ = runtime.shutdown_now().wait();
-    debug!(target: "nitox", "can_pong_to_ping::connection_result {:#?}", connection_result);
-    assert!(connection_result.is_ok());
+    debug!(target: "nitox", "can_respond::result {:#?}", result);
+    let (response, no_reply) = result.unwrap();
+    assert_eq!(&*response.subject, "answer");
+    match no_reply {
+        Err(NatsError::NoReplySubject(subject)) => assert_eq!(subject, "answer"),
+        res => panic!("Responded to a message without reply subject: {:?}", res),
+    }
+}
+
+#[test]
+fn can_unsubscribe_on_drop() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1354, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1354")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let cmd = SubCommand::builder().subject("foo").sid("dropped").build().unwrap();
+            client.subscribe(cmd.clone()).and_then(move |subscription| {
+                drop(subscription);
+                // The sid has been released along with the subscription
+                client.subscribe(cmd)
+            })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_unsubscribe_on_drop::result {:#?}", result);
+    assert_eq!(result.unwrap().sid(), "dropped");
+}
+
+#[test]
+fn can_pause_subscriptions() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1355, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1355")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    subscription.pause();
+                    client
+                        .publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap())
+                        .and_then(move |_| client.request("bar".into(), "baz".into()))
+                        .map(move |_| subscription)
+                })
+        })
+        .and_then(|mut subscription| {
+            // The message has been received, but isn't yielded while the subscription is paused
+            assert!(subscription.is_paused());
+            assert!(subscription.poll().unwrap().is_not_ready());
+            assert_eq!(subscription.stats().pending_msgs(), 1);
+
+            subscription.resume();
+            subscription.into_future().map_err(|(e, _)| e)
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_pause_subscriptions::result {:#?}", result);
+    let (msg, _) = result.unwrap();
+    assert_eq!(msg.unwrap().payload, "bar");
+}
+
+#[test]
+fn can_fork_subscriptions() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1358, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1358")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    let fork = subscription.fork();
+                    let publishes: Vec<_> = (0..2)
+                        .map(|_| client.publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap()))
+                        .collect();
+
+                    future::join_all(publishes)
+                        .and_then(move |_| client.request("bar".into(), "baz".into()))
+                        .and_then(move |_| subscription.drain().map(move |_| (subscription, fork)))
+                })
+        })
+        .and_then(|(subscription, fork)| subscription.collect().join(fork.collect()));
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_fork_subscriptions::result {:#?}", result);
+    let (msgs, forked_msgs) = result.unwrap();
+    assert_eq!(msgs.len(), 2);
+    assert_eq!(forked_msgs, msgs);
+}
+
+#[test]
+fn can_filter_subscriptions() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1359, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1359")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("foo.>").build().unwrap())
+                .and_then(move |subscription| {
+                    subscription.set_filter(Some(SubjectFilter::prefixes(vec!["foo.keep."])));
+                    let publishes: Vec<_> = vec!["foo.keep.1", "foo.drop", "foo.keep.2"]
+                        .into_iter()
+                        .map(|subject| {
+                            client.publish(PubCommand::builder().subject(subject).payload("bar").build().unwrap())
+                        })
+                        .collect();
+
+                    future::join_all(publishes)
+                        .and_then(move |_| client.request("bar".into(), "baz".into()))
+                        .and_then(move |_| subscription.drain().map(move |_| subscription))
+                })
+        })
+        .and_then(|subscription| {
+            let stats = subscription.stats();
+            subscription.collect().map(move |msgs| (msgs, stats))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_filter_subscriptions::result {:#?}", result);
+    let (msgs, stats) = result.unwrap();
+    let subjects: Vec<_> = msgs.iter().map(|msg| msg.subject.to_string()).collect();
+    assert_eq!(subjects, vec!["foo.keep.1", "foo.keep.2"]);
+    assert_eq!(stats.filtered(), 1);
+}
+
+#[test]
+fn can_route_wildcard_subscriptions() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1360, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1360")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client.subscribe_router("foo.>".into()).and_then(move |router| {
+                let single = router.route("foo.a");
+                let wildcard = router.route("foo.*");
+                // Messages are dispatched in order, so `foo.c.d` has been discarded once the others are routed
+                let publishes: Vec<_> = vec!["foo.c.d", "foo.a", "foo.b"]
+                    .into_iter()
+                    .map(|subject| {
+                        client.publish(PubCommand::builder().subject(subject).payload("bar").build().unwrap())
+                    })
+                    .collect();
+
+                future::join_all(publishes)
+                    .and_then(move |_| single.take(1).collect().join(wildcard.take(2).collect()))
+                    .map(move |routed| (routed, router))
+            })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_route_wildcard_subscriptions::result {:#?}", result);
+    let ((single, wildcard), router) = result.unwrap();
+    assert_eq!(&*single[0].subject, "foo.a");
+    let subjects: Vec<_> = wildcard.iter().map(|msg| msg.subject.to_string()).collect();
+    assert_eq!(subjects, vec!["foo.a", "foo.b"]);
+    assert_eq!(router.unrouted(), 1);
+}
+
+#[test]
+fn can_set_max_msgs() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1361, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1361")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    let publish = client.publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap());
+                    publish
+                        .and_then(move |_| subscription.into_future().map_err(|(e, _)| e))
+                        .and_then(move |(msg, subscription)| {
+                            assert!(msg.is_some());
+                            // The message already received counts
+                            subscription.set_max_msgs(3).map(move |_| (client, subscription))
+                        })
+                })
+        })
+        .and_then(|(client, subscription)| {
+            let publishes: Vec<_> = (0..4)
+                .map(|_| client.publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap()))
+                .collect();
+
+            future::join_all(publishes).and_then(move |_| subscription.collect())
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_set_max_msgs::result {:#?}", result);
+    assert_eq!(result.unwrap().len(), 2);
+}
+
+#[test]
+fn can_unsubscribe_after_max_msgs() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1362, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1362")
+        .build()
+        .unwrap();
+
+    let sub_cmd = SubCommand::builder().subject("foo").build().unwrap();
+    let unsub_cmd = UnsubCommand::builder()
+        .sid(sub_cmd.sid.clone())
+        .max_msgs(Some(2))
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| {
+            client.subscribe(sub_cmd).and_then(move |subscription| {
+                client.unsubscribe(unsub_cmd).and_then(move |_| {
+                    let publishes: Vec<_> = (0..3)
+                        .map(|_| client.publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap()))
+                        .collect();
+
+                    future::join_all(publishes).and_then(move |_| subscription.collect())
+                })
+            })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_unsubscribe_after_max_msgs::result {:#?}", result);
+    assert_eq!(result.unwrap().len(), 2);
+}
+
+#[test]
+fn can_queue_subscribe() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1345, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1345")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let invalid = client.queue_subscribe("foo".into(), "bad group".into()).wait();
+            assert!(invalid.is_err());
+
+            client
+                .queue_subscribe("foo".into(), "workers".into())
+                .and_then(move |subscription| {
+                    client
+                        .publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap())
+                        .and_then(move |_| subscription.into_future().map_err(|(e, _)| e))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_queue_subscribe::result {:#?}", result);
+    let (msg, _) = result.unwrap();
+    assert_eq!(msg.unwrap().payload, "bar");
+}
+
+#[test]
+fn can_subscribe_with_handler() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1349, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1349")
+        .build()
+        .unwrap();
+
+    let (handled_tx, handled_rx) = mpsc::unbounded();
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| {
+            client
+                .subscribe_with_handler(SubCommand::builder().subject("foo").build().unwrap(), 2, move |msg| {
+                    handled_tx.unbounded_send(msg).map_err(|_| NatsError::InnerBrokenChain)
+                })
+                .and_then(move |_| {
+                    let publishes: Vec<_> = (0..3)
+                        .map(|_| client.publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap()))
+                        .collect();
+
+                    future::join_all(publishes)
+                })
+                .and_then(|_| handled_rx.take(3).collect().map_err(|_| NatsError::InnerBrokenChain))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_subscribe_with_handler::result {:#?}", result);
+    let msgs = result.unwrap();
+    assert_eq!(msgs.len(), 3);
+    assert!(msgs.iter().all(|msg| msg.payload == "bar"));
+}
+
+#[test]
+fn can_bound_subscription_buffers() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1346, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1346")
+        .pending_msgs_limit(2)
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    let publishes: Vec<_> = (0..3)
+                        .map(|_| client.publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap()))
+                        .collect();
+
+                    // The mock server replies in order, so once the request is answered all the messages have been
+                    // dispatched to the subscription
+                    future::join_all(publishes)
+                        .and_then(move |_| client.request("bar".into(), "baz".into()))
+                        .and_then(move |_| subscription.drain().map(move |_| subscription))
+                })
+        }).and_then(|subscription| {
+            let stats = subscription.stats();
+            assert_eq!(stats.pending_msgs(), 2);
+            assert_eq!(stats.dropped(), 1);
+            subscription.collect().map(move |msgs| (msgs, stats))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_bound_subscription_buffers::result {:#?}", result);
+    let (msgs, stats) = result.unwrap();
+    assert_eq!(msgs.len(), 2);
+    assert_eq!(stats.delivered_msgs(), 2);
+    assert_eq!(stats.delivered_bytes(), 6);
+    assert_eq!(stats.pending_msgs(), 0);
+    assert_eq!(stats.pending_bytes(), 0);
+}
+
+#[test]
+fn can_drop_oldest_messages() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1356, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1356")
+        .pending_msgs_limit(2)
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe_with_overflow_policy(
+                    SubCommand::builder().subject("echo").build().unwrap(),
+                    OverflowPolicy::DropOldest,
+                )
+                .and_then(move |subscription| {
+                    let publishes: Vec<_> = vec!["1", "2", "3"]
+                        .into_iter()
+                        .map(|payload| {
+                            client.publish(PubCommand::builder().subject("echo").payload(payload).build().unwrap())
+                        })
+                        .collect();
+
+                    future::join_all(publishes)
+                        .and_then(move |_| client.request("bar".into(), "baz".into()))
+                        .and_then(move |_| subscription.drain().map(move |_| subscription))
+                })
+        })
+        .and_then(|subscription| {
+            let stats = subscription.stats();
+            subscription.collect().map(move |msgs| (msgs, stats))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_drop_oldest_messages::result {:#?}", result);
+    let (msgs, stats) = result.unwrap();
+    let payloads: Vec<_> = msgs.into_iter().map(|msg| msg.payload).collect();
+    assert_eq!(payloads, vec!["2", "3"]);
+    assert_eq!(stats.dropped(), 1);
+}
+
+#[test]
+fn can_apply_backpressure() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1357, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1357")
+        .pending_msgs_limit(1)
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe_with_overflow_policy(
+                    SubCommand::builder().subject("echo").build().unwrap(),
+                    OverflowPolicy::Backpressure,
+                )
+                .and_then(move |subscription| {
+                    let publishes: Vec<_> = vec!["1", "2", "3"]
+                        .into_iter()
+                        .map(|payload| {
+                            client.publish(PubCommand::builder().subject("echo").payload(payload).build().unwrap())
+                        })
+                        .collect();
+
+                    future::join_all(publishes).map(move |_| subscription)
+                })
+        })
+        .and_then(|subscription| {
+            let stats = subscription.stats();
+            let inner_stats = stats.clone();
+            subscription
+                .inspect(move |_| assert!(inner_stats.pending_msgs() <= 1))
+                .take(3)
+                .collect()
+                .map(move |msgs| (msgs, stats))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_apply_backpressure::result {:#?}", result);
+    let (msgs, stats) = result.unwrap();
+    let payloads: Vec<_> = msgs.into_iter().map(|msg| msg.payload).collect();
+    assert_eq!(payloads, vec!["1", "2", "3"]);
+    assert_eq!(stats.dropped(), 0);
+}
+
+#[test]
+fn can_report_slow_consumers() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1347, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1347")
+        .pending_msgs_limit(1)
+        .slow_consumer_errors(true)
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let slow_consumers = client
+                .events()
+                .filter_map(|event| match event {
+                    NatsClientEvent::SlowConsumer { dropped, .. } => Some(dropped),
+                    _ => None,
+                }).into_future()
+                .map(|(dropped, _)| dropped)
+                .map_err(|(e, _)| e);
+
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    let publishes: Vec<_> = (0..3)
+                        .map(|_| client.publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap()))
+                        .collect();
+
+                    future::join_all(publishes)
+                        .and_then(move |_| client.request("bar".into(), "baz".into()))
+                        .and_then(move |_| subscription.drain().map(move |_| subscription))
+                }).and_then(|subscription| subscription.then(Ok::<_, NatsError>).collect())
+                .join(slow_consumers)
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_report_slow_consumers::result {:#?}", result);
+    let (items, dropped) = result.unwrap();
+    assert_eq!(dropped, Some(1));
+    assert_eq!(items.len(), 2);
+    assert!(items[0].is_ok());
+    match items[1] {
+        Err(NatsError::SlowConsumer(1)) => {}
+        ref item => panic!("Expected a SlowConsumer error, got {:?}", item),
+    }
+}
+
+#[test]
+fn can_request() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1339, None);
+    debug!(target: "nitox", "can_request::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1339")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| client.request("foo2".into(), "foo".into()));
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!("can_request::connection_result {:#?}", connection_result);
+    assert!(connection_result.is_ok());
+    let msg = connection_result.unwrap();
+    debug!("can_request::msg {:#?}", msg);
+    assert_eq!(msg.payload, "bar");
+}
+
+#[test]
+fn can_fail_request_without_responders() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1340, None);
+    debug!(target: "nitox", "can_fail_request_without_responders::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder()
+        .headers(Some(true))
+        .no_responders(Some(true))
+        .build()
+        .unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1340")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            assert!(client.headers_enabled());
+            client.request("no-responders".into(), "foo".into())
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(
+        "can_fail_request_without_responders::connection_result {:#?}",
+        connection_result
+    );
+    match connection_result {
+        Err(NatsError::NoResponders) => {}
+        r => panic!("Expected NoResponders, got {:?}", r),
+    }
+}
+
+#[test]
+fn can_receive_info_updates() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1341, None);
+    debug!(target: "nitox", "can_receive_info_updates::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1341")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options).and_then(|client| {
+        let events = client.events();
+        client.connect().and_then(|client| {
+            events
+                .skip_while(|event| match event {
+                    NatsClientEvent::ServerInfoUpdated(info) => future::ok(info.connect_urls.is_none()),
+                    _ => future::ok(true),
+                }).into_future()
+                .map(move |(event, _)| (client, event.unwrap()))
+                .map_err(|(e, _)| e)
+        })
+    });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!("can_receive_info_updates::connection_result {:#?}", connection_result);
+    assert!(connection_result.is_ok());
+    let (client, event) = connection_result.unwrap();
+    let expected_urls = Some(vec!["127.0.0.1:4223".to_string()]);
+    match event {
+        NatsClientEvent::ServerInfoUpdated(info) => assert_eq!(info.connect_urls, expected_urls),
+        e => panic!("Unexpected event {:?}", e),
+    }
+    assert_eq!(client.server_info().unwrap().connect_urls, expected_urls);
+}
+
+#[test]
+fn can_publish_large_payloads() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1342, None);
+    debug!(target: "nitox", "can_publish_large_payloads::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1342")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("large").build().unwrap())
+                .and_then(move |stream| {
+                    let payload = vec![b'a'; 2 * 1024 * 1024];
+                    client
+                        .publish(PubCommand::builder().subject("large").payload(payload).build().unwrap())
+                        .and_then(move |_| {
+                            client
+                                .publish(PubCommand::builder().subject("large").payload("small").build().unwrap())
+                                .map(move |_| client)
+                        }).and_then(|client| {
+                            stream
+                                .take(2)
+                                .collect()
+                                .map(move |msgs| (client, msgs))
+                        })
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    assert!(connection_result.is_ok());
+    let (_, msgs) = connection_result.unwrap();
+    assert_eq!(msgs.len(), 2);
+}
+
+type BoxFutNothing = Box<dyn Future<Item = (), Error = NatsError> + Send + 'static>;
+fn spawn_responder(
+    client: NatsClient,
+    sub_stream: impl Stream<Item = Message, Error = NatsError> + Send + 'static,
+) -> BoxFutNothing {
+    tokio::spawn(
+        sub_stream
+            .for_each(move |msg| {
+                let pub_command = PubCommand::builder()
+                    .subject(msg.reply_to.unwrap())
+                    .payload("bar")
+                    .build()
+                    .unwrap();
+
+                client.publish(pub_command)
+            }).into_future()
+            .map_err(|_| ()),
+    );
+
+    Box::new(future::ok(()))
+}
+
+const ROUNDTRIP_COUNT: usize = 1_000_000;
+
+#[test]
+fn can_request_a_lot() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:4222")
+        .build()
+        .unwrap();
+
+    let fut_requester = NatsClient::from_options(options.clone())
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let mut fut_vec = vec![];
+
+            for _ in 0..ROUNDTRIP_COUNT {
+                fut_vec.push(client.request("foo-requests".into(), "foo".into()).map_err(|_| ()));
+            }
+
+            future::join_all(fut_vec).map_err(|_| NatsError::InnerBrokenChain)
+        }).map(|_| ());
+
+    let fut_answerer = NatsClient::from_options(options.clone())
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let sub_command = SubCommand::builder().subject("foo-requests").build().unwrap();
+            client
+                .subscribe(sub_command)
+                .map_err(|_| NatsError::InnerBrokenChain)
+                .and_then(move |sub_stream| spawn_responder(client, sub_stream))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut_requester.then(|r| tx.send(r).map_err(|_| ())));
+    runtime.spawn(fut_answerer.map_err(|_| ()));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!("can_request_a_lot::connection_result {:#?}", connection_result);
+    assert!(connection_result.is_ok());
+}
+
+#[test]
+fn can_request_a_lot_pedantic() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let connect_cmd = ConnectCommand::builder().pedantic(true).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:4222")
+        .build()
+        .unwrap();
+
+    let fut_requester = NatsClient::from_options(options.clone())
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let mut fut_vec = vec![];
+
+            for _ in 0..ROUNDTRIP_COUNT / 100 {
+                fut_vec.push(client.request("foo-requests".into(), "foo".into()).map_err(|_| ()));
+            }
+
+            future::join_all(fut_vec).map_err(|_| NatsError::InnerBrokenChain)
+        }).map(|_| ());
+
+    let fut_answerer = NatsClient::from_options(options.clone())
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let sub_command = SubCommand::builder().subject("foo-requests").build().unwrap();
+            client
+                .subscribe(sub_command)
+                .map_err(|_| NatsError::InnerBrokenChain)
+                .and_then(move |sub_stream| spawn_responder(client, sub_stream))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut_requester.then(|r| tx.send(r).map_err(|_| ())));
+    runtime.spawn(fut_answerer.map_err(|_| ()));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!("can_request_a_lot::connection_result {:#?}", connection_result);
+    assert!(connection_result.is_ok());
+}
+
+#[test]
+fn can_pong_to_ping() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    let tcp_res = create_tcp_mock(&mut runtime, 1338, None);
+    debug!(target: "nitox", "can_pong_to_ping::tcp_result {:#?}", tcp_res);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1338")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .skip_while(|op| future::ok(*op != Op::PING))
+                .into_future()
+                .map(|(op, _)| op.unwrap())
+                .map_err(|(e, _)| e)
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let connection_result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_pong_to_ping::connection_result {:#?}", connection_result);
+    assert!(connection_result.is_ok());
+}
+
+#[test]
+fn can_subscribe_acknowledged() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1363, Some(true));
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().verbose(true).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1363")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("forbidden").build().unwrap())
+                .then(move |rejected| {
+                    client
+                        .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                        .and_then(move |subscription| {
+                            client
+                                .publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap())
+                                .and_then(move |_| subscription.into_future().map_err(|(e, _)| e))
+                                .map(move |(msg, _)| (rejected.map(|_| ()), msg))
+                        })
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_subscribe_acknowledged::result {:#?}", result);
+    let (rejected, msg) = result.unwrap();
+    match rejected {
+        Err(NatsError::ServerError(_)) => {}
+        res => panic!("Subscription rejected by the server has resolved: {:?}", res),
+    }
+    assert_eq!(msg.unwrap().payload, "bar");
+}
+
+#[test]
+fn can_batch_subscriptions() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1364, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1364")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    let batches = subscription.batches(2, Duration::from_millis(100));
+                    let cmd = PubCommand::builder().subject("foo").build().unwrap();
+                    client
+                        .publish(cmd.clone())
+                        .join3(client.publish(cmd.clone()), client.publish(cmd))
+                        .and_then(move |_| batches.take(2).collect())
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_batch_subscriptions::result {:#?}", result);
+    let batches = result.unwrap();
+    assert_eq!(batches.len(), 2);
+    assert_eq!(batches[0].len(), 2);
+    assert_eq!(batches[1].len(), 1);
+}
+
+#[test]
+fn can_forward_subscriptions_to_channels() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1365, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1365")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    let messages = subscription.into_channel();
+                    client
+                        .publish(PubCommand::builder().subject("foo").build().unwrap())
+                        .map(move |_| (client, messages))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    debug!(target: "nitox", "can_forward_subscriptions_to_channels::result {:#?}", result);
+    let (_client, messages) = result.unwrap();
+    // Received from a plain thread, outside of the runtime
+    let msg = ::std::thread::spawn(move || messages.recv_timeout(Duration::from_secs(5)))
+        .join()
+        .unwrap();
+    let _ = runtime.shutdown_now().wait();
+    assert_eq!(msg.unwrap().payload, "bar");
+}
+
+#[test]
+fn can_intercept_messages() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1366, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1366")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client.intercept(|msg| {
+                if &*msg.subject == "drop" {
+                    return None;
+                }
+
+                let mut msg = msg.clone();
+                msg.payload = msg.payload.to_ascii_uppercase().into();
+                Some(msg)
+            });
+
+            client
+                .subscribe(SubCommand::builder().subject(">").build().unwrap())
+                .and_then(move |subscription| {
+                    client
+                        .publish(PubCommand::builder().subject("drop").build().unwrap())
+                        .and_then(move |_| client.publish(PubCommand::builder().subject("foo").build().unwrap()))
+                        .and_then(move |_| subscription.into_future().map_err(|(e, _)| e))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_intercept_messages::result {:#?}", result);
+    let (msg, _) = result.unwrap();
+    let msg = msg.unwrap();
+    assert_eq!(&*msg.subject, "foo");
+    assert_eq!(msg.payload, "BAR");
+}
+
+#[test]
+fn can_set_pending_limits() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1367, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1367")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    // Room for a single 3 bytes payload
+                    subscription.set_pending_limits(None, Some(5));
+                    let publishes: Vec<_> = (0..3)
+                        .map(|_| client.publish(PubCommand::builder().subject("foo").build().unwrap()))
+                        .collect();
+
+                    future::join_all(publishes)
+                        .and_then(move |_| client.request("bar".into(), "baz".into()))
+                        .and_then(move |_| subscription.drain().map(move |_| subscription))
+                })
+        })
+        .and_then(|subscription| {
+            let stats = subscription.stats();
+            subscription.collect().map(move |msgs| (msgs, stats))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_set_pending_limits::result {:#?}", result);
+    let (msgs, stats) = result.unwrap();
+    assert_eq!(msgs.len(), 1);
+    assert_eq!(stats.dropped(), 2);
+}
+
+#[test]
+fn can_publish_with_reply() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1368, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1368")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("inbox").build().unwrap())
+                .and_then(move |subscription| {
+                    client
+                        .publish_with_reply("foo".into(), "inbox".into(), "bar".into())
+                        .and_then(move |_| subscription.into_future().map_err(|(e, _)| e))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_publish_with_reply::result {:#?}", result);
+    let (msg, _) = result.unwrap();
+    // The mock server replies on the reply subject
+    assert_eq!(&*msg.unwrap().subject, "inbox");
+}
+
+#[test]
+fn can_pipeline_publishes() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1369, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1369")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    let mut pipeline = client.pipeline();
+                    for _ in 0..3 {
+                        pipeline
+                            .publish(PubCommand::builder().subject("foo").payload("bar").build().unwrap())
+                            .unwrap();
+                    }
+
+                    assert_eq!(pipeline.len(), 3);
+                    pipeline.flush().and_then(move |_| subscription.take(3).collect())
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_pipeline_publishes::result {:#?}", result);
+    assert_eq!(result.unwrap().len(), 3);
+}
+
+#[test]
+fn can_forward_streams_to_publishers() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1370, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1370")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    let cmds = (0..5).map(|_| PubCommand::builder().subject("foo").payload("bar").build().unwrap());
+                    // Waits for the server every other command
+                    stream::iter_ok(cmds)
+                        .forward(client.publisher().max_in_flight(2))
+                        .and_then(move |_| subscription.take(5).collect())
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_forward_streams_to_publishers::result {:#?}", result);
+    assert_eq!(result.unwrap().len(), 5);
+}
+
+#[test]
+fn can_publish_json() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1371, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1371")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe_typed::<Vec<u32>>(SubCommand::builder().subject("echo").build().unwrap())
+                .and_then(move |subscription| {
+                    client
+                        .publish_json("echo".into(), &vec![1, 2, 3])
+                        .and_then(move |_| subscription.into_future().map_err(|(e, _)| e))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_publish_json::result {:#?}", result);
+    let (payload, _) = result.unwrap().0.unwrap();
+    assert_eq!(payload, vec![1, 2, 3]);
+}
+
+#[test]
+fn can_reject_oversized_payloads() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1372, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1372")
+        .max_payload(4u32)
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            assert_eq!(client.max_payload(), Some(4));
+            client
+                .publish(PubCommand::builder().subject("foo").payload("fooba").build().unwrap())
+                .then(move |res| {
+                    client
+                        .publish(PubCommand::builder().subject("foo").payload("foo").build().unwrap())
+                        .map(move |_| res)
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_reject_oversized_payloads::result {:#?}", result);
+    match result.unwrap() {
+        Err(NatsError::MaxPayloadOverflow(max_payload)) => assert_eq!(max_payload, 4),
+        res => panic!("Oversized payload has been published: {:?}", res),
+    }
+}
+
+#[test]
+fn can_reject_requests_oversized_by_their_headers() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1423, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1423")
+        .max_payload(16u32)
+        .correlation_ids(true)
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let mut headers = Headers::new();
+            headers.insert("Key", "value");
+            client.request("echo".into(), "foo".into()).then(Ok).join3(
+                client
+                    .request_with_headers("echo".into(), headers, "foo".into())
+                    .then(Ok),
+                client
+                    .request_multi("echo".into(), "foo".into(), 1, Duration::from_secs(1))
+                    .map(|_| ())
+                    .then(Ok),
+            )
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_reject_requests_oversized_by_their_headers::result {:#?}", result);
+    let (request, with_headers, multi) = result.unwrap();
+    for result in [request.map(|_| ()), with_headers.map(|_| ()), multi] {
+        match result {
+            Err(NatsError::MaxPayloadOverflow(max_payload)) => assert_eq!(max_payload, 16),
+            r => panic!("Expected MaxPayloadOverflow, got {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn can_publish_acknowledged() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1373, Some(true));
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().verbose(true).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1373")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let rejected = client.publish_ack(PubCommand::builder().subject("forbidden").build().unwrap());
+            let accepted = client.publish_ack(PubCommand::builder().subject("foo").build().unwrap());
+            rejected.then(|rejected| accepted.map(move |_| rejected))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_publish_acknowledged::result {:#?}", result);
+    match result.unwrap() {
+        Err(NatsError::ServerError(_)) => {}
+        res => panic!("PUB rejected by the server has been acknowledged: {:?}", res),
+    }
+}
+
+fn publish_echo_with_headers(port: usize, headers: bool) -> Message {
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, port, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(headers)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri(format!("127.0.0.1:{}", port))
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("echo").build().unwrap())
+                .and_then(move |subscription| {
+                    let cmd = PubCommand::builder()
+                        .subject("echo")
+                        .header("Foo", "bar")
+                        .payload("toto")
+                        .build()
+                        .unwrap();
+                    client
+                        .publish(cmd)
+                        .and_then(move |_| subscription.into_future().map_err(|(e, _)| e))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "publish_echo_with_headers::result {:#?}", result);
+    result.unwrap().0.unwrap()
+}
+
+#[test]
+fn can_publish_with_headers() {
+    elog!();
+    let msg = publish_echo_with_headers(1374, true);
+    assert_eq!(msg.payload, "toto");
+    assert_eq!(msg.headers.unwrap().get("Foo"), Some("bar"));
+}
+
+#[test]
+fn can_publish_without_negotiated_headers() {
+    elog!();
+    let msg = publish_echo_with_headers(1375, false);
+    assert_eq!(msg.payload, "toto");
+    assert!(msg.headers.is_none());
+}
+
+#[test]
+fn can_publish_through_a_full_send_buffer() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1376, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1376")
+        .send_buffer_size(1usize)
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    let cmds = (0..50).map(|_| PubCommand::builder().subject("foo").payload("bar").build().unwrap());
+                    let publishes = future::join_all(cmds.clone().map(|cmd| client.publish(cmd)).collect::<Vec<_>>());
+                    publishes
+                        .and_then(move |_| stream::iter_ok(cmds).forward(client.publisher()))
+                        .and_then(move |_| subscription.take(100).collect())
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_publish_through_a_full_send_buffer::result {:#?}", result);
+    assert_eq!(result.unwrap().len(), 100);
+}
+
+/// Accepts a single connection and resets it once the client has sent its CONNECT. Nothing listens on the
+/// port afterwards, so the client cannot reconnect
+fn create_resetting_mock(runtime: &mut tokio::runtime::Runtime, port: usize) -> Result<(), NatsError> {
+    let listener = TcpListener::bind(&format!("127.0.0.1:{}", port).parse()?)?;
+    runtime.spawn(
+        listener
+            .incoming()
+            .take(1)
+            .from_err()
+            .and_then(|socket| {
+                socket.set_linger(Some(Duration::from_secs(0)))?;
+                Ok(OpCodec::default().framed(socket))
+            })
+            .and_then(|socket| {
+                socket.send(Op::INFO(
+                    ServerInfo::builder()
+                        .server_id("nitox-nats")
+                        .version(::std::env::var("CARGO_PKG_VERSION").unwrap())
+                        .go("lol")
+                        .host("127.0.0.1")
+                        .port(4222u32)
+                        .max_payload(::std::u32::MAX)
+                        .build()
+                        .unwrap(),
+                ))
+            })
+            .and_then(|socket| socket.into_future().map_err(|(e, _)| e))
+            .for_each(|_| future::ok(()))
+            .map_err(|_| ()),
+    );
+
+    Ok(())
+}
+
+fn disconnected_client(
+    runtime: &mut tokio::runtime::Runtime,
+    port: usize,
+    policy: DisconnectedPublishPolicy,
+) -> NatsClient {
+    let tcp_res = create_resetting_mock(runtime, port);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri(format!("127.0.0.1:{}", port))
+        .disconnected_publish(policy)
+        .build()
+        .unwrap();
+
+    let client = runtime
+        .block_on(NatsClient::from_options(options).and_then(|client| client.connect()))
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while client.is_connected() {
+        assert!(Instant::now() < deadline, "the client hasn't noticed the disconnection");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    client
+}
+
+fn foo_cmd() -> PubCommand {
+    PubCommand::builder().subject("foo").payload("bar").build().unwrap()
+}
+
+#[test]
+fn can_choose_disconnected_publish_policy() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let client = disconnected_client(&mut runtime, 1377, DisconnectedPublishPolicy::Fail);
+    match runtime.block_on(client.publish(foo_cmd())) {
+        Err(NatsError::ServerDisconnected(None)) => {}
+        r => panic!("Expected ServerDisconnected, got {:?}", r),
+    }
+
+    let client = disconnected_client(&mut runtime, 1378, DisconnectedPublishPolicy::Buffer(1));
+    assert!(runtime.block_on(client.publish(foo_cmd())).is_ok());
+    match runtime.block_on(client.publish(foo_cmd())) {
+        Err(NatsError::ServerDisconnected(None)) => {}
+        r => panic!("Expected ServerDisconnected, got {:?}", r),
+    }
+
+    let client = disconnected_client(&mut runtime, 1379, DisconnectedPublishPolicy::Wait);
+    let timeout = tokio::timer::Delay::new(Instant::now() + Duration::from_millis(100));
+    let waiting = client.publish(foo_cmd()).select2(timeout);
+    match runtime.block_on(waiting) {
+        Ok(future::Either::B(_)) => {}
+        _ => panic!("The message has been published while disconnected"),
+    }
+
+    let _ = runtime.shutdown_now().wait();
+}
+
+#[test]
+fn can_apply_disconnected_publish_policy_to_bulk_publishing() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let client = disconnected_client(&mut runtime, 1421, DisconnectedPublishPolicy::Fail);
+    let mut pipeline = client.pipeline();
+    pipeline.publish(foo_cmd()).unwrap();
+    let results = [
+        runtime.block_on(pipeline.flush()),
+        runtime.block_on(client.publisher().send(foo_cmd()).map(|_| ())),
+        runtime.block_on(client.publish_all(stream::iter_ok(vec![foo_cmd()])).map(|_| ())),
+        runtime.block_on(
+            client
+                .request_multi("foo".into(), "bar".into(), 1, Duration::from_secs(1))
+                .map(|_| ()),
+        ),
+    ];
+    for result in results {
+        match result {
+            Err(NatsError::ServerDisconnected(None)) => {}
+            r => panic!("Expected ServerDisconnected, got {:?}", r),
+        }
+    }
+
+    let client = disconnected_client(&mut runtime, 1422, DisconnectedPublishPolicy::Buffer(2));
+    let mut pipeline = client.pipeline();
+    pipeline.publish(foo_cmd()).unwrap().publish(foo_cmd()).unwrap();
+    let flushed = pipeline
+        .flush()
+        .select2(tokio::timer::Delay::new(Instant::now() + Duration::from_millis(100)));
+    match runtime.block_on(flushed) {
+        Ok(future::Either::B(_)) => {}
+        _ => panic!("The pipeline has been flushed while disconnected"),
+    }
+
+    match runtime.block_on(client.publish(foo_cmd())) {
+        Err(NatsError::ServerDisconnected(None)) => {}
+        r => panic!("Expected ServerDisconnected, got {:?}", r),
+    }
+
+    let _ = runtime.shutdown_now().wait();
+}
+
+#[test]
+fn can_publish_and_flush() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1380, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1380")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    client
+                        .publish_flush(PubCommand::builder().subject("foo").payload("bar").build().unwrap())
+                        .and_then(move |_| subscription.into_future().map_err(|(e, _)| e))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_publish_and_flush::result {:#?}", result);
+    assert_eq!(result.unwrap().0.unwrap().payload, "bar");
+}
+
+#[test]
+fn can_compress_payloads() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1381, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1381")
+        .compress_above(64usize)
+        .build()
+        .unwrap();
+
+    let payload = "toto".repeat(1024);
+    let cmd = PubCommand::builder()
+        .subject("echo")
+        .payload(payload.clone())
+        .build()
+        .unwrap();
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| {
+            client
+                .subscribe(SubCommand::builder().subject("echo").build().unwrap())
+                .and_then(move |subscription| {
+                    client
+                        .publish(cmd)
+                        .and_then(move |_| subscription.into_future().map_err(|(e, _)| e))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_compress_payloads::result {:#?}", result);
+    let msg = result.unwrap().0.unwrap();
+    assert_eq!(msg.payload, payload);
+    assert!(msg.headers.unwrap().get(nitox::CONTENT_ENCODING_HEADER).is_none());
+}
+
+#[test]
+fn can_leave_payloads_compressed_without_opting_in() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1419, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1419")
+        .build()
+        .unwrap();
+
+    // "toto" 16 times, gzipped
+    let gzipped: &[u8] =
+        b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\x2b\xc9\x2f\xa1\x08\x02\x00\x67\xd9\x77\xee\x40\x00\x00\x00";
+    let mut headers = Headers::new();
+    headers.insert(nitox::CONTENT_ENCODING_HEADER, nitox::GZIP_ENCODING);
+    let cmd = PubCommand::builder()
+        .subject("echo")
+        .payload(gzipped)
+        .headers(Some(headers))
+        .build()
+        .unwrap();
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| {
+            client
+                .subscribe(SubCommand::builder().subject("echo").build().unwrap())
+                .and_then(move |subscription| {
+                    client
+                        .publish(cmd)
+                        .and_then(move |_| subscription.into_future().map_err(|(e, _)| e))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_leave_payloads_compressed_without_opting_in::result {:#?}", result);
+    let msg = result.unwrap().0.unwrap();
+    assert_eq!(msg.payload, gzipped);
+    assert_eq!(msg.headers.unwrap().get(nitox::CONTENT_ENCODING_HEADER), Some("gzip"));
+}
+
+#[test]
+fn can_publish_streams() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1382, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1382")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    let cmds = (0..10).map(|_| PubCommand::builder().subject("foo").payload("bar").build().unwrap());
+                    client
+                        .publish_all(stream::iter_ok(cmds))
+                        .and_then(move |published| subscription.take(10).collect().map(move |msgs| (published, msgs)))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_publish_streams::result {:#?}", result);
+    let (published, msgs) = result.unwrap();
+    assert_eq!(published, 10);
+    assert_eq!(msgs.len(), 10);
+}
+
+#[test]
+fn can_publish_on_several_subjects() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1383, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1383")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject(">").build().unwrap())
+                .and_then(move |subscription| {
+                    client
+                        .publish_multi(vec!["foo", "bar", "baz"], "toto".into())
+                        .and_then(move |_| subscription.take(3).collect())
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_publish_on_several_subjects::result {:#?}", result);
+    let subjects: Vec<String> = result.unwrap().into_iter().map(|msg| msg.subject.to_string()).collect();
+    assert_eq!(subjects, vec!["foo", "bar", "baz"]);
+}
+
+#[test]
+fn can_publish_with_templates() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1384, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1384")
+        .build()
+        .unwrap();
+
+    let template = PubTemplate::new("echo", None).unwrap();
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| {
+            client
+                .subscribe(SubCommand::builder().subject("echo").build().unwrap())
+                .and_then(move |subscription| {
+                    client
+                        .publish_template(&template, b"toto")
+                        .and_then(move |_| client.publish_template(&template, b"tata"))
+                        .and_then(move |_| subscription.take(2).collect())
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_publish_with_templates::result {:#?}", result);
+    let payloads: Vec<Vec<u8>> = result.unwrap().into_iter().map(|msg| msg.payload.to_vec()).collect();
+    assert_eq!(payloads, vec![b"toto".to_vec(), b"tata".to_vec()]);
+}
+
+#[test]
+fn can_drain_the_client() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1385, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1385")
+        .will(Some(PubCommand::builder().subject("gone").build().unwrap()))
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .subscribe(SubCommand::builder().subject("foo").build().unwrap())
+                .and_then(move |subscription| {
+                    client
+                        .publish_flush(foo_cmd())
+                        .and_then(move |_| client.drain().map(move |_| client))
+                        .and_then(move |client| subscription.collect().map(move |msgs| (client, msgs)))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_drain_the_client::result {:#?}", result);
+    let (client, msgs) = result.unwrap();
+    // The message received before draining is still delivered, then the stream ends
+    assert_eq!(msgs.len(), 1);
+    // The server replied to the will, which nobody is subscribed to anymore
+    assert_eq!(client.decoder_stats().msgs(), 2);
+}
+
+#[test]
+fn can_request_several_replies() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1386, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1386")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            // The mock server only replies once, the first request ends on its deadline
+            client
+                .request_multi("foo".into(), "bar".into(), 3, Duration::from_millis(200))
+                .and_then(|replies| replies.collect())
+                .and_then(move |waited| {
+                    client
+                        .request_multi("foo".into(), "bar".into(), 1, Duration::from_secs(30))
+                        .and_then(|replies| replies.collect())
+                        .map(move |counted| (waited, counted))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    let start = Instant::now();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_request_several_replies::result {:#?}", result);
+    let (waited, counted) = result.unwrap();
+    assert_eq!(waited.len(), 1);
+    assert_eq!(counted.len(), 1);
+    assert!(start.elapsed() < Duration::from_secs(30));
+}
+
+#[test]
+fn can_mux_requests() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1387, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1387")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let requests: Vec<_> = (0..10).map(|_| client.request("foo".into(), "bar".into())).collect();
+            future::join_all(requests)
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_mux_requests::result {:#?}", result);
+    let replies = result.unwrap();
+    // All the replies come through the same subscription, on the inbox of their own request
+    let sids: HashSet<String> = replies.iter().map(|msg| msg.sid.clone()).collect();
+    let inboxes: HashSet<String> = replies.iter().map(|msg| msg.subject.to_string()).collect();
+    assert_eq!(sids.len(), 1);
+    assert_eq!(inboxes.len(), 10);
+    assert!(inboxes.iter().all(|inbox| inbox.starts_with("_INBOX.")));
+}
+
+#[test]
+fn can_request_typed() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1388, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1388")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .request_typed::<_, Vec<u32>>("echo".into(), &vec![1, 2, 3])
+                .and_then(move |echoed| {
+                    // The mock server replies `bar` to anything else, which isn't JSON
+                    client
+                        .request_typed::<_, Vec<u32>>("foo".into(), &vec![1, 2, 3])
+                        .then(move |res| Ok((echoed, res)))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_request_typed::result {:#?}", result);
+    let (echoed, res) = result.unwrap();
+    assert_eq!(echoed, vec![1, 2, 3]);
+    match res {
+        Err(NatsError::PayloadDecodeError(_)) => {}
+        res => panic!("Expected a PayloadDecodeError, got {:?}", res),
+    }
+}
+
+#[test]
+fn can_time_out_requests() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1389, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder()
+        .headers(Some(true))
+        .no_responders(Some(true))
+        .build()
+        .unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1389")
+        .request_timeout(Some(Duration::from_millis(500)))
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let start = Instant::now();
+            client
+                .request("no-responders".into(), "foo".into())
+                .then(move |res| Ok((res, start.elapsed())))
+                .and_then(move |no_responders| {
+                    // The mock server never replies on that subject
+                    client
+                        .request("silent".into(), "foo".into())
+                        .then(move |res| Ok((no_responders, res)))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_time_out_requests::result {:#?}", result);
+    let ((no_responders, elapsed), silent) = result.unwrap();
+    match no_responders {
+        Err(NatsError::NoResponders) => assert!(elapsed < Duration::from_millis(500)),
+        r => panic!("Expected NoResponders, got {:?}", r),
+    }
+    match silent {
+        Err(NatsError::RequestTimeout(timeout)) => assert_eq!(timeout, Duration::from_millis(500)),
+        r => panic!("Expected RequestTimeout, got {:?}", r),
+    }
+}
+
+#[test]
+fn can_cancel_requests() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1390, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1390")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            // The mock server never replies on that subject
+            let cancelled = client.request("silent".into(), "foo".into());
+            let dropped = client.request("silent".into(), "foo".into());
+            assert_eq!(client.pending_requests(), 2);
+            assert_ne!(cancelled.inbox(), dropped.inbox());
+            cancelled.cancel();
+            assert_eq!(client.pending_requests(), 1);
+            drop(dropped);
+            assert_eq!(client.pending_requests(), 0);
+
+            client
+                .request("foo".into(), "bar".into())
+                .map(move |msg| (msg, client.pending_requests()))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_cancel_requests::result {:#?}", result);
+    let (msg, pending) = result.unwrap();
+    assert_eq!(msg.payload, "bar");
+    assert_eq!(pending, 0);
+}
+
+#[test]
+fn can_fail_requests_when_the_connection_ends() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    // Closes the connection as soon as a request is received
+    let listener = TcpListener::bind(&"127.0.0.1:1391".parse().unwrap()).unwrap();
+    runtime.spawn(
+        listener
+            .incoming()
+            .take(1)
+            .map(|socket| OpCodec::default().framed(socket))
+            .from_err()
+            .and_then(|socket| {
+                socket.send(Op::INFO(
+                    ServerInfo::builder()
+                        .server_id("nitox-nats")
+                        .version(::std::env::var("CARGO_PKG_VERSION").unwrap())
+                        .go("lol")
+                        .host("127.0.0.1")
+                        .port(4222u32)
+                        .max_payload(::std::u32::MAX)
+                        .build()
+                        .unwrap(),
+                ))
+            })
+            .and_then(|socket| {
+                socket
+                    .skip_while(|op| Ok(!matches!(op, Op::PUB(_))))
+                    .into_future()
+                    .map_err(|(e, _)| e)
+            })
+            .for_each(|_| future::ok(()))
+            .map_err(|_| ()),
+    );
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1391")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| client.request("foo".into(), "bar".into()));
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_fail_requests_when_the_connection_ends::result {:#?}", result);
+    match result {
+        Err(NatsError::NoReply(inbox)) => assert!(inbox.starts_with("_INBOX.")),
+        r => panic!("Expected NoReply, got {:?}", r),
+    }
+}
+
+#[test]
+fn can_serve_requests() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1392, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1392")
+        .build()
+        .unwrap();
+
+    let (answers_tx, answers_rx) = mpsc::unbounded();
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| {
+            // The mock server delivers the answers to the service itself, which discards them
+            client.intercept(move |msg| {
+                if &*msg.subject == "answer" {
+                    let _ = answers_tx.unbounded_send(msg.payload.clone());
+                }
+
+                Some(msg.clone())
+            });
+
+            client
+                .serve(SubCommand::builder().subject("ask").build().unwrap(), 4, |msg| {
+                    let mut answer = msg.payload.to_vec();
+                    answer.reverse();
+                    Ok(answer.into())
+                })
+                .and_then(move |responder| {
+                    // Without a reply subject
+                    client
+                        .publish(PubCommand::builder().subject("foo").payload("oof").build().unwrap())
+                        .and_then(move |_| {
+                            client.publish(PubCommand::builder().subject("ask").payload("foo").build().unwrap())
+                        })
+                        .and_then(move |_| answers_rx.into_future().map_err(|_| NatsError::InnerBrokenChain))
+                        .and_then(move |(answer, _)| responder.drain().map(move |_| answer))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    debug!(target: "nitox", "can_serve_requests::result {:#?}", result);
+    let _ = runtime.shutdown_now().wait();
+    // The mock server asks with `bar` as payload
+    assert_eq!(result.unwrap().unwrap(), "rab");
+}
+
+#[test]
+fn can_request_with_per_request_inboxes() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1393, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1393")
+        .request_style(RequestStyle::PerRequest)
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client.request("foo".into(), "bar".into()).and_then(move |first| {
+                client
+                    .request("foo".into(), "bar".into())
+                    .map(move |second| (first, second))
+            })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_request_with_per_request_inboxes::result {:#?}", result);
+    let (first, second) = result.unwrap();
+    // Each reply is received on the subscription of its own inbox
+    assert_ne!(first.sid, second.sid);
+    assert!(!first.subject.starts_with("_INBOX."));
+    assert_eq!(second.payload, "bar");
+}
+
+#[test]
+fn can_retry_requests() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1394, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder()
+        .headers(Some(true))
+        .no_responders(Some(true))
+        .build()
+        .unwrap();
+    let retry = RequestRetryPolicy {
+        backoff: Duration::from_millis(50),
+        on_timeout: false,
+        ..RequestRetryPolicy::default()
+    };
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1394")
+        .request_timeout(Some(Duration::from_millis(200)))
+        .request_retry(Some(retry))
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let start = Instant::now();
+            client
+                .request("no-responders".into(), "foo".into())
+                .then(move |res| Ok((res, start.elapsed())))
+                .and_then(move |no_responders| {
+                    let msgs = client.decoder_stats().msgs();
+                    // Timeouts aren't retried by that policy
+                    client
+                        .request("silent".into(), "foo".into())
+                        .then(move |res| Ok((no_responders, msgs, res)))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_retry_requests::result {:#?}", result);
+    let ((no_responders, elapsed), msgs, silent) = result.unwrap();
+    match no_responders {
+        Err(NatsError::NoResponders) => {}
+        r => panic!("Expected NoResponders, got {:?}", r),
+    }
+    // Three attempts, waiting 50ms then 100ms between them
+    assert_eq!(msgs, 3);
+    assert!(elapsed >= Duration::from_millis(150));
+    match silent {
+        Err(NatsError::RequestTimeout(_)) => {}
+        r => panic!("Expected RequestTimeout, got {:?}", r),
+    }
+}
+
+#[test]
+fn can_correlate_requests() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1395, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1395")
+        .correlation_ids(true)
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            // The mock server echoes the headers of the request in its reply
+            let request = client.request("echo".into(), "foo".into());
+            let correlation_id = request.correlation_id().map(String::from);
+            request
+                .map(move |reply| (correlation_id, reply))
+                .and_then(move |single| {
+                    client
+                        .request_multi("echo".into(), "foo".into(), 1, Duration::from_secs(30))
+                        .and_then(|replies| {
+                            let correlation_id = replies.correlation_id().map(String::from);
+                            replies.collect().map(move |replies| (correlation_id, replies))
+                        })
+                        .map(move |multi| (single, multi))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_correlate_requests::result {:#?}", result);
+    let ((correlation_id, reply), (multi_correlation_id, replies)) = result.unwrap();
+    assert!(correlation_id.is_some());
+    assert_eq!(reply.correlation_id(), correlation_id.as_deref());
+    assert!(multi_correlation_id.is_some());
+    assert_ne!(multi_correlation_id, correlation_id);
+    assert_eq!(replies[0].correlation_id(), multi_correlation_id.as_deref());
+}
+
+#[test]
+fn can_request_a_quorum() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1396, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1396")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            client
+                .request_fastest("foo".into(), "bar".into(), Duration::from_secs(30))
+                .and_then(move |fastest| {
+                    // The mock server only replies once
+                    client
+                        .request_quorum("foo".into(), "bar".into(), 2, Duration::from_millis(200))
+                        .then(move |quorum| Ok((fastest, quorum)))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_request_a_quorum::result {:#?}", result);
+    let (fastest, quorum) = result.unwrap();
+    assert_eq!(fastest.payload, "bar");
+    match quorum {
+        Err(NatsError::RequestTimeout(timeout)) => assert_eq!(timeout, Duration::from_millis(200)),
+        r => panic!("Expected RequestTimeout, got {:?}", r),
+    }
+}
+
+#[test]
+fn can_wrap_requests_with_middleware() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1397, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1397")
+        .build()
+        .unwrap();
+
+    let completed = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let completed_inner = std::sync::Arc::clone(&completed);
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| {
+            client.before_request(|cmd| {
+                cmd.headers.get_or_insert_with(Headers::new).insert("Tenant", "acme");
+            });
+            client.after_request(move |_, res, _| {
+                completed_inner.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                match res {
+                    Ok(ref msg) if msg.payload == "bar" => Err(NatsError::GenericError("invalid reply".into())),
+                    res => res,
+                }
+            });
+
+            // The mock server echoes the headers of the request in its reply
+            client.request("echo".into(), "foo".into()).and_then(move |echoed| {
+                client
+                    .request("foo".into(), "foo".into())
+                    .then(|res| Ok((echoed, res)))
+                    .and_then(move |single| {
+                        client
+                            .request_quorum("echo".into(), "foo".into(), 1, Duration::from_secs(30))
+                            .and_then(move |quorum| {
+                                client
+                                    .request_fastest("foo".into(), "foo".into(), Duration::from_secs(30))
+                                    .then(|res| Ok((single, quorum, res)))
+                            })
+                    })
+            })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_wrap_requests_with_middleware::result {:#?}", result);
+    let ((echoed, rejected), quorum, fastest) = result.unwrap();
+    assert_eq!(echoed.headers.unwrap().get("Tenant"), Some("acme"));
+    match rejected {
+        Err(NatsError::GenericError(e)) => assert_eq!(e, "invalid reply"),
+        r => panic!("Expected GenericError, got {:?}", r),
+    }
+    assert_eq!(quorum.len(), 1);
+    assert_eq!(quorum[0].headers.as_ref().unwrap().get("Tenant"), Some("acme"));
+    match fastest {
+        Err(NatsError::GenericError(e)) => assert_eq!(e, "invalid reply"),
+        r => panic!("Expected GenericError, got {:?}", r),
+    }
+    assert_eq!(completed.load(std::sync::atomic::Ordering::SeqCst), 4);
+}
+
+#[test]
+fn can_call_the_jetstream_api() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1398, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1398")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            js.account_info().and_then(move |info| {
+                js.request::<AccountInfo>("NOPE", "".into())
+                    .then(move |res| Ok((info, res)))
+            })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_call_the_jetstream_api::result {:#?}", result);
+    let (info, res) = result.unwrap();
+    assert_eq!(info.storage, 1024);
+    assert_eq!(info.streams, 1);
+    match res {
+        Err(NatsError::JetStreamError(e)) => assert_eq!(e.kind(), JsErrorCode::BadRequest),
+        r => panic!("Expected JetStreamError, got {:?}", r),
+    }
+}
+
+#[test]
+fn can_manage_jetstream_streams() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1399, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1399")
+        .build()
+        .unwrap();
+
+    let config = StreamConfig::builder()
+        .name("ORDERS")
+        .subjects(vec!["orders.>".into()])
+        .storage(StorageType::Memory)
+        .max_msgs(1000)
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            let mut invalid = config.clone();
+            invalid.name = "ORDERS.*".into();
+            assert!(matches!(
+                js.add_stream(&invalid).wait(),
+                Err(NatsError::CommandBuildError(_))
+            ));
+            assert!(matches!(
+                js.update_stream(&invalid).wait(),
+                Err(NatsError::CommandBuildError(_))
+            ));
+            js.add_stream(&config)
+                .join4(
+                    js.stream_info("missing").then(Ok),
+                    js.delete_stream("ORDERS"),
+                    js.list_streams(),
+                )
+                .map(|(added, missing, deleted, streams)| (config, added, missing, deleted, streams))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_manage_jetstream_streams::result {:#?}", result);
+    let (config, added, missing, deleted, streams) = result.unwrap();
+    assert_eq!(added.config, config);
+    assert_eq!(added.state.messages, 3);
+    match missing {
+        Err(NatsError::JetStreamError(e)) => assert_eq!(e.kind(), JsErrorCode::StreamNotFound),
+        r => panic!("Expected JetStreamError, got {:?}", r),
+    }
+    assert!(deleted);
+    let names: Vec<_> = streams.into_iter().map(|info| info.config.name).collect();
+    assert_eq!(names, vec!["A", "B", "C"]);
+}
+
+#[test]
+fn can_manage_jetstream_consumers() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1400, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1400")
+        .build()
+        .unwrap();
+
+    let config = ConsumerConfig::builder()
+        .durable_name("worker".to_string())
+        .ack_wait(Duration::from_secs(5))
+        .max_deliver(3)
+        .filter_subject("orders.new".to_string())
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            js.add_consumer("ORDERS", &config)
+                .join4(
+                    js.consumer_info("ORDERS", "missing").then(Ok),
+                    js.delete_consumer("ORDERS", "worker"),
+                    js.list_consumers("ORDERS"),
+                )
+                .map(|(added, missing, deleted, consumers)| (config, added, missing, deleted, consumers))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_manage_jetstream_consumers::result {:#?}", result);
+    let (config, added, missing, deleted, consumers) = result.unwrap();
+    assert_eq!(added.stream_name, "ORDERS");
+    assert_eq!(added.name, "worker");
+    assert_eq!(added.config, config);
+    assert_eq!(added.num_pending, 5);
+    match missing {
+        Err(NatsError::JetStreamError(e)) => assert_eq!(e.kind(), JsErrorCode::ConsumerNotFound),
+        r => panic!("Expected JetStreamError, got {:?}", r),
+    }
+    assert!(deleted);
+    assert_eq!(consumers.len(), 1);
+    assert_eq!(consumers[0].config.durable_name, Some("worker".into()));
+}
+
+#[test]
+fn can_publish_to_jetstream() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1401, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1401")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            js.publish("js.orders".into(), "foo".into())
+                .and_then(move |ack| js.publish("js.full".into(), "foo".into()).then(|res| Ok((ack, res))))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_publish_to_jetstream::result {:#?}", result);
+    let (ack, rejected) = result.unwrap();
+    assert_eq!(ack.stream, "JS");
+    assert_eq!(ack.sequence, 7);
+    assert!(!ack.duplicate);
+    match rejected {
+        Err(NatsError::JetStreamError(e)) => assert_eq!(e.code, 503),
+        r => panic!("Expected JetStreamError, got {:?}", r),
+    }
+}
+
+#[test]
+fn can_fetch_from_pull_consumers() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1402, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1402")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            let consumer = js.pull_consumer("ORDERS", "worker").unwrap();
+            consumer
+                .fetch(10, Duration::from_secs(0))
+                .and_then(|batch| batch.collect())
+                .and_then(move |drained| {
+                    consumer
+                        .fetch(1, Duration::from_secs(5))
+                        .and_then(|batch| batch.collect())
+                        .map(|single| (drained, single))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_fetch_from_pull_consumers::result {:#?}", result);
+    let (drained, single) = result.unwrap();
+    let payloads: Vec<_> = drained.iter().map(|msg| msg.payload.to_vec()).collect();
+    assert_eq!(payloads, vec![b"msg 1".to_vec(), b"msg 2".to_vec()]);
+    assert_eq!(single.len(), 1);
+    assert_eq!(single[0].payload, "msg 1");
+}
+
+#[test]
+fn can_subscribe_to_push_consumers() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1403, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1403")
+        .build()
+        .unwrap();
+
+    let config = ConsumerConfig::builder()
+        .durable_name("worker".to_string())
+        .deliver_subject("push.orders".to_string())
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| {
+            let client = std::sync::Arc::new(client);
+            let js = JsContext::new(std::sync::Arc::clone(&client));
+            js.subscribe("ORDERS", &config)
+                .and_then(move |subscription| {
+                    let publishes = vec!["3", "4"].into_iter().map(move |seq| {
+                        client.publish(
+                            PubCommand::builder()
+                                .subject("push.orders")
+                                .payload(seq)
+                                .build()
+                                .unwrap(),
+                        )
+                    });
+                    future::join_all(publishes).map(move |_| subscription)
+                })
+                .and_then(|subscription| subscription.into_future().map_err(|(e, _)| e))
+                .and_then(|(first, subscription)| {
+                    subscription
+                        .into_future()
+                        .map_err(|(e, _)| e)
+                        .map(move |(second, subscription)| (first, second, subscription))
+                })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_subscribe_to_push_consumers::result {:#?}", result);
+    let (first, second, subscription) = result.unwrap();
+    assert_eq!(first.unwrap().payload, "3");
+    assert_eq!(second.unwrap().payload, "4");
+    assert_eq!(subscription.subject(), "push.orders");
+    assert_eq!(subscription.info().name, "worker");
+    assert_eq!(subscription.stream_sequence(), 4);
+    assert_eq!(subscription.consumer_sequence(), 4);
+}
+
+#[test]
+fn can_acknowledge_jetstream_messages() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1404, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1404")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            js.pull_consumer("ORDERS", "worker")
+                .unwrap()
+                .fetch(1, Duration::from_secs(5))
+                .and_then(|batch| batch.into_future().map_err(|(e, _)| e))
+        })
+        .and_then(|(msg, _)| {
+            let msg = msg.unwrap();
+            msg.in_progress()
+                .join4(msg.nak(Some(Duration::from_secs(1))), msg.nak(None), msg.term())
+                .and_then(move |_| msg.ack().map(move |_| msg))
+                .and_then(|msg| msg.ack_sync().map(move |_| msg))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_acknowledge_jetstream_messages::result {:#?}", result);
+    let msg = result.unwrap();
+    assert_eq!(msg.payload, "msg 1");
+    let meta = msg.metadata().unwrap();
+    assert_eq!(meta.stream, "ORDERS");
+    assert_eq!(meta.consumer, "worker");
+    assert_eq!(meta.delivered, 1);
+    assert_eq!(meta.stream_sequence, 1);
+    assert_eq!(meta.pending, 1);
+}
+
+#[test]
+fn can_use_key_value_stores() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1405, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1405")
+        .build()
+        .unwrap();
+
+    let config = kv::KvConfig::builder().bucket("config").history(5).build().unwrap();
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| JsContext::new(std::sync::Arc::new(client)).create_bucket(&config))
+        .and_then(|store| {
+            store
+                .get("port")
+                .join3(store.get("missing"), store.get("deleted"))
+                .map(move |entries| (store, entries))
+        })
+        .and_then(|(store, entries)| {
+            let revisions = store.put("port", "8080".into()).join4(
+                store.update("port", "8081".into(), 1),
+                store.delete("port"),
+                store.purge("port"),
+            );
+            let conflict = store.create("port", "8082".into()).then(Ok);
+            revisions
+                .join(conflict)
+                .map(move |(revisions, conflict)| (entries, revisions, conflict))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_use_key_value_stores::result {:#?}", result);
+    let ((port, missing, deleted), revisions, conflict) = result.unwrap();
+    let port = port.unwrap();
+    assert_eq!(port.bucket, "config");
+    assert_eq!(port.key, "port");
+    assert_eq!(port.value, "value");
+    assert_eq!(port.revision, 1);
+    assert_eq!(port.operation, kv::KvOperation::Put);
+    assert!(missing.is_none());
+    assert!(deleted.is_none());
+    assert_eq!(revisions, (2, 2, 2, 2));
+    match conflict {
+        Err(NatsError::JetStreamError(e)) => assert_eq!(e.kind(), JsErrorCode::WrongLastSequence),
+        r => panic!("Expected JetStreamError, got {:?}", r),
+    }
+}
+
+#[test]
+fn can_use_object_stores() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1406, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1406")
+        .build()
+        .unwrap();
+
+    let config = object_store::ObjectStoreConfig::builder()
+        .bucket("files")
+        .build()
+        .unwrap();
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| JsContext::new(std::sync::Arc::new(client)).create_object_store(&config))
+        .and_then(|store| {
+            let store = store.with_chunk_size(4);
+            let data = stream::iter_ok(vec!["0123456".into(), "789".into()]);
+            store.put("blob", data).map(move |info| (store, info))
+        })
+        .and_then(|(store, info)| {
+            let download = store.get("blob").and_then(|reader| reader.unwrap().concat2());
+            let torn = store.get("torn").and_then(|reader| reader.unwrap().concat2()).then(Ok);
+            download
+                .join4(
+                    store.get("missing"),
+                    store.delete("missing").join(store.delete("blob")),
+                    torn,
+                )
+                .map(move |(data, missing, deleted, torn)| (info, data, missing.is_none(), deleted, torn))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_use_object_stores::result {:#?}", result);
+    let (info, data, missing, deleted, torn) = result.unwrap();
+    assert_eq!(info.name, "blob");
+    assert_eq!(info.bucket, "files");
+    assert_eq!(info.size, 10);
+    assert_eq!(info.chunks, 3);
+    assert_eq!(data, "0123456789");
+    assert!(missing);
+    assert_eq!(deleted, (false, true));
+    match torn {
+        Err(NatsError::PayloadDecodeError(_)) => {}
+        r => panic!("Expected PayloadDecodeError, got {:?}", r),
+    }
+}
+
+#[test]
+fn can_publish_deduplicated_messages() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1407, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1407")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            let new = PublishOptions::builder().msg_id("new".to_string()).build().unwrap();
+            let dup = PublishOptions::builder().msg_id("dup".to_string()).build().unwrap();
+            js.publish_with_options("js.orders".into(), &new, "foo".into())
+                .join(js.publish_with_options("js.orders".into(), &dup, "foo".into()))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_publish_deduplicated_messages::result {:#?}", result);
+    let (stored, duplicate) = result.unwrap();
+    assert_eq!(stored.sequence, 7);
+    assert!(!stored.duplicate);
+    assert_eq!(duplicate.sequence, 6);
+    assert!(duplicate.duplicate);
+}
+
+#[test]
+fn can_guard_jetstream_publishes() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1408, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1408")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            let expected = PublishOptions::builder()
+                .expected_stream("JS".to_string())
+                .expected_last_sequence(6)
+                .build()
+                .unwrap();
+            let other_stream = PublishOptions::builder()
+                .expected_stream("OTHER".to_string())
+                .build()
+                .unwrap();
+            let outdated = PublishOptions::builder().expected_last_sequence(3).build().unwrap();
+            js.publish_with_options("js.orders".into(), &expected, "foo".into())
+                .join3(
+                    js.publish_with_options("js.orders".into(), &other_stream, "foo".into())
+                        .then(Ok),
+                    js.publish_with_options("js.orders".into(), &outdated, "foo".into())
+                        .then(Ok),
+                )
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_guard_jetstream_publishes::result {:#?}", result);
+    let (ack, other_stream, outdated) = result.unwrap();
+    assert_eq!(ack.sequence, 7);
+    match other_stream {
+        Err(NatsError::JetStreamError(e)) => assert_eq!(e.kind(), JsErrorCode::StreamNotMatch),
+        r => panic!("Expected JetStreamError, got {:?}", r),
+    }
+    match outdated {
+        Err(NatsError::JetStreamError(e)) => assert_eq!(e.kind(), JsErrorCode::WrongLastSequence),
+        r => panic!("Expected JetStreamError, got {:?}", r),
+    }
+}
+
+#[test]
+fn can_detect_stalled_push_consumers() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1409, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1409")
+        .build()
+        .unwrap();
+
+    let config = ConsumerConfig::builder()
+        .deliver_subject("push.heartbeats".to_string())
+        .idle_heartbeat(Duration::from_millis(100))
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| {
+            let client = std::sync::Arc::new(client);
+            let js = JsContext::new(std::sync::Arc::clone(&client));
+            js.subscribe("ORDERS", &config)
+                .and_then(move |subscription| {
+                    let heartbeat = PubCommand::builder()
+                        .subject("push.heartbeats")
+                        .payload("heartbeat")
+                        .build()
+                        .unwrap();
+                    client.publish(heartbeat).map(move |_| subscription)
+                })
+                .and_then(|subscription| subscription.into_future().then(Ok))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_detect_stalled_push_consumers::result {:#?}", result);
+    match result.unwrap() {
+        Err((NatsError::MissedHeartbeats(timeout), subscription)) => {
+            assert_eq!(timeout, Duration::from_millis(200));
+            assert!(subscription.last_heartbeat().is_some());
+        }
+        r => panic!("Expected MissedHeartbeats, got {:?}", r),
+    }
+}
+
+#[test]
+fn can_answer_flow_control_requests() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1410, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1410")
+        .build()
+        .unwrap();
+
+    assert!(ConsumerConfig::builder().flow_control(true).build().is_err());
+    let config = ConsumerConfig::builder()
+        .deliver_subject("push.flow".to_string())
+        .idle_heartbeat(Duration::from_secs(5))
+        .flow_control(true)
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| {
+            let client = std::sync::Arc::new(client);
+            let js = JsContext::new(std::sync::Arc::clone(&client));
+            js.subscribe("ORDERS", &config)
+                .and_then(move |subscription| {
+                    let request = PubCommand::builder()
+                        .subject("push.flow")
+                        .payload("flow-control")
+                        .build()
+                        .unwrap();
+                    client.publish(request).map(move |_| subscription)
+                })
+                .and_then(|subscription| subscription.into_future().map_err(|(e, _)| e))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_answer_flow_control_requests::result {:#?}", result);
+    let (msg, subscription) = result.unwrap();
+    assert_eq!(msg.unwrap().payload, "resumed");
+    assert_eq!(subscription.stream_sequence(), 2);
+}
+
+#[test]
+fn can_call_the_jetstream_api_of_a_domain() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1411, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1411")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            assert_eq!(js.clone().with_domain("hub").with_domain("").prefix(), "$JS.API");
+            let js = js.with_domain("hub");
+            assert_eq!(js.prefix(), "$JS.hub.API");
+            assert_eq!(js.domain(), Some("hub"));
+            js.account_info().join(js.stream_info("ORDERS"))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_call_the_jetstream_api_of_a_domain::result {:#?}", result);
+    let (info, stream) = result.unwrap();
+    assert_eq!(info.storage, 1024);
+    assert_eq!(stream.config.name, "ORDERS");
+}
+
+#[test]
+fn can_get_stored_messages() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1412, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1412")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            let classic = js.get_message("ORDERS", 1).join3(
+                js.get_last_message_for_subject("ORDERS", "orders.new"),
+                js.get_message("ORDERS", 2).then(Ok),
+            );
+            let direct = js.direct_get_message("ORDERS", 1).join3(
+                js.direct_get_last_message_for_subject("ORDERS", "orders.new"),
+                js.direct_get_message("ORDERS", 2).then(Ok),
+            );
+            classic.join(direct)
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_get_stored_messages::result {:#?}", result);
+    let (classic, direct) = result.unwrap();
+    for (by_seq, by_subject, missing) in [classic, direct] {
+        assert_eq!(by_seq, by_subject);
+        assert_eq!(by_seq.subject, "orders.new");
+        assert_eq!(by_seq.sequence, 1);
+        assert_eq!(by_seq.payload, "msg 1");
+        assert_eq!(by_seq.time, "2018-10-01T00:00:00Z");
+        assert_eq!(by_seq.headers.unwrap().get("Nats-Msg-Id"), Some("order-1"));
+        match missing {
+            Err(NatsError::JetStreamError(e)) => assert_eq!(e.kind(), JsErrorCode::NoMessageFound),
+            r => panic!("Expected JetStreamError, got {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn can_purge_streams_and_delete_messages() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1413, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1413")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            let filtered = PurgeOptions::builder()
+                .filter("orders.new".to_string())
+                .build()
+                .unwrap();
+            let kept = PurgeOptions::builder().keep(4).build().unwrap();
+            let up_to = PurgeOptions::builder().sequence(8).build().unwrap();
+            let purged = js.purge_stream("ORDERS", &PurgeOptions::default()).join4(
+                js.purge_stream("ORDERS", &filtered),
+                js.purge_stream("ORDERS", &kept),
+                js.purge_stream("ORDERS", &up_to),
+            );
+            let deleted = js
+                .delete_message("ORDERS", 1)
+                .join(js.delete_message("ORDERS", 2).then(Ok));
+            purged.join(deleted)
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_purge_streams_and_delete_messages::result {:#?}", result);
+    let (purged, (deleted, missing)) = result.unwrap();
+    assert_eq!(purged, (10, 3, 6, 7));
+    assert!(deleted);
+    match missing {
+        Err(NatsError::JetStreamError(e)) => assert_eq!(e.kind(), JsErrorCode::SequenceNotFound),
+        r => panic!("Expected JetStreamError, got {:?}", r),
+    }
+}
+
+#[test]
+fn can_watch_key_value_stores() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1414, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1414")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            let kv = js.key_value("config").unwrap();
+            kv.watch(">")
+                .and_then(|watcher| watcher.take(3).collect())
+                .join(kv.watch("services..port").then(Ok))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_watch_key_value_stores::result {:#?}", result);
+    let (entries, invalid) = result.unwrap();
+    let revisions: Vec<_> = entries
+        .iter()
+        .map(|entry| (entry.key.as_str(), entry.revision, entry.operation))
+        .collect();
+    assert_eq!(
+        revisions,
+        vec![
+            ("port", 1, kv::KvOperation::Put),
+            ("port", 2, kv::KvOperation::Delete),
+            ("host", 3, kv::KvOperation::Put),
+        ]
+    );
+    assert_eq!(entries[0].value, "8080");
+    assert_eq!(entries[0].bucket, "config");
+    assert_eq!(entries[2].created, "2018-10-01T00:00:00Z");
+    match invalid {
+        Err(NatsError::CommandBuildError(_)) => {}
+        r => panic!("Expected CommandBuildError, got {:?}", r),
+    }
+}
+
+#[test]
+fn can_pause_consumers() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1415, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1415")
+        .build()
+        .unwrap();
+
+    let until = std::time::UNIX_EPOCH + Duration::from_secs(1_538_352_060);
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            js.pause_consumer("ORDERS", "worker", until).and_then(move |paused| {
+                js.resume_consumer("ORDERS", "worker")
+                    .map(move |resumed| (paused, resumed))
+            })
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_pause_consumers::result {:#?}", result);
+    let (paused, resumed) = result.unwrap();
+    assert!(paused.paused);
+    assert_eq!(paused.pause_until.unwrap(), "2018-10-01T00:01:00Z");
+    assert_eq!(paused.pause_remaining, Duration::from_secs(60));
+    assert!(!resumed.paused);
+    assert_eq!(resumed.pause_remaining, Duration::default());
+}
+
+#[test]
+fn can_retry_jetstream_publishes_during_elections() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1416, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder()
+        .headers(Some(true))
+        .no_responders(Some(true))
+        .build()
+        .unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1416")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            let impatient = js.clone().with_publish_retry(PublishRetry {
+                attempts: 1,
+                wait: Duration::from_millis(10),
+            });
+            let start = Instant::now();
+            js.publish("js.election.a".into(), "msg".into())
+                .map(move |ack| (ack, start.elapsed()))
+                .join(impatient.publish("js.election.b".into(), "msg".into()).then(Ok))
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_retry_jetstream_publishes_during_elections::result {:#?}", result);
+    let ((ack, elapsed), impatient) = result.unwrap();
+    assert_eq!(ack.stream, "JS");
+    assert!(elapsed >= Duration::from_millis(500));
+    match impatient {
+        Err(NatsError::NoResponders) => {}
+        r => panic!("Expected NoResponders, got {:?}", r),
+    }
+}
+
+#[test]
+fn can_read_the_history_of_keys() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1417, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1417")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            let kv = js.key_value("config").unwrap();
+            let history = kv.history("port").join(kv.history("missing"));
+            let revisions = kv.get_revision("port", 1).join4(
+                kv.get_revision("port", 2),
+                kv.get_revision("port", 3),
+                kv.get_revision("port", 9),
+            );
+            history.join(revisions)
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_read_the_history_of_keys::result {:#?}", result);
+    let ((history, missing), (first, deleted, other_key, removed)) = result.unwrap();
+    let revisions: Vec<_> = history.iter().map(|entry| (entry.revision, entry.operation)).collect();
+    assert_eq!(revisions, vec![(1, kv::KvOperation::Put), (2, kv::KvOperation::Delete)]);
+    assert_eq!(history[0].value, "8080");
+    assert!(missing.is_empty());
+    let first = first.unwrap();
+    assert_eq!((first.key.as_str(), first.revision), ("port", 1));
+    assert_eq!(first.value, "8080");
+    assert!(deleted.is_none());
+    assert!(other_key.is_none());
+    assert!(removed.is_none());
+}
+
+#[test]
+fn can_fetch_batches_with_options() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1418, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().headers(Some(true)).build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1418")
+        .build()
+        .unwrap();
+
+    let limited = FetchOptions::builder()
+        .batch(10)
+        .max_bytes(7)
+        .expires(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    let waiting = FetchOptions::builder()
+        .batch(10)
+        .expires(Duration::from_secs(5))
+        .idle_heartbeat(Duration::from_millis(100))
+        .build()
+        .unwrap();
+    let oversized = FetchOptions::builder().batch(1000).build().unwrap();
+    let empty = FetchOptions {
+        batch: 0,
+        ..oversized.clone()
+    };
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(move |client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            let worker = js.pull_consumer("ORDERS", "worker").unwrap();
+            let stalled = js.pull_consumer("ORDERS", "stalled").unwrap();
+            let collect = |fetch: Fetch| fetch.collect().then(Ok::<_, NatsError>);
+            worker
+                .fetch_with_options(&limited)
+                .and_then(collect)
+                .join3(
+                    worker.fetch_with_options(&waiting).and_then(collect),
+                    worker.fetch_with_options(&oversized).and_then(collect),
+                )
+                .join4(
+                    stalled.fetch_with_options(&waiting).and_then(collect),
+                    worker.fetch_with_options(&empty).then(Ok::<_, NatsError>),
+                    worker.fetch(0, Duration::from_secs(1)).then(Ok::<_, NatsError>),
+                )
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "can_fetch_batches_with_options::result {:#?}", result);
+    let ((limited, waiting, oversized), stalled, empty, zero) = result.unwrap();
+    let limited = limited.unwrap();
+    assert_eq!(limited.len(), 1);
+    assert_eq!(limited[0].payload, "msg 1");
+    assert_eq!(waiting.unwrap().len(), 2);
+    match oversized {
+        Err(NatsError::JetStreamError(e)) => {
+            assert_eq!(e.code, 409);
+            assert_eq!(e.description, "Exceeded MaxRequestBatch of 256");
+        }
+        r => panic!("Expected JetStreamError, got {:?}", r),
+    }
+    match stalled {
+        Err(NatsError::MissedHeartbeats(timeout)) => assert_eq!(timeout, Duration::from_millis(200)),
+        r => panic!("Expected MissedHeartbeats, got {:?}", r),
+    }
+    for fetch in [empty, zero] {
+        match fetch {
+            Err(NatsError::CommandBuildError(_)) => {}
+            r => panic!("Expected CommandBuildError, got {:?}", r),
+        }
+    }
+
+    let invalid = FetchOptions::builder()
+        .expires(Duration::from_secs(1))
+        .idle_heartbeat(Duration::from_secs(1))
+        .build();
+    assert!(invalid.is_err());
+    assert!(FetchOptions::builder().batch(0).build().is_err());
+}
+
+#[test]
+fn cannot_change_keys_and_objects_without_headers() {
+    elog!();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let tcp_res = create_tcp_mock(&mut runtime, 1420, None);
+    assert!(tcp_res.is_ok());
+
+    let connect_cmd = ConnectCommand::builder().build().unwrap();
+    let options = NatsClientOptions::builder()
+        .connect_command(connect_cmd)
+        .cluster_uri("127.0.0.1:1420")
+        .build()
+        .unwrap();
+
+    let fut = NatsClient::from_options(options)
+        .and_then(|client| client.connect())
+        .and_then(|client| {
+            let js = JsContext::new(std::sync::Arc::new(client));
+            let kv = js.key_value("config").unwrap();
+            let objects = js.object_store("files").unwrap();
+            let data = stream::iter_ok(vec!["0123456".into()]);
+            let changes = kv.delete("port").map(|_| ()).then(Ok::<_, NatsError>).join5(
+                kv.purge("port").map(|_| ()).then(Ok),
+                kv.create("port", "8082".into()).map(|_| ()).then(Ok),
+                kv.update("port", "8081".into(), 1).map(|_| ()).then(Ok),
+                objects.put("blob", data).map(|_| ()).then(Ok),
+            );
+            kv.put("port", "8080".into()).join(changes)
+        });
+
+    let (tx, rx) = oneshot::channel();
+    runtime.spawn(fut.then(|r| tx.send(r).map_err(|e| panic!("Cannot send Result {:?}", e))));
+    let result = rx.wait().expect("Cannot wait for a result");
+    let _ = runtime.shutdown_now().wait();
+    debug!(target: "nitox", "cannot_change_keys_and_objects_without_headers::result {:#?}", result);
+    let (revision, (delete, purge, create, update, put)) = result.unwrap();
+    assert_eq!(revision, 2);
+    for change in [delete, purge, create, update, put] {
+        match change {
+            Err(NatsError::CommandBuildError(_)) => {}
+            r => panic!("Expected CommandBuildError, got {:?}", r),
+        }
+    }
 }